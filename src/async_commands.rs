@@ -0,0 +1,525 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Async versions of the commands, for use with an `AsyncTransport`
+//!
+//! They send the same requests as the `*_transport` functions. Because the
+//! transports match responses by sequence number, commands for different
+//! devices, or independent commands for one device, can run concurrently.
+
+use anyhow::{bail, Context, Error, Result};
+use log::{debug, info};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::async_transport::AsyncTransport;
+use crate::firmware::load_image;
+use crate::image::{check_erase, DEFAULT_ERASE_SLOT};
+use crate::nmp_hdr::*;
+
+fn get_rc(response_body: &serde_cbor::Value) -> Option<i64> {
+    if let serde_cbor::Value::Map(object) = response_body {
+        if let Some(serde_cbor::Value::Integer(rc)) =
+            object.get(&serde_cbor::Value::Text("rc".to_string()))
+        {
+            return Some(*rc as i64);
+        }
+    }
+    None
+}
+
+/// Send a request, check the result code and decode the response
+async fn request<T, Req, Rsp>(
+    transport: &T,
+    op: NmpOp,
+    group: NmpGroup,
+    id: impl NmpId,
+    req: &Req,
+) -> Result<Rsp, Error>
+where
+    T: AsyncTransport,
+    Req: Serialize,
+    Rsp: DeserializeOwned,
+{
+    let body = serde_cbor::to_vec(req)?;
+    let (_response_header, response_body) =
+        transport.transceive(op, group, id.to_u8(), &body).await?;
+
+    debug!("response_body: {}", serde_json::to_string_pretty(&response_body)?);
+
+    if let Some(rc) = get_rc(&response_body) {
+        if rc != 0 {
+            bail!("Error from device: rc={}", rc);
+        }
+    }
+
+    serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))
+}
+
+fn empty_map() -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+
+// ==================== OS/Default Management ====================
+
+/// Send an echo request
+pub async fn echo_async<T: AsyncTransport>(transport: &T, message: &str) -> Result<String, Error> {
+    info!("send echo request: {}", message);
+    let req = EchoReq {
+        d: message.to_string(),
+    };
+    let rsp: EchoRsp = request(transport, NmpOp::Write, NmpGroup::Default, NmpIdDef::Echo, &req).await?;
+    Ok(rsp.r)
+}
+
+/// Reset the device
+pub async fn reset_async<T: AsyncTransport>(transport: &T) -> Result<(), Error> {
+    info!("send reset request");
+    let _: serde_cbor::Value =
+        request(transport, NmpOp::Write, NmpGroup::Default, NmpIdDef::Reset, &empty_map()).await?;
+    info!("reset complete");
+    Ok(())
+}
+
+/// Get task/thread statistics
+pub async fn taskstat_async<T: AsyncTransport>(transport: &T) -> Result<TaskStatRsp, Error> {
+    info!("send taskstat request");
+    request(transport, NmpOp::Read, NmpGroup::Default, NmpIdDef::TaskStat, &empty_map()).await
+}
+
+/// Get MCUmgr parameters
+pub async fn mcumgr_params_async<T: AsyncTransport>(transport: &T) -> Result<McumgrParamsRsp, Error> {
+    info!("send mcumgr_params request");
+    request(transport, NmpOp::Read, NmpGroup::Default, NmpIdDef::McumgrParams, &empty_map()).await
+}
+
+/// Get OS/application information
+pub async fn os_info_async<T: AsyncTransport>(transport: &T, format: Option<&str>) -> Result<String, Error> {
+    info!("send os_info request");
+    let req = OsInfoReq {
+        format: format.map(|s| s.to_string()),
+    };
+    let rsp: OsInfoRsp = request(transport, NmpOp::Read, NmpGroup::Default, NmpIdDef::Info, &req).await?;
+    Ok(rsp.output)
+}
+
+/// Get bootloader information
+pub async fn bootloader_info_async<T: AsyncTransport>(
+    transport: &T,
+    query: Option<&str>,
+) -> Result<BootloaderInfoRsp, Error> {
+    info!("send bootloader_info request");
+    let req = BootloaderInfoReq {
+        query: query.map(|s| s.to_string()),
+    };
+    request(transport, NmpOp::Read, NmpGroup::Default, NmpIdDef::BootloaderInfo, &req).await
+}
+
+// ==================== Image Management ====================
+
+/// List images
+pub async fn list_async<T: AsyncTransport>(transport: &T) -> Result<ImageStateRsp, Error> {
+    info!("send image list request");
+    request(transport, NmpOp::Read, NmpGroup::Image, NmpIdImage::State, &empty_map()).await
+}
+
+/// Set image pending/confirm
+pub async fn test_async<T: AsyncTransport>(
+    transport: &T,
+    hash: Vec<u8>,
+    confirm: Option<bool>,
+) -> Result<(), Error> {
+    info!("set image pending request");
+    let req = ImageStateReq { hash, confirm };
+    let _: serde_cbor::Value =
+        request(transport, NmpOp::Write, NmpGroup::Image, NmpIdImage::State, &req).await?;
+    Ok(())
+}
+
+/// Erase an image slot, see `erase`
+pub async fn erase_async<T: AsyncTransport>(
+    transport: &T,
+    slot: Option<u32>,
+    force: bool,
+) -> Result<(), Error> {
+    if !force {
+        check_erase(&list_async(transport).await?, slot.unwrap_or(DEFAULT_ERASE_SLOT))?;
+    }
+    info!("erase request");
+    let req = ImageEraseReq { slot };
+    let _: serde_cbor::Value =
+        request(transport, NmpOp::Write, NmpGroup::Image, NmpIdImage::Erase, &req).await?;
+    Ok(())
+}
+
+/// Upload an image
+pub async fn upload_image_async<T, F>(
+    transport: &T,
+    filename: &Path,
+    image_num: u8,
+    upgrade_only: bool,
+    mut progress: Option<F>,
+) -> Result<(), Error>
+where
+    T: AsyncTransport,
+    F: FnMut(u64, u64),
+{
+    info!("upload file: {}", filename.display());
+    let data = load_image(&tokio::fs::read(filename).await?)
+        .with_context(|| format!("{}", filename.display()))?;
+    info!("{} bytes to transfer", data.len());
+
+    #[derive(serde::Deserialize)]
+    struct UploadRsp {
+        off: u32,
+        #[serde(rename = "match")]
+        matches: Option<bool>,
+    }
+
+    let mtu = transport.mtu();
+    let mut off: usize = 0;
+    while off < data.len() {
+        let chunk_len = mtu.min(data.len() - off);
+        let chunk = data[off..off + chunk_len].to_vec();
+        let req = if off == 0 {
+            ImageUploadReq {
+                image_num,
+                off: 0,
+                len: Some(data.len() as u32),
+                data_sha: Some(Sha256::digest(&data).to_vec()),
+                upgrade: upgrade_only.then_some(true),
+                data: chunk,
+            }
+        } else {
+            ImageUploadReq {
+                image_num,
+                off: off as u32,
+                len: None,
+                data_sha: None,
+                upgrade: None,
+                data: chunk,
+            }
+        };
+
+        let rsp: UploadRsp =
+            request(transport, NmpOp::Write, NmpGroup::Image, NmpIdImage::Upload, &req).await?;
+        if rsp.matches == Some(false) {
+            bail!("the device reports that the uploaded data doesn't match its SHA256");
+        }
+        if rsp.off as usize <= off {
+            bail!("wrong offset received");
+        }
+        off = rsp.off as usize;
+
+        if let Some(ref mut f) = progress {
+            f(off as u64, data.len() as u64);
+        }
+    }
+
+    info!("upload complete");
+    Ok(())
+}
+
+// ==================== Shell Management ====================
+
+/// Execute a shell command
+pub async fn shell_exec_async<T: AsyncTransport>(
+    transport: &T,
+    argv: Vec<String>,
+) -> Result<ShellExecRsp, Error> {
+    info!("send shell exec request: {:?}", argv);
+    let req = ShellExecReq { argv };
+    request(transport, NmpOp::Write, NmpGroup::Shell, NmpIdShell::Exec, &req).await
+}
+
+// ==================== File System Management ====================
+
+/// Download a file from the device
+pub async fn fs_download_async<T: AsyncTransport>(
+    transport: &T,
+    remote_path: &str,
+    local_path: &Path,
+) -> Result<(), Error> {
+    info!("download file: {} -> {}", remote_path, local_path.display());
+
+    let mut file_data: Vec<u8> = Vec::new();
+    let mut total_len: Option<u32> = None;
+    loop {
+        let req = FsDownloadReq {
+            name: remote_path.to_string(),
+            off: file_data.len() as u32,
+        };
+        let rsp: FsDownloadRsp =
+            request(transport, NmpOp::Read, NmpGroup::Fs, NmpIdFs::File, &req).await?;
+        if rsp.off == 0 {
+            total_len = rsp.len;
+        }
+        if rsp.off as usize != file_data.len() {
+            bail!("wrong offset received");
+        }
+        file_data.extend_from_slice(&rsp.data);
+
+        if rsp.data.is_empty() || total_len.is_some_and(|len| file_data.len() >= len as usize) {
+            break;
+        }
+    }
+
+    tokio::fs::write(local_path, &file_data).await?;
+    info!("downloaded {} bytes", file_data.len());
+    Ok(())
+}
+
+/// Upload a file to the device
+pub async fn fs_upload_async<T: AsyncTransport>(
+    transport: &T,
+    local_path: &Path,
+    remote_path: &str,
+) -> Result<(), Error> {
+    info!("upload file: {} -> {}", local_path.display(), remote_path);
+
+    let file_data = tokio::fs::read(local_path).await?;
+    let total_len = file_data.len() as u32;
+    let mtu = transport.mtu();
+    let mut offset: u32 = 0;
+    while offset < total_len {
+        let chunk_size = mtu.min((total_len - offset) as usize);
+        let req = FsUploadReq {
+            name: remote_path.to_string(),
+            off: offset,
+            data: file_data[offset as usize..offset as usize + chunk_size].to_vec(),
+            len: if offset == 0 { Some(total_len) } else { None },
+        };
+        let rsp: FsUploadRsp =
+            request(transport, NmpOp::Write, NmpGroup::Fs, NmpIdFs::File, &req).await?;
+        if rsp.off <= offset {
+            bail!("wrong offset received");
+        }
+        offset = rsp.off;
+    }
+
+    info!("uploaded {} bytes", total_len);
+    Ok(())
+}
+
+/// Get file status
+pub async fn fs_stat_async<T: AsyncTransport>(transport: &T, path: &str) -> Result<FsStatRsp, Error> {
+    info!("stat file: {}", path);
+    let req = FsStatReq {
+        name: path.to_string(),
+    };
+    request(transport, NmpOp::Read, NmpGroup::Fs, NmpIdFs::FileStat, &req).await
+}
+
+/// Calculate hash/checksum of a file
+pub async fn fs_hash_async<T: AsyncTransport>(
+    transport: &T,
+    path: &str,
+    hash_type: Option<&str>,
+    off: Option<u32>,
+    len: Option<u32>,
+) -> Result<FsHashRsp, Error> {
+    info!("hash file: {}", path);
+    let req = FsHashReq {
+        name: path.to_string(),
+        hash_type: hash_type.map(|s| s.to_string()),
+        off,
+        len,
+    };
+    request(transport, NmpOp::Read, NmpGroup::Fs, NmpIdFs::FileHash, &req).await
+}
+
+// ==================== Statistics Management ====================
+
+/// List available statistics groups
+pub async fn stat_list_async<T: AsyncTransport>(transport: &T) -> Result<StatListRsp, Error> {
+    info!("send stat list request");
+    request(transport, NmpOp::Read, NmpGroup::Stat, NmpIdStat::List, &empty_map()).await
+}
+
+/// Read statistics from a specific group
+pub async fn stat_read_async<T: AsyncTransport>(transport: &T, name: &str) -> Result<StatReadRsp, Error> {
+    info!("send stat read request: {}", name);
+    let req = StatReadReq {
+        name: name.to_string(),
+    };
+    request(transport, NmpOp::Read, NmpGroup::Stat, NmpIdStat::Read, &req).await
+}
+
+// ==================== Settings/Config Management ====================
+
+/// Read a settings value
+pub async fn settings_read_async<T: AsyncTransport>(
+    transport: &T,
+    name: &str,
+    max_size: Option<u32>,
+) -> Result<SettingsReadRsp, Error> {
+    info!("read setting: {}", name);
+    let req = SettingsReadReq {
+        name: name.to_string(),
+        max_size,
+    };
+    request(transport, NmpOp::Read, NmpGroup::Config, NmpIdConfig::Val, &req).await
+}
+
+/// Write a settings value
+pub async fn settings_write_async<T: AsyncTransport>(
+    transport: &T,
+    name: &str,
+    value: Vec<u8>,
+) -> Result<(), Error> {
+    info!("write setting: {} = {:?}", name, value);
+    let req = SettingsWriteReq {
+        name: name.to_string(),
+        val: value,
+    };
+    let _: serde_cbor::Value =
+        request(transport, NmpOp::Write, NmpGroup::Config, NmpIdConfig::Val, &req).await?;
+    Ok(())
+}
+
+/// Delete a settings value
+pub async fn settings_delete_async<T: AsyncTransport>(transport: &T, name: &str) -> Result<(), Error> {
+    info!("delete setting: {}", name);
+    let req = SettingsDeleteReq {
+        name: name.to_string(),
+    };
+    let _: serde_cbor::Value =
+        request(transport, NmpOp::Write, NmpGroup::Config, NmpIdConfig::Delete, &req).await?;
+    Ok(())
+}
+
+/// Commit settings
+pub async fn settings_commit_async<T: AsyncTransport>(transport: &T) -> Result<(), Error> {
+    info!("commit settings");
+    let _: serde_cbor::Value =
+        request(transport, NmpOp::Write, NmpGroup::Config, NmpIdConfig::Commit, &SettingsCommitReq {}).await?;
+    Ok(())
+}
+
+/// Load settings from persistent storage
+pub async fn settings_load_async<T: AsyncTransport>(transport: &T) -> Result<(), Error> {
+    info!("load settings");
+    let _: serde_cbor::Value =
+        request(transport, NmpOp::Read, NmpGroup::Config, NmpIdConfig::LoadSave, &SettingsLoadReq {}).await?;
+    Ok(())
+}
+
+/// Save settings to persistent storage
+pub async fn settings_save_async<T: AsyncTransport>(transport: &T) -> Result<(), Error> {
+    info!("save settings");
+    let _: serde_cbor::Value =
+        request(transport, NmpOp::Write, NmpGroup::Config, NmpIdConfig::LoadSave, &SettingsSaveReq {}).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_transport::{AsyncTcpTransport, AsyncUdpTransport};
+    use crate::transfer::UdpSpecs;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answer an echo packet, echoing the request header with the response op
+    fn echo_response(request: &[u8]) -> Vec<u8> {
+        let (header, body) = crate::transfer::decode_packet(request).unwrap();
+        let req: EchoReq = serde_cbor::from_slice(body).unwrap();
+        let body = serde_cbor::to_vec(&EchoRsp { r: req.d }).unwrap();
+        let mut response = NmpHdr {
+            op: NmpOp::WriteRsp,
+            len: body.len() as u16,
+            ..header
+        }
+        .serialize()
+        .unwrap();
+        response.extend_from_slice(&body);
+        response
+    }
+
+    #[tokio::test]
+    async fn test_udp_out_of_order_responses() {
+        // device answering two requests in reverse order
+        let device = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let specs = UdpSpecs {
+            host: "127.0.0.1".to_string(),
+            port: device.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (len, peer) = device.recv_from(&mut buf).await.unwrap();
+            let first = buf[..len].to_vec();
+            let (len, _) = device.recv_from(&mut buf).await.unwrap();
+            let second = buf[..len].to_vec();
+            device.send_to(&echo_response(&second), peer).await.unwrap();
+            device.send_to(&echo_response(&first), peer).await.unwrap();
+        });
+
+        let transport = AsyncUdpTransport::new(&specs).await.unwrap();
+        let (a, b) = tokio::join!(echo_async(&transport, "first"), echo_async(&transport, "second"));
+        assert_eq!(a.unwrap(), "first");
+        assert_eq!(b.unwrap(), "second");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_cancelled_request() {
+        let (client, mut device) = tokio::io::duplex(4096);
+        let transport = AsyncTcpTransport::from_stream(client, 512);
+
+        // the first request is cancelled by a timeout before the device
+        // answers it, its late response must not be taken for the second
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(50), echo_async(&transport, "lost")).await;
+        assert!(cancelled.is_err());
+
+        let device_task = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let mut header = [0u8; 8];
+                device.read_exact(&mut header).await.unwrap();
+                let mut packet = header.to_vec();
+                packet.resize(8 + u16::from_be_bytes([header[2], header[3]]) as usize, 0);
+                device.read_exact(&mut packet[8..]).await.unwrap();
+                requests.push(packet);
+            }
+            for request in &requests {
+                device.write_all(&echo_response(request)).await.unwrap();
+            }
+            device
+        });
+
+        assert_eq!(echo_async(&transport, "kept").await.unwrap(), "kept");
+        drop(device_task.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_cancelled_mid_write() {
+        // a small pipe stalls the write of a long request until the device
+        // reads, so the request is cancelled in the middle of its packet
+        let (client, mut device) = tokio::io::duplex(16);
+        let transport = AsyncTcpTransport::from_stream(client, 512);
+        let long = "x".repeat(300);
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(50), echo_async(&transport, &long)).await;
+        assert!(cancelled.is_err());
+
+        // the device still receives the whole cancelled packet, then the next
+        tokio::spawn(async move {
+            loop {
+                let mut header = [0u8; 8];
+                if device.read_exact(&mut header).await.is_err() {
+                    break;
+                }
+                let mut packet = header.to_vec();
+                packet.resize(8 + u16::from_be_bytes([header[2], header[3]]) as usize, 0);
+                device.read_exact(&mut packet[8..]).await.unwrap();
+                device.write_all(&echo_response(&packet)).await.unwrap();
+            }
+        });
+        let kept = tokio::time::timeout(Duration::from_secs(2), echo_async(&transport, "kept"));
+        assert_eq!(kept.await.unwrap().unwrap(), "kept");
+    }
+}
@@ -0,0 +1,500 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+use anyhow::{bail, Context, Error, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::debug;
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::nmp_hdr::*;
+use crate::transfer::{
+    decode_packet, encode_request, FrameDecoder, SerialSpecs, TransportError, UdpSpecs,
+};
+
+/// Async counterpart of `Transport`
+///
+/// Requests take `&self`, so several requests can be in flight on one
+/// connection; responses are matched to requests by sequence number.
+/// Dropping a pending `transceive` future cancels the request: a late
+/// response is then discarded.
+pub trait AsyncTransport: Send + Sync {
+    /// Send an SMP request and receive a response
+    fn transceive(
+        &self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> impl Future<Output = Result<(NmpHdr, serde_cbor::Value), Error>> + Send;
+
+    /// Set the timeout for subsequent requests
+    fn set_timeout(&self, timeout_ms: u32);
+
+    /// Get the MTU for this transport
+    fn mtu(&self) -> usize;
+}
+
+type PendingSender = oneshot::Sender<(NmpHdr, Vec<u8>)>;
+
+/// Requests waiting for their response, by sequence number
+struct Dispatcher {
+    pending: Mutex<HashMap<u8, PendingSender>>,
+    next_seq: AtomicU8,
+    closed: AtomicBool,
+}
+
+impl Dispatcher {
+    fn new() -> Arc<Self> {
+        Arc::new(Dispatcher {
+            pending: Mutex::new(HashMap::new()),
+            next_seq: AtomicU8::new(thread_rng().gen::<u8>()),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Allocate a sequence number not used by another pending request
+    fn register(self: &Arc<Self>) -> Result<PendingRequest, Error> {
+        if self.closed.load(Ordering::SeqCst) {
+            bail!("connection closed");
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() > u8::MAX as usize {
+            bail!("too many requests in flight");
+        }
+        let seq = loop {
+            let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+            if !pending.contains_key(&seq) {
+                break seq;
+            }
+        };
+        let (tx, rx) = oneshot::channel();
+        pending.insert(seq, tx);
+        Ok(PendingRequest {
+            dispatcher: Arc::clone(self),
+            seq,
+            rx,
+        })
+    }
+
+    /// Hand a received packet to the request waiting for it
+    fn dispatch(&self, packet: &[u8]) {
+        let (header, body) = match decode_packet(packet) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                debug!("invalid packet dropped: {}", e);
+                return;
+            }
+        };
+        let waiter = self.pending.lock().unwrap().remove(&header.seq);
+        match waiter {
+            Some(tx) => {
+                // the receiver is gone if the request was just cancelled
+                let _ = tx.send((header, body.to_vec()));
+            }
+            None => debug!("response without pending request dropped, seq {}", header.seq),
+        }
+    }
+
+    /// Fail all pending and future requests
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+/// Registration of a request; unregisters itself when dropped, which makes
+/// `transceive` cancellation-safe
+struct PendingRequest {
+    dispatcher: Arc<Dispatcher>,
+    seq: u8,
+    rx: oneshot::Receiver<(NmpHdr, Vec<u8>)>,
+}
+
+impl PendingRequest {
+    /// Wait for the response and verify it
+    async fn response(
+        &mut self,
+        op: NmpOp,
+        group: NmpGroup,
+        timeout_ms: u32,
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        let (header, body) =
+            match tokio::time::timeout(Duration::from_millis(timeout_ms as u64), &mut self.rx).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(_)) => bail!("connection closed"),
+                Err(_) => bail!(TransportError::Timeout),
+            };
+
+        let expected_op_type = match op {
+            NmpOp::Read => NmpOp::ReadRsp,
+            NmpOp::Write => NmpOp::WriteRsp,
+            _ => bail!("unexpected request op type"),
+        };
+        if header.op != expected_op_type || header.group != group {
+            bail!("wrong response types");
+        }
+
+        let value = if body.is_empty() {
+            serde_cbor::Value::Map(std::collections::BTreeMap::new())
+        } else {
+            serde_cbor::from_slice(&body).with_context(|| "Failed to parse CBOR response")?
+        };
+        Ok((header, value))
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.dispatcher.pending.lock().unwrap().remove(&self.seq);
+    }
+}
+
+fn packet(op: NmpOp, group: NmpGroup, id: u8, body: &[u8], seq: u8) -> Result<Vec<u8>, Error> {
+    let header = NmpHdr {
+        op,
+        flags: 0,
+        len: body.len() as u16,
+        group,
+        seq,
+        id,
+    };
+    let mut packet = header.serialize()?;
+    packet.extend_from_slice(body);
+    Ok(packet)
+}
+
+/// SMP over UDP, one packet per datagram
+pub struct AsyncUdpTransport {
+    socket: Arc<UdpSocket>,
+    dispatcher: Arc<Dispatcher>,
+    reader: JoinHandle<()>,
+    timeout_ms: AtomicU32,
+    mtu: usize,
+}
+
+impl AsyncUdpTransport {
+    pub async fn new(specs: &UdpSpecs) -> Result<Self, Error> {
+        let addr_str = format!("{}:{}", specs.host, specs.port);
+        let addr: SocketAddr = lookup_host(&addr_str)
+            .await
+            .with_context(|| format!("Failed to resolve address: {addr_str}"))?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No address found for: {addr_str}"))?;
+        let bind_addr = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .with_context(|| "Failed to bind UDP socket")?;
+        socket
+            .connect(addr)
+            .await
+            .with_context(|| format!("Failed to connect UDP socket to {addr}"))?;
+        let socket = Arc::new(socket);
+
+        let dispatcher = Dispatcher::new();
+        let reader = {
+            let socket = Arc::clone(&socket);
+            let dispatcher = Arc::clone(&dispatcher);
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    match socket.recv(&mut buf).await {
+                        Ok(len) => dispatcher.dispatch(&buf[..len]),
+                        Err(e) => {
+                            // e.g. ICMP port unreachable, keep listening
+                            debug!("UDP receive error: {}", e);
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok(AsyncUdpTransport {
+            socket,
+            dispatcher,
+            reader,
+            timeout_ms: AtomicU32::new(specs.timeout_s * 1000),
+            mtu: specs.mtu,
+        })
+    }
+}
+
+impl AsyncTransport for AsyncUdpTransport {
+    async fn transceive(
+        &self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        let mut request = self.dispatcher.register()?;
+        let data = packet(op, group, id, body, request.seq)?;
+        self.socket
+            .send(&data)
+            .await
+            .with_context(|| "Failed to send UDP packet")?;
+        request
+            .response(op, group, self.timeout_ms.load(Ordering::SeqCst))
+            .await
+    }
+
+    fn set_timeout(&self, timeout_ms: u32) {
+        self.timeout_ms.store(timeout_ms, Ordering::SeqCst);
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+impl Drop for AsyncUdpTransport {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+type WriteRequest = (Vec<u8>, oneshot::Sender<std::io::Result<()>>);
+
+/// Task owning the writer half, writing the packets of concurrent requests
+/// one after the other
+///
+/// A packet is always written whole: cancelling the request that sent it
+/// only stops waiting for the write, so no partial frame is left on the
+/// stream.
+struct PacketWriter {
+    tx: mpsc::UnboundedSender<WriteRequest>,
+}
+
+impl PacketWriter {
+    /// Start the writer task; it ends once the transport is dropped
+    fn spawn<W>(mut writer: W) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (tx, mut rx) = mpsc::unbounded_channel::<WriteRequest>();
+        tokio::spawn(async move {
+            while let Some((data, done)) = rx.recv().await {
+                let mut result = writer.write_all(&data).await;
+                if result.is_ok() {
+                    result = writer.flush().await;
+                }
+                // nobody waits if the request was cancelled
+                let _ = done.send(result);
+            }
+        });
+        PacketWriter { tx }
+    }
+
+    /// Write a packet and wait until it is written
+    async fn write(&self, data: Vec<u8>) -> Result<(), Error> {
+        let (done, written) = oneshot::channel();
+        if self.tx.send((data, done)).is_err() {
+            bail!("connection closed");
+        }
+        match written.await {
+            Ok(result) => Ok(result?),
+            Err(_) => bail!("connection closed"),
+        }
+    }
+}
+
+/// SMP over TCP, packets are sent back to back as header and body
+pub struct AsyncTcpTransport {
+    writer: PacketWriter,
+    dispatcher: Arc<Dispatcher>,
+    reader: JoinHandle<()>,
+    timeout_ms: AtomicU32,
+    mtu: usize,
+}
+
+impl AsyncTcpTransport {
+    /// Connect to an SMP server; `specs.port` is the TCP port
+    pub async fn new(specs: &UdpSpecs) -> Result<Self, Error> {
+        let addr_str = format!("{}:{}", specs.host, specs.port);
+        let stream = TcpStream::connect(&addr_str)
+            .await
+            .with_context(|| format!("Failed to connect to {addr_str}"))?;
+        stream.set_nodelay(true)?;
+        let transport = Self::from_stream(stream, specs.mtu);
+        transport.set_timeout(specs.timeout_s * 1000);
+        Ok(transport)
+    }
+
+    /// Use an established stream
+    pub fn from_stream<S>(stream: S, mtu: usize) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut read_half, write_half) = tokio::io::split(stream);
+        let dispatcher = Dispatcher::new();
+        let reader = {
+            let dispatcher = Arc::clone(&dispatcher);
+            tokio::spawn(async move {
+                let mut header = [0u8; 8];
+                loop {
+                    if read_half.read_exact(&mut header).await.is_err() {
+                        break;
+                    }
+                    let len = BigEndian::read_u16(&header[2..4]) as usize;
+                    let mut packet = header.to_vec();
+                    packet.resize(8 + len, 0);
+                    if read_half.read_exact(&mut packet[8..]).await.is_err() {
+                        break;
+                    }
+                    dispatcher.dispatch(&packet);
+                }
+                debug!("TCP connection closed");
+                dispatcher.close();
+            })
+        };
+
+        AsyncTcpTransport {
+            writer: PacketWriter::spawn(write_half),
+            dispatcher,
+            reader,
+            timeout_ms: AtomicU32::new(5000),
+            mtu,
+        }
+    }
+}
+
+impl AsyncTransport for AsyncTcpTransport {
+    async fn transceive(
+        &self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        let mut request = self.dispatcher.register()?;
+        let data = packet(op, group, id, body, request.seq)?;
+        self.writer.write(data).await?;
+        request
+            .response(op, group, self.timeout_ms.load(Ordering::SeqCst))
+            .await
+    }
+
+    fn set_timeout(&self, timeout_ms: u32) {
+        self.timeout_ms.store(timeout_ms, Ordering::SeqCst);
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+impl Drop for AsyncTcpTransport {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// SMP over a serial console, with base64 line framing and CRC
+pub struct AsyncSerialTransport {
+    writer: PacketWriter,
+    dispatcher: Arc<Dispatcher>,
+    reader: JoinHandle<()>,
+    timeout_ms: AtomicU32,
+    linelength: usize,
+    mtu: usize,
+}
+
+impl AsyncSerialTransport {
+    /// Open the serial port of the specs
+    pub fn new(specs: &SerialSpecs) -> Result<Self, Error> {
+        let port = tokio_serial::new(&specs.device, specs.baudrate)
+            .open_native_async()
+            .with_context(|| format!("failed to open serial port {}", &specs.device))?;
+        let transport = Self::from_stream(port, specs.linelength, specs.mtu);
+        transport.set_timeout(specs.initial_timeout_s * 1000);
+        Ok(transport)
+    }
+
+    /// Use an established byte stream, e.g. a pseudo terminal
+    pub fn from_stream<S>(stream: S, linelength: usize, mtu: usize) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let dispatcher = Dispatcher::new();
+        let reader = {
+            let dispatcher = Arc::clone(&dispatcher);
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(read_half);
+                let mut decoder = FrameDecoder::new();
+                let mut line = Vec::new();
+                loop {
+                    line.clear();
+                    match lines.read_until(b'\n', &mut line).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => (),
+                    }
+                    let line = line.strip_suffix(b"\n").unwrap_or(&line);
+                    match decoder.push_line(line) {
+                        Ok(Some(packet)) => dispatcher.dispatch(&packet),
+                        Ok(None) => (),
+                        Err(e) => debug!("invalid frame dropped: {}", e),
+                    }
+                }
+                debug!("serial port closed");
+                dispatcher.close();
+            })
+        };
+
+        AsyncSerialTransport {
+            writer: PacketWriter::spawn(write_half),
+            dispatcher,
+            reader,
+            timeout_ms: AtomicU32::new(5000),
+            linelength,
+            mtu,
+        }
+    }
+}
+
+impl AsyncTransport for AsyncSerialTransport {
+    async fn transceive(
+        &self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        let mut request = self.dispatcher.register()?;
+        let (data, _) = encode_request(
+            self.linelength,
+            op,
+            group,
+            id,
+            &body.to_vec(),
+            request.seq,
+        )?;
+        self.writer.write(data).await?;
+        request
+            .response(op, group, self.timeout_ms.load(Ordering::SeqCst))
+            .await
+    }
+
+    fn set_timeout(&self, timeout_ms: u32) {
+        self.timeout_ms.store(timeout_ms, Ordering::SeqCst);
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+impl Drop for AsyncSerialTransport {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! SMP server running the device simulator, for testing firmware updates
+//! without hardware: serves UDP, TCP and the console framing on a
+//! pseudo-terminal, and can persist the device's flash to a directory.
+
+use anyhow::{Context, Error, Result};
+use clap::Parser;
+use log::{error, info, LevelFilter};
+use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use mcumgr_client::*;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:1337";
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// serve UDP on this address [default: 127.0.0.1:1337]
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = DEFAULT_ADDR)]
+    udp: Option<String>,
+
+    /// serve TCP on this address [default: 127.0.0.1:1337]
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = DEFAULT_ADDR)]
+    tcp: Option<String>,
+
+    /// serve the serial console on a pseudo-terminal
+    #[arg(long)]
+    pty: bool,
+
+    /// symbolic link to create to the pseudo-terminal, e.g. /tmp/ttySIM
+    #[arg(long, value_name = "PATH")]
+    pty_link: Option<PathBuf>,
+
+    /// directory to persist the flash slots, filesystem and settings to
+    #[arg(long, value_name = "DIR")]
+    dir: Option<PathBuf>,
+
+    /// number of images
+    #[arg(long, default_value_t = 2)]
+    images: usize,
+
+    /// size of each slot in bytes
+    #[arg(long, default_value_t = 512 * 1024)]
+    slot_size: usize,
+
+    /// MCUboot mode reported by bootloader-info (0 = single slot, 2 = overwrite only, 3 = swap using move)
+    #[arg(long, default_value_t = 3)]
+    mcuboot_mode: i32,
+
+    /// reject uploads not newer than the running image
+    #[arg(long)]
+    no_downgrade: bool,
+
+    /// verbose mode
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+fn main() {
+    let mut cli = Cli::parse();
+
+    let level_filter = if cli.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    TermLogger::init(
+        level_filter,
+        Config::default(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )
+    .unwrap_or_else(|_| SimpleLogger::init(LevelFilter::Info, Default::default()).unwrap());
+
+    // without a choice, serve everything
+    if cli.udp.is_none() && cli.tcp.is_none() && !cli.pty && cli.pty_link.is_none() {
+        cli.udp = Some(DEFAULT_ADDR.to_string());
+        cli.tcp = Some(DEFAULT_ADDR.to_string());
+        cli.pty = true;
+    }
+
+    if let Err(e) = run(&cli) {
+        error!("Error: {:#}", e);
+        process::exit(1);
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), Error> {
+    let config = SimulatorConfig {
+        images: cli.images,
+        slot_size: cli.slot_size,
+        mcuboot_mode: cli.mcuboot_mode,
+        no_downgrade: cli.no_downgrade,
+        ..Default::default()
+    };
+    let simulator = match &cli.dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+            info!("storage: {}", dir.display());
+            Simulator::open(config, dir)?
+        }
+        None => Simulator::new(config),
+    };
+    let simulator = Arc::new(Mutex::new(simulator));
+
+    // the servers stop when dropped
+    let _udp = match &cli.udp {
+        Some(addr) => {
+            let server = UdpServer::bind(addr, simulator.clone())?;
+            info!("udp: {}", server.addr());
+            Some(server)
+        }
+        None => None,
+    };
+    let _tcp = match &cli.tcp {
+        Some(addr) => {
+            let server = TcpServer::bind(addr, simulator.clone())?;
+            info!("tcp: {}", server.addr());
+            Some(server)
+        }
+        None => None,
+    };
+    let _pty = if cli.pty || cli.pty_link.is_some() {
+        Some(serve_pty(cli, simulator)?)
+    } else {
+        None
+    };
+
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(unix)]
+fn serve_pty(cli: &Cli, simulator: Arc<Mutex<Simulator>>) -> Result<PtyServer, Error> {
+    let server = PtyServer::spawn(simulator)?;
+    info!("pty: {}", server.path());
+    if let Some(link) = &cli.pty_link {
+        let _ = std::fs::remove_file(link);
+        std::os::unix::fs::symlink(server.path(), link)
+            .with_context(|| format!("failed to create {}", link.display()))?;
+        info!("pty link: {}", link.display());
+    }
+    Ok(server)
+}
+
+#[cfg(not(unix))]
+fn serve_pty(_cli: &Cli, _simulator: Arc<Mutex<Simulator>>) -> Result<(), Error> {
+    anyhow::bail!("pseudo-terminals are not supported on this platform")
+}
@@ -11,6 +11,7 @@ use crate::transfer::open_port;
 use crate::transfer::transceive;
 use crate::transfer::SerialSpecs;
 use crate::transfer::Transport;
+use crate::transfer::TransportError;
 
 pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
     info!("send reset request");
@@ -19,7 +20,7 @@ pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
     let mut port = open_port(specs)?;
 
     // send request
-    let body = Vec::new();
+    let body = serde_cbor::to_vec(&ResetReq { force: None })?;
     let (data, request_header) = encode_request(
         specs.linelength,
         NmpOp::Write,
@@ -32,7 +33,7 @@ pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
     
     // verify sequence id
     if response_header.seq != request_header.seq {
-        bail!("wrong sequence number");
+        bail!(TransportError::Seq);
     }
 
     // verify response
@@ -71,7 +72,7 @@ pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
 pub fn reset_transport(transport: &mut dyn Transport) -> Result<(), Error> {
     info!("send reset request");
 
-    let body = Vec::new();
+    let body = serde_cbor::to_vec(&ResetReq { force: None })?;
     let (_response_header, response_body) = transport.transceive(
         NmpOp::Write,
         NmpGroup::Default,
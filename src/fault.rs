@@ -0,0 +1,511 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Fault injection for testing the client against bad links
+//!
+//! A scenario is a list of rules, each injecting a fault into the requests
+//! it matches. The random decisions use a seeded generator, so a failing
+//! scenario can be reproduced exactly.
+//!
+//! ```json
+//! {
+//!   "seed": 42,
+//!   "rules": [
+//!     { "fault": "drop", "probability": 0.1 },
+//!     { "fault": { "delay": { "ms": 300 } }, "command": "Image/Upload", "nth": 3 },
+//!     { "fault": { "error_rc": { "rc": 6 } }, "offset": 4096, "times": 1 },
+//!     { "fault": "reset", "offset": 8192, "times": 1 }
+//!   ]
+//! }
+//! ```
+
+use anyhow::{Context, Error, Result};
+use lazy_static::lazy_static;
+use log::info;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::nmp_hdr::*;
+use crate::transfer::{encode_request, FrameDecoder, PacketTap, Transport, TransportError};
+
+/// Fault injected into a request or its response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// The request is lost, the device doesn't answer
+    Drop,
+    /// The response arrives late, a timeout if later than the transport's
+    Delay { ms: u64 },
+    /// The response is sent twice
+    Duplicate,
+    /// The response arrives after the response to the next request
+    Reorder,
+    /// The response frame fails the CRC check
+    CorruptCrc,
+    /// The last characters of the response frame are missing
+    TruncateBase64,
+    /// The response carries another sequence number
+    WrongSeq,
+    /// The device answers with this result code instead of handling the request
+    ErrorRc { rc: i32 },
+    /// The device resets when receiving the request, which is lost
+    Reset,
+}
+
+/// Rule injecting a fault into the matching requests
+///
+/// All given conditions must match. Without any condition, every request
+/// matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaultRule {
+    pub fault: Fault,
+    /// Command name, e.g. `Image/Upload`, see `command_name`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Transfer offset: matches the upload or download request at this
+    /// offset, or the upload request whose data contains it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u32>,
+    /// Number of the request, counting from 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nth: Option<u64>,
+    /// Probability of injecting the fault into a matching request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probability: Option<f64>,
+    /// Maximum number of injections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub times: Option<u32>,
+}
+
+impl FaultRule {
+    /// Rule injecting the fault into every request
+    pub fn new(fault: Fault) -> Self {
+        FaultRule {
+            fault,
+            command: None,
+            offset: None,
+            nth: None,
+            probability: None,
+            times: None,
+        }
+    }
+
+    fn matches(&self, name: &str, request: u64, range: Option<(u32, u32)>) -> bool {
+        if self.command.as_deref().is_some_and(|c| c != name) {
+            return false;
+        }
+        if self.nth.is_some_and(|n| n != request) {
+            return false;
+        }
+        if let Some(offset) = self.offset {
+            match range {
+                Some((off, len)) if off == offset || (off < offset && offset < off + len) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Scriptable fault scenario
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FaultScenario {
+    /// Seed of the random generator
+    #[serde(default)]
+    pub seed: u64,
+    pub rules: Vec<FaultRule>,
+}
+
+impl FaultScenario {
+    /// Load a scenario from a JSON file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read fault scenario {}", path.display()))?;
+        serde_json::from_str(&data)
+            .with_context(|| format!("invalid fault scenario {}", path.display()))
+    }
+}
+
+/// Decides which faults to inject into each request
+#[derive(Debug)]
+pub struct FaultInjector {
+    rules: Vec<FaultRule>,
+    fired: Vec<u32>,
+    rng: StdRng,
+    requests: u64,
+    log: Vec<String>,
+}
+
+impl FaultInjector {
+    pub fn new(scenario: FaultScenario) -> Self {
+        FaultInjector {
+            fired: vec![0; scenario.rules.len()],
+            rules: scenario.rules,
+            rng: StdRng::seed_from_u64(scenario.seed),
+            requests: 0,
+            log: Vec::new(),
+        }
+    }
+
+    /// Get the faults to inject into a request
+    pub fn faults(&mut self, group: NmpGroup, id: u8, body: &[u8]) -> Vec<Fault> {
+        self.requests += 1;
+        let name = command_name(group, id);
+        let range = transfer_range(body);
+
+        let mut faults = Vec::new();
+        for (rule, fired) in self.rules.iter().zip(self.fired.iter_mut()) {
+            if rule.times.is_some_and(|times| *fired >= times)
+                || !rule.matches(&name, self.requests, range)
+            {
+                continue;
+            }
+            // always draw, so the decisions don't depend on earlier rules
+            let draw: f64 = self.rng.gen();
+            if draw >= rule.probability.unwrap_or(1.0) {
+                continue;
+            }
+            *fired += 1;
+            let entry = format!("request {} {}: {:?}", self.requests, name, rule.fault);
+            info!("fault: {}", entry);
+            self.log.push(entry);
+            faults.push(rule.fault.clone());
+        }
+        faults
+    }
+
+    /// Description of each injected fault, in order
+    pub fn log(&self) -> &[String] {
+        &self.log
+    }
+
+    /// Number of requests seen
+    pub fn requests(&self) -> u64 {
+        self.requests
+    }
+}
+
+/// Get the offset and data length of an upload or download request
+fn transfer_range(body: &[u8]) -> Option<(u32, u32)> {
+    let value: serde_cbor::Value = serde_cbor::from_slice(body).ok()?;
+    let map = match value {
+        serde_cbor::Value::Map(map) => map,
+        _ => return None,
+    };
+    let off = match map.get(&serde_cbor::Value::Text("off".to_string())) {
+        Some(serde_cbor::Value::Integer(off)) => *off as u32,
+        _ => return None,
+    };
+    let len = match map.get(&serde_cbor::Value::Text("data".to_string())) {
+        Some(serde_cbor::Value::Bytes(data)) => data.len() as u32,
+        _ => 0,
+    };
+    Some((off, len))
+}
+
+/// Error returned when no response arrives, like a serial port timeout
+pub(crate) fn timeout_error() -> Error {
+    TransportError::Timeout.into()
+}
+
+/// Get the error of a response whose frame lost its last base64 characters
+///
+/// The response is framed like a device does on a serial console, truncated
+/// and fed to the frame decoder. An incomplete frame waits for the missing
+/// characters, which never arrive.
+fn truncated_frame_error(header: &NmpHdr, value: &serde_cbor::Value) -> Error {
+    let body = match serde_cbor::to_vec(value) {
+        Ok(body) => body,
+        Err(e) => return e.into(),
+    };
+    let (mut framed, _) =
+        match encode_request(128, header.op, header.group, header.id, &body, header.seq) {
+            Ok(framed) => framed,
+            Err(e) => return e,
+        };
+    let end = framed.len() - 1;
+    framed.drain(end.saturating_sub(3)..end);
+
+    let mut decoder = FrameDecoder::new();
+    for line in framed.split(|&b| b == b'\n') {
+        match decoder.push_line(line) {
+            Ok(None) => {}
+            Ok(Some(_)) => break,
+            Err(e) => return e,
+        }
+    }
+    timeout_error()
+}
+
+/// Transport wrapper injecting faults into the exchanges
+///
+/// The faults are emulated by the errors the transport would report: a lost
+/// or damaged response is a timeout or a decoding error. A reordered
+/// response is held back and read first in the next exchange, which then
+/// fails with a wrong sequence number. A reset sends a reset command to the
+/// device.
+pub struct FaultTransport<T: Transport> {
+    inner: T,
+    injector: FaultInjector,
+    held: Option<NmpHdr>,
+}
+
+impl<T: Transport> FaultTransport<T> {
+    pub fn new(inner: T, scenario: FaultScenario) -> Self {
+        FaultTransport {
+            inner,
+            injector: FaultInjector::new(scenario),
+            held: None,
+        }
+    }
+
+    pub fn injector(&self) -> &FaultInjector {
+        &self.injector
+    }
+
+    /// Get the wrapped transport
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for FaultTransport<T> {
+    fn transceive(
+        &mut self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        let faults = self.injector.faults(group, id, body);
+
+        for fault in &faults {
+            match fault {
+                Fault::Drop => return Err(timeout_error()),
+                Fault::Reset => {
+                    let reset_body = serde_cbor::to_vec(&BTreeMap::<String, String>::new())?;
+                    let _ = self.inner.transceive(
+                        NmpOp::Write,
+                        NmpGroup::Default,
+                        NmpIdDef::Reset.to_u8(),
+                        &reset_body,
+                    );
+                    return Err(timeout_error());
+                }
+                Fault::ErrorRc { rc } => {
+                    let header = NmpHdr {
+                        op: if op == NmpOp::Read {
+                            NmpOp::ReadRsp
+                        } else {
+                            NmpOp::WriteRsp
+                        },
+                        flags: 0,
+                        len: 0,
+                        group,
+                        seq: 0,
+                        id,
+                    };
+                    let mut map = BTreeMap::new();
+                    map.insert(
+                        serde_cbor::Value::Text("rc".to_string()),
+                        serde_cbor::Value::Integer(*rc as i128),
+                    );
+                    return Ok((header, serde_cbor::Value::Map(map)));
+                }
+                Fault::Delay { ms } => match self.inner.timeout() {
+                    // the response arrives after the transport gave up
+                    Some(timeout) if *ms > u64::from(timeout) => {
+                        std::thread::sleep(Duration::from_millis(timeout.into()));
+                        let _ = self.inner.transceive(op, group, id, body);
+                        return Err(timeout_error());
+                    }
+                    _ => std::thread::sleep(Duration::from_millis(*ms)),
+                },
+                _ => {}
+            }
+        }
+
+        if faults.contains(&Fault::Duplicate) {
+            // the device receives the request twice
+            let _ = self.inner.transceive(op, group, id, body);
+        }
+        let response = self.inner.transceive(op, group, id, body)?;
+        if let Some(late) = self.held.take() {
+            if late.seq != response.0.seq {
+                return Err(Error::new(TransportError::Seq)
+                    .context(format!("late response with seq {} read first", late.seq)));
+            }
+        }
+        for fault in &faults {
+            match fault {
+                Fault::Reorder => {
+                    self.held = Some(response.0);
+                    return Err(timeout_error());
+                }
+                Fault::TruncateBase64 => {
+                    return Err(truncated_frame_error(&response.0, &response.1));
+                }
+                Fault::CorruptCrc => anyhow::bail!(TransportError::Crc),
+                Fault::WrongSeq => anyhow::bail!(TransportError::Seq),
+                _ => {}
+            }
+        }
+        Ok(response)
+    }
+
+    fn set_timeout(&mut self, timeout_ms: u32) -> Result<(), Error> {
+        self.inner.set_timeout(timeout_ms)
+    }
+
+    fn timeout(&self) -> Option<u32> {
+        self.inner.timeout()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn linelength(&self) -> usize {
+        self.inner.linelength()
+    }
+
+    fn set_tap(&mut self, tap: PacketTap) -> bool {
+        self.inner.set_tap(tap)
+    }
+
+    fn set_frame_tap(&mut self, tap: PacketTap) -> bool {
+        self.inner.set_frame_tap(tap)
+    }
+}
+
+lazy_static! {
+    static ref PORT_FAULTS: Mutex<HashMap<String, Arc<Mutex<FaultInjector>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Inject faults into the frames of the simulated serial device with the
+/// given name (see `shared_simulator`), or stop injecting with `None`
+///
+/// Unlike `FaultTransport`, the faults are applied to the serial frames, so
+/// the client's own framing and retry logic is exercised.
+pub fn set_port_faults(name: &str, scenario: Option<FaultScenario>) {
+    let mut faults = PORT_FAULTS.lock().unwrap();
+    match scenario {
+        Some(scenario) => {
+            faults.insert(
+                name.to_string(),
+                Arc::new(Mutex::new(FaultInjector::new(scenario))),
+            );
+        }
+        None => {
+            faults.remove(name);
+        }
+    }
+}
+
+/// Get the fault injector of a simulated serial device
+pub(crate) fn port_faults(name: &str) -> Option<Arc<Mutex<FaultInjector>>> {
+    PORT_FAULTS.lock().unwrap().get(name).cloned()
+}
+
+/// Get the log of the faults injected into a simulated serial device
+pub fn port_fault_log(name: &str) -> Vec<String> {
+    port_faults(name)
+        .map(|faults| faults.lock().unwrap().log().to_vec())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::echo_transport;
+    use crate::simulator::{Simulator, UdpServer};
+    use crate::transfer::{transport_error, UdpSpecs, UdpTransport};
+
+    fn upload_body(off: u32, len: usize) -> Vec<u8> {
+        let req = ImageUploadReq {
+            image_num: 0,
+            off,
+            len: None,
+            data_sha: None,
+            upgrade: None,
+            data: vec![0; len],
+        };
+        serde_cbor::to_vec(&req).unwrap()
+    }
+
+    #[test]
+    fn test_scenario_rules() {
+        let scenario: FaultScenario = serde_json::from_str(
+            r#"{
+                "seed": 7,
+                "rules": [
+                    { "fault": "drop", "probability": 0.5 },
+                    { "fault": { "error_rc": { "rc": 6 } }, "command": "Image/Upload", "offset": 1000, "times": 1 },
+                    { "fault": { "delay": { "ms": 10 } }, "nth": 2 }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let run = || {
+            let mut injector = FaultInjector::new(scenario.clone());
+            let mut all = Vec::new();
+            for i in 0..40 {
+                all.push(injector.faults(NmpGroup::Image, 1, &upload_body(i * 512, 512)));
+            }
+            (all, injector.log().to_vec())
+        };
+
+        // same seed, same decisions
+        let (faults, log) = run();
+        assert_eq!(run().1, log);
+
+        assert!(faults[1].contains(&Fault::Delay { ms: 10 }));
+        assert!(faults[1].contains(&Fault::ErrorRc { rc: 6 }));
+        assert!(!faults[2].contains(&Fault::ErrorRc { rc: 6 }));
+        let drops = faults.iter().filter(|f| f.contains(&Fault::Drop)).count();
+        assert!(drops > 5 && drops < 35, "{drops} drops");
+    }
+    #[test]
+    fn test_reorder_and_truncate() {
+        let simulator = Arc::new(Mutex::new(Simulator::default()));
+        let server = UdpServer::spawn(simulator).unwrap();
+        let udp = UdpTransport::new(&UdpSpecs {
+            host: "127.0.0.1".to_string(),
+            port: server.addr().port(),
+            mtu: 256,
+            ..Default::default()
+        })
+        .unwrap();
+        let scenario: FaultScenario = serde_json::from_str(
+            r#"{ "rules": [
+                { "fault": "reorder", "nth": 2 },
+                { "fault": "truncate_base64", "nth": 4 }
+            ] }"#,
+        )
+        .unwrap();
+        let mut transport = FaultTransport::new(udp, scenario);
+        let mut kinds = Vec::new();
+        for _ in 0..5 {
+            let result = echo_transport(&mut transport, "hello");
+            kinds.push(result.as_ref().err().map(|e| transport_error(e).unwrap()));
+        }
+
+        // the held response is read first in the next exchange
+        assert_eq!(
+            kinds,
+            vec![
+                None,
+                Some(TransportError::Timeout),
+                Some(TransportError::Seq),
+                Some(TransportError::Timeout),
+                None
+            ]
+        );
+    }
+}
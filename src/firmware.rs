@@ -0,0 +1,366 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Firmware files: Intel HEX, Motorola S-record and ELF are converted to the
+//! flat binary sent by image upload
+
+use anyhow::{bail, Context, Error, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use log::info;
+use std::fmt;
+use std::path::Path;
+
+use crate::mcuboot::McubootImage;
+
+/// Value of erased flash, used to fill the gaps between segments
+const FILL_BYTE: u8 = 0xff;
+/// Largest address range of a converted image, to catch RAM segments
+const MAX_SPAN: u64 = 64 * 1024 * 1024;
+
+/// Format of a firmware file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareFormat {
+    Binary,
+    IntelHex,
+    SRecord,
+    Elf,
+}
+
+impl fmt::Display for FirmwareFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FirmwareFormat::Binary => "binary",
+            FirmwareFormat::IntelHex => "Intel HEX",
+            FirmwareFormat::SRecord => "S-record",
+            FirmwareFormat::Elf => "ELF",
+        })
+    }
+}
+
+impl FirmwareFormat {
+    /// Detect the format from the contents of a file
+    pub fn detect(data: &[u8]) -> FirmwareFormat {
+        if data.starts_with(b"\x7fELF") {
+            return FirmwareFormat::Elf;
+        }
+        if !data.iter().all(|b| b.is_ascii()) {
+            return FirmwareFormat::Binary;
+        }
+        let text = String::from_utf8_lossy(data);
+        let first = text.trim_start().as_bytes();
+        match first {
+            [b':', ..] => FirmwareFormat::IntelHex,
+            [b'S', b'0'..=b'9', ..] => FirmwareFormat::SRecord,
+            _ => FirmwareFormat::Binary,
+        }
+    }
+}
+
+/// Convert a firmware file to a flat binary, from its lowest address
+pub fn to_binary(data: &[u8]) -> Result<(FirmwareFormat, Vec<u8>), Error> {
+    let format = FirmwareFormat::detect(data);
+    let segments = match format {
+        FirmwareFormat::Binary => return Ok((format, data.to_vec())),
+        FirmwareFormat::IntelHex => parse_intel_hex(data)?,
+        FirmwareFormat::SRecord => parse_srecord(data)?,
+        FirmwareFormat::Elf => parse_elf(data)?,
+    };
+    Ok((format, flatten(segments)?))
+}
+
+/// Load an image to upload: convert it to a binary and check that it's a
+/// valid MCUboot image
+pub fn load_image(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let (format, binary) = to_binary(data)?;
+    if format != FirmwareFormat::Binary {
+        info!("converted {} to a {} byte binary", format, binary.len());
+    }
+    let image = McubootImage::parse(&binary)?;
+    image.check_hash()?;
+    Ok(binary)
+}
+
+/// Read and load an image file, see `load_image`
+pub fn read_image(path: &Path) -> Result<Vec<u8>, Error> {
+    let data = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    load_image(&data).with_context(|| format!("{}", path.display()))
+}
+
+/// Join segments (address, data) to one binary, filling the gaps
+fn flatten(mut segments: Vec<(u64, Vec<u8>)>) -> Result<Vec<u8>, Error> {
+    segments.retain(|(_, data)| !data.is_empty());
+    segments.sort_by_key(|(addr, _)| *addr);
+    let Some(base) = segments.first().map(|(addr, _)| *addr) else {
+        bail!("no data in firmware file");
+    };
+    let end = segments
+        .iter()
+        .map(|(addr, data)| addr + data.len() as u64)
+        .max()
+        .unwrap_or(base);
+    if end - base > MAX_SPAN {
+        bail!(
+            "firmware spans 0x{:x}..0x{:x}, more than {} MB",
+            base,
+            end,
+            MAX_SPAN >> 20
+        );
+    }
+
+    let mut binary = Vec::with_capacity((end - base) as usize);
+    for (addr, data) in segments {
+        let off = (addr - base) as usize;
+        if off < binary.len() {
+            bail!("overlapping data at address 0x{:x}", addr);
+        }
+        binary.resize(off, FILL_BYTE);
+        binary.extend_from_slice(&data);
+    }
+    Ok(binary)
+}
+
+/// Decode the hex digits of a record, checking its length byte
+fn record_bytes(line: &str, line_nb: usize) -> Result<Vec<u8>, Error> {
+    let bytes = hex::decode(line).with_context(|| format!("line {line_nb}: invalid hex"))?;
+    if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+        bail!("line {}: wrong record length", line_nb);
+    }
+    Ok(bytes)
+}
+
+fn parse_intel_hex(data: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+    let text = String::from_utf8_lossy(data);
+    let mut segments = Vec::new();
+    let mut base: u64 = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line_nb = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(record) = line.strip_prefix(':') else {
+            bail!("line {}: missing ':'", line_nb);
+        };
+        // the length byte counts the data only
+        let bytes = hex::decode(record).with_context(|| format!("line {line_nb}: invalid hex"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            bail!("line {}: wrong record length", line_nb);
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            bail!("line {}: wrong checksum", line_nb);
+        }
+        let addr = BigEndian::read_u16(&bytes[1..3]) as u64;
+        let payload = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => segments.push((base + addr, payload.to_vec())),
+            0x01 => return Ok(segments),
+            0x02 if payload.len() == 2 => base = (BigEndian::read_u16(payload) as u64) << 4,
+            0x04 if payload.len() == 2 => base = (BigEndian::read_u16(payload) as u64) << 16,
+            // start addresses
+            0x03 | 0x05 => {}
+            kind => bail!("line {}: unsupported record type {:02x}", line_nb, kind),
+        }
+    }
+    bail!("missing end of file record")
+}
+
+fn parse_srecord(data: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+    let text = String::from_utf8_lossy(data);
+    let mut segments = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_nb = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut chars = line.chars();
+        if chars.next() != Some('S') {
+            bail!("line {}: missing 'S'", line_nb);
+        }
+        let kind = chars.next().unwrap_or(' ');
+        let bytes = record_bytes(chars.as_str(), line_nb)?;
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+            bail!("line {}: wrong checksum", line_nb);
+        }
+        let addr_len = match kind {
+            '1' => 2,
+            '2' => 3,
+            '3' => 4,
+            // header, record counts and start addresses
+            '0' | '5' | '6' | '7' | '8' | '9' => continue,
+            _ => bail!("line {}: unsupported record type S{}", line_nb, kind),
+        };
+        if bytes.len() < 2 + addr_len {
+            bail!("line {}: wrong record length", line_nb);
+        }
+        let addr = BigEndian::read_uint(&bytes[1..1 + addr_len], addr_len);
+        segments.push((addr, bytes[1 + addr_len..bytes.len() - 1].to_vec()));
+    }
+    Ok(segments)
+}
+
+/// Loadable segments of an ELF file, at their load (physical) address
+fn parse_elf(data: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+    const PT_LOAD: u32 = 1;
+    if data.len() < 52 {
+        bail!("truncated ELF file");
+    }
+    let is64 = match data[4] {
+        1 => false,
+        2 => true,
+        class => bail!("unsupported ELF class {}", class),
+    };
+    let big_endian = match data[5] {
+        1 => false,
+        2 => true,
+        encoding => bail!("unsupported ELF data encoding {}", encoding),
+    };
+    let read = |off: usize, len: usize| -> Result<u64, Error> {
+        let bytes = off
+            .checked_add(len)
+            .and_then(|end| data.get(off..end))
+            .context("truncated ELF file")?;
+        Ok(if big_endian {
+            BigEndian::read_uint(bytes, len)
+        } else {
+            LittleEndian::read_uint(bytes, len)
+        })
+    };
+
+    let (phoff, phentsize, phnum) = if is64 {
+        (read(32, 8)?, read(54, 2)?, read(56, 2)?)
+    } else {
+        (read(28, 4)?, read(42, 2)?, read(44, 2)?)
+    };
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = i
+            .checked_mul(phentsize)
+            .and_then(|off| off.checked_add(phoff))
+            .and_then(|off| usize::try_from(off).ok())
+            .filter(|ph| ph.checked_add(if is64 { 56 } else { 32 }).is_some())
+            .context("invalid ELF program header")?;
+        let (kind, offset, paddr, filesz) = if is64 {
+            (read(ph, 4)?, read(ph + 8, 8)?, read(ph + 24, 8)?, read(ph + 32, 8)?)
+        } else {
+            (read(ph, 4)?, read(ph + 4, 4)?, read(ph + 12, 4)?, read(ph + 16, 4)?)
+        };
+        // segments without file contents, like .bss, are not flashed
+        if kind != PT_LOAD as u64 || filesz == 0 {
+            continue;
+        }
+        let (start, end) = offset
+            .checked_add(filesz)
+            .and_then(|end| Some((usize::try_from(offset).ok()?, usize::try_from(end).ok()?)))
+            .context("invalid ELF program header")?;
+        let contents = data.get(start..end).context("ELF segment outside of the file")?;
+        segments.push((paddr, contents.to_vec()));
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intel_hex_line(kind: u8, addr: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        format!(":{}\n", hex::encode_upper(bytes))
+    }
+
+    fn srecord_line(kind: char, addr: u32, data: &[u8]) -> String {
+        let mut bytes = vec![(data.len() + 5) as u8];
+        bytes.extend_from_slice(&addr.to_be_bytes());
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(!sum);
+        format!("S{}{}\n", kind, hex::encode_upper(bytes))
+    }
+
+    #[test]
+    fn test_formats() {
+        // two records at 0x0001_0000 and 0x0001_0008, with a gap
+        let hex = intel_hex_line(4, 0, &[0x00, 0x01])
+            + &intel_hex_line(0, 0, &[1, 2, 3, 4])
+            + &intel_hex_line(0, 8, &[5, 6])
+            + &intel_hex_line(1, 0, &[]);
+        let expected = vec![1, 2, 3, 4, 0xff, 0xff, 0xff, 0xff, 5, 6];
+        assert_eq!(
+            to_binary(hex.as_bytes()).unwrap(),
+            (FirmwareFormat::IntelHex, expected.clone())
+        );
+
+        let srec = "S00600004844521B\n".to_string()
+            + &srecord_line('3', 0x0001_0000, &[1, 2, 3, 4])
+            + &srecord_line('3', 0x0001_0008, &[5, 6])
+            + "S70500010000F9\n";
+        assert_eq!(
+            to_binary(srec.as_bytes()).unwrap(),
+            (FirmwareFormat::SRecord, expected.clone())
+        );
+
+        // ELF32 little endian with a text and a .bss segment
+        let mut elf = vec![0u8; 52 + 2 * 32];
+        elf[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        LittleEndian::write_u32(&mut elf[28..], 52);
+        LittleEndian::write_u16(&mut elf[42..], 32);
+        LittleEndian::write_u16(&mut elf[44..], 2);
+        for (i, (offset, paddr, filesz)) in [(116, 0x0001_0000, 10), (0, 0x2000_0000, 0)]
+            .into_iter()
+            .enumerate()
+        {
+            let ph = &mut elf[52 + i * 32..];
+            LittleEndian::write_u32(&mut ph[0..], 1);
+            LittleEndian::write_u32(&mut ph[4..], offset);
+            LittleEndian::write_u32(&mut ph[8..], 0x0800_0000);
+            LittleEndian::write_u32(&mut ph[12..], paddr);
+            LittleEndian::write_u32(&mut ph[16..], filesz);
+            LittleEndian::write_u32(&mut ph[20..], filesz + 0x100);
+        }
+        elf.extend_from_slice(&expected);
+        assert_eq!(to_binary(&elf).unwrap(), (FirmwareFormat::Elf, expected));
+
+        assert_eq!(
+            to_binary(&[0x3d, 0xb8, 0xf3, 0x96]).unwrap().0,
+            FirmwareFormat::Binary
+        );
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let record = intel_hex_line(0, 0, &[1, 2, 3, 4]);
+        let corrupted = record.replace("01020304", "01020305") + &intel_hex_line(1, 0, &[]);
+        assert!(to_binary(corrupted.as_bytes()).is_err());
+        assert!(to_binary(record.as_bytes()).is_err());
+
+        let overlap = record.clone() + &intel_hex_line(0, 2, &[9]) + &intel_hex_line(1, 0, &[]);
+        assert!(to_binary(overlap.as_bytes()).is_err());
+
+        // flash and RAM addresses in one file
+        let srec = srecord_line('3', 0x0001_0000, &[1]) + &srecord_line('3', 0x2000_0000, &[2]);
+        assert!(to_binary(srec.as_bytes()).is_err());
+
+        // ELF64 program headers with offsets overflowing the address space
+        let mut elf = vec![0u8; 64 + 56];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        LittleEndian::write_u64(&mut elf[32..], u64::MAX - 10);
+        LittleEndian::write_u16(&mut elf[54..], 56);
+        LittleEndian::write_u16(&mut elf[56..], 2);
+        let err = to_binary(&elf).unwrap_err();
+        assert_eq!(err.to_string(), "invalid ELF program header");
+        LittleEndian::write_u64(&mut elf[32..], 64);
+        LittleEndian::write_u16(&mut elf[56..], 1);
+        LittleEndian::write_u32(&mut elf[64..], 1);
+        LittleEndian::write_u64(&mut elf[64 + 8..], u64::MAX - 4);
+        LittleEndian::write_u64(&mut elf[64 + 32..], 16);
+        let err = to_binary(&elf).unwrap_err();
+        assert_eq!(err.to_string(), "invalid ELF program header");
+
+        // a converted file must be an MCUboot image
+        let hex = intel_hex_line(0, 0, &[1, 2, 3, 4]) + &intel_hex_line(1, 0, &[]);
+        let err = load_image(hex.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("not an MCUboot image"), "{err}");
+    }
+}
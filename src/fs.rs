@@ -1,18 +1,22 @@
 // Copyright © 2026 Rudis Laboratories LLC
 
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, Context, Error, Result};
 use indicatif::{ProgressBar, ProgressStyle};
-use log::{debug, info};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::fmt;
 use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::Path;
-use std::time::Duration;
 
 use crate::nmp_hdr::*;
+use crate::shell::{shell_exec, shell_exec_transport};
 use crate::transfer::encode_request;
 use crate::transfer::next_seq_id;
 use crate::transfer::open_port;
 use crate::transfer::transceive;
 use crate::transfer::SerialSpecs;
+use crate::transfer::SerialTransport;
 use crate::transfer::Transport;
 
 fn check_answer(request_header: &NmpHdr, response_header: &NmpHdr) -> bool {
@@ -52,25 +56,173 @@ fn get_rc(response_body: &serde_cbor::Value) -> Option<i32> {
     None
 }
 
+/// Timeout of the packets after the first one of a transfer
+const SUBSEQUENT_TIMEOUT_MS: u32 = 200;
+
+/// Timeout of the hash of a transferred file, the device reads all of it
+const HASH_TIMEOUT_MS: u32 = 10_000;
+
+/// Options of a file transfer
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    /// Compare the file with the fs hash of the device after the transfer
+    pub verify: bool,
+    /// Times a file is transferred again after a mismatch
+    pub retries: u32,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            verify: true,
+            retries: 2,
+        }
+    }
+}
+
+/// Outcome of a file transfer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferReport {
+    pub len: u64,
+    /// The hash type that verified the file, none when not verified
+    pub verified: Option<HashType>,
+    /// Transfers made, more than one after a mismatch
+    pub attempts: u32,
+}
+
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes", self.len)?;
+        match self.verified {
+            Some(hash_type) => write!(f, ", verified by {hash_type}")?,
+            None => write!(f, ", not verified")?,
+        }
+        if self.attempts > 1 {
+            write!(f, " after {} transfers", self.attempts)?;
+        }
+        Ok(())
+    }
+}
+
 /// Download a file from the device
 ///
-/// Downloads a file from the remote path on the device to a local file.
-pub fn download(specs: &SerialSpecs, remote_path: &str, local_path: &Path) -> Result<(), Error> {
+/// Downloads a file from the remote path on the device to a local file. The
+/// data goes to a `.part` file next to it, renamed once verified with the fs
+/// hash of the device.
+pub fn download(
+    specs: &SerialSpecs,
+    remote_path: &str,
+    local_path: &Path,
+    options: &TransferOptions,
+) -> Result<TransferReport, Error> {
+    let mut transport = SerialTransport::new(specs)?;
+    let timeout = specs.subsequent_timeout_ms;
+    download_file(&mut transport, remote_path, local_path, options, timeout)
+}
+
+/// Download a file from the device to a writer, such as stdout; the data is
+/// verified once written, so a mismatch fails without a retry
+pub fn download_to(
+    specs: &SerialSpecs,
+    remote_path: &str,
+    writer: &mut dyn Write,
+    options: &TransferOptions,
+) -> Result<TransferReport, Error> {
+    let mut transport = SerialTransport::new(specs)?;
+    let timeout = specs.subsequent_timeout_ms;
+    download_to_writer(&mut transport, remote_path, writer, options, timeout)
+}
+
+/// Upload a file to the device
+///
+/// Uploads a local file to the remote path on the device, reading it as it
+/// goes, and verifies it with the fs hash of the device.
+pub fn upload(
+    specs: &SerialSpecs,
+    local_path: &Path,
+    remote_path: &str,
+    options: &TransferOptions,
+) -> Result<TransferReport, Error> {
+    let mut transport = SerialTransport::new(specs)?;
+    let timeout = specs.subsequent_timeout_ms;
+    upload_file(&mut transport, local_path, remote_path, options, timeout)
+}
+
+/// Upload `len` bytes read from a reader to the device; the device needs the
+/// length of a file up front. The reader is read once, so a mismatch fails
+/// without a retry.
+pub fn upload_from(
+    specs: &SerialSpecs,
+    reader: &mut dyn Read,
+    len: u64,
+    remote_path: &str,
+    options: &TransferOptions,
+) -> Result<TransferReport, Error> {
+    let mut transport = SerialTransport::new(specs)?;
+    let timeout = specs.subsequent_timeout_ms;
+    upload_from_reader(&mut transport, reader, len, remote_path, options, timeout)
+}
+
+fn download_file(
+    transport: &mut dyn Transport,
+    remote_path: &str,
+    local_path: &Path,
+    options: &TransferOptions,
+    subsequent_timeout_ms: u32,
+) -> Result<TransferReport, Error> {
     info!("download file: {} -> {}", remote_path, local_path.display());
 
-    let mut port = open_port(specs)?;
-    let mut file_data: Vec<u8> = Vec::new();
+    let mut name = local_path
+        .file_name()
+        .with_context(|| format!("{} is not a file name", local_path.display()))?
+        .to_os_string();
+    name.push(".part");
+    let part = local_path.with_file_name(name);
+    let result = verified_transfer(transport, remote_path, options, options.retries, &mut |t| {
+        let file = fs::File::create(&part)
+            .with_context(|| format!("failed to create {}", part.display()))?;
+        let mut writer = BufWriter::new(file);
+        let digest = download_stream(t, remote_path, &mut writer, subsequent_timeout_ms)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(digest)
+    });
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            let _ = fs::remove_file(&part);
+            return Err(e);
+        }
+    };
+    fs::rename(&part, local_path)
+        .with_context(|| format!("failed to rename {}", part.display()))?;
+    Ok(report)
+}
+
+fn download_to_writer(
+    transport: &mut dyn Transport,
+    remote_path: &str,
+    writer: &mut dyn Write,
+    options: &TransferOptions,
+    subsequent_timeout_ms: u32,
+) -> Result<TransferReport, Error> {
+    info!("download file: {}", remote_path);
+    verified_transfer(transport, remote_path, options, 0, &mut |t| {
+        download_stream(t, remote_path, writer, subsequent_timeout_ms)
+    })
+}
+
+fn download_stream(
+    transport: &mut dyn Transport,
+    remote_path: &str,
+    writer: &mut dyn Write,
+    subsequent_timeout_ms: u32,
+) -> Result<StreamDigest, Error> {
+    let mut digest = StreamDigest::default();
     let mut offset: u32 = 0;
     let mut total_len: Option<u32> = None;
 
-    // Create progress bar (will be set up after we know the file size)
-    let pb = ProgressBar::new(0);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=> "),
-    );
+    // The length is set once known from the first chunk
+    let pb = progress_bar(0);
 
     loop {
         let req = FsDownloadReq {
@@ -79,20 +231,8 @@ pub fn download(specs: &SerialSpecs, remote_path: &str, local_path: &Path) -> Re
         };
         let body = serde_cbor::to_vec(&req)?;
 
-        let (data, request_header) = encode_request(
-            specs.linelength,
-            NmpOp::Read,
-            NmpGroup::Fs,
-            NmpIdFs::File,
-            &body,
-            next_seq_id(),
-        )?;
-
-        let (response_header, response_body) = transceive(&mut *port, &data)?;
-
-        if !check_answer(&request_header, &response_header) {
-            bail!("wrong answer types");
-        }
+        let (_response_header, response_body) =
+            transport.transceive(NmpOp::Read, NmpGroup::Fs, NmpIdFs::File.to_u8(), &body)?;
 
         debug!("response_body: {}", serde_json::to_string_pretty(&response_body)?);
 
@@ -113,90 +253,109 @@ pub fn download(specs: &SerialSpecs, remote_path: &str, local_path: &Path) -> Re
                 pb.set_length(len as u64);
             }
         }
+        // The data already written can't be taken back
+        if rsp.off != offset {
+            bail!("wrong offset received: {}, expected {}", rsp.off, offset);
+        }
 
-        // Append data
-        file_data.extend_from_slice(&rsp.data);
-        offset = rsp.off + rsp.data.len() as u32;
+        writer
+            .write_all(&rsp.data)
+            .context("failed to write the downloaded data")?;
+        digest.update(&rsp.data);
+        offset += rsp.data.len() as u32;
         pb.set_position(offset as u64);
 
-        // Check if we're done
-        if let Some(len) = total_len {
-            if offset >= len {
-                break;
-            }
-        }
-
-        // If no data was returned, we're done
-        if rsp.data.is_empty() {
+        // Done with the whole file, or when no data was returned
+        if total_len.is_some_and(|len| offset >= len) || rsp.data.is_empty() {
             break;
         }
 
         // Reduce timeout for subsequent packets
-        port.set_timeout(Duration::from_millis(specs.subsequent_timeout_ms as u64))?;
+        transport.set_timeout(subsequent_timeout_ms)?;
     }
+    writer.flush().context("failed to write the downloaded data")?;
 
     pb.finish_with_message("download complete");
+    info!("downloaded {} bytes", offset);
 
-    // Write to local file
-    fs::write(local_path, &file_data)?;
-    info!("downloaded {} bytes", file_data.len());
-
-    Ok(())
+    Ok(digest)
 }
 
-/// Upload a file to the device
-///
-/// Uploads a local file to the remote path on the device.
-pub fn upload(specs: &SerialSpecs, local_path: &Path, remote_path: &str) -> Result<(), Error> {
+fn upload_file(
+    transport: &mut dyn Transport,
+    local_path: &Path,
+    remote_path: &str,
+    options: &TransferOptions,
+    subsequent_timeout_ms: u32,
+) -> Result<TransferReport, Error> {
     info!("upload file: {} -> {}", local_path.display(), remote_path);
 
-    let mut port = open_port(specs)?;
-    let file_data = fs::read(local_path)?;
-    let total_len = file_data.len() as u32;
+    verified_transfer(transport, remote_path, options, options.retries, &mut |t| {
+        let file = fs::File::open(local_path)
+            .with_context(|| format!("failed to read {}", local_path.display()))?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        upload_stream(t, &mut reader, len, remote_path, subsequent_timeout_ms)
+    })
+}
+
+fn upload_from_reader(
+    transport: &mut dyn Transport,
+    reader: &mut dyn Read,
+    len: u64,
+    remote_path: &str,
+    options: &TransferOptions,
+    subsequent_timeout_ms: u32,
+) -> Result<TransferReport, Error> {
+    info!("upload file: {}", remote_path);
+    verified_transfer(transport, remote_path, options, 0, &mut |t| {
+        upload_stream(t, reader, len, remote_path, subsequent_timeout_ms)
+    })
+}
+
+fn upload_stream(
+    transport: &mut dyn Transport,
+    reader: &mut dyn Read,
+    len: u64,
+    remote_path: &str,
+    subsequent_timeout_ms: u32,
+) -> Result<StreamDigest, Error> {
+    let Ok(total_len) = u32::try_from(len) else {
+        bail!("{} bytes is too large for the fs group", len);
+    };
+    let mtu = transport.mtu();
+    let mut digest = StreamDigest::default();
+    // Data read but not yet taken by the device
+    let mut pending: Vec<u8> = Vec::new();
     let mut offset: u32 = 0;
 
     info!("{} bytes to transfer", total_len);
 
-    // Create progress bar
-    let pb = ProgressBar::new(total_len as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=> "),
-    );
+    let pb = progress_bar(total_len as u64);
 
-    while offset < total_len {
+    // An empty file still takes one request to create it
+    loop {
         // Calculate chunk size based on MTU
-        let mut chunk_size = specs.mtu;
-        if offset + chunk_size as u32 > total_len {
-            chunk_size = (total_len - offset) as usize;
+        let chunk_size = mtu.min((total_len - offset) as usize);
+        if pending.len() < chunk_size {
+            let start = pending.len();
+            pending.resize(chunk_size, 0);
+            reader
+                .read_exact(&mut pending[start..])
+                .context("failed to read the data to upload")?;
+            digest.update(&pending[start..]);
         }
 
-        let chunk = file_data[offset as usize..(offset as usize + chunk_size)].to_vec();
-
         let req = FsUploadReq {
             name: remote_path.to_string(),
             off: offset,
-            data: chunk,
+            data: pending[..chunk_size].to_vec(),
             len: if offset == 0 { Some(total_len) } else { None },
         };
         let body = serde_cbor::to_vec(&req)?;
 
-        let (data, request_header) = encode_request(
-            specs.linelength,
-            NmpOp::Write,
-            NmpGroup::Fs,
-            NmpIdFs::File,
-            &body,
-            next_seq_id(),
-        )?;
-
-        let (response_header, response_body) = transceive(&mut *port, &data)?;
-
-        if !check_answer(&request_header, &response_header) {
-            bail!("wrong answer types");
-        }
+        let (_response_header, response_body) =
+            transport.transceive(NmpOp::Write, NmpGroup::Fs, NmpIdFs::File.to_u8(), &body)?;
 
         debug!("response_body: {}", serde_json::to_string_pretty(&response_body)?);
 
@@ -210,19 +369,154 @@ pub fn upload(specs: &SerialSpecs, local_path: &Path, remote_path: &str) -> Resu
         let rsp: FsUploadRsp = serde_cbor::value::from_value(response_body)
             .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
 
+        // The device may take less than the chunk, the rest is sent again
+        let taken = rsp.off.wrapping_sub(offset) as usize;
+        if rsp.off < offset || taken > chunk_size || (taken == 0 && chunk_size > 0) {
+            bail!("wrong offset received: {}, sent {} bytes at {}", rsp.off, chunk_size, offset);
+        }
+        pending.drain(..taken);
         offset = rsp.off;
         pb.set_position(offset as u64);
 
-        // Reduce timeout for subsequent packets
-        if offset > 0 {
-            port.set_timeout(Duration::from_millis(specs.subsequent_timeout_ms as u64))?;
+        if offset >= total_len {
+            break;
         }
+
+        // Reduce timeout for subsequent packets
+        transport.set_timeout(subsequent_timeout_ms)?;
     }
 
     pb.finish_with_message("upload complete");
     info!("uploaded {} bytes", total_len);
 
-    Ok(())
+    Ok(digest)
+}
+
+fn progress_bar(len: u64) -> ProgressBar {
+    let pb = ProgressBar::new(len);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    pb
+}
+
+/// SHA256 and CRC32 of the data of a transfer, computed as it streams
+#[derive(Default)]
+struct StreamDigest {
+    sha: Sha256,
+    crc: crc32fast::Hasher,
+    len: u64,
+}
+
+impl StreamDigest {
+    fn update(&mut self, data: &[u8]) {
+        self.sha.update(data);
+        self.crc.update(data);
+        self.len += data.len() as u64;
+    }
+
+    /// The hash as the device outputs it
+    fn output(&self, hash_type: HashType) -> Vec<u8> {
+        match hash_type {
+            HashType::Sha256 => self.sha.clone().finalize().to_vec(),
+            HashType::Crc32 => self.crc.clone().finalize().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// Result of comparing a transfer with the fs hash of the device
+enum Verification {
+    Match(HashType),
+    Mismatch(HashType),
+    /// The device has no fs hash
+    Unavailable,
+}
+
+/// Run a transfer and compare it with the fs hash of the device, running it
+/// again after a mismatch up to `retries` times
+fn verified_transfer(
+    transport: &mut dyn Transport,
+    remote_path: &str,
+    options: &TransferOptions,
+    retries: u32,
+    transfer: &mut dyn FnMut(&mut dyn Transport) -> Result<StreamDigest, Error>,
+) -> Result<TransferReport, Error> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let digest = transfer(transport)?;
+        let report = TransferReport {
+            len: digest.len,
+            verified: None,
+            attempts,
+        };
+        if !options.verify {
+            return Ok(report);
+        }
+        match verify_transfer(transport, remote_path, &digest)? {
+            Verification::Match(hash_type) => {
+                info!("{}: verified by {}", remote_path, hash_type);
+                return Ok(TransferReport {
+                    verified: Some(hash_type),
+                    ..report
+                });
+            }
+            Verification::Unavailable => return Ok(report),
+            Verification::Mismatch(hash_type) if attempts > retries => {
+                bail!(
+                    "{}: the {} of the device doesn't match the transferred data, {} transfers",
+                    remote_path,
+                    hash_type,
+                    attempts
+                );
+            }
+            Verification::Mismatch(hash_type) => warn!(
+                "{}: the {} of the device doesn't match the transferred data, transferring again",
+                remote_path,
+                hash_type
+            ),
+        }
+    }
+}
+
+/// Compare the data of a transfer with the fs hash of the file on the device,
+/// by the best hash type of the device, with the timeout of the transport
+/// restored afterwards
+fn verify_transfer(
+    transport: &mut dyn Transport,
+    remote_path: &str,
+    digest: &StreamDigest,
+) -> Result<Verification, Error> {
+    let previous = transport.timeout();
+    transport.set_timeout(HASH_TIMEOUT_MS)?;
+    let verification = compare_hash(transport, remote_path, digest);
+    if let Some(timeout) = previous {
+        transport.set_timeout(timeout)?;
+    }
+    Ok(verification)
+}
+
+/// Compare the data of a transfer with the fs hash of the device
+fn compare_hash(
+    transport: &mut dyn Transport,
+    remote_path: &str,
+    digest: &StreamDigest,
+) -> Verification {
+    let hash_type = best_hash_type_transport(transport);
+    let rsp = match hash_transport(transport, remote_path, Some(hash_type.name()), None, None) {
+        Ok(rsp) => rsp,
+        Err(e) => {
+            warn!("{}: not verified, no fs hash from the device: {:#}", remote_path, e);
+            return Verification::Unavailable;
+        }
+    };
+    if rsp.len as u64 != digest.len || rsp.output != digest.output(hash_type) {
+        return Verification::Mismatch(hash_type);
+    }
+    Verification::Match(hash_type)
 }
 
 /// Get file status (size) from the device
@@ -310,158 +604,340 @@ pub fn hash(
     Ok(rsp)
 }
 
-// ==================== Transport-based versions ====================
-
-/// Download a file using a transport
-pub fn download_transport(transport: &mut dyn Transport, remote_path: &str, local_path: &Path) -> Result<(), Error> {
-    info!("download file: {} -> {}", remote_path, local_path.display());
-
-    let mut file_data: Vec<u8> = Vec::new();
-    let mut offset: u32 = 0;
-    let mut total_len: Option<u32> = None;
-
-    // Create progress bar
-    let pb = ProgressBar::new(0);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=> "),
-    );
-
-    loop {
-        let req = FsDownloadReq {
-            name: remote_path.to_string(),
-            off: offset,
-        };
-        let body = serde_cbor::to_vec(&req)?;
-
-        let (_response_header, response_body) = transport.transceive(
-            NmpOp::Read,
-            NmpGroup::Fs,
-            NmpIdFs::File.to_u8(),
-            &body,
-        )?;
+/// Hash or checksum type used to compare files with the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashType {
+    Sha256,
+    Crc32,
+}
 
-        debug!("response_body: {}", serde_json::to_string_pretty(&response_body)?);
+impl fmt::Display for HashType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
-        // Check for rc error
-        if let Some(rc) = get_rc(&response_body) {
-            if rc != 0 {
-                bail!("Error from device: rc={}", rc);
-            }
+impl HashType {
+    /// Name of the type in fs hash requests
+    pub fn name(self) -> &'static str {
+        match self {
+            HashType::Sha256 => "sha256",
+            HashType::Crc32 => "crc32",
         }
+    }
 
-        let rsp: FsDownloadRsp = serde_cbor::value::from_value(response_body)
-            .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
-
-        // On first chunk, get the total length
-        if offset == 0 {
-            if let Some(len) = rsp.len {
-                total_len = Some(len);
-                pb.set_length(len as u64);
-            }
+    /// SHA256 if the device supports it, else CRC32
+    pub fn best(supported: &[String]) -> HashType {
+        if supported.iter().any(|t| t == "sha256") {
+            HashType::Sha256
+        } else {
+            HashType::Crc32
         }
+    }
 
-        // Append data
-        file_data.extend_from_slice(&rsp.data);
-        offset = rsp.off + rsp.data.len() as u32;
-        pb.set_position(offset as u64);
-
-        // Check if we're done
-        if let Some(len) = total_len {
-            if offset >= len {
+    /// Hash of a local file, as the device outputs it
+    pub fn digest_file(self, path: &Path) -> Result<Vec<u8>, Error> {
+        let mut file = fs::File::open(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let mut sha = Sha256::new();
+        let mut crc = crc32fast::Hasher::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
                 break;
             }
+            match self {
+                HashType::Sha256 => sha.update(&buf[..n]),
+                HashType::Crc32 => crc.update(&buf[..n]),
+            }
         }
+        Ok(match self {
+            HashType::Sha256 => sha.finalize().to_vec(),
+            HashType::Crc32 => crc.finalize().to_be_bytes().to_vec(),
+        })
+    }
+}
 
-        // If no data was returned, we're done
-        if rsp.data.is_empty() {
-            break;
-        }
+/// Hash and checksum types the device supports
+pub fn hash_types(specs: &SerialSpecs) -> Result<Vec<String>, Error> {
+    debug!("hash types request");
 
-        // Reduce timeout for subsequent packets
-        transport.set_timeout(200)?;
-    }
+    let mut port = open_port(specs)?;
+    let body = serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new())?;
+    let (data, request_header) = encode_request(
+        specs.linelength,
+        NmpOp::Read,
+        NmpGroup::Fs,
+        NmpIdFs::SupportedHashTypes,
+        &body,
+        next_seq_id(),
+    )?;
 
-    pb.finish_with_message("download complete");
+    let (response_header, response_body) = transceive(&mut *port, &data)?;
 
-    // Write to local file
-    fs::write(local_path, &file_data)?;
-    info!("downloaded {} bytes", file_data.len());
+    if !check_answer(&request_header, &response_header) {
+        bail!("wrong answer types");
+    }
 
-    Ok(())
+    parse_hash_types(response_body)
 }
 
-/// Upload a file using a transport
-pub fn upload_transport(transport: &mut dyn Transport, local_path: &Path, remote_path: &str) -> Result<(), Error> {
-    info!("upload file: {} -> {}", local_path.display(), remote_path);
+fn parse_hash_types(response_body: serde_cbor::Value) -> Result<Vec<String>, Error> {
+    debug!("response_body: {}", serde_json::to_string_pretty(&response_body)?);
 
-    let file_data = fs::read(local_path)?;
-    let total_len = file_data.len() as u32;
-    let mut offset: u32 = 0;
-    let mtu = transport.mtu();
+    let rsp: FsHashTypesRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
 
-    info!("{} bytes to transfer", total_len);
+    if rsp.rc != 0 {
+        bail!("Error from device: rc={}", rsp.rc);
+    }
 
-    // Create progress bar
-    let pb = ProgressBar::new(total_len as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=> "),
-    );
+    Ok(rsp.types.into_keys().collect())
+}
 
-    while offset < total_len {
-        // Calculate chunk size based on MTU
-        let mut chunk_size = mtu;
-        if offset + chunk_size as u32 > total_len {
-            chunk_size = (total_len - offset) as usize;
+/// The best hash type of the device, see `HashType::best`; CRC32 when the
+/// device can't list its types
+pub fn best_hash_type(specs: &SerialSpecs) -> HashType {
+    match hash_types(specs) {
+        Ok(types) => HashType::best(&types),
+        Err(e) => {
+            debug!("no hash types: {}", e);
+            HashType::Crc32
         }
+    }
+}
 
-        let chunk = file_data[offset as usize..(offset as usize + chunk_size)].to_vec();
+/// Entry of a directory on the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Size of a file, known from `list_dir_long` only
+    pub size: Option<u64>,
+}
 
-        let req = FsUploadReq {
-            name: remote_path.to_string(),
-            off: offset,
-            data: chunk,
-            len: if offset == 0 { Some(total_len) } else { None },
-        };
-        let body = serde_cbor::to_vec(&req)?;
+impl fmt::Display for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = self.size.map_or("-".to_string(), |size| size.to_string());
+        let kind = if self.is_dir { "dir" } else { "file" };
+        let slash = if self.is_dir { "/" } else { "" };
+        write!(f, "{:<4} {:>10}  {}{}", kind, size, self.name, slash)
+    }
+}
 
-        let (_response_header, response_body) = transport.transceive(
-            NmpOp::Write,
-            NmpGroup::Fs,
-            NmpIdFs::File.to_u8(),
-            &body,
-        )?;
+/// What went wrong in an fs shell command, from the errno the device printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsErrorKind {
+    NotFound,
+    Exists,
+    NotEmpty,
+    NotADirectory,
+    IsADirectory,
+    NoSpace,
+    /// The device shell has no such command
+    Unsupported,
+    Other,
+}
 
-        debug!("response_body: {}", serde_json::to_string_pretty(&response_body)?);
+/// Failure of an fs command in the device shell; reach it with
+/// `Error::downcast_ref`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsShellError {
+    pub command: String,
+    pub path: String,
+    pub kind: FsErrorKind,
+    /// Return code of the shell command
+    pub ret: i32,
+    /// Output of the shell command
+    pub output: String,
+}
 
-        // Check for rc error
-        if let Some(rc) = get_rc(&response_body) {
-            if rc != 0 {
-                bail!("Error from device: rc={}", rc);
+impl fmt::Display for FsShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fs {} {}: ", self.command, self.path)?;
+        match self.kind {
+            FsErrorKind::NotFound => f.write_str("no such file or directory"),
+            FsErrorKind::Exists => f.write_str("already exists"),
+            FsErrorKind::NotEmpty => f.write_str("directory not empty"),
+            FsErrorKind::NotADirectory => f.write_str("not a directory"),
+            FsErrorKind::IsADirectory => f.write_str("is a directory"),
+            FsErrorKind::NoSpace => f.write_str("no space left on the device"),
+            FsErrorKind::Unsupported => {
+                write!(f, "the device shell has no fs {} command", self.command)
             }
+            FsErrorKind::Other => write!(f, "failed, ret={}: {}", self.ret, self.output.trim()),
         }
+    }
+}
 
-        let rsp: FsUploadRsp = serde_cbor::value::from_value(response_body)
-            .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+impl std::error::Error for FsShellError {}
 
-        offset = rsp.off;
-        pb.set_position(offset as u64);
+/// List a directory on the device
+///
+/// The SMP fs group has no directory listing, so this runs `fs ls` in the
+/// device shell, which needs the shell group and the fs shell commands.
+pub fn list_dir(specs: &SerialSpecs, path: &str) -> Result<Vec<DirEntry>, Error> {
+    let rsp = shell_exec(specs, vec!["fs".into(), "ls".into(), path.into()])?;
+    parse_ls(path, &rsp)
+}
 
-        // Reduce timeout for subsequent packets
-        if offset > 0 {
-            transport.set_timeout(200)?;
+/// List a directory on the device with the size of each file, which `fs ls`
+/// doesn't print: each file takes an fs stat request
+pub fn list_dir_long(specs: &SerialSpecs, path: &str) -> Result<Vec<DirEntry>, Error> {
+    let mut entries = list_dir(specs, path)?;
+    for entry in entries.iter_mut().filter(|e| !e.is_dir) {
+        let rsp = stat(specs, &join_path(path, &entry.name))?;
+        entry.size = Some(rsp.len as u64);
+    }
+    Ok(entries)
+}
+
+/// Create a directory on the device, with `fs mkdir` in the device shell
+pub fn mkdir(specs: &SerialSpecs, path: &str) -> Result<(), Error> {
+    let rsp = shell_exec(specs, vec!["fs".into(), "mkdir".into(), path.into()])?;
+    check_shell("mkdir", path, &rsp)
+}
+
+/// Remove a file or an empty directory on the device, with `fs rm` in the
+/// device shell
+pub fn remove(specs: &SerialSpecs, path: &str) -> Result<(), Error> {
+    let rsp = shell_exec(specs, vec!["fs".into(), "rm".into(), path.into()])?;
+    check_shell("rm", path, &rsp)
+}
+
+/// Move a file on the device, with `fs mv` in the device shell
+///
+/// The Zephyr fs shell has no `fs mv`, so without it the file is copied
+/// through the fs group, in memory, and then removed.
+pub fn rename(specs: &SerialSpecs, from: &str, to: &str) -> Result<(), Error> {
+    let rsp = shell_exec(specs, vec!["fs".into(), "mv".into(), from.into(), to.into()])?;
+    match check_shell("mv", from, &rsp) {
+        Err(e) if is_unsupported(&e) => {
+            debug!("{:#}, copying", e);
+            let options = TransferOptions::default();
+            let mut data = Vec::new();
+            download_to(specs, from, &mut data, &options)?;
+            upload_from(specs, &mut &data[..], data.len() as u64, to, &options)?;
+            remove(specs, from)
         }
+        result => result,
     }
+}
 
-    pb.finish_with_message("upload complete");
-    info!("uploaded {} bytes", total_len);
+fn is_unsupported(e: &Error) -> bool {
+    e.downcast_ref::<FsShellError>()
+        .is_some_and(|e| e.kind == FsErrorKind::Unsupported)
+}
 
-    Ok(())
+fn check_shell(command: &str, path: &str, rsp: &ShellExecRsp) -> Result<(), Error> {
+    if rsp.rc == 0 {
+        return Ok(());
+    }
+    let kind = if rsp.o.contains("command not found") || rsp.o.contains("wrong parameter count")
+    {
+        FsErrorKind::Unsupported
+    } else {
+        // Zephyr and newlib numbers, as printed by the fs shell
+        match shell_errno(&rsp.o) {
+            Some(2) => FsErrorKind::NotFound,
+            Some(17) => FsErrorKind::Exists,
+            Some(20) => FsErrorKind::NotADirectory,
+            Some(21) => FsErrorKind::IsADirectory,
+            Some(28) => FsErrorKind::NoSpace,
+            Some(39 | 90) => FsErrorKind::NotEmpty,
+            _ => FsErrorKind::Other,
+        }
+    };
+    Err(FsShellError {
+        command: command.to_string(),
+        path: path.to_string(),
+        kind,
+        ret: rsp.rc,
+        output: rsp.o.clone(),
+    }
+    .into())
+}
+
+/// The last negative number in the output of a shell command, like `-2` in
+/// `Failed to remove /lfs/x (-2)`, as a positive errno
+fn shell_errno(output: &str) -> Option<i32> {
+    output.match_indices('-').rev().find_map(|(i, _)| {
+        let digits: String =
+            output[i + 1..].chars().take_while(char::is_ascii_digit).collect();
+        digits.parse().ok()
+    })
+}
+
+/// Parse the output of `fs ls`: one entry per line, directories end with `/`
+fn parse_ls(path: &str, rsp: &ShellExecRsp) -> Result<Vec<DirEntry>, Error> {
+    check_shell("ls", path, rsp)?;
+    let entries = rsp
+        .o
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.strip_suffix('/') {
+            Some(name) => DirEntry {
+                name: name.to_string(),
+                is_dir: true,
+                size: None,
+            },
+            None => DirEntry {
+                name: line.to_string(),
+                is_dir: false,
+                size: None,
+            },
+        })
+        .collect();
+    Ok(entries)
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+// ==================== Transport-based versions ====================
+
+/// Download a file using a transport
+pub fn download_transport(
+    transport: &mut dyn Transport,
+    remote_path: &str,
+    local_path: &Path,
+    options: &TransferOptions,
+) -> Result<TransferReport, Error> {
+    download_file(transport, remote_path, local_path, options, SUBSEQUENT_TIMEOUT_MS)
+}
+
+/// Download a file to a writer using a transport, see `download_to`
+pub fn download_to_transport(
+    transport: &mut dyn Transport,
+    remote_path: &str,
+    writer: &mut dyn Write,
+    options: &TransferOptions,
+) -> Result<TransferReport, Error> {
+    download_to_writer(transport, remote_path, writer, options, SUBSEQUENT_TIMEOUT_MS)
+}
+
+/// Upload a file using a transport
+pub fn upload_transport(
+    transport: &mut dyn Transport,
+    local_path: &Path,
+    remote_path: &str,
+    options: &TransferOptions,
+) -> Result<TransferReport, Error> {
+    upload_file(transport, local_path, remote_path, options, SUBSEQUENT_TIMEOUT_MS)
+}
+
+/// Upload from a reader using a transport, see `upload_from`
+pub fn upload_from_transport(
+    transport: &mut dyn Transport,
+    reader: &mut dyn Read,
+    len: u64,
+    remote_path: &str,
+    options: &TransferOptions,
+) -> Result<TransferReport, Error> {
+    upload_from_reader(transport, reader, len, remote_path, options, SUBSEQUENT_TIMEOUT_MS)
 }
 
 /// Get file status using a transport
@@ -528,3 +1004,77 @@ pub fn hash_transport(
 
     Ok(rsp)
 }
+
+/// List a directory using a transport, see `list_dir`
+pub fn list_dir_transport(transport: &mut dyn Transport, path: &str) -> Result<Vec<DirEntry>, Error> {
+    let rsp = shell_exec_transport(transport, vec!["fs".into(), "ls".into(), path.into()])?;
+    parse_ls(path, &rsp)
+}
+
+/// Create a directory using a transport, see `mkdir`
+pub fn mkdir_transport(transport: &mut dyn Transport, path: &str) -> Result<(), Error> {
+    let rsp = shell_exec_transport(transport, vec!["fs".into(), "mkdir".into(), path.into()])?;
+    check_shell("mkdir", path, &rsp)
+}
+
+/// List a directory with the file sizes using a transport, see `list_dir_long`
+pub fn list_dir_long_transport(
+    transport: &mut dyn Transport,
+    path: &str,
+) -> Result<Vec<DirEntry>, Error> {
+    let mut entries = list_dir_transport(transport, path)?;
+    for entry in entries.iter_mut().filter(|e| !e.is_dir) {
+        let rsp = stat_transport(transport, &join_path(path, &entry.name))?;
+        entry.size = Some(rsp.len as u64);
+    }
+    Ok(entries)
+}
+
+/// Move a file using a transport, see `rename`
+pub fn rename_transport(transport: &mut dyn Transport, from: &str, to: &str) -> Result<(), Error> {
+    let argv = vec!["fs".into(), "mv".into(), from.into(), to.into()];
+    let rsp = shell_exec_transport(transport, argv)?;
+    match check_shell("mv", from, &rsp) {
+        Err(e) if is_unsupported(&e) => {
+            debug!("{:#}, copying", e);
+            let options = TransferOptions::default();
+            let mut data = Vec::new();
+            download_to_transport(transport, from, &mut data, &options)?;
+            upload_from_transport(transport, &mut &data[..], data.len() as u64, to, &options)?;
+            remove_transport(transport, from)
+        }
+        result => result,
+    }
+}
+
+/// Remove a file or an empty directory using a transport, see `remove`
+pub fn remove_transport(transport: &mut dyn Transport, path: &str) -> Result<(), Error> {
+    let rsp = shell_exec_transport(transport, vec!["fs".into(), "rm".into(), path.into()])?;
+    check_shell("rm", path, &rsp)
+}
+
+/// Hash and checksum types the device supports, using a transport
+pub fn hash_types_transport(transport: &mut dyn Transport) -> Result<Vec<String>, Error> {
+    debug!("hash types request");
+
+    let body = serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new())?;
+    let (_response_header, response_body) = transport.transceive(
+        NmpOp::Read,
+        NmpGroup::Fs,
+        NmpIdFs::SupportedHashTypes.to_u8(),
+        &body,
+    )?;
+
+    parse_hash_types(response_body)
+}
+
+/// The best hash type of the device using a transport, see `best_hash_type`
+pub fn best_hash_type_transport(transport: &mut dyn Transport) -> HashType {
+    match hash_types_transport(transport) {
+        Ok(types) => HashType::best(&types),
+        Err(e) => {
+            debug!("no hash types: {}", e);
+            HashType::Crc32
+        }
+    }
+}
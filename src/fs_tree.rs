@@ -0,0 +1,522 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Recursive transfers of directory trees to and from the device filesystem
+//!
+//! The SMP fs group only knows files, so the device tree is walked and
+//! created with `fs ls` and `fs mkdir` in the device shell, see `list_dir`.
+//! Include and exclude globs select the files by their path relative to the
+//! transferred directory, with `/` separators. A sync compares the files
+//! with the fs hash of the device and only uploads those that differ.
+
+use anyhow::{bail, Context, Error, Result};
+use log::{debug, info, warn};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::fs::{self, DirEntry, FsErrorKind, FsShellError, HashType, TransferOptions};
+use crate::nmp_hdr::FsHashRsp;
+use crate::transfer::{SerialSpecs, Transport};
+
+/// Selection of the files of a tree by their relative path
+#[derive(Debug, Clone, Default)]
+pub struct TreeFilter {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl TreeFilter {
+    /// Select the files matching one of the include globs, or all files
+    /// without any, and none of the exclude globs. `*` also matches `/`, so
+    /// `*.wav` selects the WAV files of all directories. A directory matching
+    /// an exclude glob is skipped with its content.
+    pub fn new(include: &[String], exclude: &[String]) -> Result<TreeFilter, Error> {
+        let compile = |patterns: &[String]| -> Result<Vec<glob::Pattern>, Error> {
+            patterns
+                .iter()
+                .map(|p| glob::Pattern::new(p).with_context(|| format!("invalid glob {p}")))
+                .collect()
+        };
+        Ok(TreeFilter {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    /// Whether the file with this relative path is transferred
+    pub fn matches(&self, path: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(path)))
+            && !self.excludes(path)
+    }
+
+    fn excludes(&self, path: &str) -> bool {
+        self.exclude.iter().any(|p| p.matches(path))
+    }
+}
+
+/// Step of a tree transfer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeAction {
+    /// Create a directory on the device
+    MakeRemoteDir(String),
+    /// Create a local directory
+    MakeLocalDir(PathBuf),
+    Upload {
+        local: PathBuf,
+        remote: String,
+        size: u64,
+    },
+    Download {
+        remote: String,
+        local: PathBuf,
+    },
+    /// The file on the device has the hash of the local one
+    Unchanged { remote: String, hash_type: HashType },
+    /// Remove a file or an empty directory on the device
+    RemoveRemote(String),
+}
+
+impl fmt::Display for TreeAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TreeAction::MakeRemoteDir(path) => write!(f, "mkdir {path}"),
+            TreeAction::MakeLocalDir(path) => write!(f, "mkdir {}", path.display()),
+            TreeAction::Upload {
+                local,
+                remote,
+                size,
+            } => write!(f, "upload {} -> {} ({} bytes)", local.display(), remote, size),
+            TreeAction::Download { remote, local } => {
+                write!(f, "download {} -> {}", remote, local.display())
+            }
+            TreeAction::Unchanged { remote, hash_type } => {
+                write!(f, "unchanged {remote} (same {hash_type})")
+            }
+            TreeAction::RemoveRemote(path) => write!(f, "rm {path}"),
+        }
+    }
+}
+
+/// Requests of a tree transfer, over a serial port or a transport
+trait FsDevice {
+    fn list_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Error>;
+    fn mkdir(&mut self, path: &str) -> Result<(), Error>;
+    fn upload(&mut self, local: &Path, remote: &str) -> Result<(), Error>;
+    fn download(&mut self, remote: &str, local: &Path) -> Result<(), Error>;
+    fn remove(&mut self, path: &str) -> Result<(), Error>;
+    fn best_hash_type(&mut self) -> HashType;
+    fn hash(&mut self, path: &str, hash_type: HashType) -> Result<FsHashRsp, Error>;
+}
+
+struct SerialDevice<'a>(&'a SerialSpecs, &'a TransferOptions);
+
+impl FsDevice for SerialDevice<'_> {
+    fn list_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        fs::list_dir(self.0, path)
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), Error> {
+        fs::mkdir(self.0, path)
+    }
+
+    fn upload(&mut self, local: &Path, remote: &str) -> Result<(), Error> {
+        fs::upload(self.0, local, remote, self.1)?;
+        Ok(())
+    }
+
+    fn download(&mut self, remote: &str, local: &Path) -> Result<(), Error> {
+        fs::download(self.0, remote, local, self.1)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), Error> {
+        fs::remove(self.0, path)
+    }
+
+    fn best_hash_type(&mut self) -> HashType {
+        fs::best_hash_type(self.0)
+    }
+
+    fn hash(&mut self, path: &str, hash_type: HashType) -> Result<FsHashRsp, Error> {
+        fs::hash(self.0, path, Some(hash_type.name()), None, None)
+    }
+}
+
+struct TransportDevice<'a>(&'a mut dyn Transport, &'a TransferOptions);
+
+impl FsDevice for TransportDevice<'_> {
+    fn list_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        fs::list_dir_transport(self.0, path)
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), Error> {
+        fs::mkdir_transport(self.0, path)
+    }
+
+    fn upload(&mut self, local: &Path, remote: &str) -> Result<(), Error> {
+        fs::upload_transport(self.0, local, remote, self.1)?;
+        Ok(())
+    }
+
+    fn download(&mut self, remote: &str, local: &Path) -> Result<(), Error> {
+        fs::download_transport(self.0, remote, local, self.1)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), Error> {
+        fs::remove_transport(self.0, path)
+    }
+
+    fn best_hash_type(&mut self) -> HashType {
+        fs::best_hash_type_transport(self.0)
+    }
+
+    fn hash(&mut self, path: &str, hash_type: HashType) -> Result<FsHashRsp, Error> {
+        fs::hash_transport(self.0, path, Some(hash_type.name()), None, None)
+    }
+}
+
+/// Upload the selected files of a local directory to a directory on the
+/// device, creating the missing directories; with `dry_run` only the steps
+/// are returned
+pub fn upload_dir(
+    specs: &SerialSpecs,
+    local_dir: &Path,
+    remote_dir: &str,
+    filter: &TreeFilter,
+    options: &TransferOptions,
+    dry_run: bool,
+) -> Result<Vec<TreeAction>, Error> {
+    upload_tree(&mut SerialDevice(specs, options), local_dir, remote_dir, filter, dry_run)
+}
+
+/// Upload a directory tree using a transport, see `upload_dir`
+pub fn upload_dir_transport(
+    transport: &mut dyn Transport,
+    local_dir: &Path,
+    remote_dir: &str,
+    filter: &TreeFilter,
+    options: &TransferOptions,
+    dry_run: bool,
+) -> Result<Vec<TreeAction>, Error> {
+    let device = &mut TransportDevice(transport, options);
+    upload_tree(device, local_dir, remote_dir, filter, dry_run)
+}
+
+/// Download the selected files of a directory on the device to a local
+/// directory; with `dry_run` only the steps are returned
+pub fn download_dir(
+    specs: &SerialSpecs,
+    remote_dir: &str,
+    local_dir: &Path,
+    filter: &TreeFilter,
+    options: &TransferOptions,
+    dry_run: bool,
+) -> Result<Vec<TreeAction>, Error> {
+    download_tree(&mut SerialDevice(specs, options), remote_dir, local_dir, filter, dry_run)
+}
+
+/// Download a directory tree using a transport, see `download_dir`
+pub fn download_dir_transport(
+    transport: &mut dyn Transport,
+    remote_dir: &str,
+    local_dir: &Path,
+    filter: &TreeFilter,
+    options: &TransferOptions,
+    dry_run: bool,
+) -> Result<Vec<TreeAction>, Error> {
+    let device = &mut TransportDevice(transport, options);
+    download_tree(device, remote_dir, local_dir, filter, dry_run)
+}
+
+/// Upload the selected files of a local directory that differ from the files
+/// on the device, by the best hash type of the device; with `delete` also
+/// remove the selected files and the directories on the device missing
+/// locally. With `dry_run` only the steps are returned.
+pub fn sync_dir(
+    specs: &SerialSpecs,
+    local_dir: &Path,
+    remote_dir: &str,
+    filter: &TreeFilter,
+    delete: bool,
+    options: &TransferOptions,
+    dry_run: bool,
+) -> Result<Vec<TreeAction>, Error> {
+    let device = &mut SerialDevice(specs, options);
+    sync_tree(device, local_dir, remote_dir, filter, delete, dry_run)
+}
+
+/// Sync a directory tree using a transport, see `sync_dir`
+pub fn sync_dir_transport(
+    transport: &mut dyn Transport,
+    local_dir: &Path,
+    remote_dir: &str,
+    filter: &TreeFilter,
+    delete: bool,
+    options: &TransferOptions,
+    dry_run: bool,
+) -> Result<Vec<TreeAction>, Error> {
+    let device = &mut TransportDevice(transport, options);
+    sync_tree(device, local_dir, remote_dir, filter, delete, dry_run)
+}
+
+fn upload_tree(
+    device: &mut dyn FsDevice,
+    local_dir: &Path,
+    remote_dir: &str,
+    filter: &TreeFilter,
+    dry_run: bool,
+) -> Result<Vec<TreeAction>, Error> {
+    if !local_dir.is_dir() {
+        bail!("{} is not a directory", local_dir.display());
+    }
+    let remote_dir = remote_dir.trim_end_matches('/');
+    let mut actions = Vec::new();
+    let tree = remote_tree(device, remote_dir, filter, &mut actions)?;
+    let mut dirs: BTreeSet<String> = tree.dirs.into_iter().collect();
+
+    for (path, local) in walk_local(local_dir, filter)? {
+        make_remote_dirs(remote_dir, &path, &mut dirs, &mut actions);
+        let size = std::fs::metadata(&local)?.len();
+        actions.push(TreeAction::Upload {
+            local,
+            remote: remote_path(remote_dir, &path),
+            size,
+        });
+    }
+    if !dry_run {
+        run(device, &actions)?;
+    }
+    Ok(actions)
+}
+
+fn sync_tree(
+    device: &mut dyn FsDevice,
+    local_dir: &Path,
+    remote_dir: &str,
+    filter: &TreeFilter,
+    delete: bool,
+    dry_run: bool,
+) -> Result<Vec<TreeAction>, Error> {
+    if !local_dir.is_dir() {
+        bail!("{} is not a directory", local_dir.display());
+    }
+    let remote_dir = remote_dir.trim_end_matches('/');
+    let mut actions = Vec::new();
+    let tree = remote_tree(device, remote_dir, filter, &mut actions)?;
+    let mut dirs: BTreeSet<String> = tree.dirs.iter().cloned().collect();
+    let local_files = walk_local(local_dir, filter)?;
+    let mut hash_type = None;
+
+    for (path, local) in &local_files {
+        let remote = remote_path(remote_dir, path);
+        let size = std::fs::metadata(local)?.len();
+        if tree.files.contains(path) {
+            let hash_type = *hash_type.get_or_insert_with(|| device.best_hash_type());
+            // a file that can't be hashed is uploaded again
+            let unchanged = match device.hash(&remote, hash_type) {
+                Ok(rsp) => rsp.len as u64 == size && rsp.output == hash_type.digest_file(local)?,
+                Err(e) => {
+                    warn!("{}: {:#}, uploading it", remote, e);
+                    false
+                }
+            };
+            if unchanged {
+                actions.push(TreeAction::Unchanged { remote, hash_type });
+                continue;
+            }
+        } else {
+            make_remote_dirs(remote_dir, path, &mut dirs, &mut actions);
+        }
+        actions.push(TreeAction::Upload {
+            local: local.clone(),
+            remote,
+            size,
+        });
+    }
+
+    if delete {
+        let local_paths: BTreeSet<&str> = local_files.iter().map(|(p, _)| p.as_str()).collect();
+        for path in tree.files.iter().filter(|p| !local_paths.contains(p.as_str())) {
+            actions.push(TreeAction::RemoveRemote(remote_path(remote_dir, path)));
+        }
+        // children sort after their parents, so they are removed first
+        for dir in tree.dirs.iter().rev() {
+            let prefix = format!("{dir}/");
+            if !local_dir.join(dir).is_dir() && !tree.kept.iter().any(|p| p.starts_with(&prefix)) {
+                actions.push(TreeAction::RemoveRemote(remote_path(remote_dir, dir)));
+            }
+        }
+    }
+    if !dry_run {
+        run(device, &actions)?;
+    }
+    Ok(actions)
+}
+
+/// The tree below a directory on the device, or a step creating the
+/// directory when it doesn't exist
+fn remote_tree(
+    device: &mut dyn FsDevice,
+    remote_dir: &str,
+    filter: &TreeFilter,
+    actions: &mut Vec<TreeAction>,
+) -> Result<RemoteTree, Error> {
+    match device.list_dir(&remote_path(remote_dir, "")) {
+        Ok(_) => walk_remote(device, remote_dir, filter),
+        Err(e)
+            if e.downcast_ref::<FsShellError>()
+                .is_some_and(|e| e.kind == FsErrorKind::NotFound) =>
+        {
+            debug!("{}: {:#}", remote_path(remote_dir, ""), e);
+            actions.push(TreeAction::MakeRemoteDir(remote_path(remote_dir, "")));
+            Ok(RemoteTree::default())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Steps creating the missing parent directories of a file on the device
+fn make_remote_dirs(
+    remote_dir: &str,
+    path: &str,
+    dirs: &mut BTreeSet<String>,
+    actions: &mut Vec<TreeAction>,
+) {
+    let components: Vec<&str> = path.split('/').collect();
+    for i in 1..components.len() {
+        let dir = components[..i].join("/");
+        if dirs.insert(dir.clone()) {
+            actions.push(TreeAction::MakeRemoteDir(remote_path(remote_dir, &dir)));
+        }
+    }
+}
+
+fn download_tree(
+    device: &mut dyn FsDevice,
+    remote_dir: &str,
+    local_dir: &Path,
+    filter: &TreeFilter,
+    dry_run: bool,
+) -> Result<Vec<TreeAction>, Error> {
+    let remote_dir = remote_dir.trim_end_matches('/');
+    let tree = walk_remote(device, remote_dir, filter)?;
+    let mut actions = Vec::new();
+    let mut dirs = BTreeSet::new();
+    if !local_dir.is_dir() {
+        actions.push(TreeAction::MakeLocalDir(local_dir.to_path_buf()));
+    }
+    for path in tree.files {
+        let components: Vec<&str> = path.split('/').collect();
+        let mut local = local_dir.to_path_buf();
+        for dir in &components[..components.len() - 1] {
+            local.push(dir);
+            if !local.is_dir() && dirs.insert(local.clone()) {
+                actions.push(TreeAction::MakeLocalDir(local.clone()));
+            }
+        }
+        local.push(components[components.len() - 1]);
+        actions.push(TreeAction::Download {
+            remote: remote_path(remote_dir, &path),
+            local,
+        });
+    }
+    if !dry_run {
+        run(device, &actions)?;
+    }
+    Ok(actions)
+}
+
+fn run(device: &mut dyn FsDevice, actions: &[TreeAction]) -> Result<(), Error> {
+    for action in actions {
+        info!("{}", action);
+        match action {
+            TreeAction::MakeRemoteDir(path) => device.mkdir(path)?,
+            TreeAction::MakeLocalDir(path) => std::fs::create_dir(path)
+                .with_context(|| format!("failed to create {}", path.display()))?,
+            TreeAction::Upload { local, remote, .. } => device.upload(local, remote)?,
+            TreeAction::Download { remote, local } => device.download(remote, local)?,
+            TreeAction::Unchanged { .. } => {}
+            TreeAction::RemoveRemote(path) => device.remove(path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Path on the device of a relative path below a directory
+fn remote_path(dir: &str, path: &str) -> String {
+    match (dir, path) {
+        ("", "") => "/".to_string(),
+        (dir, "") => dir.to_string(),
+        (dir, path) => format!("{dir}/{path}"),
+    }
+}
+
+fn relative_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// Directories and selected files below a directory on the device, as
+/// relative paths
+#[derive(Debug, Default)]
+struct RemoteTree {
+    dirs: Vec<String>,
+    files: Vec<String>,
+    /// Files not selected and excluded directories
+    kept: Vec<String>,
+}
+
+fn walk_remote(
+    device: &mut dyn FsDevice,
+    root: &str,
+    filter: &TreeFilter,
+) -> Result<RemoteTree, Error> {
+    let mut tree = RemoteTree::default();
+    let mut pending = vec![String::new()];
+    while let Some(dir) = pending.pop() {
+        for entry in device.list_dir(&remote_path(root, &dir))? {
+            let path = relative_path(&dir, &entry.name);
+            if entry.is_dir && !filter.excludes(&path) {
+                tree.dirs.push(path.clone());
+                pending.push(path);
+            } else if !entry.is_dir && filter.matches(&path) {
+                tree.files.push(path);
+            } else {
+                tree.kept.push(path);
+            }
+        }
+    }
+    tree.dirs.sort();
+    tree.files.sort();
+    Ok(tree)
+}
+
+/// Selected files below a local directory, as relative paths with their local
+/// paths, sorted
+fn walk_local(root: &Path, filter: &TreeFilter) -> Result<Vec<(String, PathBuf)>, Error> {
+    let mut files = Vec::new();
+    let mut pending = vec![(String::new(), root.to_path_buf())];
+    while let Some((dir, local_dir)) = pending.pop() {
+        let entries = std::fs::read_dir(&local_dir)
+            .with_context(|| format!("failed to read {}", local_dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = relative_path(&dir, &name);
+            let local = entry.path();
+            if local.is_dir() {
+                if !filter.excludes(&path) {
+                    pending.push((path, local));
+                }
+            } else if filter.matches(&path) {
+                files.push((path, local));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
@@ -1,22 +1,49 @@
 // Copyright © 2023-2024 Vouch.io LLC, 2026 Rudis Laboratories LLC
 
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, Context, Error, Result};
 use humantime::format_duration;
 use log::{debug, info, warn};
+use serialport::SerialPort;
 use sha2::{Digest, Sha256};
-use std::fs::read;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::time::Duration;
 use std::time::Instant;
 
+use crate::firmware::load_image;
+use crate::mcuboot::{ImageVersion, McubootImage};
 use crate::nmp_hdr::*;
+use crate::os::{bootloader_info, bootloader_info_transport};
+use crate::package::{is_package, parse_package, UploadImage};
 use crate::transfer::encode_request;
+use crate::transfer::is_transient_error;
 use crate::transfer::next_seq_id;
 use crate::transfer::open_port;
 use crate::transfer::transceive;
 use crate::transfer::SerialSpecs;
 use crate::transfer::Transport;
 
+/// Number of retries per chunk when uploading through a transport
+const TRANSPORT_RETRIES: u32 = 4;
+/// Timeout of the first chunk of the next images of a package
+const ERASE_TIMEOUT_MS: u32 = 10_000;
+/// Timeout of the chunks once the device erased the slot
+const SUBSEQUENT_TIMEOUT_MS: u32 = 200;
+/// Slot erased by default, the secondary slot of image 0
+pub(crate) const DEFAULT_ERASE_SLOT: u32 = 1;
+
+/// Options of `upload`
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// Image number of a firmware file; packages name their images
+    pub image: u8,
+    /// Have the device refuse images not newer than the running ones
+    pub upgrade_only: bool,
+    /// Erase the target slots first, so the device answers the first chunk
+    /// without erasing
+    pub erase: bool,
+}
+
 fn get_rc(response_body: &serde_cbor::Value) -> Option<u32> {
     let mut rc: Option<u32> = None;
     if let serde_cbor::Value::Map(object) = response_body {
@@ -56,8 +83,17 @@ fn check_answer(request_header: &NmpHdr, response_header: &NmpHdr) -> bool {
     true
 }
 
-pub fn erase(specs: &SerialSpecs, slot: Option<u32>) -> Result<(), Error> {
+/// Erase an image slot, numbered `2 * image + slot`, by default the
+/// secondary slot of image 0
+///
+/// A slot holding the running, a confirmed or a pending image is only erased
+/// when forced.
+pub fn erase(specs: &SerialSpecs, slot: Option<u32>, force: bool) -> Result<(), Error> {
+    if !force {
+        check_erase(&list(specs)?, slot.unwrap_or(DEFAULT_ERASE_SLOT))?;
+    }
     info!("erase request");
+    let start_time = Instant::now();
 
     // open serial port
     let mut port = open_port(specs)?;
@@ -86,9 +122,68 @@ pub fn erase(specs: &SerialSpecs, slot: Option<u32>) -> Result<(), Error> {
     }
 
     log::debug!("{:?}", response_body);
+    info!("erase took {}", format_erase_time(start_time));
     Ok(())
 }
 
+/// Refuse to erase a slot, numbered `2 * image + slot`, holding the running,
+/// a confirmed or a pending image
+pub(crate) fn check_erase(state: &ImageStateRsp, slot: u32) -> Result<(), Error> {
+    let Some(entry) = state
+        .images
+        .iter()
+        .find(|e| e.image == slot / 2 && e.slot == slot % 2)
+    else {
+        return Ok(());
+    };
+    let holds = if entry.active {
+        "the running"
+    } else if entry.confirmed {
+        "a confirmed"
+    } else if entry.pending {
+        "a pending"
+    } else {
+        return Ok(());
+    };
+    bail!(
+        "slot {} holds {} image ({}), force to erase it anyway",
+        slot,
+        holds,
+        entry.version
+    )
+}
+
+/// The slot an upload to an image writes: the secondary slot, or the slot not
+/// running with direct-xip
+pub(crate) fn upload_slot(state: Option<&ImageStateRsp>, image: u8) -> u32 {
+    state
+        .and_then(|state| {
+            state
+                .images
+                .iter()
+                .find(|e| e.image == image as u32 && e.active)
+        })
+        .map_or(1, |running| if running.slot == 1 { 0 } else { 1 })
+}
+
+/// Slots the images are written to, numbered as for `erase`, checked to be
+/// free to erase
+fn erase_targets(state: &ImageStateRsp, images: &[UploadImage]) -> Result<Vec<u32>, Error> {
+    images
+        .iter()
+        .map(|image| {
+            let slot = 2 * image.image as u32 + upload_slot(Some(state), image.image);
+            check_erase(state, slot)?;
+            Ok(slot)
+        })
+        .collect()
+}
+
+fn format_erase_time(start_time: Instant) -> String {
+    let elapsed = Duration::from_millis(start_time.elapsed().as_millis() as u64);
+    format_duration(elapsed).to_string()
+}
+
 pub fn test(specs: &SerialSpecs, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error> {
     info!("set image pending request");
 
@@ -154,34 +249,279 @@ pub fn list(specs: &SerialSpecs) -> Result<ImageStateRsp, Error> {
     Ok(ans)
 }
 
+/// Load the images to upload from a file: the images of a DFU package as
+/// given by its manifest, or the firmware file as image `image`
+pub fn upload_images(filename: &Path, image: u8) -> Result<Vec<UploadImage>, Error> {
+    let filename_string = filename.to_string_lossy();
+    let data = std::fs::read(filename).with_context(|| format!("failed to read {filename_string}"))?;
+    if is_package(&data) {
+        let images = parse_package(&data).with_context(|| filename_string.to_string())?;
+        for image in &images {
+            info!("{}: image {}", image.file, image.image);
+        }
+        return Ok(images);
+    }
+
+    let data = load_image(&data).with_context(|| filename_string.to_string())?;
+    Ok(vec![UploadImage {
+        image,
+        file: filename_string.to_string(),
+        data,
+        packaged: false,
+    }])
+}
+
+/// Check that the device lists each uploaded image with its hash
+fn check_uploaded(images: &[UploadImage], state: &ImageStateRsp) -> Result<(), Error> {
+    for image in images {
+        let hash = image.hash()?;
+        let listed = state
+            .images
+            .iter()
+            .any(|e| e.image == image.image as u32 && e.hash == hash);
+        if !listed {
+            bail!(
+                "{}: after the upload, image {} has no slot with hash {}",
+                image.file,
+                image.image,
+                hex::encode(&hash)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Settings of `check_upgrade`
+#[derive(Debug, Clone, Default)]
+pub struct UpgradeCheck {
+    /// The bootloader refuses downgrades, as reported by bootloader info
+    pub no_downgrade: bool,
+    /// Only warn about downgrades and unmet dependencies, for lab use
+    pub allow_downgrade: bool,
+    /// Security counters of the running images by image number, when known,
+    /// for example from the command line: the device doesn't report them
+    pub security_counters: BTreeMap<u32, u32>,
+}
+
+/// Check images against the images on the device before uploading them
+///
+/// Downgrades are refused when the bootloader would reject them: an older
+/// version than the running (active) image with downgrade prevention, or a
+/// lower security counter. The dependencies of the images must be met by the
+/// other images after the update. Images already on the device are only
+/// reported.
+pub fn check_upgrade(
+    images: &[UploadImage],
+    state: &ImageStateRsp,
+    check: &UpgradeCheck,
+) -> Result<(), Error> {
+    let version_of = |entry: &ImageStateEntry| entry.version.parse::<ImageVersion>().ok();
+    // the active slot, either one with direct-XIP; devices not reporting it
+    // run from the primary slot
+    let running = |image: u32| {
+        let mut entries = state.images.iter().filter(|e| e.image == image);
+        entries
+            .clone()
+            .find(|e| e.active)
+            .or_else(|| entries.find(|e| e.slot == 0))
+            .and_then(version_of)
+    };
+    let pending = |image: u32| {
+        state
+            .images
+            .iter()
+            .find(|e| e.image == image && e.pending && !e.active)
+            .and_then(version_of)
+    };
+
+    let mut problems = Vec::new();
+    let mut parsed = Vec::new();
+    for upload in images {
+        parsed.push((upload, McubootImage::parse(&upload.data)?));
+    }
+    for (upload, image) in &parsed {
+        let number = upload.image as u32;
+        let version = image.header.version;
+        let hash = image.hash();
+        if let Some(entry) = state.images.iter().find(|e| e.image == number && e.hash == hash) {
+            warn!(
+                "{}: already in slot {} of image {}, uploading it again changes nothing",
+                upload.file, entry.slot, number
+            );
+        }
+
+        if let Some(running) = running(number) {
+            if version.cmp_release(&running).is_lt() {
+                let problem = format!(
+                    "{}: version {} is older than the running {} of image {}",
+                    upload.file, version, running, number
+                );
+                if check.no_downgrade {
+                    problems.push(format!("{problem}, the bootloader refuses downgrades"));
+                } else {
+                    warn!("{}", problem);
+                }
+            }
+        }
+        if let (Some(counter), Some(running)) = (
+            image.security_counter(),
+            check.security_counters.get(&number),
+        ) {
+            if counter < *running {
+                problems.push(format!(
+                    "{}: security counter {} is lower than the running {} of image {}",
+                    upload.file, counter, running, number
+                ));
+            }
+        }
+
+        for (dependency, minimum) in image.dependencies() {
+            // the version after the update: uploaded now, pending, or running
+            let after = parsed
+                .iter()
+                .find(|(u, _)| u.image == dependency)
+                .map(|(_, i)| i.header.version)
+                .or_else(|| pending(dependency as u32))
+                .or_else(|| running(dependency as u32));
+            match after {
+                None => problems.push(format!(
+                    "{}: depends on image {}, which isn't on the device",
+                    upload.file, dependency
+                )),
+                Some(after) if after.cmp_release(&minimum).is_lt() => problems.push(format!(
+                    "{}: depends on image {} version {}, it would be {}",
+                    upload.file, dependency, minimum, after
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if check.allow_downgrade {
+        for problem in &problems {
+            warn!("{}, uploading anyway", problem);
+        }
+        return Ok(());
+    }
+    bail!("{}; allow downgrades to upload anyway", problems.join("; "))
+}
+
+/// Check the images to upload against the device, see `check_upgrade`
+///
+/// The downgrade prevention reported by the bootloader is added to `check`.
+pub fn check_upload(
+    specs: &SerialSpecs,
+    images: &[UploadImage],
+    check: &UpgradeCheck,
+) -> Result<(), Error> {
+    let state = list(specs)?;
+    // not all devices support bootloader info
+    let no_downgrade = match bootloader_info(specs, Some("mode")) {
+        Ok(info) => info.no_downgrade.unwrap_or(false),
+        Err(e) => {
+            debug!("no bootloader info: {}", e);
+            false
+        }
+    };
+    let check = UpgradeCheck {
+        no_downgrade: check.no_downgrade || no_downgrade,
+        ..check.clone()
+    };
+    check_upgrade(images, &state, &check)
+}
+
+/// Check the images to upload against the device using a transport, see
+/// `check_upload`
+pub fn check_upload_transport(
+    transport: &mut dyn Transport,
+    images: &[UploadImage],
+    check: &UpgradeCheck,
+) -> Result<(), Error> {
+    let state = list_transport(transport)?;
+    let no_downgrade = match bootloader_info_transport(transport, Some("mode")) {
+        Ok(info) => info.no_downgrade.unwrap_or(false),
+        Err(e) => {
+            debug!("no bootloader info: {}", e);
+            false
+        }
+    };
+    let check = UpgradeCheck {
+        no_downgrade: check.no_downgrade || no_downgrade,
+        ..check.clone()
+    };
+    check_upgrade(images, &state, &check)
+}
+
+/// Upload a firmware file, or all images of a DFU package one after the other
+///
+/// The progress covers all images.
 pub fn upload<F>(
     specs: &SerialSpecs,
-    filename: &PathBuf,
-    slot: u8,
+    filename: &Path,
+    options: &UploadOptions,
     mut progress: Option<F>,
 ) -> Result<(), Error>
 where
     F: FnMut(u64, u64),
 {
-    let filename_string = filename.to_string_lossy();
-    info!("upload file: {}", filename_string);
+    info!("upload file: {}", filename.to_string_lossy());
+    let images = upload_images(filename, options.image)?;
+    send_images(specs, &images, options, &mut |off, total| {
+        if let Some(ref mut f) = progress {
+            f(off, total);
+        }
+        Ok(())
+    })
+}
 
-    // special feature: if the name contains "slot1" or "slot3", then use this slot
-    let filename_lowercase = filename_string.to_lowercase();
-    let mut slot = slot;
-    if filename_lowercase.contains(&"slot1".to_lowercase()) {
-        slot = 1;
-    }
-    if filename_lowercase.contains(&"slot3".to_lowercase()) {
-        slot = 3;
+/// Upload images one after the other; an error of the progress callback
+/// aborts the upload
+pub fn send_images(
+    specs: &SerialSpecs,
+    images: &[UploadImage],
+    options: &UploadOptions,
+    progress: &mut dyn FnMut(u64, u64) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let total: u64 = images.iter().map(|i| i.data.len() as u64).sum();
+
+    if options.erase {
+        for slot in erase_targets(&list(specs)?, images)? {
+            erase(specs, Some(slot), true)?;
+        }
     }
-    info!("flashing to slot {}", slot);
 
     // open serial port
     let mut port = open_port(specs)?;
 
-    // load file
-    let data = read(filename)?;
+    let mut done = 0;
+    for image in images {
+        // the device erases the slot when receiving the first chunk
+        let timeout = if options.erase {
+            Duration::from_millis(specs.subsequent_timeout_ms as u64)
+        } else {
+            Duration::from_secs(specs.initial_timeout_s as u64)
+        };
+        port.set_timeout(timeout)?;
+        upload_port(specs, &mut *port, image, options.upgrade_only, &mut |off| {
+            progress(done + off, total)
+        })?;
+        done += image.data.len() as u64;
+    }
+    drop(port);
+    check_uploaded(images, &list(specs)?)
+}
+
+fn upload_port(
+    specs: &SerialSpecs,
+    port: &mut dyn SerialPort,
+    image: &UploadImage,
+    upgrade_only: bool,
+    progress: &mut dyn FnMut(u64) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let data = &image.data;
     info!("{} bytes to transfer", data.len());
 
     // transfer in blocks
@@ -196,8 +536,7 @@ where
         debug!("try_length: {}", try_length);
         let seq_id = next_seq_id();
         loop {
-            // get slot
-            let image_num = slot;
+            let image_num = image.image;
 
             // create image upload request
             if off + try_length > data.len() {
@@ -210,8 +549,8 @@ where
                     image_num,
                     off: off as u32,
                     len: Some(len),
-                    data_sha: Some(Sha256::digest(&data).to_vec()),
-                    upgrade: None,
+                    data_sha: Some(Sha256::digest(data).to_vec()),
+                    upgrade: upgrade_only.then_some(true),
                     data: chunk,
                 }
             } else {
@@ -253,21 +592,27 @@ where
 
             // send request
             sent_blocks += 1;
-            let (response_header, response_body) = match transceive(&mut *port, &chunk) {
+            let (response_header, response_body) = match transceive(port, &chunk) {
                 Ok(ret) => ret,
-                Err(e) if e.to_string() == "Operation timed out" => {
+                Err(e) if is_transient_error(&e) => {
                     if nb_retry == 0 {
                         return Err(e);
                     }
                     nb_retry -= 1;
-                    debug!("missed answer, nb_retry: {}", nb_retry);
+                    debug!("missed answer ({}), nb_retry: {}", e, nb_retry);
                     continue;
                 }
                 Err(e) => return Err(e),
             };
 
             if !check_answer(&request_header, &response_header) {
-                bail!("wrong answer types")
+                // e.g. a late answer to an earlier request
+                if nb_retry == 0 {
+                    bail!("wrong answer types")
+                }
+                nb_retry -= 1;
+                debug!("wrong answer, nb_retry: {}", nb_retry);
+                continue;
             }
 
             // verify result code and update offset
@@ -280,11 +625,19 @@ where
                     match key {
                         serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
                             if let serde_cbor::Value::Integer(rc) = val {
+                                if *rc != 0 && off == 0 && upgrade_only {
+                                    bail!("rc = {}, the device refused the image, not an upgrade?", rc);
+                                }
                                 if *rc != 0 {
                                     bail!("rc = {}", rc);
                                 }
                             }
                         }
+                        serde_cbor::Value::Text(match_key) if match_key == "match" => {
+                            if let serde_cbor::Value::Bool(false) = val {
+                                bail!("the device reports that the uploaded data doesn't match its SHA256");
+                            }
+                        }
                         serde_cbor::Value::Text(off_key) if off_key == "off" => {
                             if let serde_cbor::Value::Integer(off_val) = val {
                                 off = *off_val as usize;
@@ -303,9 +656,7 @@ where
             bail!("wrong offset received");
         }
 
-        if let Some(ref mut f) = progress {
-            f(off as u64, data.len() as u64);
-        }
+        progress(off as u64)?;
 
         //info!("{}% uploaded", 100 * off / data.len());
         if off == data.len() {
@@ -333,9 +684,17 @@ where
 
 // ==================== Transport-based versions ====================
 
-/// Erase an image slot using a transport
-pub fn erase_transport(transport: &mut dyn Transport, slot: Option<u32>) -> Result<(), Error> {
+/// Erase an image slot using a transport, see `erase`
+pub fn erase_transport(
+    transport: &mut dyn Transport,
+    slot: Option<u32>,
+    force: bool,
+) -> Result<(), Error> {
+    if !force {
+        check_erase(&list_transport(transport)?, slot.unwrap_or(DEFAULT_ERASE_SLOT))?;
+    }
     info!("erase request");
+    let start_time = Instant::now();
 
     let req = ImageEraseReq { slot };
     let body = serde_cbor::to_vec(&req)?;
@@ -354,6 +713,7 @@ pub fn erase_transport(transport: &mut dyn Transport, slot: Option<u32>) -> Resu
     }
 
     debug!("{:?}", response_body);
+    info!("erase took {}", format_erase_time(start_time));
     Ok(())
 }
 
@@ -401,32 +761,65 @@ pub fn list_transport(transport: &mut dyn Transport) -> Result<ImageStateRsp, Er
     Ok(ans)
 }
 
-/// Upload an image using a transport
+/// Upload a firmware file, or all images of a DFU package, using a transport
 pub fn upload_image_transport<F>(
     transport: &mut dyn Transport,
-    filename: &PathBuf,
-    slot: u8,
+    filename: &Path,
+    options: &UploadOptions,
     mut progress: Option<F>,
 ) -> Result<(), Error>
 where
     F: FnMut(u64, u64),
 {
-    let filename_string = filename.to_string_lossy();
-    info!("upload file: {}", filename_string);
+    info!("upload file: {}", filename.to_string_lossy());
+    let images = upload_images(filename, options.image)?;
+    send_images_transport(transport, &images, options, &mut |off, total| {
+        if let Some(ref mut f) = progress {
+            f(off, total);
+        }
+        Ok(())
+    })
+}
 
-    // special feature: if the name contains "slot1" or "slot3", then use this slot
-    let filename_lowercase = filename_string.to_lowercase();
-    let mut slot = slot;
-    if filename_lowercase.contains("slot1") {
-        slot = 1;
+/// Upload images one after the other using a transport; an error of the
+/// progress callback aborts the upload
+pub fn send_images_transport(
+    transport: &mut dyn Transport,
+    images: &[UploadImage],
+    options: &UploadOptions,
+    progress: &mut dyn FnMut(u64, u64) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let total: u64 = images.iter().map(|i| i.data.len() as u64).sum();
+
+    if options.erase {
+        for slot in erase_targets(&list_transport(transport)?, images)? {
+            erase_transport(transport, Some(slot), true)?;
+        }
     }
-    if filename_lowercase.contains("slot3") {
-        slot = 3;
+
+    let mut done = 0;
+    for (i, image) in images.iter().enumerate() {
+        if options.erase {
+            transport.set_timeout(SUBSEQUENT_TIMEOUT_MS)?;
+        } else if i > 0 {
+            // the device erases the slot when receiving the first chunk
+            transport.set_timeout(ERASE_TIMEOUT_MS)?;
+        }
+        upload_transport(transport, image, options.upgrade_only, &mut |off| {
+            progress(done + off, total)
+        })?;
+        done += image.data.len() as u64;
     }
-    info!("flashing to slot {}", slot);
+    check_uploaded(images, &list_transport(transport)?)
+}
 
-    // load file
-    let data = read(filename)?;
+fn upload_transport(
+    transport: &mut dyn Transport,
+    image: &UploadImage,
+    upgrade_only: bool,
+    progress: &mut dyn FnMut(u64) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let data = &image.data;
     info!("{} bytes to transfer", data.len());
 
     let mtu = transport.mtu();
@@ -436,13 +829,13 @@ where
     let start_time = Instant::now();
     let mut sent_blocks: u32 = 0;
     let mut confirmed_blocks: u32 = 0;
+    let mut nb_retry = TRANSPORT_RETRIES;
 
     while off < data.len() {
         let mut try_length = mtu;
         debug!("try_length: {}", try_length);
 
-        // get slot
-        let image_num = slot;
+        let image_num = image.image;
 
         // create image upload request
         if off + try_length > data.len() {
@@ -455,8 +848,8 @@ where
                 image_num,
                 off: off as u32,
                 len: Some(len),
-                data_sha: Some(Sha256::digest(&data).to_vec()),
-                upgrade: None,
+                data_sha: Some(Sha256::digest(data).to_vec()),
+                upgrade: upgrade_only.then_some(true),
                 data: chunk,
             }
         } else {
@@ -475,12 +868,25 @@ where
         let body = serde_cbor::to_vec(&req)?;
 
         sent_blocks += 1;
-        let (_response_header, response_body) = transport.transceive(
+        let (_response_header, response_body) = match transport.transceive(
             NmpOp::Write,
             NmpGroup::Image,
             NmpIdImage::Upload.to_u8(),
             &body,
-        )?;
+        ) {
+            Ok(ret) => {
+                nb_retry = TRANSPORT_RETRIES;
+                ret
+            }
+            Err(e) if is_transient_error(&e) && nb_retry > 0 => {
+                // send the same chunk again, the device answers with its
+                // offset if it did receive it
+                nb_retry -= 1;
+                debug!("missed answer ({}), nb_retry: {}", e, nb_retry);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
 
         // verify result code and update offset
         debug!(
@@ -493,11 +899,19 @@ where
                 match key {
                     serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
                         if let serde_cbor::Value::Integer(rc) = val {
+                            if *rc != 0 && off == 0 && upgrade_only {
+                                bail!("rc = {}, the device refused the image, not an upgrade?", rc);
+                            }
                             if *rc != 0 {
                                 bail!("rc = {}", rc);
                             }
                         }
                     }
+                    serde_cbor::Value::Text(match_key) if match_key == "match" => {
+                        if let serde_cbor::Value::Bool(false) = val {
+                            bail!("the device reports that the uploaded data doesn't match its SHA256");
+                        }
+                    }
                     serde_cbor::Value::Text(off_key) if off_key == "off" => {
                         if let serde_cbor::Value::Integer(off_val) = val {
                             off = *off_val as usize;
@@ -509,13 +923,11 @@ where
         }
         confirmed_blocks += 1;
 
-        if let Some(ref mut f) = progress {
-            f(off as u64, data.len() as u64);
-        }
+        progress(off as u64)?;
 
         // Reduce timeout for subsequent packets
         if off > 0 && off < data.len() {
-            transport.set_timeout(200)?;
+            transport.set_timeout(SUBSEQUENT_TIMEOUT_MS)?;
         }
     }
 
@@ -532,3 +944,51 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::build_image;
+
+    fn entry(slot: u32, version: &str, active: bool) -> ImageStateEntry {
+        ImageStateEntry {
+            image: 0,
+            slot,
+            version: version.to_string(),
+            hash: vec![slot as u8; 32],
+            bootable: true,
+            pending: false,
+            confirmed: active,
+            active,
+            permanent: false,
+        }
+    }
+
+    #[test]
+    fn test_check_upgrade_active_slot() {
+        // direct-XIP running 2.0.0 from the secondary slot
+        let state = ImageStateRsp {
+            images: vec![entry(0, "1.0.0", false), entry(1, "2.0.0", true)],
+            split_status: None,
+        };
+        let upload = UploadImage {
+            image: 0,
+            file: "app.bin".to_string(),
+            data: build_image((1, 5, 0, 0), &[1; 100]),
+            packaged: false,
+        };
+        let check = UpgradeCheck {
+            no_downgrade: true,
+            ..Default::default()
+        };
+        let err = check_upgrade(std::slice::from_ref(&upload), &state, &check).unwrap_err();
+        assert!(err.to_string().contains("older than the running 2.0.0"), "{err}");
+
+        // running from the primary slot
+        let state = ImageStateRsp {
+            images: vec![entry(0, "1.0.0", true), entry(1, "2.0.0", false)],
+            split_status: None,
+        };
+        check_upgrade(&[upload], &state, &check).unwrap();
+    }
+}
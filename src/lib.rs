@@ -1,33 +1,122 @@
+#[cfg(feature = "async")]
+mod async_commands;
+#[cfg(feature = "async")]
+mod async_transport;
 mod default;
+#[cfg(any(test, feature = "sim"))]
+mod fault;
+mod firmware;
 mod fs;
+mod fs_tree;
 mod image;
+mod mcuboot;
 mod nmp_hdr;
 mod os;
+mod package;
+mod pcap;
+mod ports;
+mod record;
 mod settings;
+mod settings_schema;
+mod settings_value;
 mod shell;
+mod sign;
+#[cfg(any(test, feature = "sim"))]
+mod simulator;
 mod stat;
+mod target;
+mod state;
+#[cfg(any(test, feature = "sim"))]
 mod test_serial_port;
+mod trace;
 mod transfer;
+mod update;
+mod verify;
 
+#[cfg(feature = "async")]
+pub use crate::async_commands::{
+    bootloader_info_async, echo_async, erase_async, fs_download_async, fs_hash_async,
+    fs_stat_async, fs_upload_async, list_async, mcumgr_params_async, os_info_async, reset_async,
+    settings_commit_async, settings_delete_async, settings_load_async, settings_read_async,
+    settings_save_async, settings_write_async, shell_exec_async, stat_list_async,
+    stat_read_async, taskstat_async, test_async, upload_image_async,
+};
+#[cfg(feature = "async")]
+pub use crate::async_transport::{
+    AsyncSerialTransport, AsyncTcpTransport, AsyncTransport, AsyncUdpTransport,
+};
 pub use crate::default::{reset, reset_transport};
+#[cfg(feature = "sim")]
+pub use crate::fault::{
+    port_fault_log, set_port_faults, Fault, FaultInjector, FaultRule, FaultScenario, FaultTransport,
+};
+pub use crate::firmware::{load_image, read_image, to_binary, FirmwareFormat};
 pub use crate::fs::{
-    download as fs_download, download_transport, hash as fs_hash, hash_transport,
-    stat as fs_stat, stat_transport, upload as fs_upload, upload_transport,
+    best_hash_type, best_hash_type_transport, download as fs_download,
+    download_to as fs_download_to, download_to_transport, download_transport, hash as fs_hash,
+    hash_transport, hash_types as fs_hash_types, hash_types_transport, list_dir as fs_list_dir,
+    list_dir_long as fs_list_dir_long, list_dir_long_transport, list_dir_transport,
+    mkdir as fs_mkdir, mkdir_transport, remove as fs_remove, remove_transport, rename as fs_rename,
+    rename_transport, stat as fs_stat, stat_transport, upload as fs_upload,
+    upload_from as fs_upload_from, upload_from_transport, upload_transport, DirEntry, FsErrorKind,
+    FsShellError, HashType, TransferOptions, TransferReport,
+};
+pub use crate::fs_tree::{
+    download_dir as fs_download_dir, download_dir_transport, sync_dir as fs_sync_dir,
+    sync_dir_transport, upload_dir as fs_upload_dir, upload_dir_transport, TreeAction, TreeFilter,
+};
+pub use crate::image::{
+    check_upgrade, check_upload, check_upload_transport, erase, erase_transport, list, list_transport,
+    send_images, send_images_transport, test, test_transport, upload, upload_image_transport,
+    upload_images, UpgradeCheck, UploadOptions,
 };
-pub use crate::image::{erase, erase_transport, list, list_transport, test, test_transport, upload, upload_image_transport};
+pub use crate::mcuboot::{ImageHeader, ImageVersion, McubootImage, Tlv};
 pub use crate::nmp_hdr::{
-    BootloaderInfoRsp, FsHashRsp, FsStatRsp, McumgrParamsRsp, SettingsReadRsp, ShellExecRsp,
-    StatListRsp, StatReadRsp, TaskInfo, TaskStatRsp,
+    command_name, BootloaderInfoRsp, FsHashRsp, FsStatRsp, ImageStateEntry, ImageStateRsp,
+    McumgrParamsRsp, SettingsReadRsp, ShellExecRsp, StatListRsp, StatReadRsp, TaskInfo,
+    TaskStatRsp,
 };
 pub use crate::os::{
     bootloader_info, bootloader_info_transport, echo, echo_transport, mcuboot_mode_name,
     mcumgr_params, mcumgr_params_transport, os_info, os_info_transport, taskstat, taskstat_transport,
 };
+pub use crate::package::{is_package, parse_package, UploadImage};
+pub use crate::pcap::{PcapTransport, PcapWriter, LINKTYPE_SMP};
+pub use crate::ports::{find_ports, list_ports, probe_port, probe_ports, PortFilter, PortInfo};
+pub use crate::record::{
+    Exchange, RecordedRequest, RecordedResponse, RecordingHeader, RecordingTransport,
+    ReplayTransport,
+};
 pub use crate::settings::{
     settings_commit, settings_commit_transport, settings_delete, settings_delete_transport,
-    settings_load, settings_load_transport, settings_read, settings_read_transport,
-    settings_save, settings_save_transport, settings_write, settings_write_transport,
+    settings_load, settings_load_transport, settings_read, settings_read_as,
+    settings_read_as_transport, settings_read_transport, settings_save, settings_save_transport,
+    settings_write, settings_write_as, settings_write_as_transport, settings_write_transport,
+};
+pub use crate::settings_schema::{
+    settings_apply, settings_apply_transport, settings_dump, settings_dump_transport,
+    SettingReport, SettingSchema, SettingState, SettingUpdate, SettingsSchema,
+};
+pub use crate::settings_value::{Endian, SettingCodec, SettingType, SettingValue};
+pub use crate::sign::{
+    sign_image, version_security_counter, KeyType, PublicKeyFormat, SignOptions, SigningKey,
 };
 pub use crate::shell::{shell_exec, shell_exec_transport};
+#[cfg(feature = "sim")]
+pub use crate::simulator::{
+    build_image, shared_simulator, Simulator, SimulatorConfig, TcpServer, UdpServer,
+};
+#[cfg(all(unix, feature = "sim"))]
+pub use crate::test_serial_port::PtyServer;
+pub use crate::state::{format_image_state, next_reset, SlotFlag};
 pub use crate::stat::{stat_list, stat_list_transport, stat_read, stat_read_transport};
-pub use crate::transfer::{ConnSpec, SerialSpecs, SerialTransport, Transport, UdpSpecs, UdpTransport};
\ No newline at end of file
+pub use crate::target::{plan_upload, Target, TargetRule, TargetRules, TargetSource, UploadPlan};
+pub use crate::trace::{cbor_diag, TraceTransport};
+pub use crate::update::{
+    update, update_transport, UpdateOptions, UpdateOutcome, UpdatePhase, UpdateReport, UpdatedImage,
+};
+pub use crate::verify::{verify_image, VerifiedImage, VerifyingKey};
+pub use crate::transfer::{
+    decode_packet, is_transient_error, transport_error, ConnSpec, Direction, FrameDecoder,
+    PacketTap, SerialSpecs, SerialTransport, Transport, TransportError, UdpSpecs, UdpTransport,
+};
\ No newline at end of file
@@ -1,14 +1,14 @@
 // Copyright © 2023-2024 Vouch.io LLC, 2026 Rudis Laboratories LLC
 
-use anyhow::{Error, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Error, Result};
+use clap::{Args, Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
 use log::{error, info, LevelFilter};
-use serialport::available_ports;
-use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
-use std::env;
-use std::path::PathBuf;
+use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode, WriteLogger};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
 
 use mcumgr_client::*;
 
@@ -25,6 +25,65 @@ fn format_bytes(size: u32) -> String {
     format!("{size:.1} TB")
 }
 
+/// Parse a USB vendor or product ID, given in hex with or without 0x prefix
+fn parse_usb_id(s: &str) -> Result<u16, String> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16).map_err(|e| format!("invalid USB ID '{s}': {e}"))
+}
+
+/// Parse a number, in hex with a 0x prefix
+fn parse_number<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let number = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(digits) => u64::from_str_radix(digits, 16),
+        None => s.parse(),
+    };
+    number
+        .ok()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| format!("invalid number '{s}'"))
+}
+
+/// Parse an image dependency, IMAGE:VERSION
+fn parse_dependency(s: &str) -> Result<(u8, ImageVersion), String> {
+    let (image, version) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid dependency '{s}', expected IMAGE:VERSION"))?;
+    let image = image
+        .parse()
+        .map_err(|_| format!("invalid image number '{image}'"))?;
+    Ok((image, version.parse().map_err(|e: Error| e.to_string())?))
+}
+
+/// Parse the security counter of a running image, IMAGE:COUNTER
+fn parse_security_counter(s: &str) -> Result<(u32, u32), String> {
+    let (image, counter) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid security counter '{s}', expected IMAGE:COUNTER"))?;
+    let image = image
+        .parse()
+        .map_err(|_| format!("invalid image number '{image}'"))?;
+    Ok((image, parse_number(counter)?))
+}
+
+/// Format a port with its USB metadata for display
+fn format_port(port: &PortInfo) -> String {
+    match (port.vid, port.pid) {
+        (Some(vid), Some(pid)) => format!(
+            "{:<24} {:04x}:{:04x} {:<20} {:<20} {}",
+            port.name,
+            vid,
+            pid,
+            port.serial_number.as_deref().unwrap_or("-"),
+            port.manufacturer.as_deref().unwrap_or("-"),
+            port.product.as_deref().unwrap_or("-")
+        ),
+        _ => format!("{:<24} {:<9} {:<20} {:<20} -", port.name, "-", "-", "-"),
+    }
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -32,6 +91,22 @@ struct Cli {
     #[arg(short, long, default_value = "")]
     device: String,
 
+    /// USB vendor ID (hex) of the serial port to auto-detect
+    #[arg(long, value_parser = parse_usb_id)]
+    vid: Option<u16>,
+
+    /// USB product ID (hex) of the serial port to auto-detect
+    #[arg(long, value_parser = parse_usb_id)]
+    pid: Option<u16>,
+
+    /// USB serial number of the serial port to auto-detect
+    #[arg(long)]
+    serial_number: Option<String>,
+
+    /// send an echo to each auto-detect candidate and keep only those answering
+    #[arg(long)]
+    probe: bool,
+
     /// UDP host (use instead of --device for UDP connection)
     #[arg(long)]
     host: Option<String>,
@@ -40,6 +115,28 @@ struct Cli {
     #[arg(long, default_value_t = 1337)]
     port: u16,
 
+    /// record all requests and responses to a file
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// replay a recording instead of connecting to a device
+    #[arg(long, value_name = "FILE", conflicts_with = "record")]
+    replay: Option<PathBuf>,
+
+    /// capture all SMP traffic to a pcapng file, with the raw serial data on a
+    /// second interface
+    #[arg(long, value_name = "FILE")]
+    pcap: Option<PathBuf>,
+
+    /// print each request and response decoded on one line
+    #[arg(long)]
+    trace: bool,
+
+    /// inject the faults of a JSON scenario, for testing
+    #[cfg(feature = "sim")]
+    #[arg(long, value_name = "FILE")]
+    faults: Option<PathBuf>,
+
     /// verbose mode
     #[arg(short, long)]
     verbose: bool,
@@ -91,6 +188,35 @@ impl Cli {
         self.host.is_some()
     }
 
+    /// Check if the transport must be wrapped for tracing, capture, recording,
+    /// replay or fault injection
+    ///
+    /// Serial commands then run through the transport API instead of the
+    /// port-based functions.
+    fn wraps_transport(&self) -> bool {
+        self.trace
+            || self.pcap.is_some()
+            || self.record.is_some()
+            || self.replay.is_some()
+            || self.injects_faults()
+    }
+
+    /// Check if faults are injected, an option of the `sim` feature
+    fn injects_faults(&self) -> bool {
+        #[cfg(feature = "sim")]
+        return self.faults.is_some();
+        #[cfg(not(feature = "sim"))]
+        false
+    }
+
+    fn port_filter(&self) -> PortFilter {
+        PortFilter {
+            vid: self.vid,
+            pid: self.pid,
+            serial_number: self.serial_number.clone(),
+        }
+    }
+
     fn udp_specs(&self) -> UdpSpecs {
         UdpSpecs {
             host: self.host.clone().unwrap_or_default(),
@@ -103,17 +229,110 @@ impl Cli {
 
 #[derive(Subcommand)]
 enum Commands {
+    /// list serial ports with their USB metadata
+    Ports,
+
     // ============== Image Management ==============
-    /// list slots on the device
-    List,
+    /// list slots on the device, and what happens at the next reset
+    List {
+        /// print the raw response as JSON
+        #[arg(long)]
+        json: bool,
+    },
 
-    /// upload a firmware file to the device
+    /// upload a firmware image (binary, Intel HEX, S-record or ELF) or a DFU
+    /// package (zip with manifest.json) to the device
     Upload {
         filename: PathBuf,
 
-        /// slot number
-        #[arg(short, long, default_value_t = 1)]
-        slot: u8,
+        /// image number of a firmware file, a DFU package names its images
+        #[arg(short, long)]
+        image: Option<u8>,
+
+        /// JSON rules mapping file name patterns to image numbers
+        #[arg(long, value_name = "FILE")]
+        rules: Option<PathBuf>,
+
+        /// upload without asking when the target image is ambiguous
+        #[arg(short, long)]
+        yes: bool,
+
+        /// mark the uploaded images for test, to boot them at the next reset
+        #[arg(long)]
+        test: bool,
+
+        /// refuse to upload images not signed by this public key (repeatable)
+        #[arg(long, value_name = "KEY")]
+        require_signed_by: Vec<PathBuf>,
+
+        /// upload downgrades and images with unmet dependencies anyway
+        #[arg(long)]
+        allow_downgrade: bool,
+
+        /// security counter of a running image, IMAGE:COUNTER, to refuse
+        /// images with a lower one (repeatable)
+        #[arg(long, value_name = "IMAGE:COUNTER", value_parser = parse_security_counter)]
+        security_counter: Vec<(u32, u32)>,
+
+        /// have the device refuse images not newer than the running one
+        #[arg(long)]
+        upgrade_only: bool,
+
+        /// erase the target slot first, the upload then starts without delay
+        #[arg(long)]
+        erase: bool,
+    },
+
+    /// update the device: upload, test, reset, check that the new image runs
+    /// and confirm it
+    Update {
+        /// firmware file or DFU package
+        filename: PathBuf,
+
+        /// image number of a firmware file, a DFU package names its images
+        #[arg(short, long)]
+        image: Option<u8>,
+
+        /// JSON rules mapping file name patterns to image numbers
+        #[arg(long, value_name = "FILE")]
+        rules: Option<PathBuf>,
+
+        /// update without asking when the target image is ambiguous
+        #[arg(short, long)]
+        yes: bool,
+
+        /// update to an older version or with unmet dependencies anyway
+        #[arg(long)]
+        allow_downgrade: bool,
+
+        /// security counter of a running image, IMAGE:COUNTER, to refuse
+        /// images with a lower one (repeatable)
+        #[arg(long, value_name = "IMAGE:COUNTER", value_parser = parse_security_counter)]
+        security_counter: Vec<(u32, u32)>,
+
+        /// have the device refuse images not newer than the running one
+        #[arg(long)]
+        upgrade_only: bool,
+
+        /// erase the target slot first, the upload then starts without delay
+        #[arg(long)]
+        erase: bool,
+
+        /// leave the new image in test: the next reset reverts it
+        #[arg(long)]
+        no_confirm: bool,
+
+        /// seconds for the upload
+        #[arg(long, default_value_t = 600)]
+        upload_timeout: u64,
+
+        /// seconds for the device to come back after the reset
+        #[arg(long, default_value_t = 60)]
+        reboot_timeout: u64,
+
+        /// seconds for each of the test, reset, verify and confirm phases
+        #[arg(long, default_value_t = 30)]
+        phase_timeout: u64,
     },
 
     /// mark an image for testing or confirm it
@@ -125,8 +344,105 @@ enum Commands {
 
     /// erase an image slot
     Erase {
+        /// slot number, 2 * image + slot (default: 1)
         #[arg(short, long)]
         slot: Option<u32>,
+
+        /// erase the slot even if it holds the running, a confirmed or a
+        /// pending image
+        #[arg(long)]
+        force: bool,
+    },
+
+    // ============== Image Signing ==============
+    /// make a signed MCUboot image of a binary
+    Sign {
+        /// binary, Intel HEX, S-record or ELF file
+        input: PathBuf,
+
+        /// signed image to write
+        output: PathBuf,
+
+        /// private key in PEM; without it the image is only hashed
+        #[arg(short, long)]
+        key: Option<PathBuf>,
+
+        /// image version, major.minor.revision+build
+        #[arg(short, long, default_value = "0.0.0")]
+        version: ImageVersion,
+
+        /// header size, the binary starts after it
+        #[arg(short = 'H', long, default_value = "0x200", value_parser = parse_number::<u16>)]
+        header_size: u16,
+
+        /// insert the header instead of expecting zero bytes for it
+        #[arg(long)]
+        pad_header: bool,
+
+        /// slot size, to check that the image fits with its trailer
+        #[arg(short = 'S', long, value_parser = parse_number::<u32>)]
+        slot_size: Option<u32>,
+
+        /// flash write alignment
+        #[arg(long, default_value_t = 1)]
+        align: u32,
+
+        /// maximum flash write alignment of the device
+        #[arg(long, default_value_t = 8)]
+        max_align: u32,
+
+        /// maximum number of sectors of a slot
+        #[arg(long, default_value_t = 128)]
+        max_sectors: u32,
+
+        /// pad the image to the slot size, with the boot trailer magic
+        #[arg(long)]
+        pad: bool,
+
+        /// pad the image and mark it as confirmed
+        #[arg(long)]
+        confirm: bool,
+
+        /// the bootloader runs in overwrite-only mode
+        #[arg(long)]
+        overwrite_only: bool,
+
+        /// value of erased flash
+        #[arg(long, default_value = "0xff", value_parser = parse_number::<u8>)]
+        erased_val: u8,
+
+        /// security counter, a number or "auto" to derive it from the version
+        #[arg(short, long)]
+        security_counter: Option<String>,
+
+        /// minimum version of another image, IMAGE:VERSION
+        #[arg(short, long, value_parser = parse_dependency)]
+        dependency: Vec<(u8, ImageVersion)>,
+
+        /// how the public key is stored: hash or full
+        #[arg(long, default_value = "hash", value_parser = ["hash", "full"])]
+        public_key_format: String,
+    },
+
+    /// check the signature of an image or DFU package against trusted keys
+    Verify {
+        /// image, Intel HEX, S-record, ELF or DFU package
+        filename: PathBuf,
+
+        /// trusted public key in PEM, or private key (repeatable)
+        #[arg(short, long, required = true)]
+        key: Vec<PathBuf>,
+    },
+
+    /// generate a private key for signing images
+    Keygen {
+        /// PEM file to write
+        #[arg(short, long)]
+        key: PathBuf,
+
+        /// key type: ecdsa-p256, ed25519, rsa-2048 or rsa-3072
+        #[arg(short, long, default_value = "ecdsa-p256")]
+        r#type: KeyType,
     },
 
     // ============== OS/Default Management ==============
@@ -172,22 +488,125 @@ enum Commands {
     },
 
     // ============== File System Management ==============
-    /// download a file from the device
+    /// download a file or directory tree from the device
     FsDownload {
         /// remote file path on device
         remote_path: String,
 
-        /// local file path to save to
+        /// local file path to save to, `-` for stdout
         local_path: PathBuf,
+
+        /// transfer a directory tree, creating the missing directories
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// only transfer the files matching this glob, relative to the
+        /// directory (repeatable)
+        #[arg(long, value_name = "GLOB", requires = "recursive")]
+        include: Vec<String>,
+
+        /// skip the files and directories matching this glob (repeatable)
+        #[arg(long, value_name = "GLOB", requires = "recursive")]
+        exclude: Vec<String>,
+
+        /// show what would be transferred, without transferring
+        #[arg(long, requires = "recursive")]
+        dry_run: bool,
+
+        /// skip comparing the files with the fs hash of the device afterwards
+        #[arg(long)]
+        no_verify: bool,
     },
 
-    /// upload a file to the device
+    /// upload a file or directory tree to the device
     FsUpload {
         /// local file path to upload
         local_path: PathBuf,
 
         /// remote file path on device
         remote_path: String,
+
+        /// transfer a directory tree, creating the missing directories
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// only transfer the files matching this glob, relative to the
+        /// directory (repeatable)
+        #[arg(long, value_name = "GLOB", requires = "recursive")]
+        include: Vec<String>,
+
+        /// skip the files and directories matching this glob (repeatable)
+        #[arg(long, value_name = "GLOB", requires = "recursive")]
+        exclude: Vec<String>,
+
+        /// show what would be transferred, without transferring
+        #[arg(long, requires = "recursive")]
+        dry_run: bool,
+
+        /// skip comparing the files with the fs hash of the device afterwards
+        #[arg(long)]
+        no_verify: bool,
+    },
+
+    /// upload the files of a directory tree whose hash differs on the device
+    FsSync {
+        /// local directory
+        local_dir: PathBuf,
+
+        /// directory on the device
+        remote_dir: String,
+
+        /// remove the files and directories on the device missing locally
+        #[arg(long)]
+        delete: bool,
+
+        /// only sync the files matching this glob, relative to the directory
+        /// (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// skip the files and directories matching this glob (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// show what would be done, without transferring or removing
+        #[arg(long)]
+        dry_run: bool,
+
+        /// skip comparing the files with the fs hash of the device afterwards
+        #[arg(long)]
+        no_verify: bool,
+    },
+
+    /// list a directory on the device, with the fs shell
+    FsLs {
+        /// directory on the device
+        path: String,
+
+        /// show the type and size of each entry, a stat request per file
+        #[arg(short, long)]
+        long: bool,
+    },
+
+    /// remove a file or an empty directory on the device, with the fs shell
+    FsRm {
+        /// path on the device
+        path: String,
+    },
+
+    /// create a directory on the device, with the fs shell
+    FsMkdir {
+        /// directory on the device
+        path: String,
+    },
+
+    /// move a file on the device, with the fs shell or else by copying it
+    FsMv {
+        /// current path on the device
+        from: String,
+
+        /// new path on the device
+        to: String,
     },
 
     /// get file status (size) from the device
@@ -225,6 +644,14 @@ enum Commands {
         /// maximum size of value to read
         #[arg(short, long)]
         max_size: Option<u32>,
+
+        /// decode the value: u8..u64, i8..i64, bool, string or bytes
+        #[arg(long = "as", value_name = "TYPE")]
+        as_type: Option<SettingCodec>,
+
+        /// decode integers as big-endian
+        #[arg(long, requires = "as_type")]
+        big_endian: bool,
     },
 
     /// write a settings value
@@ -232,8 +659,12 @@ enum Commands {
         /// setting name/key
         name: String,
 
-        /// value to write (hex string)
-        value: String,
+        #[command(flatten)]
+        value: SettingValueArgs,
+
+        /// encode integers as big-endian
+        #[arg(long)]
+        big_endian: bool,
     },
 
     /// delete a settings value
@@ -250,88 +681,202 @@ enum Commands {
 
     /// save settings to persistent storage
     SettingsSave,
+
+    /// write the settings of a TOML or JSON file, checked with a schema, then
+    /// save and commit them; if anything fails, the previous values are
+    /// restored
+    SettingsApply {
+        /// values to write, a table of setting keys
+        #[arg(required_unless_present = "defaults")]
+        file: Option<PathBuf>,
+
+        /// schema describing the settings (TOML or JSON)
+        #[arg(long)]
+        schema: PathBuf,
+
+        /// write the default of the settings not in the file
+        #[arg(long)]
+        defaults: bool,
+
+        /// check and show the values, without writing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// read and decode all the settings of a schema
+    SettingsDump {
+        /// schema describing the settings (TOML or JSON)
+        #[arg(long)]
+        schema: PathBuf,
+
+        /// print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
-fn main() {
-    // show program name and version
-    let name = env!("CARGO_PKG_NAME");
-    let version = env!("CARGO_PKG_VERSION");
-    println!("{name} {version}");
-    println!();
+/// Value of a settings write, as hex or typed
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+struct SettingValueArgs {
+    /// value to write (hex string)
+    hex: Option<String>,
+
+    #[arg(long, value_name = "N")]
+    u8: Option<String>,
+
+    #[arg(long, value_name = "N")]
+    u16: Option<String>,
+
+    #[arg(long, value_name = "N")]
+    u32: Option<String>,
 
+    #[arg(long, value_name = "N")]
+    u64: Option<String>,
+
+    #[arg(long, value_name = "N", allow_hyphen_values = true)]
+    i8: Option<String>,
+
+    #[arg(long, value_name = "N", allow_hyphen_values = true)]
+    i16: Option<String>,
+
+    #[arg(long, value_name = "N", allow_hyphen_values = true)]
+    i32: Option<String>,
+
+    #[arg(long, value_name = "N", allow_hyphen_values = true)]
+    i64: Option<String>,
+
+    /// true, false, 1 or 0
+    #[arg(long, value_name = "BOOL")]
+    bool: Option<String>,
+
+    /// UTF-8 text, without a NUL terminator
+    #[arg(long, value_name = "TEXT")]
+    string: Option<String>,
+
+    /// raw bytes read from a file
+    #[arg(long, value_name = "FILE")]
+    file: Option<PathBuf>,
+}
+
+impl SettingValueArgs {
+    /// The bytes to write
+    fn encode(&self, big_endian: bool) -> Result<Vec<u8>, Error> {
+        if let Some(path) = &self.file {
+            return std::fs::read(path)
+                .with_context(|| format!("failed to read {}", path.display()));
+        }
+        let typed = [
+            (SettingType::U8, &self.u8),
+            (SettingType::U16, &self.u16),
+            (SettingType::U32, &self.u32),
+            (SettingType::U64, &self.u64),
+            (SettingType::I8, &self.i8),
+            (SettingType::I16, &self.i16),
+            (SettingType::I32, &self.i32),
+            (SettingType::I64, &self.i64),
+            (SettingType::Bool, &self.bool),
+            (SettingType::String, &self.string),
+            (SettingType::Bytes, &self.hex),
+        ];
+        let (setting_type, text) = typed
+            .into_iter()
+            .find_map(|(setting_type, text)| Some((setting_type, text.as_ref()?)))
+            .context("no value to write")?;
+        let endian = if big_endian { Endian::Big } else { Endian::Little };
+        let codec = SettingCodec::new(setting_type, endian);
+        codec.encode(&codec.parse(text)?)
+    }
+}
+
+/// Print a settings value, decoded if a type is given, else as hex with a
+/// guess at text
+fn print_setting(
+    name: &str,
+    val: &[u8],
+    as_type: Option<SettingCodec>,
+    big_endian: bool,
+) -> Result<(), Error> {
+    if let Some(mut codec) = as_type {
+        if big_endian {
+            codec.endian = Endian::Big;
+        }
+        println!("Setting '{}': {} ({})", name, codec.decode(val)?, codec);
+        println!("  (hex): {}", hex::encode(val));
+        return Ok(());
+    }
+    println!("Setting '{}': {}", name, hex::encode(val));
+    // Try to also print as string if it's valid UTF-8
+    if let Ok(s) = std::str::from_utf8(val) {
+        if s.chars().all(|c| c.is_ascii_graphic() || c.is_ascii_whitespace()) {
+            println!("  (as string): {s}");
+        }
+    }
+    Ok(())
+}
+
+fn main() {
     // parse command line arguments
     let mut cli = Cli::parse();
 
+    // a download to stdout leaves stdout to the data, the rest goes to stderr
+    let data_on_stdout = matches!(
+        &cli.command,
+        Commands::FsDownload { local_path, .. } if local_path == Path::new("-")
+    );
+
+    // show program name and version
+    if !data_on_stdout {
+        let name = env!("CARGO_PKG_NAME");
+        let version = env!("CARGO_PKG_VERSION");
+        println!("{name} {version}");
+        println!();
+    }
+
     // initialize the logger with the desired level filter based on the verbose flag
     let level_filter = if cli.verbose {
         LevelFilter::Debug
     } else {
         LevelFilter::Info
     };
-    TermLogger::init(
-        level_filter,
-        Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )
-    .unwrap_or_else(|_| SimpleLogger::init(LevelFilter::Info, Default::default()).unwrap());
+    let mode = if data_on_stdout {
+        TerminalMode::Stderr
+    } else {
+        TerminalMode::Mixed
+    };
+    TermLogger::init(level_filter, Config::default(), mode, ColorChoice::Auto).unwrap_or_else(|_| {
+        if data_on_stdout {
+            WriteLogger::init(LevelFilter::Info, Default::default(), std::io::stderr()).unwrap()
+        } else {
+            SimpleLogger::init(LevelFilter::Info, Default::default()).unwrap()
+        }
+    });
 
     // Check if using UDP or serial connection
     let use_udp = cli.is_udp();
 
-    // If using serial, auto-detect device if not specified
-    if !use_udp && cli.device.is_empty() {
-        let mut bootloaders = Vec::new();
-        if let Ok(ports) = available_ports() {
-            for port in ports {
-                let name = port.port_name;
-                // on Mac, use only special names
-                if env::consts::OS == "macos" {
-                    if name.contains("cu.usbmodem") {
-                        bootloaders.push(name);
-                    }
-                } else {
-                    bootloaders.push(name);
-                }
-            }
+    if let Commands::Ports = cli.command {
+        if let Err(e) = print_ports(&cli) {
+            error!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if matches!(cli.command, Commands::Sign { .. } | Commands::Verify { .. } | Commands::Keygen { .. }) {
+        if let Err(e) = execute_local_command(&cli.command) {
+            error!("Error: {:#}", e);
+            process::exit(1);
         }
+        return;
+    }
 
-        // if there is one bootloader device, then use it
-        if bootloaders.len() == 1 {
-            cli.device = bootloaders[0].clone();
-            info!(
-                "One bootloader device found, setting device to: {}",
-                cli.device
-            );
-        } else {
-            // otherwise print all devices, and use a device, if there is only one device
-            if cli.device.is_empty() {
-                match available_ports() {
-                    Ok(ports) => match ports.len() {
-                        0 => {
-                            error!("No serial port found.");
-                            process::exit(1);
-                        }
-                        1 => {
-                            cli.device = ports[0].port_name.clone();
-                            info!(
-                                "Only one serial port found, setting device to: {}",
-                                cli.device
-                            );
-                        }
-                        _ => {
-                            error!("More than one serial port found, please specify one:");
-                            for p in ports {
-                                println!("{}", p.port_name);
-                            }
-                            process::exit(1);
-                        }
-                    },
-                    Err(e) => {
-                        println!("Error listing serial ports: {e}");
-                        process::exit(1);
-                    }
-                }
+    // If using serial, auto-detect device if not specified
+    if !use_udp && cli.replay.is_none() && cli.device.is_empty() {
+        match detect_device(&cli) {
+            Ok(device) => cli.device = device,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
             }
         }
     }
@@ -339,11 +884,9 @@ fn main() {
     let specs = SerialSpecs::from(&cli);
 
     // execute command
-    let result = if use_udp {
-        // UDP transport mode
-        let udp_specs = cli.udp_specs();
-        info!("Using UDP transport: {}:{}", udp_specs.host, udp_specs.port);
-        execute_command_udp(&cli.command, &udp_specs)
+    let result = if use_udp || cli.wraps_transport() {
+        open_transport(&cli, &specs)
+            .and_then(|mut transport| execute_command_transport(&cli.command, &mut transport))
     } else {
         // Serial transport mode
         execute_command_serial(&cli.command, &specs)
@@ -351,32 +894,402 @@ fn main() {
 
     // show error, if failed
     if let Err(e) = result {
-        error!("Error: {}", e);
+        error!("Error: {:#}", e);
         process::exit(1);
     }
 }
 
+/// Open the transport for the connection options, wrapped for tracing, capture and recording if requested
+fn open_transport(cli: &Cli, specs: &SerialSpecs) -> Result<Box<dyn Transport>, Error> {
+    let transport: Box<dyn Transport> = if let Some(path) = &cli.replay {
+        Box::new(ReplayTransport::open(path)?)
+    } else if cli.is_udp() {
+        // UDP transport mode
+        let udp_specs = cli.udp_specs();
+        info!("Using UDP transport: {}:{}", udp_specs.host, udp_specs.port);
+        Box::new(UdpTransport::new(&udp_specs)?)
+    } else {
+        Box::new(SerialTransport::new(specs)?)
+    };
+
+    #[cfg(feature = "sim")]
+    let transport: Box<dyn Transport> = match &cli.faults {
+        Some(path) => Box::new(FaultTransport::new(transport, FaultScenario::load(path)?)),
+        None => transport,
+    };
+
+    let transport: Box<dyn Transport> = if cli.trace {
+        Box::new(TraceTransport::new(transport))
+    } else {
+        transport
+    };
+
+    let transport: Box<dyn Transport> = match &cli.pcap {
+        Some(path) => Box::new(PcapTransport::create(path, transport)?),
+        None => transport,
+    };
+
+    match &cli.record {
+        Some(path) => Ok(Box::new(RecordingTransport::create(path, transport)?)),
+        None => Ok(transport),
+    }
+}
+
+/// List the serial ports matching the USB selectors, probing them if requested
+fn print_ports(cli: &Cli) -> Result<(), Error> {
+    let mut ports = find_ports(&cli.port_filter())?;
+    if cli.probe {
+        ports = probe_ports(&SerialSpecs::from(cli), ports);
+    }
+    if ports.is_empty() {
+        println!("No serial port found.");
+        return Ok(());
+    }
+    println!(
+        "{:<24} {:<9} {:<20} {:<20} Product",
+        "Port", "VID:PID", "Serial Number", "Manufacturer"
+    );
+    println!("{}", "-".repeat(90));
+    for port in &ports {
+        println!("{}", format_port(port));
+    }
+    Ok(())
+}
+
+/// Check that the images to upload are signed by one of the keys, before
+/// spending an upload on images the bootloader would refuse
+fn check_signed_by(images: &[UploadImage], keys: &[PathBuf]) -> Result<(), Error> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let keys = keys
+        .iter()
+        .map(|path| VerifyingKey::load(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    for image in images {
+        let verified = verify_image(&image.data, &keys)
+            .map_err(|e| anyhow::anyhow!("{}: {}, not uploading", image.file, e))?;
+        info!(
+            "{}: signed by trusted key {}",
+            image.file,
+            hex::encode(&verified.key_hash)
+        );
+    }
+    Ok(())
+}
+
+/// Print which image and slot each image of a file is written to, and ask
+/// for confirmation when nothing chose the image on a multi-image device.
+/// The images of a firmware file are numbered with the chosen image.
+fn confirm_targets(
+    filename: &Path,
+    images: &mut [UploadImage],
+    image: Option<u8>,
+    rules: Option<&Path>,
+    yes: bool,
+    state: Option<ImageStateRsp>,
+) -> Result<(), Error> {
+    let rules = match rules {
+        Some(path) => TargetRules::load(path)?,
+        None => TargetRules::default(),
+    };
+    let plan = plan_upload(filename, images, image, &rules, state.as_ref())?;
+    for target in &plan.targets {
+        println!("{target}");
+    }
+    let Some(reason) = plan.ambiguous else {
+        return Ok(());
+    };
+    if yes {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("{}: choose the image with --image or --rules, or pass --yes", reason);
+    }
+    print!("{reason}, write image {} anyway? [y/N] ", plan.image);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        anyhow::bail!("aborted");
+    }
+    Ok(())
+}
+
+/// Print a directory listing, with the type and size of each entry if long
+fn print_dir_entries(entries: &[DirEntry], long: bool) {
+    if long {
+        println!("{:<4} {:>10}  Name", "Type", "Size");
+    }
+    for entry in entries {
+        if long {
+            println!("{entry}");
+        } else if entry.is_dir {
+            println!("{}/", entry.name);
+        } else {
+            println!("{}", entry.name);
+        }
+    }
+}
+
+/// Options of the fs transfers, verifying them unless opted out
+fn transfer_options(no_verify: bool) -> TransferOptions {
+    TransferOptions {
+        verify: !no_verify,
+        ..Default::default()
+    }
+}
+
+/// Print the steps of a tree transfer for a dry run, else count the files
+fn print_tree_actions(actions: &[TreeAction], dry_run: bool) {
+    if dry_run {
+        for action in actions {
+            println!("{action}");
+        }
+        return;
+    }
+    let count = |f: fn(&TreeAction) -> bool| actions.iter().filter(|a| f(a)).count();
+    let files = count(|a| matches!(a, TreeAction::Upload { .. } | TreeAction::Download { .. }));
+    let unchanged = count(|a| matches!(a, TreeAction::Unchanged { .. }));
+    let removed = count(|a| matches!(a, TreeAction::RemoveRemote(_)));
+    let mut summary = format!("{files} files transferred");
+    if unchanged > 0 {
+        summary += &format!(", {unchanged} unchanged");
+    }
+    if removed > 0 {
+        summary += &format!(", {removed} removed");
+    }
+    println!("{summary}");
+}
+
+/// Print the values of a settings apply, and what was done
+fn print_settings_updates(updates: &[SettingUpdate], dry_run: bool) {
+    for update in updates {
+        println!("{update}");
+    }
+    if dry_run {
+        println!("{} settings checked, nothing written", updates.len());
+    } else {
+        println!("{} settings written, saved and committed", updates.len());
+    }
+}
+
+/// Print a settings dump, with the descriptions of the schema
+fn print_setting_reports(reports: &[SettingReport], json: bool) -> Result<(), Error> {
+    if json {
+        let reports: Vec<_> = reports.iter().map(SettingReport::to_json).collect();
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+        return Ok(());
+    }
+    for report in reports {
+        println!("{report}");
+        if let Some(description) = &report.schema.description {
+            println!("    {description}");
+        }
+    }
+    Ok(())
+}
+
+/// Run the commands that work on local files, without a device
+fn execute_local_command(command: &Commands) -> Result<(), Error> {
+    match command {
+        Commands::Sign {
+            input,
+            output,
+            key,
+            version,
+            header_size,
+            pad_header,
+            slot_size,
+            align,
+            max_align,
+            max_sectors,
+            pad,
+            confirm,
+            overwrite_only,
+            erased_val,
+            security_counter,
+            dependency,
+            public_key_format,
+        } => {
+            let data = std::fs::read(input)
+                .with_context(|| format!("failed to read {}", input.display()))?;
+            let (format, binary) = to_binary(&data)?;
+            let key = key.as_deref().map(SigningKey::load).transpose()?;
+            let security_counter = match security_counter.as_deref() {
+                None => None,
+                Some("auto") => Some(version_security_counter(version)),
+                Some(counter) => Some(parse_number(counter).map_err(Error::msg)?),
+            };
+            let options = SignOptions {
+                version: *version,
+                header_size: *header_size,
+                pad_header: *pad_header,
+                slot_size: *slot_size,
+                align: *align,
+                max_align: *max_align,
+                max_sectors: *max_sectors,
+                pad: *pad,
+                confirm: *confirm,
+                overwrite_only: *overwrite_only,
+                erased_val: *erased_val,
+                security_counter,
+                dependencies: dependency.clone(),
+                public_key_format: match public_key_format.as_str() {
+                    "full" => PublicKeyFormat::Full,
+                    _ => PublicKeyFormat::Hash,
+                },
+            };
+            let image = sign_image(&binary, key.as_ref(), &options)?;
+            std::fs::write(output, &image)
+                .with_context(|| format!("failed to write {}", output.display()))?;
+            let signed = match &key {
+                Some(key) => format!("signed with {}", key.key_type()),
+                None => "unsigned".to_string(),
+            };
+            info!(
+                "{} image {} {}, {}, written to {}",
+                format,
+                version,
+                signed,
+                format_bytes(image.len() as u32),
+                output.display()
+            );
+        }
+        Commands::Verify { filename, key } => {
+            let keys = key
+                .iter()
+                .map(|path| VerifyingKey::load(path))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut failed = false;
+            for image in upload_images(filename, 0)? {
+                println!("{} (image {}):", image.file, image.image);
+                match verify_image(&image.data, &keys) {
+                    Ok(verified) => {
+                        println!("  version:   {}", verified.version);
+                        println!("  hash:      {}", hex::encode(&verified.hash));
+                        println!(
+                            "  signed by: {} key {} ({})",
+                            verified.key_type,
+                            hex::encode(&verified.key_hash),
+                            key[verified.key_index].display()
+                        );
+                        println!("  encrypted: {}", if verified.encrypted { "yes" } else { "no" });
+                    }
+                    Err(e) => {
+                        println!("  FAILED: {}", e);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                anyhow::bail!("verification failed");
+            }
+        }
+        Commands::Keygen { key, r#type } => {
+            SigningKey::generate(*r#type)?.save(key)?;
+            info!("{} key written to {}", r#type, key.display());
+        }
+        _ => unreachable!("not a local command"),
+    }
+    Ok(())
+}
+
+/// Select the serial port to use when no device was given
+///
+/// Candidates are the ports matching the USB selectors. When there is more
+/// than one, USB ports are preferred over built-in UARTs, and with `--probe`
+/// only ports on which a device answers an echo are kept.
+fn detect_device(cli: &Cli) -> Result<String, Error> {
+    let filter = cli.port_filter();
+    let mut candidates = find_ports(&filter)?;
+    if candidates.len() > 1 && filter.is_empty() && candidates.iter().any(|p| p.is_usb()) {
+        candidates.retain(|p| p.is_usb());
+    }
+    if cli.probe {
+        candidates = probe_ports(&SerialSpecs::from(cli), candidates);
+    }
+
+    match candidates.len() {
+        0 => {
+            if filter.is_empty() && !cli.probe {
+                Err(anyhow::anyhow!("No serial port found."))
+            } else {
+                Err(anyhow::anyhow!("No serial port matches the selection."))
+            }
+        }
+        1 => {
+            let device = candidates.remove(0).name;
+            info!("One matching serial port found, setting device to: {}", device);
+            Ok(device)
+        }
+        _ => {
+            error!("More than one serial port found, please specify one with --device, --vid, --pid, --serial-number or --probe:");
+            for port in &candidates {
+                println!("{}", format_port(port));
+            }
+            Err(anyhow::anyhow!("Ambiguous serial port selection."))
+        }
+    }
+}
+
 fn execute_command_serial(command: &Commands, specs: &SerialSpecs) -> Result<(), Error> {
     match command {
+        Commands::Ports | Commands::Sign { .. } | Commands::Verify { .. } | Commands::Keygen { .. } => {
+            unreachable!("handled before opening a connection")
+        }
+
         // ============== Image Management ==============
-        Commands::List => {
+        Commands::List { json } => {
             let v = list(specs)?;
-            print!("response: {}", serde_json::to_string_pretty(&v)?);
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&v)?);
+                return Ok(());
+            }
+            // not all devices support bootloader info
+            let mode = bootloader_info(specs, Some("mode")).ok().and_then(|i| i.mode);
+            print!("{}", format_image_state(&v, mode));
             Ok(())
         }
 
-        Commands::Upload { filename, slot } => {
+        Commands::Upload {
+            filename,
+            image,
+            rules,
+            yes,
+            test: mark_test,
+            require_signed_by,
+            allow_downgrade,
+            security_counter,
+            upgrade_only,
+            erase: erase_first,
+        } => {
+            let mut images = upload_images(filename, 0)?;
+            check_signed_by(&images, require_signed_by)?;
+            let state = list(specs).ok();
+            confirm_targets(filename, &mut images, *image, rules.as_deref(), *yes, state)?;
+            let check = UpgradeCheck {
+                allow_downgrade: *allow_downgrade,
+                security_counters: security_counter.iter().copied().collect(),
+                ..Default::default()
+            };
+            check_upload(specs, &images, &check)?;
+
             // create a progress bar
             let pb = ProgressBar::new(1_u64);
             pb.set_style(ProgressStyle::default_bar()
             .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
             .unwrap().progress_chars("=> "));
 
-            upload(
+            send_images(
                 specs,
-                filename,
-                *slot,
-                Some(|offset, total| {
+                &images,
+                &UploadOptions {
+                    upgrade_only: *upgrade_only,
+                    erase: *erase_first,
+                    ..Default::default()
+                },
+                &mut |offset, total| {
                     if let Some(l) = pb.length() {
                         if l != total {
                             pb.set_length(total)
@@ -388,15 +1301,72 @@ fn execute_command_serial(command: &Commands, specs: &SerialSpecs) -> Result<(),
                     if offset >= total {
                         pb.finish_with_message("upload complete");
                     }
+                    Ok(())
+                },
+            )?;
+
+            if *mark_test {
+                for upload in &images {
+                    test(specs, upload.hash()?, None)?;
+                }
+            }
+            Ok(())
+        }
+
+        Commands::Update {
+            filename,
+            image,
+            rules,
+            yes,
+            allow_downgrade,
+            security_counter,
+            upgrade_only,
+            erase: erase_first,
+            no_confirm,
+            upload_timeout,
+            reboot_timeout,
+            phase_timeout,
+        } => {
+            let mut images = upload_images(filename, 0)?;
+            let state = list(specs).ok();
+            confirm_targets(filename, &mut images, *image, rules.as_deref(), *yes, state)?;
+            let options = UpdateOptions {
+                allow_downgrade: *allow_downgrade,
+                upgrade_only: *upgrade_only,
+                erase: *erase_first,
+                confirm: !*no_confirm,
+                upload_timeout: Duration::from_secs(*upload_timeout),
+                reboot_timeout: Duration::from_secs(*reboot_timeout),
+                test_timeout: Duration::from_secs(*phase_timeout),
+                reset_timeout: Duration::from_secs(*phase_timeout),
+                verify_timeout: Duration::from_secs(*phase_timeout),
+                confirm_timeout: Duration::from_secs(*phase_timeout),
+                security_counters: security_counter.iter().copied().collect(),
+            };
+            let pb = ProgressBar::new(1_u64);
+            pb.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .unwrap().progress_chars("=> "));
+
+            let report = update(
+                specs,
+                &images,
+                &options,
+                Some(|offset, total| {
+                    pb.set_length(total);
+                    pb.set_position(offset);
                 }),
-            )
+            )?;
+            pb.finish_and_clear();
+            println!("{report}");
+            Ok(())
         }
 
         Commands::Test { hash, confirm } => {
             test(specs, hex::decode(hash)?, *confirm)
         }
 
-        Commands::Erase { slot } => erase(specs, *slot),
+        Commands::Erase { slot, force } => erase(specs, *slot, *force),
 
         // ============== OS/Default Management ==============
         Commands::Reset => reset(specs),
@@ -478,14 +1448,106 @@ fn execute_command_serial(command: &Commands, specs: &SerialSpecs) -> Result<(),
         }
 
         // ============== File System Management ==============
-        Commands::FsDownload { remote_path, local_path } => {
-            fs_download(specs, remote_path, local_path)
+        Commands::FsDownload {
+            remote_path,
+            local_path,
+            recursive,
+            include,
+            exclude,
+            dry_run,
+            no_verify,
+        } => {
+            let options = transfer_options(*no_verify);
+            if !*recursive && local_path == Path::new("-") {
+                let stdout = &mut std::io::stdout().lock();
+                let report = fs_download_to(specs, remote_path, stdout, &options)?;
+                info!("{report}");
+                return Ok(());
+            }
+            if !*recursive {
+                println!("{}", fs_download(specs, remote_path, local_path, &options)?);
+                return Ok(());
+            }
+            let filter = TreeFilter::new(include, exclude)?;
+            let actions = fs_download_dir(
+                specs,
+                remote_path,
+                local_path,
+                &filter,
+                &options,
+                *dry_run,
+            )?;
+            print_tree_actions(&actions, *dry_run);
+            Ok(())
         }
 
-        Commands::FsUpload { local_path, remote_path } => {
-            fs_upload(specs, local_path, remote_path)
+        Commands::FsUpload {
+            local_path,
+            remote_path,
+            recursive,
+            include,
+            exclude,
+            dry_run,
+            no_verify,
+        } => {
+            let options = transfer_options(*no_verify);
+            if !*recursive {
+                println!("{}", fs_upload(specs, local_path, remote_path, &options)?);
+                return Ok(());
+            }
+            let filter = TreeFilter::new(include, exclude)?;
+            let actions = fs_upload_dir(
+                specs,
+                local_path,
+                remote_path,
+                &filter,
+                &options,
+                *dry_run,
+            )?;
+            print_tree_actions(&actions, *dry_run);
+            Ok(())
+        }
+
+        Commands::FsSync {
+            local_dir,
+            remote_dir,
+            delete,
+            include,
+            exclude,
+            dry_run,
+            no_verify,
+        } => {
+            let options = transfer_options(*no_verify);
+            let filter = TreeFilter::new(include, exclude)?;
+            let actions = fs_sync_dir(
+                specs,
+                local_dir,
+                remote_dir,
+                &filter,
+                *delete,
+                &options,
+                *dry_run,
+            )?;
+            print_tree_actions(&actions, *dry_run);
+            Ok(())
+        }
+
+        Commands::FsLs { path, long } => {
+            let entries = if *long {
+                fs_list_dir_long(specs, path)?
+            } else {
+                fs_list_dir(specs, path)?
+            };
+            print_dir_entries(&entries, *long);
+            Ok(())
         }
 
+        Commands::FsRm { path } => fs_remove(specs, path),
+
+        Commands::FsMkdir { path } => fs_mkdir(specs, path),
+
+        Commands::FsMv { from, to } => fs_rename(specs, from, to),
+
         Commands::FsStat { path } => {
             let result = fs_stat(specs, path)?;
             println!("File: {path}");
@@ -523,21 +1585,22 @@ fn execute_command_serial(command: &Commands, specs: &SerialSpecs) -> Result<(),
         }
 
         // ============== Settings/Config Management ==============
-        Commands::SettingsRead { name, max_size } => {
+        Commands::SettingsRead {
+            name,
+            max_size,
+            as_type,
+            big_endian,
+        } => {
             let result = settings_read(specs, name, *max_size)?;
-            println!("Setting '{}': {}", name, hex::encode(&result.val));
-            // Try to also print as string if it's valid UTF-8
-            if let Ok(s) = std::str::from_utf8(&result.val) {
-                if s.chars().all(|c| c.is_ascii_graphic() || c.is_ascii_whitespace()) {
-                    println!("  (as string): {s}");
-                }
-            }
-            Ok(())
+            print_setting(name, &result.val, *as_type, *big_endian)
         }
 
-        Commands::SettingsWrite { name, value } => {
-            let bytes = hex::decode(value)
-                .map_err(|e| anyhow::anyhow!("Invalid hex value: {}", e))?;
+        Commands::SettingsWrite {
+            name,
+            value,
+            big_endian,
+        } => {
+            let bytes = value.encode(*big_endian)?;
             settings_write(specs, name, bytes)?;
             println!("Setting '{name}' written successfully");
             Ok(())
@@ -566,33 +1629,85 @@ fn execute_command_serial(command: &Commands, specs: &SerialSpecs) -> Result<(),
             println!("Settings saved successfully");
             Ok(())
         }
+
+        Commands::SettingsApply {
+            file,
+            schema,
+            defaults,
+            dry_run,
+        } => {
+            let updates = SettingsSchema::load(schema)?.load_values(file.as_deref(), *defaults)?;
+            if !*dry_run {
+                settings_apply(specs, &updates)?;
+            }
+            print_settings_updates(&updates, *dry_run);
+            Ok(())
+        }
+
+        Commands::SettingsDump { schema, json } => {
+            let reports = settings_dump(specs, &SettingsSchema::load(schema)?)?;
+            print_setting_reports(&reports, *json)
+        }
     }
 }
 
-fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error> {
-    // Create UDP transport
-    let mut transport = UdpTransport::new(specs)?;
-
+fn execute_command_transport(command: &Commands, transport: &mut dyn Transport) -> Result<(), Error> {
     match command {
+        Commands::Ports | Commands::Sign { .. } | Commands::Verify { .. } | Commands::Keygen { .. } => {
+            unreachable!("handled before opening a connection")
+        }
+
         // ============== Image Management ==============
-        Commands::List => {
-            let v = list_transport(&mut transport)?;
-            print!("response: {}", serde_json::to_string_pretty(&v)?);
+        Commands::List { json } => {
+            let v = list_transport(transport)?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&v)?);
+                return Ok(());
+            }
+            // not all devices support bootloader info
+            let mode = bootloader_info_transport(transport, Some("mode")).ok().and_then(|i| i.mode);
+            print!("{}", format_image_state(&v, mode));
             Ok(())
         }
 
-        Commands::Upload { filename, slot } => {
+        Commands::Upload {
+            filename,
+            image,
+            rules,
+            yes,
+            test: mark_test,
+            require_signed_by,
+            allow_downgrade,
+            security_counter,
+            upgrade_only,
+            erase: erase_first,
+        } => {
+            let mut images = upload_images(filename, 0)?;
+            check_signed_by(&images, require_signed_by)?;
+            let state = list_transport(transport).ok();
+            confirm_targets(filename, &mut images, *image, rules.as_deref(), *yes, state)?;
+            let check = UpgradeCheck {
+                allow_downgrade: *allow_downgrade,
+                security_counters: security_counter.iter().copied().collect(),
+                ..Default::default()
+            };
+            check_upload_transport(transport, &images, &check)?;
+
             // create a progress bar
             let pb = ProgressBar::new(1_u64);
             pb.set_style(ProgressStyle::default_bar()
             .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
             .unwrap().progress_chars("=> "));
 
-            upload_image_transport(
-                &mut transport,
-                filename,
-                *slot,
-                Some(|offset: u64, total: u64| {
+            send_images_transport(
+                transport,
+                &images,
+                &UploadOptions {
+                    upgrade_only: *upgrade_only,
+                    erase: *erase_first,
+                    ..Default::default()
+                },
+                &mut |offset: u64, total: u64| {
                     if let Some(l) = pb.length() {
                         if l != total {
                             pb.set_length(total)
@@ -604,27 +1719,84 @@ fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error
                     if offset >= total {
                         pb.finish_with_message("upload complete");
                     }
+                    Ok(())
+                },
+            )?;
+
+            if *mark_test {
+                for upload in &images {
+                    test_transport(transport, upload.hash()?, None)?;
+                }
+            }
+            Ok(())
+        }
+
+        Commands::Update {
+            filename,
+            image,
+            rules,
+            yes,
+            allow_downgrade,
+            security_counter,
+            upgrade_only,
+            erase: erase_first,
+            no_confirm,
+            upload_timeout,
+            reboot_timeout,
+            phase_timeout,
+        } => {
+            let mut images = upload_images(filename, 0)?;
+            let state = list_transport(transport).ok();
+            confirm_targets(filename, &mut images, *image, rules.as_deref(), *yes, state)?;
+            let options = UpdateOptions {
+                allow_downgrade: *allow_downgrade,
+                upgrade_only: *upgrade_only,
+                erase: *erase_first,
+                confirm: !*no_confirm,
+                upload_timeout: Duration::from_secs(*upload_timeout),
+                reboot_timeout: Duration::from_secs(*reboot_timeout),
+                test_timeout: Duration::from_secs(*phase_timeout),
+                reset_timeout: Duration::from_secs(*phase_timeout),
+                verify_timeout: Duration::from_secs(*phase_timeout),
+                confirm_timeout: Duration::from_secs(*phase_timeout),
+                security_counters: security_counter.iter().copied().collect(),
+            };
+            let pb = ProgressBar::new(1_u64);
+            pb.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .unwrap().progress_chars("=> "));
+
+            let report = update_transport(
+                transport,
+                &images,
+                &options,
+                Some(|offset, total| {
+                    pb.set_length(total);
+                    pb.set_position(offset);
                 }),
-            )
+            )?;
+            pb.finish_and_clear();
+            println!("{report}");
+            Ok(())
         }
 
         Commands::Test { hash, confirm } => {
-            test_transport(&mut transport, hex::decode(hash)?, *confirm)
+            test_transport(transport, hex::decode(hash)?, *confirm)
         }
 
-        Commands::Erase { slot } => erase_transport(&mut transport, *slot),
+        Commands::Erase { slot, force } => erase_transport(transport, *slot, *force),
 
         // ============== OS/Default Management ==============
-        Commands::Reset => reset_transport(&mut transport),
+        Commands::Reset => reset_transport(transport),
 
         Commands::Echo { message } => {
-            let response = echo_transport(&mut transport, message)?;
+            let response = echo_transport(transport, message)?;
             println!("Echo response: {response}");
             Ok(())
         }
 
         Commands::Taskstat => {
-            let stats = taskstat_transport(&mut transport)?;
+            let stats = taskstat_transport(transport)?;
             println!("Task Statistics:");
             println!("{:<24} {:>5} {:>6} {:>10} {:>10}", "Task", "Prio", "State", "Stack Use", "Stack Size");
             println!("{}", "-".repeat(59));
@@ -638,7 +1810,7 @@ fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error
         }
 
         Commands::McumgrParams => {
-            let params = mcumgr_params_transport(&mut transport)?;
+            let params = mcumgr_params_transport(transport)?;
             println!("MCUmgr Parameters:");
             println!("  Buffer size:  {}", format_bytes(params.buf_size));
             println!("  Buffer count: {}", params.buf_count);
@@ -646,14 +1818,14 @@ fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error
         }
 
         Commands::OsInfo { format } => {
-            let info = os_info_transport(&mut transport, Some(format))?;
+            let info = os_info_transport(transport, Some(format))?;
             println!("OS Information:");
             println!("{info}");
             Ok(())
         }
 
         Commands::BootloaderInfo { query } => {
-            let info = bootloader_info_transport(&mut transport, query.as_deref())?;
+            let info = bootloader_info_transport(transport, query.as_deref())?;
             println!("Bootloader Information:");
             println!("  Bootloader: {}", info.bootloader);
             if let Some(mode) = info.mode {
@@ -666,7 +1838,7 @@ fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error
         }
 
         Commands::Hwid => {
-            let info = os_info_transport(&mut transport, Some("h"))?;
+            let info = os_info_transport(transport, Some("h"))?;
             // Parse "hwid:XXXX" format
             if let Some(stripped) = info.strip_prefix("hwid:") {
                 println!("Hardware ID: {}", stripped.trim().to_uppercase());
@@ -683,7 +1855,7 @@ fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error
             if command.is_empty() {
                 return Err(anyhow::anyhow!("No command provided"));
             }
-            let result = shell_exec_transport(&mut transport, command.clone())?;
+            let result = shell_exec_transport(transport, command.clone())?;
             if !result.o.is_empty() {
                 print!("{}", result.o);
             }
@@ -694,23 +1866,115 @@ fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error
         }
 
         // ============== File System Management ==============
-        Commands::FsDownload { remote_path, local_path } => {
-            download_transport(&mut transport, remote_path, local_path)
+        Commands::FsDownload {
+            remote_path,
+            local_path,
+            recursive,
+            include,
+            exclude,
+            dry_run,
+            no_verify,
+        } => {
+            let options = transfer_options(*no_verify);
+            if !*recursive && local_path == Path::new("-") {
+                let stdout = &mut std::io::stdout().lock();
+                let report = download_to_transport(transport, remote_path, stdout, &options)?;
+                info!("{report}");
+                return Ok(());
+            }
+            if !*recursive {
+                println!("{}", download_transport(transport, remote_path, local_path, &options)?);
+                return Ok(());
+            }
+            let filter = TreeFilter::new(include, exclude)?;
+            let actions = download_dir_transport(
+                transport,
+                remote_path,
+                local_path,
+                &filter,
+                &options,
+                *dry_run,
+            )?;
+            print_tree_actions(&actions, *dry_run);
+            Ok(())
+        }
+
+        Commands::FsUpload {
+            local_path,
+            remote_path,
+            recursive,
+            include,
+            exclude,
+            dry_run,
+            no_verify,
+        } => {
+            let options = transfer_options(*no_verify);
+            if !*recursive {
+                println!("{}", upload_transport(transport, local_path, remote_path, &options)?);
+                return Ok(());
+            }
+            let filter = TreeFilter::new(include, exclude)?;
+            let actions = upload_dir_transport(
+                transport,
+                local_path,
+                remote_path,
+                &filter,
+                &options,
+                *dry_run,
+            )?;
+            print_tree_actions(&actions, *dry_run);
+            Ok(())
         }
 
-        Commands::FsUpload { local_path, remote_path } => {
-            upload_transport(&mut transport, local_path, remote_path)
+        Commands::FsSync {
+            local_dir,
+            remote_dir,
+            delete,
+            include,
+            exclude,
+            dry_run,
+            no_verify,
+        } => {
+            let options = transfer_options(*no_verify);
+            let filter = TreeFilter::new(include, exclude)?;
+            let actions = sync_dir_transport(
+                transport,
+                local_dir,
+                remote_dir,
+                &filter,
+                *delete,
+                &options,
+                *dry_run,
+            )?;
+            print_tree_actions(&actions, *dry_run);
+            Ok(())
         }
 
+        Commands::FsLs { path, long } => {
+            let entries = if *long {
+                list_dir_long_transport(transport, path)?
+            } else {
+                list_dir_transport(transport, path)?
+            };
+            print_dir_entries(&entries, *long);
+            Ok(())
+        }
+
+        Commands::FsRm { path } => remove_transport(transport, path),
+
+        Commands::FsMkdir { path } => mkdir_transport(transport, path),
+
+        Commands::FsMv { from, to } => rename_transport(transport, from, to),
+
         Commands::FsStat { path } => {
-            let result = stat_transport(&mut transport, path)?;
+            let result = stat_transport(transport, path)?;
             println!("File: {path}");
             println!("  Size: {} ({} bytes)", format_bytes(result.len), result.len);
             Ok(())
         }
 
         Commands::FsHash { path, hash_type } => {
-            let result = hash_transport(&mut transport, path, hash_type.as_deref(), None, None)?;
+            let result = hash_transport(transport, path, hash_type.as_deref(), None, None)?;
             println!("File: {path}");
             println!("  Type:   {}", result.hash_type);
             println!("  Offset: {}", result.off);
@@ -721,7 +1985,7 @@ fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error
 
         // ============== Statistics Management ==============
         Commands::StatList => {
-            let result = stat_list_transport(&mut transport)?;
+            let result = stat_list_transport(transport)?;
             println!("Available statistics groups:");
             for name in result.stat_list {
                 println!("  {name}");
@@ -730,7 +1994,7 @@ fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error
         }
 
         Commands::StatRead { name } => {
-            let result = stat_read_transport(&mut transport, name)?;
+            let result = stat_read_transport(transport, name)?;
             println!("Statistics for '{}':", result.name);
             for (field, value) in result.fields.iter() {
                 println!("  {field}: {value}");
@@ -739,48 +2003,68 @@ fn execute_command_udp(command: &Commands, specs: &UdpSpecs) -> Result<(), Error
         }
 
         // ============== Settings/Config Management ==============
-        Commands::SettingsRead { name, max_size } => {
-            let result = settings_read_transport(&mut transport, name, *max_size)?;
-            println!("Setting '{}': {}", name, hex::encode(&result.val));
-            // Try to also print as string if it's valid UTF-8
-            if let Ok(s) = std::str::from_utf8(&result.val) {
-                if s.chars().all(|c| c.is_ascii_graphic() || c.is_ascii_whitespace()) {
-                    println!("  (as string): {s}");
-                }
-            }
-            Ok(())
+        Commands::SettingsRead {
+            name,
+            max_size,
+            as_type,
+            big_endian,
+        } => {
+            let result = settings_read_transport(transport, name, *max_size)?;
+            print_setting(name, &result.val, *as_type, *big_endian)
         }
 
-        Commands::SettingsWrite { name, value } => {
-            let bytes = hex::decode(value)
-                .map_err(|e| anyhow::anyhow!("Invalid hex value: {}", e))?;
-            settings_write_transport(&mut transport, name, bytes)?;
+        Commands::SettingsWrite {
+            name,
+            value,
+            big_endian,
+        } => {
+            let bytes = value.encode(*big_endian)?;
+            settings_write_transport(transport, name, bytes)?;
             println!("Setting '{name}' written successfully");
             Ok(())
         }
 
         Commands::SettingsDelete { name } => {
-            settings_delete_transport(&mut transport, name)?;
+            settings_delete_transport(transport, name)?;
             println!("Setting '{name}' deleted successfully");
             Ok(())
         }
 
         Commands::SettingsCommit => {
-            settings_commit_transport(&mut transport)?;
+            settings_commit_transport(transport)?;
             println!("Settings committed successfully");
             Ok(())
         }
 
         Commands::SettingsLoad => {
-            settings_load_transport(&mut transport)?;
+            settings_load_transport(transport)?;
             println!("Settings loaded successfully");
             Ok(())
         }
 
         Commands::SettingsSave => {
-            settings_save_transport(&mut transport)?;
+            settings_save_transport(transport)?;
             println!("Settings saved successfully");
             Ok(())
         }
+
+        Commands::SettingsApply {
+            file,
+            schema,
+            defaults,
+            dry_run,
+        } => {
+            let updates = SettingsSchema::load(schema)?.load_values(file.as_deref(), *defaults)?;
+            if !*dry_run {
+                settings_apply_transport(transport, &updates)?;
+            }
+            print_settings_updates(&updates, *dry_run);
+            Ok(())
+        }
+
+        Commands::SettingsDump { schema, json } => {
+            let reports = settings_dump_transport(transport, &SettingsSchema::load(schema)?)?;
+            print_setting_reports(&reports, *json)
+        }
     }
 }
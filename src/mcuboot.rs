@@ -0,0 +1,299 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! MCUboot image format: header, TLV area and hash check
+
+use anyhow::{bail, Error, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// Image header magic
+pub const IMAGE_MAGIC: u32 = 0x96f3_b83d;
+/// Size of the image header structure, `hdr_size` may be larger
+pub const IMAGE_HEADER_SIZE: usize = 32;
+/// Header flag of images encrypted with AES-128
+pub const IMAGE_F_ENCRYPTED_AES128: u32 = 0x04;
+/// Header flag of images encrypted with AES-256
+pub const IMAGE_F_ENCRYPTED_AES256: u32 = 0x08;
+/// Magic of the unprotected TLV area
+pub const TLV_INFO_MAGIC: u16 = 0x6907;
+/// Magic of the protected TLV area, covered by the hash
+pub const TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+/// SHA256 of the public key the image is signed with
+pub const TLV_KEYHASH: u16 = 0x01;
+/// Public key the image is signed with
+pub const TLV_PUBKEY: u16 = 0x02;
+/// SHA256 of the header, payload and protected TLVs
+pub const TLV_SHA256: u16 = 0x10;
+pub const TLV_RSA2048_PSS: u16 = 0x20;
+pub const TLV_ECDSA_SIG: u16 = 0x22;
+pub const TLV_RSA3072_PSS: u16 = 0x23;
+pub const TLV_ED25519: u16 = 0x24;
+/// Minimum version of another image, protected
+pub const TLV_DEPENDENCY: u16 = 0x40;
+/// Security counter, protected
+pub const TLV_SEC_CNT: u16 = 0x50;
+
+/// Image version, ordered like MCUboot compares versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build: u32,
+}
+
+impl fmt::Display for ImageVersion {
+    /// Format like the image manager of Zephyr: the build number only if set
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.revision)?;
+        if self.build != 0 {
+            write!(f, ".{}", self.build)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ImageVersion {
+    type Err = Error;
+
+    /// Parse a version like imgtool, `major[.minor[.revision]][+build]`, or
+    /// like the image manager lists it, `major.minor.revision.build`
+    fn from_str(s: &str) -> Result<ImageVersion, Error> {
+        let (numbers, build) = match s.split_once('+') {
+            Some((numbers, build)) => (numbers, Some(build)),
+            None => (s, None),
+        };
+        let mut parts: Vec<&str> = numbers.split('.').collect();
+        let build = match build {
+            None if parts.len() == 4 => parts.pop(),
+            build => build,
+        };
+        if parts.len() > 3 {
+            bail!("invalid version '{}', expected major.minor.revision+build", s);
+        }
+        let number = |i: usize| parts.get(i).copied().unwrap_or("0");
+        let parse = || -> Result<ImageVersion, std::num::ParseIntError> {
+            Ok(ImageVersion {
+                major: number(0).parse()?,
+                minor: number(1).parse()?,
+                revision: number(2).parse()?,
+                build: build.unwrap_or("0").parse()?,
+            })
+        };
+        parse().map_err(|e| anyhow::anyhow!("invalid version '{}': {}", s, e))
+    }
+}
+
+impl ImageVersion {
+    /// Compare like MCUboot for downgrade prevention and dependencies,
+    /// without the build number
+    pub fn cmp_release(&self, other: &ImageVersion) -> std::cmp::Ordering {
+        (self.major, self.minor, self.revision).cmp(&(other.major, other.minor, other.revision))
+    }
+
+    /// Encode the version like the image header
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0u8; 8];
+        bytes[0] = self.major;
+        bytes[1] = self.minor;
+        LittleEndian::write_u16(&mut bytes[2..], self.revision);
+        LittleEndian::write_u32(&mut bytes[4..], self.build);
+        bytes
+    }
+}
+
+/// Image header
+#[derive(Debug, Clone)]
+pub struct ImageHeader {
+    pub load_addr: u32,
+    pub hdr_size: u16,
+    pub protect_tlv_size: u16,
+    pub img_size: u32,
+    pub flags: u32,
+    pub version: ImageVersion,
+}
+
+impl ImageHeader {
+    pub fn parse(data: &[u8]) -> Result<ImageHeader, Error> {
+        if data.len() < IMAGE_HEADER_SIZE {
+            bail!("not an MCUboot image: {} bytes are too short", data.len());
+        }
+        let magic = LittleEndian::read_u32(data);
+        if magic != IMAGE_MAGIC {
+            bail!("not an MCUboot image: wrong header magic 0x{:08x}", magic);
+        }
+        let header = ImageHeader {
+            load_addr: LittleEndian::read_u32(&data[4..]),
+            hdr_size: LittleEndian::read_u16(&data[8..]),
+            protect_tlv_size: LittleEndian::read_u16(&data[10..]),
+            img_size: LittleEndian::read_u32(&data[12..]),
+            flags: LittleEndian::read_u32(&data[16..]),
+            version: ImageVersion {
+                major: data[20],
+                minor: data[21],
+                revision: LittleEndian::read_u16(&data[22..]),
+                build: LittleEndian::read_u32(&data[24..]),
+            },
+        };
+        if (header.hdr_size as usize) < IMAGE_HEADER_SIZE {
+            bail!("invalid MCUboot image: header size {}", header.hdr_size);
+        }
+        Ok(header)
+    }
+
+    /// Whether the payload is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & (IMAGE_F_ENCRYPTED_AES128 | IMAGE_F_ENCRYPTED_AES256) != 0
+    }
+}
+
+/// Entry of a TLV area
+#[derive(Debug, Clone)]
+pub struct Tlv {
+    pub kind: u16,
+    pub value: Vec<u8>,
+    /// in the protected area, covered by the hash and signature
+    pub protected: bool,
+}
+
+/// Parsed MCUboot image
+#[derive(Debug, Clone)]
+pub struct McubootImage {
+    pub header: ImageHeader,
+    pub tlvs: Vec<Tlv>,
+    /// SHA256 of the header, payload and protected TLVs
+    pub digest: Vec<u8>,
+    /// Size of the image up to the end of the TLV area, without padding
+    pub len: usize,
+}
+
+impl McubootImage {
+    /// Parse the header and the TLV areas of an image
+    pub fn parse(data: &[u8]) -> Result<McubootImage, Error> {
+        let header = ImageHeader::parse(data)?;
+        let payload_end = header.hdr_size as usize + header.img_size as usize;
+        if payload_end > data.len() {
+            bail!(
+                "truncated MCUboot image: {} bytes, the header announces {}",
+                data.len(),
+                payload_end
+            );
+        }
+
+        let mut tlvs = Vec::new();
+        let mut pos = payload_end;
+        if header.protect_tlv_size > 0 {
+            let end = parse_tlv_area(data, pos, TLV_PROT_INFO_MAGIC, &mut tlvs)?;
+            if end - pos != header.protect_tlv_size as usize {
+                bail!("invalid MCUboot image: protected TLV size mismatch");
+            }
+            pos = end;
+        }
+        let digest = Sha256::digest(&data[..pos]).to_vec();
+        let len = parse_tlv_area(data, pos, TLV_INFO_MAGIC, &mut tlvs)?;
+        Ok(McubootImage {
+            header,
+            tlvs,
+            digest,
+            len,
+        })
+    }
+
+    /// Value of the first TLV of a kind
+    pub fn tlv(&self, kind: u16) -> Option<&[u8]> {
+        self.tlvs
+            .iter()
+            .find(|t| t.kind == kind)
+            .map(|t| t.value.as_slice())
+    }
+
+    /// Hash identifying the image on the device: the SHA256 TLV, or the
+    /// digest if there is none
+    pub fn hash(&self) -> Vec<u8> {
+        self.tlv(TLV_SHA256)
+            .map(|h| h.to_vec())
+            .unwrap_or_else(|| self.digest.clone())
+    }
+
+    /// Security counter of the protected TLVs
+    pub fn security_counter(&self) -> Option<u32> {
+        self.tlvs
+            .iter()
+            .find(|t| t.protected && t.kind == TLV_SEC_CNT && t.value.len() == 4)
+            .map(|t| LittleEndian::read_u32(&t.value))
+    }
+
+    /// Minimum versions of other images: (image number, version)
+    pub fn dependencies(&self) -> Vec<(u8, ImageVersion)> {
+        self.tlvs
+            .iter()
+            .filter(|t| t.protected && t.kind == TLV_DEPENDENCY && t.value.len() == 12)
+            .map(|t| {
+                let v = &t.value[4..];
+                let version = ImageVersion {
+                    major: v[0],
+                    minor: v[1],
+                    revision: LittleEndian::read_u16(&v[2..]),
+                    build: LittleEndian::read_u32(&v[4..]),
+                };
+                (t.value[0], version)
+            })
+            .collect()
+    }
+
+    /// Check that the image has a SHA256 TLV matching its contents. The hash
+    /// of an encrypted image covers the plaintext and can't be checked.
+    pub fn check_hash(&self) -> Result<(), Error> {
+        match self.tlv(TLV_SHA256) {
+            None => bail!("invalid MCUboot image: no SHA256 TLV"),
+            Some(_) if self.header.is_encrypted() => Ok(()),
+            Some(hash) if hash != self.digest.as_slice() => {
+                bail!("invalid MCUboot image: SHA256 mismatch, the image is corrupted")
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// Parse a TLV area at `pos`, append its entries and return its end
+fn parse_tlv_area(
+    data: &[u8],
+    pos: usize,
+    magic: u16,
+    tlvs: &mut Vec<Tlv>,
+) -> Result<usize, Error> {
+    let protected = magic == TLV_PROT_INFO_MAGIC;
+    let area = match data.get(pos..pos + 4) {
+        Some(info) if LittleEndian::read_u16(info) == magic => info,
+        _ => bail!(
+            "invalid MCUboot image: no TLV area 0x{:04x} at offset {}",
+            magic,
+            pos
+        ),
+    };
+    let end = pos + LittleEndian::read_u16(&area[2..]) as usize;
+    if end > data.len() {
+        bail!("truncated MCUboot image: TLV area ends at {}", end);
+    }
+    let mut off = pos + 4;
+    while off < end {
+        if off + 4 > end {
+            bail!("invalid MCUboot image: truncated TLV at offset {}", off);
+        }
+        let kind = LittleEndian::read_u16(&data[off..]);
+        let len = LittleEndian::read_u16(&data[off + 2..]) as usize;
+        let value = match data.get(off + 4..off + 4 + len) {
+            Some(value) if off + 4 + len <= end => value,
+            _ => bail!("invalid MCUboot image: TLV 0x{:02x} exceeds its area", kind),
+        };
+        tlvs.push(Tlv {
+            kind,
+            value: value.to_vec(),
+            protected,
+        });
+        off += 4 + len;
+    }
+    Ok(end)
+}
@@ -1,7 +1,7 @@
 // Copyright © 2023-2024 Vouch.io LLC, 2026 Rudis Laboratories LLC
 
 use hex_buffer_serde::{Hex as _, HexForm};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
@@ -25,6 +25,11 @@ pub enum NmpErr {
     EInvalid = 3,
     ETimeout = 4,
     ENoEnt = 5,
+    EBadState = 6,
+    EMsgSize = 7,
+    ENotSup = 8,
+    ECorrupt = 9,
+    EBusy = 10,
 }
 
 #[repr(u16)]
@@ -47,8 +52,15 @@ pub trait NmpId {
     fn to_u8(&self) -> u8;
 }
 
+/// Raw command id, as passed to `Transport::transceive`
+impl NmpId for u8 {
+    fn to_u8(&self) -> u8 {
+        *self
+    }
+}
+
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromPrimitive)]
 #[allow(dead_code)]
 pub enum NmpIdDef {
     Echo = 0,
@@ -69,7 +81,7 @@ impl NmpId for NmpIdDef {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromPrimitive)]
 #[allow(dead_code)]
 pub enum NmpIdImage {
     State = 0,
@@ -86,7 +98,7 @@ impl NmpId for NmpIdImage {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromPrimitive)]
 #[allow(dead_code)]
 pub enum NmpIdStat {
     Read = 0,
@@ -94,14 +106,21 @@ pub enum NmpIdStat {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromPrimitive)]
 #[allow(dead_code)]
 pub enum NmpIdConfig {
+    /// Read (op Read) or write (op Write) a setting
     Val = 0,
+    /// Delete a setting
+    Delete = 1,
+    /// Apply the written settings
+    Commit = 2,
+    /// Load the settings from (op Read) or save them to (op Write) storage
+    LoadSave = 3,
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromPrimitive)]
 #[allow(dead_code)]
 pub enum NmpIdLog {
     Show = 0,
@@ -113,14 +132,14 @@ pub enum NmpIdLog {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromPrimitive)]
 #[allow(dead_code)]
 pub enum NmpIdCrash {
     Trigger = 0,
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromPrimitive)]
 #[allow(dead_code)]
 pub enum NmpIdRun {
     Test = 0,
@@ -128,7 +147,7 @@ pub enum NmpIdRun {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromPrimitive)]
 #[allow(dead_code)]
 pub enum NmpIdFs {
     File = 0,
@@ -145,7 +164,7 @@ impl NmpId for NmpIdFs {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromPrimitive)]
 #[allow(dead_code)]
 pub enum NmpIdShell {
     Exec = 0,
@@ -169,6 +188,34 @@ impl NmpId for NmpIdConfig {
     }
 }
 
+/// Get the display name of a command, e.g. `Image/Upload`
+///
+/// The names are those of the `NmpId*` enums; unknown command ids are shown
+/// as number.
+pub fn command_name(group: NmpGroup, id: u8) -> String {
+    let name = match group {
+        NmpGroup::Default => id_name::<NmpIdDef>(id),
+        NmpGroup::Image => id_name::<NmpIdImage>(id),
+        NmpGroup::Stat => id_name::<NmpIdStat>(id),
+        NmpGroup::Config => id_name::<NmpIdConfig>(id),
+        NmpGroup::Log => id_name::<NmpIdLog>(id),
+        NmpGroup::Crash => id_name::<NmpIdCrash>(id),
+        NmpGroup::Run => id_name::<NmpIdRun>(id),
+        NmpGroup::Fs => id_name::<NmpIdFs>(id),
+        NmpGroup::Shell => id_name::<NmpIdShell>(id),
+        NmpGroup::Split | NmpGroup::PerUser => None,
+    };
+    match name {
+        Some(name) => format!("{group:?}/{name}"),
+        None => format!("{group:?}/{id}"),
+    }
+}
+
+/// Name of the variant of a command id enum
+fn id_name<T: num::FromPrimitive + std::fmt::Debug>(id: u8) -> Option<String> {
+    T::from_u8(id).map(|id| format!("{id:?}"))
+}
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub struct NmpHdr {
     pub op: NmpOp,
@@ -202,6 +249,28 @@ impl NmpHdr {
         Ok(buffer)
     }
 
+    /// Parse a header from the first 8 bytes of a packet
+    ///
+    /// Unlike `deserialize`, unknown ops and groups are reported as error.
+    pub fn parse(data: &[u8]) -> Result<NmpHdr, anyhow::Error> {
+        if data.len() < 8 {
+            anyhow::bail!("packet too short: {} bytes", data.len());
+        }
+        let op = num::FromPrimitive::from_u8(data[0] & 0x07)
+            .ok_or_else(|| anyhow::anyhow!("unknown op: {}", data[0] & 0x07))?;
+        let group_val = BigEndian::read_u16(&data[4..6]);
+        let group = num::FromPrimitive::from_u16(group_val)
+            .ok_or_else(|| anyhow::anyhow!("unknown group: {}", group_val))?;
+        Ok(NmpHdr {
+            op,
+            flags: data[1],
+            len: BigEndian::read_u16(&data[2..4]),
+            group,
+            seq: data[6],
+            id: data[7],
+        })
+    }
+
     pub fn deserialize(cursor: &mut Cursor<&Vec<u8>>) -> Result<NmpHdr, bincode::Error> {
         let op = num::FromPrimitive::from_u8(cursor.read_u8()?).unwrap();
         let flags = cursor.read_u8()?;
@@ -220,11 +289,6 @@ impl NmpHdr {
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
-pub struct NmpBase {
-    pub hdr: NmpHdr,
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, FromPrimitive, Deserialize, Serialize)]
 pub enum SplitStatus {
     NotApplicable = 0,
@@ -395,7 +459,8 @@ pub struct ShellExecReq {
 pub struct ShellExecRsp {
     #[serde(default)]
     pub o: String,
-    #[serde(default)]
+    /// Return code of the command, `ret` in SMP version 2 responses
+    #[serde(default, alias = "ret")]
     pub rc: i32,
 }
 
@@ -475,12 +540,52 @@ pub struct FsHashRsp {
     pub off: u32,
     #[serde(rename = "len")]
     pub len: u32,
-    #[serde(rename = "output", with = "serde_bytes")]
+    #[serde(rename = "output", with = "hash_output")]
     pub output: Vec<u8>,
     #[serde(default)]
     pub rc: i32,
 }
 
+/// Hash and checksum types supported by the device, by name
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsHashTypesRsp {
+    #[serde(default)]
+    pub types: std::collections::BTreeMap<String, FsHashTypeInfo>,
+    #[serde(default)]
+    pub rc: i32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsHashTypeInfo {
+    /// 0 for a checksum sent as integer, 1 for a hash sent as bytes
+    pub format: u8,
+    /// Size of the output in bytes
+    pub size: u32,
+}
+
+/// Hash output, Zephyr sends checksums (crc32) as integer and hashes as bytes
+///
+/// A checksum is converted to its 4 big-endian bytes, like the local crc32
+/// output it is compared with.
+mod hash_output {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(output: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serde_bytes::serialize(output, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match serde_cbor::Value::deserialize(deserializer)? {
+            serde_cbor::Value::Bytes(bytes) => Ok(bytes),
+            serde_cbor::Value::Integer(checksum) => u32::try_from(checksum)
+                .map(|checksum| checksum.to_be_bytes().to_vec())
+                .map_err(|_| D::Error::custom(format!("checksum {checksum} out of range"))),
+            _ => Err(D::Error::custom("invalid hash output")),
+        }
+    }
+}
+
 // Statistics Management Group Structures
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -545,3 +650,31 @@ pub struct SettingsLoadReq {}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SettingsSaveReq {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_cbor::Value;
+    use std::collections::BTreeMap;
+
+    fn hash_rsp(output: Value) -> Result<FsHashRsp, serde_cbor::Error> {
+        let mut map = BTreeMap::new();
+        map.insert(Value::Text("type".to_string()), Value::Text("crc32".to_string()));
+        map.insert(Value::Text("off".to_string()), Value::Integer(0));
+        map.insert(Value::Text("len".to_string()), Value::Integer(4));
+        map.insert(Value::Text("output".to_string()), output);
+        serde_cbor::value::from_value(Value::Map(map))
+    }
+
+    #[test]
+    fn test_hash_output() {
+        let rsp = hash_rsp(Value::Integer(0x1234_5678)).unwrap();
+        assert_eq!(rsp.output, vec![0x12, 0x34, 0x56, 0x78]);
+        let rsp = hash_rsp(Value::Bytes(vec![1, 2, 3])).unwrap();
+        assert_eq!(rsp.output, vec![1, 2, 3]);
+
+        // a crc32 doesn't fit in more than 4 bytes
+        assert!(hash_rsp(Value::Integer(1 << 32)).is_err());
+        assert!(hash_rsp(Value::Integer(-1)).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Nordic DFU packages: zip files with a `manifest.json` and the images of a
+//! multi-image update, like `dfu_application.zip` of the nRF Connect SDK
+
+use anyhow::{bail, Context, Error, Result};
+use serde::Deserialize;
+use std::io::{Cursor, Read};
+
+use crate::firmware::load_image;
+use crate::mcuboot::McubootImage;
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestFile {
+    file: String,
+    /// image number, a string like "1" in the manifests of the SDK
+    image_index: Option<serde_json::Value>,
+}
+
+/// Image to upload: an image of a DFU package, or a firmware file
+#[derive(Debug, Clone)]
+pub struct UploadImage {
+    /// Image number on the device
+    pub image: u8,
+    /// Name of the file
+    pub file: String,
+    /// The MCUboot image
+    pub data: Vec<u8>,
+    /// From a DFU package, whose manifest gives the image number
+    pub packaged: bool,
+}
+
+impl UploadImage {
+    /// Hash of the image, as listed by the device
+    pub fn hash(&self) -> Result<Vec<u8>, Error> {
+        Ok(McubootImage::parse(&self.data)?.hash())
+    }
+}
+
+/// Whether a file is a zip file, and could be a DFU package
+pub fn is_package(data: &[u8]) -> bool {
+    data.starts_with(b"PK\x03\x04")
+}
+
+/// Read the images of a DFU package, in the order of its manifest
+pub fn parse_package(data: &[u8]) -> Result<Vec<UploadImage>, Error> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(data)).context("invalid zip file")?;
+    let manifest: Manifest = serde_json::from_slice(&read_entry(&mut zip, "manifest.json")?)
+        .context("invalid manifest.json")?;
+    if manifest.files.is_empty() {
+        bail!("no files in manifest.json");
+    }
+
+    let mut images: Vec<UploadImage> = Vec::new();
+    for file in manifest.files {
+        let image = match &file.image_index {
+            None => 0,
+            Some(serde_json::Value::Number(n)) => n.as_u64().unwrap_or(u64::MAX),
+            Some(serde_json::Value::String(s)) => s.parse().unwrap_or(u64::MAX),
+            Some(_) => u64::MAX,
+        };
+        let Ok(image) = u8::try_from(image) else {
+            bail!("{}: invalid image_index {:?}", file.file, file.image_index);
+        };
+        if let Some(other) = images.iter().find(|i| i.image == image) {
+            bail!("{} and {} are both for image {}", other.file, file.file, image);
+        }
+        let data = load_image(&read_entry(&mut zip, &file.file)?)
+            .with_context(|| file.file.clone())?;
+        images.push(UploadImage {
+            image,
+            file: file.file,
+            data,
+            packaged: true,
+        });
+    }
+    Ok(images)
+}
+
+fn read_entry(zip: &mut zip::ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Vec<u8>, Error> {
+    let mut entry = zip
+        .by_name(name)
+        .with_context(|| format!("{name} is missing in the package"))?;
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::build_image;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    fn package(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_parse_package() {
+        let app = build_image((2, 0, 0, 0), &[1; 100]);
+        let net = build_image((2, 0, 0, 0), &[2; 50]);
+        let manifest = r#"{
+            "format-version": 0,
+            "files": [
+                { "type": "application", "image_index": "0", "file": "app_update.bin" },
+                { "type": "application", "image_index": "1", "file": "net_core_app_update.bin" }
+            ],
+            "name": "dfu_application"
+        }"#;
+        let data = package(&[
+            ("manifest.json", manifest.as_bytes()),
+            ("app_update.bin", &app),
+            ("net_core_app_update.bin", &net),
+        ]);
+        assert!(is_package(&data));
+        let images = parse_package(&data).unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!((images[0].image, &images[0].data), (0, &app));
+        assert_eq!((images[1].image, &images[1].data), (1, &net));
+
+        // missing file, and two files for one image
+        let data = package(&[("manifest.json", manifest.as_bytes()), ("app_update.bin", &app)]);
+        assert!(parse_package(&data).is_err());
+        let manifest = r#"{ "files": [ { "file": "a.bin" }, { "file": "b.bin" } ] }"#;
+        let data = package(&[
+            ("manifest.json", manifest.as_bytes()),
+            ("a.bin", &app),
+            ("b.bin", &net),
+        ]);
+        assert!(parse_package(&data).is_err());
+    }
+}
@@ -0,0 +1,388 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+use anyhow::{bail, Context, Error, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use log::info;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::nmp_hdr::*;
+use crate::transfer::{Direction, PacketTap, Transport};
+
+/// Link type of the SMP interface (LINKTYPE_USER0)
+///
+/// Each packet is the 8-byte SMP header followed by the CBOR body. In
+/// Wireshark, map "DLT User 0" to an SMP dissector to decode it.
+pub const LINKTYPE_SMP: u16 = 147;
+
+/// Link type of the serial console interface (LINKTYPE_USER1)
+///
+/// Each packet is the raw data written to or read from the serial port in
+/// one exchange: the base64 lines of the SMP frames with their CRC, and any
+/// console output read with them.
+pub const LINKTYPE_SERIAL: u16 = 148;
+
+/// Interface id of the SMP packets
+pub const INTERFACE_SMP: u32 = 0;
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const EPB_FLAG_INBOUND: u32 = 0b01;
+const EPB_FLAG_OUTBOUND: u32 = 0b10;
+
+fn write_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) -> Result<(), Error> {
+    buf.write_u16::<LittleEndian>(code)?;
+    buf.write_u16::<LittleEndian>(value.len() as u16)?;
+    buf.extend_from_slice(value);
+    pad32(buf);
+    Ok(())
+}
+
+fn pad32(buf: &mut Vec<u8>) {
+    while !buf.len().is_multiple_of(4) {
+        buf.push(0);
+    }
+}
+
+/// Minimal pcapng writer with an SMP interface and optional more ones
+pub struct PcapWriter {
+    writer: Box<dyn Write>,
+    interfaces: u32,
+}
+
+impl PcapWriter {
+    /// Write the section header and interface description blocks
+    pub fn new(writer: Box<dyn Write>) -> Result<Self, Error> {
+        let mut pcap = PcapWriter {
+            writer,
+            interfaces: 0,
+        };
+
+        let mut shb = Vec::new();
+        shb.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+        shb.write_u16::<LittleEndian>(1)?;
+        shb.write_u16::<LittleEndian>(0)?;
+        // section length not specified
+        shb.write_i64::<LittleEndian>(-1)?;
+        let application = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        write_option(&mut shb, OPT_SHB_USERAPPL, application.as_bytes())?;
+        write_option(&mut shb, OPT_END, &[])?;
+        pcap.write_block(BLOCK_SHB, &shb)?;
+        pcap.add_interface(LINKTYPE_SMP, "smp")?;
+        Ok(pcap)
+    }
+
+    /// Write an interface description block, returns the interface id
+    pub fn add_interface(&mut self, linktype: u16, name: &str) -> Result<u32, Error> {
+        let mut idb = Vec::new();
+        idb.write_u16::<LittleEndian>(linktype)?;
+        idb.write_u16::<LittleEndian>(0)?;
+        // no snapshot length limit
+        idb.write_u32::<LittleEndian>(0)?;
+        write_option(&mut idb, OPT_IF_NAME, name.as_bytes())?;
+        write_option(&mut idb, OPT_END, &[])?;
+        self.write_block(BLOCK_IDB, &idb)?;
+        self.writer.flush()?;
+
+        self.interfaces += 1;
+        Ok(self.interfaces - 1)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> Result<(), Error> {
+        let total_len = (body.len() + 12) as u32;
+        self.writer.write_u32::<LittleEndian>(block_type)?;
+        self.writer.write_u32::<LittleEndian>(total_len)?;
+        self.writer.write_all(body)?;
+        self.writer.write_u32::<LittleEndian>(total_len)?;
+        Ok(())
+    }
+
+    /// Write a packet of an interface as an enhanced packet block, e.g. an
+    /// SMP packet (header and CBOR body) of `INTERFACE_SMP`
+    pub fn write_packet(
+        &mut self,
+        interface: u32,
+        time: SystemTime,
+        direction: Direction,
+        packet: &[u8],
+        comment: Option<&str>,
+    ) -> Result<(), Error> {
+        // timestamps in the default resolution of microseconds
+        let micros = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+        if interface >= self.interfaces {
+            bail!("unknown pcapng interface {}", interface);
+        }
+        let mut epb = Vec::new();
+        epb.write_u32::<LittleEndian>(interface)?;
+        epb.write_u32::<LittleEndian>((micros >> 32) as u32)?;
+        epb.write_u32::<LittleEndian>(micros as u32)?;
+        epb.write_u32::<LittleEndian>(packet.len() as u32)?;
+        epb.write_u32::<LittleEndian>(packet.len() as u32)?;
+        epb.extend_from_slice(packet);
+        pad32(&mut epb);
+        let flags = match direction {
+            Direction::Outbound => EPB_FLAG_OUTBOUND,
+            Direction::Inbound => EPB_FLAG_INBOUND,
+        };
+        write_option(&mut epb, OPT_EPB_FLAGS, &flags.to_le_bytes())?;
+        if let Some(comment) = comment {
+            write_option(&mut epb, OPT_COMMENT, comment.as_bytes())?;
+        }
+        write_option(&mut epb, OPT_END, &[])?;
+        self.write_block(BLOCK_EPB, &epb)?;
+
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Packets passed by the taps of the inner transport, with their interface
+/// and time
+type Captured = Arc<Mutex<Vec<(u32, SystemTime, Direction, Vec<u8>)>>>;
+
+/// Get a tap queueing the packets as captured on an interface
+fn capture(captured: &Captured, interface: u32) -> PacketTap {
+    let queue = captured.clone();
+    Box::new(move |direction, packet| {
+        let packet = (interface, SystemTime::now(), direction, packet.to_vec());
+        queue.lock().unwrap().push(packet);
+    })
+}
+
+/// Transport wrapper capturing all SMP traffic to a pcapng file
+///
+/// The packets are captured as sent and received by the inner transport,
+/// which must support `Transport::set_tap`. Serial transports also capture
+/// the raw console data on a second interface, see `LINKTYPE_SERIAL`.
+pub struct PcapTransport<T: Transport> {
+    inner: T,
+    pcap: PcapWriter,
+    captured: Captured,
+}
+
+impl<T: Transport> PcapTransport<T> {
+    /// Create the capture file and start capturing
+    pub fn create(path: &Path, inner: T) -> Result<Self, Error> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create capture {}", path.display()))?;
+        info!("capturing SMP traffic to {}", path.display());
+        Self::new(Box::new(BufWriter::new(file)), inner)
+    }
+
+    /// Start capturing to a writer
+    pub fn new(writer: Box<dyn Write>, mut inner: T) -> Result<Self, Error> {
+        let captured = Captured::default();
+        if !inner.set_tap(capture(&captured, INTERFACE_SMP)) {
+            bail!("the transport has no raw packets to capture");
+        }
+        let mut pcap = PcapWriter::new(writer)?;
+        let serial = pcap.interfaces;
+        if inner.set_frame_tap(capture(&captured, serial)) {
+            pcap.add_interface(LINKTYPE_SERIAL, "serial")?;
+        }
+        Ok(PcapTransport {
+            inner,
+            pcap,
+            captured,
+        })
+    }
+
+    /// Get the wrapped transport
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for PcapTransport<T> {
+    fn transceive(
+        &mut self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        let result = self.inner.transceive(op, group, id, body);
+
+        let packets = std::mem::take(&mut *self.captured.lock().unwrap());
+        let last = packets.len();
+        let mut sent = None;
+        for (i, (interface, time, direction, packet)) in packets.into_iter().enumerate() {
+            let mut comment = match direction {
+                _ if interface != INTERFACE_SMP => None,
+                Direction::Outbound => {
+                    sent = Some(time);
+                    None
+                }
+                Direction::Inbound => sent.map(|sent| {
+                    let rtt = time.duration_since(sent).unwrap_or_default();
+                    format!("rtt {:.1} ms", rtt.as_secs_f64() * 1000.0)
+                }),
+            };
+            if i + 1 == last {
+                if let Err(e) = &result {
+                    comment = Some(e.to_string());
+                }
+            }
+            self.pcap
+                .write_packet(interface, time, direction, &packet, comment.as_deref())?;
+        }
+
+        result
+    }
+
+    fn set_timeout(&mut self, timeout_ms: u32) -> Result<(), Error> {
+        self.inner.set_timeout(timeout_ms)
+    }
+
+    fn timeout(&self) -> Option<u32> {
+        self.inner.timeout()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn linelength(&self) -> usize {
+        self.inner.linelength()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::{decode_packet, FrameDecoder, SerialSpecs, SerialTransport};
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pcapng_blocks() {
+        let buf = SharedBuf::default();
+        let mut pcap = PcapWriter::new(Box::new(buf.clone())).unwrap();
+        let packet = [2, 0, 0, 3, 0, 1, 42, 1, 0xa1, 0x61, 0x61];
+        pcap.write_packet(INTERFACE_SMP, UNIX_EPOCH, Direction::Outbound, &packet, Some("x"))
+            .unwrap();
+        assert!(pcap.write_packet(1, UNIX_EPOCH, Direction::Outbound, &packet, None).is_err());
+
+        // walk the blocks: SHB, IDB, EPB, each with matching length fields
+        let data = buf.0.lock().unwrap().clone();
+        let mut blocks = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let block_type = LittleEndian::read_u32(&data[pos..]);
+            let len = LittleEndian::read_u32(&data[pos + 4..]) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(LittleEndian::read_u32(&data[pos + len - 4..]) as usize, len);
+            blocks.push((block_type, pos));
+            pos += len;
+        }
+        assert_eq!(pos, data.len());
+        let types: Vec<u32> = blocks.iter().map(|b| b.0).collect();
+        assert_eq!(types, vec![BLOCK_SHB, BLOCK_IDB, BLOCK_EPB]);
+
+        let idb = blocks[1].1;
+        assert_eq!(LittleEndian::read_u16(&data[idb + 8..]), LINKTYPE_SMP);
+
+        let epb = blocks[2].1;
+        let captured = LittleEndian::read_u32(&data[epb + 20..]) as usize;
+        assert_eq!(captured, 11);
+        let packet = &data[epb + 28..epb + 28 + captured];
+        assert_eq!(packet, &[2, 0, 0, 3, 0, 1, 42, 1, 0xa1, 0x61, 0x61]);
+        // epb_flags option follows the padded packet data
+        let flags_option = epb + 28 + 12;
+        assert_eq!(LittleEndian::read_u16(&data[flags_option..]), OPT_EPB_FLAGS);
+        assert_eq!(LittleEndian::read_u32(&data[flags_option + 4..]), EPB_FLAG_OUTBOUND);
+    }
+
+    /// Get the link types of the interfaces and the packets of the enhanced
+    /// packet blocks of a capture
+    fn captured_packets(data: &[u8]) -> (Vec<u16>, Vec<(u32, Vec<u8>)>) {
+        let mut interfaces = Vec::new();
+        let mut packets = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = LittleEndian::read_u32(&data[pos + 4..]) as usize;
+            match LittleEndian::read_u32(&data[pos..]) {
+                BLOCK_IDB => interfaces.push(LittleEndian::read_u16(&data[pos + 8..])),
+                BLOCK_EPB => {
+                    let interface = LittleEndian::read_u32(&data[pos + 8..]);
+                    let captured = LittleEndian::read_u32(&data[pos + 20..]) as usize;
+                    packets.push((interface, data[pos + 28..pos + 28 + captured].to_vec()));
+                }
+                _ => {}
+            }
+            pos += len;
+        }
+        (interfaces, packets)
+    }
+
+    #[test]
+    fn test_capture_raw_packets() {
+        let specs = SerialSpecs {
+            device: "test:pcap".to_string(),
+            initial_timeout_s: 1,
+            subsequent_timeout_ms: 200,
+            nb_retry: 1,
+            linelength: 128,
+            mtu: 256,
+            baudrate: 115_200,
+        };
+        let buf = SharedBuf::default();
+        let serial = SerialTransport::new(&specs).unwrap();
+        let mut transport = PcapTransport::new(Box::new(buf.clone()), serial).unwrap();
+        let body = serde_cbor::to_vec(&EchoReq { d: "hi".to_string() }).unwrap();
+        let (header, _) = transport
+            .transceive(NmpOp::Write, NmpGroup::Default, NmpIdDef::Echo.to_u8(), &body)
+            .unwrap();
+
+        let (interfaces, packets) = captured_packets(&buf.0.lock().unwrap());
+        assert_eq!(interfaces, vec![LINKTYPE_SMP, LINKTYPE_SERIAL]);
+        let smp: Vec<&[u8]> = packets.iter().filter(|p| p.0 == 0).map(|p| &p.1[..]).collect();
+        assert_eq!(smp.len(), 2);
+        let (request, request_body) = decode_packet(smp[0]).unwrap();
+        assert_eq!((request.seq, request.len as usize), (header.seq, body.len()));
+        assert_eq!(request_body, &body[..]);
+        let (response, response_body) = decode_packet(smp[1]).unwrap();
+        assert_eq!(response.seq, header.seq);
+        assert_eq!(response.len as usize, response_body.len());
+
+        // the serial frames decode to the same packets
+        let frames: Vec<&[u8]> = packets.iter().filter(|p| p.0 == 1).map(|p| &p.1[..]).collect();
+        assert_eq!(frames.len(), 2);
+        for (frame, packet) in frames.iter().zip(&smp) {
+            assert!(frame.starts_with(&[6, 9]) && frame.ends_with(b"\n"));
+            let mut decoder = FrameDecoder::new();
+            let decoded = frame
+                .split(|&b| b == b'\n')
+                .find_map(|line| decoder.push_line(line).unwrap())
+                .unwrap();
+            assert_eq!(&decoded[..], *packet);
+        }
+    }
+}
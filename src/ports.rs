@@ -0,0 +1,192 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+use anyhow::{Context, Error, Result};
+use log::debug;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use serialport::{available_ports, SerialPortType};
+use std::env;
+
+use crate::os::echo_transport;
+use crate::transfer::{SerialSpecs, SerialTransport};
+
+/// Serial port with the USB metadata reported by the OS, if any
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PortInfo {
+    pub name: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl PortInfo {
+    /// Check if this is a USB port
+    pub fn is_usb(&self) -> bool {
+        self.vid.is_some()
+    }
+}
+
+/// Selection criteria for serial ports; unset fields match every port
+#[derive(Debug, Clone, Default)]
+pub struct PortFilter {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+}
+
+impl PortFilter {
+    /// Check if any selector is set
+    pub fn is_empty(&self) -> bool {
+        self.vid.is_none() && self.pid.is_none() && self.serial_number.is_none()
+    }
+
+    /// Check if a port matches all selectors
+    ///
+    /// The serial number comparison is case-insensitive, because some
+    /// platforms report it in a different case than the USB descriptor.
+    pub fn matches(&self, port: &PortInfo) -> bool {
+        if self.vid.is_some() && port.vid != self.vid {
+            return false;
+        }
+        if self.pid.is_some() && port.pid != self.pid {
+            return false;
+        }
+        if let Some(serial_number) = &self.serial_number {
+            match &port.serial_number {
+                Some(s) if s.eq_ignore_ascii_case(serial_number) => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// List all serial ports with their USB metadata
+///
+/// On macOS every device shows up twice, as `/dev/tty.*` and `/dev/cu.*`.
+/// Only the `cu.*` (call-up) variant is returned, because opening the
+/// `tty.*` one blocks until carrier detect is asserted.
+pub fn list_ports() -> Result<Vec<PortInfo>, Error> {
+    let ports = available_ports().context("failed to list serial ports")?;
+
+    let mut result = Vec::new();
+    for port in ports {
+        if env::consts::OS == "macos" && port.port_name.starts_with("/dev/tty.") {
+            continue;
+        }
+        let info = match port.port_type {
+            SerialPortType::UsbPort(usb) => PortInfo {
+                name: port.port_name,
+                vid: Some(usb.vid),
+                pid: Some(usb.pid),
+                serial_number: usb.serial_number,
+                manufacturer: usb.manufacturer,
+                product: usb.product,
+            },
+            _ => PortInfo {
+                name: port.port_name,
+                ..Default::default()
+            },
+        };
+        result.push(info);
+    }
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(result)
+}
+
+/// List the serial ports matching a filter
+pub fn find_ports(filter: &PortFilter) -> Result<Vec<PortInfo>, Error> {
+    Ok(list_ports()?
+        .into_iter()
+        .filter(|port| filter.matches(port))
+        .collect())
+}
+
+/// Check if a device answering SMP requests is attached to a serial port
+///
+/// Sends an echo request with a random payload using the settings from
+/// `specs` (except the device name) and checks that it is echoed back.
+pub fn probe_port(specs: &SerialSpecs, port_name: &str) -> bool {
+    let specs = SerialSpecs {
+        device: port_name.to_string(),
+        ..specs.clone()
+    };
+
+    let token = format!("probe-{:08x}", thread_rng().gen::<u32>());
+    let result = SerialTransport::new(&specs)
+        .and_then(|mut transport| echo_transport(&mut transport, &token));
+    match result {
+        Ok(response) if response == token => true,
+        Ok(response) => {
+            debug!("{}: unexpected echo response: {}", port_name, response);
+            false
+        }
+        Err(e) => {
+            debug!("{}: no SMP answer: {}", port_name, e);
+            false
+        }
+    }
+}
+
+/// Return the ports on which a device answers SMP requests
+pub fn probe_ports(specs: &SerialSpecs, ports: Vec<PortInfo>) -> Vec<PortInfo> {
+    ports
+        .into_iter()
+        .filter(|port| probe_port(specs, &port.name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usb_port(name: &str, vid: u16, pid: u16, serial_number: &str) -> PortInfo {
+        PortInfo {
+            name: name.to_string(),
+            vid: Some(vid),
+            pid: Some(pid),
+            serial_number: Some(serial_number.to_string()),
+            manufacturer: None,
+            product: None,
+        }
+    }
+
+    #[test]
+    fn test_port_filter() {
+        let nordic = usb_port("/dev/ttyACM0", 0x1915, 0x520f, "000683A1B2C3");
+        let ftdi = usb_port("/dev/ttyUSB0", 0x0403, 0x6001, "FT5XYZ");
+        let uart = PortInfo {
+            name: "/dev/ttyS0".to_string(),
+            ..Default::default()
+        };
+
+        let any = PortFilter::default();
+        assert!(any.is_empty());
+        assert!(any.matches(&nordic) && any.matches(&ftdi) && any.matches(&uart));
+
+        let by_vid = PortFilter {
+            vid: Some(0x1915),
+            ..Default::default()
+        };
+        assert!(by_vid.matches(&nordic));
+        assert!(!by_vid.matches(&ftdi));
+        assert!(!by_vid.matches(&uart));
+
+        let by_serial = PortFilter {
+            serial_number: Some("ft5xyz".to_string()),
+            ..Default::default()
+        };
+        assert!(!by_serial.matches(&nordic));
+        assert!(by_serial.matches(&ftdi));
+
+        let by_vid_pid = PortFilter {
+            vid: Some(0x0403),
+            pid: Some(0x6015),
+            serial_number: None,
+        };
+        assert!(!by_vid_pid.matches(&ftdi));
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+use anyhow::{bail, Context, Error, Result};
+use hex_buffer_serde::{Hex as _, HexForm};
+use humantime::format_rfc3339_millis;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+use crate::nmp_hdr::*;
+use crate::transfer::{transport_error, PacketTap, Transport, TransportError};
+
+/// Version of the recording file format
+const RECORDING_VERSION: u32 = 1;
+
+/// First line of a recording file, with the transport parameters the
+/// commands used to split their requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    pub version: u32,
+    pub mtu: usize,
+    pub linelength: usize,
+}
+
+/// Request as passed to `Transport::transceive`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub op: NmpOp,
+    pub group: NmpGroup,
+    pub id: u8,
+    #[serde(with = "HexForm")]
+    pub body: Vec<u8>,
+}
+
+/// Response header with the CBOR encoded response body
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub header: NmpHdr,
+    #[serde(with = "HexForm")]
+    pub body: Vec<u8>,
+}
+
+/// One request/response exchange
+///
+/// Failed exchanges, e.g. timeouts, are recorded with the error message
+/// instead of a response, so a replay fails at the same point. Lost or damaged
+/// frames also record their kind, so a replay retries them like the original
+/// session did.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    pub timestamp: String,
+    pub rtt_us: u64,
+    pub request: RecordedRequest,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<RecordedResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<TransportError>,
+}
+
+/// Transport wrapper writing every exchange to a JSON lines file
+pub struct RecordingTransport<T: Transport> {
+    inner: T,
+    writer: Box<dyn Write>,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    /// Create the recording file and start recording
+    pub fn create(path: &Path, inner: T) -> Result<Self, Error> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create recording {}", path.display()))?;
+        info!("recording exchanges to {}", path.display());
+        Self::new(Box::new(BufWriter::new(file)), inner)
+    }
+
+    /// Start recording to a writer
+    pub fn new(mut writer: Box<dyn Write>, inner: T) -> Result<Self, Error> {
+        let header = RecordingHeader {
+            version: RECORDING_VERSION,
+            mtu: inner.mtu(),
+            linelength: inner.linelength(),
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(RecordingTransport { inner, writer })
+    }
+
+    /// Get the wrapped transport
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn transceive(
+        &mut self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        let timestamp = format_rfc3339_millis(SystemTime::now()).to_string();
+        let start = Instant::now();
+        let result = self.inner.transceive(op, group, id, body);
+        let rtt_us = start.elapsed().as_micros() as u64;
+
+        let mut exchange = Exchange {
+            timestamp,
+            rtt_us,
+            request: RecordedRequest {
+                op,
+                group,
+                id,
+                body: body.to_vec(),
+            },
+            response: None,
+            error: None,
+            error_kind: None,
+        };
+        match &result {
+            Ok((header, value)) => {
+                exchange.response = Some(RecordedResponse {
+                    header: *header,
+                    body: serde_cbor::to_vec(value)?,
+                });
+            }
+            Err(e) => {
+                exchange.error = Some(e.to_string());
+                exchange.error_kind = transport_error(e);
+            }
+        }
+
+        // flush after every exchange, so the recording is complete up to
+        // the failing request if the tool is interrupted
+        serde_json::to_writer(&mut self.writer, &exchange)?;
+        writeln!(self.writer)?;
+        self.writer.flush()?;
+
+        result
+    }
+
+    fn set_timeout(&mut self, timeout_ms: u32) -> Result<(), Error> {
+        self.inner.set_timeout(timeout_ms)
+    }
+
+    fn timeout(&self) -> Option<u32> {
+        self.inner.timeout()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn linelength(&self) -> usize {
+        self.inner.linelength()
+    }
+
+    fn set_tap(&mut self, tap: PacketTap) -> bool {
+        self.inner.set_tap(tap)
+    }
+
+    fn set_frame_tap(&mut self, tap: PacketTap) -> bool {
+        self.inner.set_frame_tap(tap)
+    }
+}
+
+/// Transport serving recorded responses in order
+///
+/// Every request must match the recorded one in op, group and command id.
+/// The request bodies are compared too, but a difference is only a warning
+/// unless strict mode is enabled, because some requests contain random data
+/// (e.g. probe echoes).
+pub struct ReplayTransport {
+    header: RecordingHeader,
+    exchanges: VecDeque<Exchange>,
+    strict: bool,
+}
+
+impl ReplayTransport {
+    /// Load a recording file
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open recording {}", path.display()))?;
+        let replay = Self::from_reader(BufReader::new(file))
+            .with_context(|| format!("invalid recording {}", path.display()))?;
+        info!(
+            "replaying {} exchanges from {}",
+            replay.remaining(),
+            path.display()
+        );
+        Ok(replay)
+    }
+
+    /// Load a recording from a reader
+    pub fn from_reader(reader: impl BufRead) -> Result<Self, Error> {
+        let mut lines = reader.lines();
+        let header: RecordingHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => bail!("empty recording"),
+        };
+        if header.version != RECORDING_VERSION {
+            bail!("unsupported recording version {}", header.version);
+        }
+
+        let mut exchanges = VecDeque::new();
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let exchange: Exchange = serde_json::from_str(&line)
+                .with_context(|| format!("line {}", i + 2))?;
+            exchanges.push_back(exchange);
+        }
+
+        Ok(ReplayTransport {
+            header,
+            exchanges,
+            strict: false,
+        })
+    }
+
+    /// Fail on request body differences instead of warning
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Number of exchanges not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.exchanges.len()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn transceive(
+        &mut self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        let exchange = match self.exchanges.pop_front() {
+            Some(exchange) => exchange,
+            None => bail!("replay: no more recorded exchanges"),
+        };
+
+        let request = &exchange.request;
+        if request.op != op || request.group != group || request.id != id {
+            bail!(
+                "replay: request {:?} {:?} id {} does not match recorded {:?} {:?} id {}",
+                op,
+                group,
+                id,
+                request.op,
+                request.group,
+                request.id
+            );
+        }
+        if request.body != body {
+            if self.strict {
+                bail!(
+                    "replay: request body {} does not match recorded {}",
+                    hex::encode(body),
+                    hex::encode(&request.body)
+                );
+            }
+            warn!("replay: request body differs from the recording");
+        }
+
+        if let Some(error) = exchange.error {
+            return Err(match exchange.error_kind {
+                Some(kind) => Error::new(kind).context(error),
+                None => Error::msg(error),
+            });
+        }
+        let response = match exchange.response {
+            Some(response) => response,
+            None => bail!("replay: recorded exchange has no response"),
+        };
+        let body = serde_cbor::from_slice(&response.body)
+            .context("replay: invalid recorded CBOR body")?;
+
+        Ok((response.header, body))
+    }
+
+    fn set_timeout(&mut self, _timeout_ms: u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        self.header.mtu
+    }
+
+    fn linelength(&self) -> usize {
+        self.header.linelength
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{list_transport, upload_image_transport};
+    use crate::simulator::{build_image, Simulator, UdpServer};
+    use crate::transfer::{UdpSpecs, UdpTransport};
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    /// Writer appending to a shared buffer, so the test can read it back
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let image = std::env::temp_dir().join(format!("mcumgr-record-{}.bin", std::process::id()));
+        std::fs::write(&image, build_image((1, 0, 1, 0), &[0x5a; 1000])).unwrap();
+
+        // record a session against a simulated device
+        let server = UdpServer::spawn(Arc::new(Mutex::new(Simulator::default()))).unwrap();
+        let udp = UdpTransport::new(&UdpSpecs {
+            host: "127.0.0.1".to_string(),
+            port: server.addr().port(),
+            mtu: 256,
+            ..Default::default()
+        })
+        .unwrap();
+        let buf = SharedBuf::default();
+        let mut recorder = RecordingTransport::new(Box::new(buf.clone()), udp).unwrap();
+        let recorded_list = list_transport(&mut recorder).unwrap();
+        upload_image_transport(&mut recorder, &image, &Default::default(), None::<fn(u64, u64)>).unwrap();
+
+        // replay it strictly without a device
+        let data = buf.0.lock().unwrap().clone();
+        let mut replay = ReplayTransport::from_reader(Cursor::new(data))
+            .unwrap()
+            .strict(true);
+        assert_eq!(replay.mtu(), 256);
+        let replayed_list = list_transport(&mut replay).unwrap();
+        assert_eq!(
+            serde_json::to_string(&replayed_list).unwrap(),
+            serde_json::to_string(&recorded_list).unwrap()
+        );
+        upload_image_transport(&mut replay, &image, &Default::default(), None::<fn(u64, u64)>).unwrap();
+        assert_eq!(replay.remaining(), 0);
+
+        // a different command does not match the recording
+        assert!(list_transport(&mut replay).is_err());
+
+        std::fs::remove_file(&image).unwrap();
+    }
+}
@@ -1,9 +1,10 @@
 // Copyright © 2026 Rudis Laboratories LLC
 
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, Context, Error, Result};
 use log::{debug, info};
 
 use crate::nmp_hdr::*;
+use crate::settings_value::{SettingCodec, SettingValue};
 use crate::transfer::encode_request;
 use crate::transfer::next_seq_id;
 use crate::transfer::open_port;
@@ -127,6 +128,27 @@ pub fn settings_write(specs: &SerialSpecs, name: &str, value: Vec<u8>) -> Result
     Ok(())
 }
 
+/// Read a settings value from the device and decode it
+pub fn settings_read_as(
+    specs: &SerialSpecs,
+    name: &str,
+    codec: &SettingCodec,
+) -> Result<SettingValue, Error> {
+    let rsp = settings_read(specs, name, None)?;
+    codec.decode(&rsp.val).with_context(|| format!("setting {name}"))
+}
+
+/// Encode a settings value and write it to the device
+pub fn settings_write_as(
+    specs: &SerialSpecs,
+    name: &str,
+    codec: &SettingCodec,
+    value: &SettingValue,
+) -> Result<(), Error> {
+    let data = codec.encode(value).with_context(|| format!("setting {name}"))?;
+    settings_write(specs, name, data)
+}
+
 /// Delete a settings value from the device
 pub fn settings_delete(specs: &SerialSpecs, name: &str) -> Result<(), Error> {
     info!("delete setting: {}", name);
@@ -140,9 +162,9 @@ pub fn settings_delete(specs: &SerialSpecs, name: &str) -> Result<(), Error> {
 
     let (data, request_header) = encode_request(
         specs.linelength,
-        NmpOp::Write,  // Delete uses Write op
+        NmpOp::Write,
         NmpGroup::Config,
-        NmpIdConfig::Val,
+        NmpIdConfig::Delete,
         &body,
         next_seq_id(),
     )?;
@@ -179,7 +201,7 @@ pub fn settings_commit(specs: &SerialSpecs) -> Result<(), Error> {
         specs.linelength,
         NmpOp::Write,
         NmpGroup::Config,
-        NmpIdConfig::Val,
+        NmpIdConfig::Commit,
         &body,
         next_seq_id(),
     )?;
@@ -216,7 +238,7 @@ pub fn settings_load(specs: &SerialSpecs) -> Result<(), Error> {
         specs.linelength,
         NmpOp::Read,
         NmpGroup::Config,
-        NmpIdConfig::Val,
+        NmpIdConfig::LoadSave,
         &body,
         next_seq_id(),
     )?;
@@ -253,7 +275,7 @@ pub fn settings_save(specs: &SerialSpecs) -> Result<(), Error> {
         specs.linelength,
         NmpOp::Write,
         NmpGroup::Config,
-        NmpIdConfig::Val,
+        NmpIdConfig::LoadSave,
         &body,
         next_seq_id(),
     )?;
@@ -283,6 +305,19 @@ pub fn settings_save(specs: &SerialSpecs) -> Result<(), Error> {
 pub fn settings_read_transport(transport: &mut dyn Transport, name: &str, max_size: Option<u32>) -> Result<SettingsReadRsp, Error> {
     info!("read setting: {}", name);
 
+    settings_read_optional_transport(transport, name, max_size)?
+        .ok_or_else(|| anyhow::format_err!("Error from device: rc={}", NmpErr::ENoEnt as i32))
+}
+
+/// Read a settings value using a transport, `None` if the device doesn't
+/// have it
+pub(crate) fn settings_read_optional_transport(
+    transport: &mut dyn Transport,
+    name: &str,
+    max_size: Option<u32>,
+) -> Result<Option<SettingsReadRsp>, Error> {
+    debug!("read setting: {}", name);
+
     let req = SettingsReadReq {
         name: name.to_string(),
         max_size,
@@ -301,11 +336,11 @@ pub fn settings_read_transport(transport: &mut dyn Transport, name: &str, max_si
     let rsp: SettingsReadRsp = serde_cbor::value::from_value(response_body)
         .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
 
-    if rsp.rc != 0 {
-        bail!("Error from device: rc={}", rsp.rc);
+    match rsp.rc {
+        0 => Ok(Some(rsp)),
+        rc if rc == NmpErr::ENoEnt as i32 => Ok(None),
+        rc => bail!("Error from device: rc={}", rc),
     }
-
-    Ok(rsp)
 }
 
 /// Write a settings value using a transport
@@ -338,6 +373,28 @@ pub fn settings_write_transport(transport: &mut dyn Transport, name: &str, value
     Ok(())
 }
 
+/// Read and decode a settings value using a transport, see `settings_read_as`
+pub fn settings_read_as_transport(
+    transport: &mut dyn Transport,
+    name: &str,
+    codec: &SettingCodec,
+) -> Result<SettingValue, Error> {
+    let rsp = settings_read_transport(transport, name, None)?;
+    codec.decode(&rsp.val).with_context(|| format!("setting {name}"))
+}
+
+/// Encode and write a settings value using a transport, see
+/// `settings_write_as`
+pub fn settings_write_as_transport(
+    transport: &mut dyn Transport,
+    name: &str,
+    codec: &SettingCodec,
+    value: &SettingValue,
+) -> Result<(), Error> {
+    let data = codec.encode(value).with_context(|| format!("setting {name}"))?;
+    settings_write_transport(transport, name, data)
+}
+
 /// Delete a settings value using a transport
 pub fn settings_delete_transport(transport: &mut dyn Transport, name: &str) -> Result<(), Error> {
     info!("delete setting: {}", name);
@@ -350,7 +407,7 @@ pub fn settings_delete_transport(transport: &mut dyn Transport, name: &str) -> R
     let (_response_header, response_body) = transport.transceive(
         NmpOp::Write,
         NmpGroup::Config,
-        NmpIdConfig::Val.to_u8(),
+        NmpIdConfig::Delete.to_u8(),
         &body,
     )?;
 
@@ -377,7 +434,7 @@ pub fn settings_commit_transport(transport: &mut dyn Transport) -> Result<(), Er
     let (_response_header, response_body) = transport.transceive(
         NmpOp::Write,
         NmpGroup::Config,
-        NmpIdConfig::Val.to_u8(),
+        NmpIdConfig::Commit.to_u8(),
         &body,
     )?;
 
@@ -404,7 +461,7 @@ pub fn settings_load_transport(transport: &mut dyn Transport) -> Result<(), Erro
     let (_response_header, response_body) = transport.transceive(
         NmpOp::Read,
         NmpGroup::Config,
-        NmpIdConfig::Val.to_u8(),
+        NmpIdConfig::LoadSave.to_u8(),
         &body,
     )?;
 
@@ -431,7 +488,7 @@ pub fn settings_save_transport(transport: &mut dyn Transport) -> Result<(), Erro
     let (_response_header, response_body) = transport.transceive(
         NmpOp::Write,
         NmpGroup::Config,
-        NmpIdConfig::Val.to_u8(),
+        NmpIdConfig::LoadSave.to_u8(),
         &body,
     )?;
 
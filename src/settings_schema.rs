@@ -0,0 +1,595 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Settings schema and bulk apply
+//!
+//! A schema describes the settings of a device in TOML, or JSON with the
+//! same layout: the key, the type as named by `SettingCodec`, and an
+//! optional default, allowed range and description.
+//!
+//! ```toml
+//! [[settings]]
+//! key = "app/interval"
+//! type = "u32"
+//! default = 1000
+//! min = 100
+//! max = 60000
+//! description = "Sampling interval in ms"
+//!
+//! [[settings]]
+//! key = "app/name"
+//! type = "string"
+//! ```
+//!
+//! A values file sets some of them with a table of keys, which may be
+//! nested: `app = { interval = 500 }` sets `app/interval`. Integers can
+//! also be given as strings, like `"0x1f4"`, and bytes as hex strings.
+
+use anyhow::{bail, Context, Error, Result};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt;
+use std::path::Path;
+
+use crate::settings::{
+    settings_commit_transport, settings_delete_transport, settings_read_optional_transport,
+    settings_save_transport, settings_write_transport,
+};
+use crate::settings_value::{SettingCodec, SettingValue};
+use crate::transfer::{SerialSpecs, SerialTransport, Transport};
+
+/// Size requested when reading the values to restore, a value this long may
+/// have been truncated
+const RESTORE_MAX_SIZE: u32 = 256;
+
+/// Schema file, as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SchemaFile {
+    #[serde(default)]
+    settings: Vec<SchemaEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SchemaEntry {
+    key: String,
+    #[serde(rename = "type")]
+    setting_type: String,
+    default: Option<serde_json::Value>,
+    min: Option<serde_json::Value>,
+    max: Option<serde_json::Value>,
+    description: Option<String>,
+}
+
+/// Setting described by a schema
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingSchema {
+    pub key: String,
+    pub codec: SettingCodec,
+    pub default: Option<SettingValue>,
+    /// Smallest allowed value of an integer
+    pub min: Option<SettingValue>,
+    /// Largest allowed value of an integer
+    pub max: Option<SettingValue>,
+    pub description: Option<String>,
+}
+
+impl SettingSchema {
+    fn from_entry(entry: SchemaEntry) -> Result<SettingSchema, Error> {
+        let codec: SettingCodec = entry.setting_type.parse()?;
+        let value = |value: Option<serde_json::Value>| {
+            value.map(|value| parse_value(&codec, &value)).transpose()
+        };
+        let setting = SettingSchema {
+            min: value(entry.min).context("invalid min")?,
+            max: value(entry.max).context("invalid max")?,
+            default: value(entry.default).context("invalid default")?,
+            key: entry.key,
+            codec,
+            description: entry.description,
+        };
+        if (setting.min.is_some() || setting.max.is_some()) && !codec.setting_type.is_integer() {
+            bail!("only integers have a range, not a {}", codec);
+        }
+        if let (Some(min), Some(max)) = (setting.min_integer(), setting.max_integer()) {
+            if min > max {
+                bail!("the range {} is empty", setting.range());
+            }
+        }
+        if let Some(default) = &setting.default {
+            setting.encode(default).context("invalid default")?;
+        }
+        Ok(setting)
+    }
+
+    /// Check that a value has the type of the setting and is in its range,
+    /// and encode it as stored on the device
+    pub fn encode(&self, value: &SettingValue) -> Result<Vec<u8>, Error> {
+        let data = self.codec.encode(value)?;
+        if !self.in_range(value) {
+            bail!("{} is out of the range {}", value, self.range());
+        }
+        Ok(data)
+    }
+
+    /// Whether a value is in the allowed range, always true for non-integers
+    pub fn in_range(&self, value: &SettingValue) -> bool {
+        let Some(value) = integer(value) else {
+            return true;
+        };
+        self.min_integer().is_none_or(|min| value >= min)
+            && self.max_integer().is_none_or(|max| value <= max)
+    }
+
+    fn min_integer(&self) -> Option<i128> {
+        self.min.as_ref().and_then(integer)
+    }
+
+    fn max_integer(&self) -> Option<i128> {
+        self.max.as_ref().and_then(integer)
+    }
+
+    /// Allowed range, like `100..=60000`
+    fn range(&self) -> String {
+        let bound = |value: &Option<SettingValue>| {
+            value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+        };
+        match &self.max {
+            Some(_) => format!("{}..={}", bound(&self.min), bound(&self.max)),
+            None => format!("{}..", bound(&self.min)),
+        }
+    }
+}
+
+/// Settings of a device, see the module documentation for the file format
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SettingsSchema {
+    pub settings: Vec<SettingSchema>,
+}
+
+impl SettingsSchema {
+    /// Load a schema from a TOML file, or a JSON file if the name doesn't
+    /// end in `.toml`
+    pub fn load(path: &Path) -> Result<SettingsSchema, Error> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read settings schema {}", path.display()))?;
+        parse_document(path, &data)
+            .and_then(SettingsSchema::from_file)
+            .with_context(|| format!("invalid settings schema {}", path.display()))
+    }
+
+    /// Parse a TOML schema
+    pub fn from_toml(text: &str) -> Result<SettingsSchema, Error> {
+        SettingsSchema::from_file(toml::from_str(text)?)
+    }
+
+    /// Parse a JSON schema
+    pub fn from_json(text: &str) -> Result<SettingsSchema, Error> {
+        SettingsSchema::from_file(serde_json::from_str(text)?)
+    }
+
+    fn from_file(file: SchemaFile) -> Result<SettingsSchema, Error> {
+        let mut schema = SettingsSchema::default();
+        for entry in file.settings {
+            let key = entry.key.clone();
+            if schema.get(&key).is_some() {
+                bail!("setting {key} is described twice");
+            }
+            let setting =
+                SettingSchema::from_entry(entry).with_context(|| format!("setting {key}"))?;
+            schema.settings.push(setting);
+        }
+        Ok(schema)
+    }
+
+    /// Get the description of a setting
+    pub fn get(&self, key: &str) -> Option<&SettingSchema> {
+        self.settings.iter().find(|setting| setting.key == key)
+    }
+
+    /// Load the values to write from a TOML or JSON file, checked with the
+    /// schema
+    ///
+    /// With `defaults`, the settings that have a default but no value in the
+    /// file, or all of them if there is no file, are set to their default.
+    pub fn load_values(
+        &self,
+        path: Option<&Path>,
+        defaults: bool,
+    ) -> Result<Vec<SettingUpdate>, Error> {
+        let table = match path {
+            Some(path) => {
+                let data = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read settings {}", path.display()))?;
+                parse_document(path, &data)
+                    .with_context(|| format!("invalid settings {}", path.display()))?
+            }
+            None => serde_json::Value::Object(Default::default()),
+        };
+        self.values(&table, defaults)
+    }
+
+    /// Check the values of a table with the schema, in the order of the
+    /// schema
+    fn values(
+        &self,
+        table: &serde_json::Value,
+        defaults: bool,
+    ) -> Result<Vec<SettingUpdate>, Error> {
+        let mut values = Vec::new();
+        flatten("", table, &mut values)?;
+        if let Some((key, _)) = values.iter().find(|(key, _)| self.get(key).is_none()) {
+            bail!("unknown setting {key}, it's not in the schema");
+        }
+
+        let mut updates = Vec::new();
+        for setting in &self.settings {
+            let value = match values.iter().find(|(key, _)| *key == setting.key) {
+                Some((_, value)) => parse_value(&setting.codec, value)
+                    .with_context(|| format!("setting {}", setting.key))?,
+                None => match &setting.default {
+                    Some(default) if defaults => default.clone(),
+                    _ => continue,
+                },
+            };
+            let data = setting.encode(&value).with_context(|| format!("setting {}", setting.key))?;
+            updates.push(SettingUpdate {
+                key: setting.key.clone(),
+                value,
+                data,
+            });
+        }
+        Ok(updates)
+    }
+}
+
+/// Checked value to write to a setting
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingUpdate {
+    pub key: String,
+    pub value: SettingValue,
+    /// Value as stored on the device
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for SettingUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} = {}", self.key, self.value)
+    }
+}
+
+/// State of a setting on the device
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingState {
+    Value(SettingValue),
+    /// The device doesn't have the setting
+    Missing,
+    /// The stored value can't be decoded with the type of the schema
+    Invalid(String),
+}
+
+/// Setting of a schema read from the device, see `settings_dump`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingReport {
+    pub schema: SettingSchema,
+    pub state: SettingState,
+    /// Value as stored on the device
+    pub data: Option<Vec<u8>>,
+}
+
+impl SettingReport {
+    /// The report as a JSON object, with bytes as hex
+    pub fn to_json(&self) -> serde_json::Value {
+        let schema = &self.schema;
+        let (value, in_range, error) = match &self.state {
+            SettingState::Value(value) => {
+                (Some(json_value(value)), Some(schema.in_range(value)), None)
+            }
+            SettingState::Missing => (None, None, None),
+            SettingState::Invalid(error) => (None, None, Some(error.as_str())),
+        };
+        json!({
+            "key": schema.key,
+            "type": schema.codec.to_string(),
+            "value": value,
+            "data": self.data.as_ref().map(hex::encode),
+            "default": schema.default.as_ref().map(json_value),
+            "in_range": in_range,
+            "error": error,
+            "description": schema.description,
+        })
+    }
+}
+
+impl fmt::Display for SettingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let schema = &self.schema;
+        match &self.state {
+            SettingState::Value(value) => {
+                write!(f, "{} = {} ({}", schema.key, value, schema.codec)?;
+                if schema.default.as_ref() == Some(value) {
+                    f.write_str(", default")?;
+                }
+                if !schema.in_range(value) {
+                    write!(f, ", out of the range {}", schema.range())?;
+                }
+            }
+            SettingState::Missing => {
+                write!(f, "{} not set ({}", schema.key, schema.codec)?;
+                if let Some(default) = &schema.default {
+                    write!(f, ", default {default}")?;
+                }
+            }
+            SettingState::Invalid(error) => {
+                write!(f, "{} invalid ({}, {}", schema.key, schema.codec, error)?;
+            }
+        }
+        f.write_str(")")
+    }
+}
+
+/// Write settings values, then save and commit them
+///
+/// The current values are read first. If a write, the save or the commit
+/// fails, they are written back and the settings that didn't exist are
+/// deleted, so the device is left as it was. Nothing is written when a
+/// current value is too long to be read back whole.
+pub fn settings_apply(specs: &SerialSpecs, updates: &[SettingUpdate]) -> Result<(), Error> {
+    let mut transport = SerialTransport::new(specs)?;
+    settings_apply_transport(&mut transport, updates)
+}
+
+/// Write, save and commit settings values using a transport, see
+/// `settings_apply`
+pub fn settings_apply_transport(
+    transport: &mut dyn Transport,
+    updates: &[SettingUpdate],
+) -> Result<(), Error> {
+    info!("apply {} settings", updates.len());
+
+    let mut previous = Vec::new();
+    for update in updates {
+        let value = settings_read_optional_transport(transport, &update.key, Some(RESTORE_MAX_SIZE))
+            .with_context(|| format!("failed to read {}", update.key))?
+            .map(|rsp| rsp.val);
+        if value.as_ref().is_some_and(|v| v.len() >= RESTORE_MAX_SIZE as usize) {
+            bail!(
+                "{}: the current value may be longer than {} bytes, it couldn't be restored",
+                update.key,
+                RESTORE_MAX_SIZE
+            );
+        }
+        previous.push(value);
+    }
+
+    let mut written = 0;
+    let mut saving = false;
+    let result = updates
+        .iter()
+        .try_for_each(|update| {
+            // a write that timed out may have happened, so it's restored too
+            written += 1;
+            settings_write_transport(transport, &update.key, update.data.clone())
+                .with_context(|| format!("failed to write {}", update.key))
+        })
+        .and_then(|()| {
+            saving = true;
+            settings_save_transport(transport).context("failed to save the settings")?;
+            settings_commit_transport(transport).context("failed to commit the settings")
+        });
+    let Err(err) = result else {
+        info!("settings applied successfully");
+        return Ok(());
+    };
+
+    warn!("{:#}, restoring the previous values", err);
+    match restore(transport, &updates[..written], &previous, saving) {
+        Ok(()) => bail!("{:#}; the previous values were restored", err),
+        Err(restore_err) => {
+            bail!("{:#}; restoring the previous values failed: {:#}", err, restore_err)
+        }
+    }
+}
+
+/// Write back the values read before applying settings, deleting the
+/// settings that didn't exist, and save them again if the new ones may have
+/// been saved
+fn restore(
+    transport: &mut dyn Transport,
+    updates: &[SettingUpdate],
+    previous: &[Option<Vec<u8>>],
+    save: bool,
+) -> Result<(), Error> {
+    let mut failed = Vec::new();
+    for (update, value) in updates.iter().zip(previous).rev() {
+        let result = match value {
+            Some(data) => settings_write_transport(transport, &update.key, data.clone()),
+            None => settings_delete_transport(transport, &update.key),
+        };
+        if let Err(err) = result {
+            warn!("failed to restore {}: {:#}", update.key, err);
+            failed.push(update.key.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        bail!("failed to restore {}", failed.join(", "));
+    }
+    if save {
+        settings_save_transport(transport)?;
+        settings_commit_transport(transport)?;
+    }
+    Ok(())
+}
+
+/// Read and decode all the settings of a schema
+///
+/// A setting the device doesn't have, or whose value doesn't decode with its
+/// type, is reported as such instead of failing.
+pub fn settings_dump(
+    specs: &SerialSpecs,
+    schema: &SettingsSchema,
+) -> Result<Vec<SettingReport>, Error> {
+    let mut transport = SerialTransport::new(specs)?;
+    settings_dump_transport(&mut transport, schema)
+}
+
+/// Read and decode all the settings of a schema using a transport, see
+/// `settings_dump`
+pub fn settings_dump_transport(
+    transport: &mut dyn Transport,
+    schema: &SettingsSchema,
+) -> Result<Vec<SettingReport>, Error> {
+    let mut reports = Vec::new();
+    for setting in &schema.settings {
+        let data = settings_read_optional_transport(transport, &setting.key, None)
+            .with_context(|| format!("failed to read {}", setting.key))?
+            .map(|rsp| rsp.val);
+        let state = match &data {
+            None => SettingState::Missing,
+            Some(data) => match setting.codec.decode(data) {
+                Ok(value) => SettingState::Value(value),
+                Err(err) => SettingState::Invalid(format!("{err:#}")),
+            },
+        };
+        reports.push(SettingReport {
+            schema: setting.clone(),
+            state,
+            data,
+        });
+    }
+    Ok(reports)
+}
+
+/// Parse a TOML file, or JSON if the name doesn't end in `.toml`
+fn parse_document<T: DeserializeOwned>(path: &Path, data: &str) -> Result<T, Error> {
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        Ok(toml::from_str(data)?)
+    } else {
+        Ok(serde_json::from_str(data)?)
+    }
+}
+
+/// Collect the values of a table, joining the keys of nested tables with `/`
+fn flatten<'a>(
+    prefix: &str,
+    table: &'a serde_json::Value,
+    values: &mut Vec<(String, &'a serde_json::Value)>,
+) -> Result<(), Error> {
+    let serde_json::Value::Object(table) = table else {
+        bail!("the settings must be a table of keys");
+    };
+    for (key, value) in table {
+        let key = format!("{prefix}{key}");
+        match value {
+            serde_json::Value::Object(_) => flatten(&format!("{key}/"), value, values)?,
+            _ => values.push((key, value)),
+        }
+    }
+    Ok(())
+}
+
+/// Convert a value of a schema or values file to a value of a codec
+fn parse_value(codec: &SettingCodec, value: &serde_json::Value) -> Result<SettingValue, Error> {
+    let value = match value {
+        serde_json::Value::String(text) => return codec.parse(text),
+        serde_json::Value::Bool(value) => SettingValue::Bool(*value),
+        serde_json::Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(value), _) => SettingValue::Unsigned(value),
+            (None, Some(value)) => SettingValue::Signed(value),
+            _ => bail!("{number} is not an integer"),
+        },
+        value => bail!("{value} is not a setting value"),
+    };
+    // a round trip makes the integers signed or not like the codec
+    codec.decode(&codec.encode(&value)?)
+}
+
+/// Value of an integer, none for other values
+fn integer(value: &SettingValue) -> Option<i128> {
+    match value {
+        SettingValue::Unsigned(value) => Some(*value as i128),
+        SettingValue::Signed(value) => Some(*value as i128),
+        _ => None,
+    }
+}
+
+fn json_value(value: &SettingValue) -> serde_json::Value {
+    match value {
+        SettingValue::Unsigned(value) => json!(value),
+        SettingValue::Signed(value) => json!(value),
+        SettingValue::Bool(value) => json!(value),
+        SettingValue::String(value) => json!(value),
+        SettingValue::Bytes(value) => json!(hex::encode(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"
+        [[settings]]
+        key = "app/interval"
+        type = "u32"
+        default = 1000
+        min = 100
+        max = 60000
+        description = "Sampling interval in ms"
+
+        [[settings]]
+        key = "app/offset"
+        type = "i16be"
+        min = -500
+
+        [[settings]]
+        key = "app/name"
+        type = "string"
+        default = "sensor"
+    "#;
+
+    #[test]
+    fn test_schema() {
+        let schema = SettingsSchema::from_toml(SCHEMA).unwrap();
+        let interval = schema.get("app/interval").unwrap();
+        assert_eq!(interval.default, Some(SettingValue::Unsigned(1000)));
+        assert_eq!(interval.range(), "100..=60000");
+        assert_eq!(schema.get("app/offset").unwrap().range(), "-500..");
+        assert!(interval.encode(&SettingValue::Unsigned(99)).is_err());
+
+        let json = r#"{ "settings": [ { "key": "a", "type": "i8", "default": -3 } ] }"#;
+        let default = &SettingsSchema::from_json(json).unwrap().settings[0].default;
+        assert_eq!(*default, Some(SettingValue::Signed(-3)));
+
+        for invalid in [
+            r#"{ "settings": [ { "key": "a", "type": "u8", "default": 256 } ] }"#,
+            r#"{ "settings": [ { "key": "a", "type": "u8", "min": 5, "max": 4 } ] }"#,
+            r#"{ "settings": [ { "key": "a", "type": "u8", "default": 9, "max": 4 } ] }"#,
+            r#"{ "settings": [ { "key": "a", "type": "string", "max": 4 } ] }"#,
+            r#"{ "settings": [ { "key": "a", "type": "u8" }, { "key": "a", "type": "u8" } ] }"#,
+            r#"{ "settings": [ { "key": "a", "type": "u8", "unit": "ms" } ] }"#,
+        ] {
+            assert!(SettingsSchema::from_json(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_values() {
+        let schema = SettingsSchema::from_toml(SCHEMA).unwrap();
+        let table = json!({ "app": { "offset": -2, "interval": "0x1f4" } });
+        let updates = schema.values(&table, false).unwrap();
+        let updates: Vec<_> = updates.iter().map(|u| u.to_string()).collect();
+        assert_eq!(updates, ["app/interval = 500", "app/offset = -2"]);
+
+        let updates = schema.values(&json!({ "app/offset": 7 }), true).unwrap();
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[1].data, [0, 7]);
+
+        assert!(schema.values(&json!({ "app/interval": 60001 }), false).is_err());
+        assert!(schema.values(&json!({ "app/offset": -501 }), false).is_err());
+        assert!(schema.values(&json!({ "app/name": 5 }), false).is_err());
+        let err = schema.values(&json!({ "app/other": 5 }), false).unwrap_err();
+        assert_eq!(err.to_string(), "unknown setting app/other, it's not in the schema");
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Typed settings values
+//!
+//! The settings group stores raw bytes. A `SettingCodec` encodes and decodes
+//! them as integers of a size and byte order, a bool, a UTF-8 string or raw
+//! bytes. Codecs are named like `u32`, `i16be` or `string`.
+
+use anyhow::{bail, Context, Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Type of a setting value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingType {
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    /// One byte, 0 or 1
+    Bool,
+    /// UTF-8 text; trailing NULs are dropped when decoding
+    String,
+    Bytes,
+}
+
+impl SettingType {
+    /// Size in bytes of an integer or bool, none for strings and bytes
+    pub fn size(self) -> Option<usize> {
+        match self {
+            SettingType::U8 | SettingType::I8 | SettingType::Bool => Some(1),
+            SettingType::U16 | SettingType::I16 => Some(2),
+            SettingType::U32 | SettingType::I32 => Some(4),
+            SettingType::U64 | SettingType::I64 => Some(8),
+            SettingType::String | SettingType::Bytes => None,
+        }
+    }
+
+    pub fn is_integer(self) -> bool {
+        !matches!(self, SettingType::Bool | SettingType::String | SettingType::Bytes)
+    }
+
+    fn is_signed(self) -> bool {
+        matches!(
+            self,
+            SettingType::I8 | SettingType::I16 | SettingType::I32 | SettingType::I64
+        )
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SettingType::U8 => "u8",
+            SettingType::U16 => "u16",
+            SettingType::U32 => "u32",
+            SettingType::U64 => "u64",
+            SettingType::I8 => "i8",
+            SettingType::I16 => "i16",
+            SettingType::I32 => "i32",
+            SettingType::I64 => "i64",
+            SettingType::Bool => "bool",
+            SettingType::String => "string",
+            SettingType::Bytes => "bytes",
+        }
+    }
+}
+
+/// Byte order of integers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Decoded setting value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingValue {
+    Unsigned(u64),
+    Signed(i64),
+    Bool(bool),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl fmt::Display for SettingValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingValue::Unsigned(value) => write!(f, "{value}"),
+            SettingValue::Signed(value) => write!(f, "{value}"),
+            SettingValue::Bool(value) => write!(f, "{value}"),
+            SettingValue::String(value) => f.write_str(value),
+            SettingValue::Bytes(value) => f.write_str(&hex::encode(value)),
+        }
+    }
+}
+
+/// How a setting value is stored: its type and, for integers, byte order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SettingCodec {
+    pub setting_type: SettingType,
+    pub endian: Endian,
+}
+
+impl SettingCodec {
+    pub fn new(setting_type: SettingType, endian: Endian) -> SettingCodec {
+        SettingCodec {
+            setting_type,
+            endian,
+        }
+    }
+
+    /// Parse a value from text: decimal or `0x` hex integers, `true`/`false`
+    /// or `1`/`0`, text as is, bytes as hex
+    pub fn parse(&self, text: &str) -> Result<SettingValue, Error> {
+        let invalid = || format!("invalid {} value {:?}", self.setting_type.name(), text);
+        let value = match self.setting_type {
+            t if t.is_signed() => {
+                let (negative, digits) = match text.strip_prefix('-') {
+                    Some(digits) => (true, digits),
+                    None => (false, text),
+                };
+                let magnitude = parse_unsigned(digits).with_context(invalid)?;
+                let value = if negative {
+                    0i64.checked_sub_unsigned(magnitude)
+                } else {
+                    i64::try_from(magnitude).ok()
+                };
+                SettingValue::Signed(value.with_context(invalid)?)
+            }
+            t if t.is_integer() => {
+                SettingValue::Unsigned(parse_unsigned(text).with_context(invalid)?)
+            }
+            SettingType::Bool => match text {
+                "true" | "1" => SettingValue::Bool(true),
+                "false" | "0" => SettingValue::Bool(false),
+                _ => bail!(invalid()),
+            },
+            SettingType::String => SettingValue::String(text.to_string()),
+            _ => SettingValue::Bytes(hex::decode(text).with_context(invalid)?),
+        };
+        self.check(&value)?;
+        Ok(value)
+    }
+
+    /// Encode a value as stored on the device
+    pub fn encode(&self, value: &SettingValue) -> Result<Vec<u8>, Error> {
+        self.check(value)?;
+        let bits = match value {
+            SettingValue::Unsigned(value) => *value,
+            SettingValue::Signed(value) => *value as u64,
+            SettingValue::Bool(value) => *value as u64,
+            SettingValue::String(value) => return Ok(value.as_bytes().to_vec()),
+            SettingValue::Bytes(value) => return Ok(value.clone()),
+        };
+        let size = self.setting_type.size().unwrap_or(8);
+        Ok(match self.endian {
+            Endian::Little => bits.to_le_bytes()[..size].to_vec(),
+            Endian::Big => bits.to_be_bytes()[8 - size..].to_vec(),
+        })
+    }
+
+    /// Decode a value stored on the device
+    pub fn decode(&self, data: &[u8]) -> Result<SettingValue, Error> {
+        let setting_type = self.setting_type;
+        if let Some(size) = setting_type.size() {
+            if data.len() != size {
+                bail!("{} bytes can't be a {}, it takes {}", data.len(), self, size);
+            }
+        }
+        let mut bytes = [0u8; 8];
+        let size = data.len().min(8);
+        match self.endian {
+            Endian::Little => bytes[..size].copy_from_slice(&data[..size]),
+            Endian::Big => bytes[8 - size..].copy_from_slice(&data[..size]),
+        }
+        let bits = match self.endian {
+            Endian::Little => u64::from_le_bytes(bytes),
+            Endian::Big => u64::from_be_bytes(bytes),
+        };
+        Ok(match setting_type {
+            t if t.is_signed() => {
+                // sign extend from the size of the type
+                let shift = 64 - 8 * size as u32;
+                SettingValue::Signed(((bits << shift) as i64) >> shift)
+            }
+            t if t.is_integer() => SettingValue::Unsigned(bits),
+            SettingType::Bool => match data[0] {
+                0 => SettingValue::Bool(false),
+                1 => SettingValue::Bool(true),
+                byte => bail!("{byte:#04x} is not a bool"),
+            },
+            SettingType::String => {
+                let text = std::str::from_utf8(data).context("the value is not UTF-8")?;
+                SettingValue::String(text.trim_end_matches('\0').to_string())
+            }
+            _ => SettingValue::Bytes(data.to_vec()),
+        })
+    }
+
+    /// Check that a value has the type of the codec and fits in its size
+    fn check(&self, value: &SettingValue) -> Result<(), Error> {
+        let setting_type = self.setting_type;
+        let bits = 8 * setting_type.size().unwrap_or(8) as u32;
+        let fits = match value {
+            SettingValue::Unsigned(value) if setting_type.is_integer() => {
+                if setting_type.is_signed() {
+                    *value < 1 << (bits - 1)
+                } else {
+                    bits == 64 || *value < 1 << bits
+                }
+            }
+            SettingValue::Signed(value) if setting_type.is_integer() => {
+                if setting_type.is_signed() {
+                    let min = i64::MIN >> (64 - bits);
+                    let max = i64::MAX >> (64 - bits);
+                    (min..=max).contains(value)
+                } else {
+                    *value >= 0 && (bits == 64 || (*value as u64) < 1 << bits)
+                }
+            }
+            SettingValue::Bool(_) => setting_type == SettingType::Bool,
+            SettingValue::String(_) => setting_type == SettingType::String,
+            SettingValue::Bytes(_) => setting_type == SettingType::Bytes,
+            _ => false,
+        };
+        if !fits {
+            bail!("{} is not a valid {}", value, self);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SettingCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.setting_type.name())?;
+        let multibyte = self.setting_type.is_integer() && self.setting_type.size() != Some(1);
+        if multibyte && self.endian == Endian::Big {
+            f.write_str("be")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SettingCodec {
+    type Err = Error;
+
+    /// A type name, integers with an optional `le` or `be` suffix
+    fn from_str(s: &str) -> Result<SettingCodec, Error> {
+        let (name, endian) = if let Some(name) = s.strip_suffix("be") {
+            (name, Endian::Big)
+        } else if let Some(name) = s.strip_suffix("le") {
+            (name, Endian::Little)
+        } else {
+            (s, Endian::Little)
+        };
+        let setting_type = match name {
+            "u8" => SettingType::U8,
+            "u16" => SettingType::U16,
+            "u32" => SettingType::U32,
+            "u64" => SettingType::U64,
+            "i8" => SettingType::I8,
+            "i16" => SettingType::I16,
+            "i32" => SettingType::I32,
+            "i64" => SettingType::I64,
+            _ if name != s => bail!("unknown setting type {s}, only integers have a byte order"),
+            "bool" => SettingType::Bool,
+            "string" | "str" => SettingType::String,
+            "bytes" | "hex" => SettingType::Bytes,
+            _ => bail!("unknown setting type {s}"),
+        };
+        Ok(SettingCodec::new(setting_type, endian))
+    }
+}
+
+/// Parse a decimal or `0x` hex number
+fn parse_unsigned(text: &str) -> Result<u64, Error> {
+    Ok(match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(digits) => u64::from_str_radix(digits, 16)?,
+        None => text.parse()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec(name: &str) -> SettingCodec {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn test_integer_codecs() {
+        let value = codec("u32").parse("1000").unwrap();
+        assert_eq!(codec("u32").encode(&value).unwrap(), [0xe8, 0x03, 0, 0]);
+        assert_eq!(codec("u32be").encode(&value).unwrap(), [0, 0, 0x03, 0xe8]);
+        assert_eq!(codec("u32be").decode(&[0, 0, 0x03, 0xe8]).unwrap(), value);
+        assert_eq!(codec("u16").parse("0xffff").unwrap(), SettingValue::Unsigned(0xffff));
+        assert!(codec("u16").parse("65536").is_err());
+        assert!(codec("u8").parse("-1").is_err());
+
+        let value = codec("i16").parse("-2").unwrap();
+        assert_eq!(codec("i16").encode(&value).unwrap(), [0xfe, 0xff]);
+        assert_eq!(codec("i16be").decode(&[0xff, 0xfe]).unwrap(), SettingValue::Signed(-2));
+        assert!(codec("i8").parse("128").is_err());
+        let min = codec("i64").parse("-9223372036854775808").unwrap();
+        assert_eq!(min, SettingValue::Signed(i64::MIN));
+        let max = codec("u64").parse("18446744073709551615").unwrap();
+        assert_eq!(max, SettingValue::Unsigned(u64::MAX));
+
+        let err = codec("u32").decode(&[1, 2]).unwrap_err();
+        assert_eq!(err.to_string(), "2 bytes can't be a u32, it takes 4");
+        assert!("boolbe".parse::<SettingCodec>().is_err());
+        assert_eq!(codec("u8be").to_string(), "u8");
+        assert_eq!(codec("i32be").to_string(), "i32be");
+    }
+
+    #[test]
+    fn test_other_codecs() {
+        assert_eq!(codec("bool").encode(&SettingValue::Bool(true)).unwrap(), [1]);
+        assert!(codec("bool").decode(&[2]).is_err());
+        let text = codec("string").decode(b"sim\0").unwrap();
+        assert_eq!(text, SettingValue::String("sim".into()));
+        assert_eq!(codec("bytes").parse("00ff").unwrap().to_string(), "00ff");
+        assert!(codec("u32").encode(&SettingValue::Bool(true)).is_err());
+    }
+}
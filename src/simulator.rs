@@ -0,0 +1,1220 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! In-process SMP device simulator
+//!
+//! Implements the command groups of this client like a Zephyr device with
+//! MCUboot: image state with test/confirm and swap on reset, an in-memory
+//! filesystem, a settings store, statistics, shell, os info and parameters.
+//! It is used as `-d test` serial port, can serve UDP for `UdpTransport` and
+//! TCP, and can persist its flash to a directory.
+
+use anyhow::{Context, Error, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use lazy_static::lazy_static;
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::mcuboot::{
+    ImageHeader, McubootImage, IMAGE_HEADER_SIZE, IMAGE_MAGIC, TLV_INFO_MAGIC, TLV_SHA256,
+};
+use crate::nmp_hdr::*;
+
+/// Chunk size of file downloads
+const FS_CHUNK_SIZE: usize = 512;
+
+/// Files of a storage directory: image state and saved settings, slot
+/// contents and the filesystem
+const STATE_FILE: &str = "state.json";
+const SLOTS_DIR: &str = "slots";
+const FS_DIR: &str = "fs";
+
+/// Configuration of a simulated device
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    /// Number of images, each with a primary and a secondary slot
+    pub images: usize,
+    /// Size of each slot in bytes
+    pub slot_size: usize,
+    /// MCUboot mode reported by bootloader info, see `mcuboot_mode_name`
+    pub mcuboot_mode: i32,
+    /// Reject uploads with a version older than the running image
+    pub no_downgrade: bool,
+    pub buf_size: u32,
+    pub buf_count: u32,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        SimulatorConfig {
+            images: 2,
+            slot_size: 512 * 1024,
+            mcuboot_mode: 3,
+            no_downgrade: false,
+            buf_size: 1024,
+            buf_count: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    data: Vec<u8>,
+    version: String,
+    hash: Vec<u8>,
+}
+
+impl Slot {
+    fn new(data: Vec<u8>) -> Slot {
+        let (version, hash) = match parse_image(&data) {
+            Some((version, hash)) => (version, hash),
+            None => ("0.0.0".to_string(), Sha256::digest(&data).to_vec()),
+        };
+        Slot {
+            data,
+            version,
+            hash,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct ImageSlots {
+    primary: Option<Slot>,
+    secondary: Option<Slot>,
+    /// image_ok flag of the primary slot
+    confirmed: bool,
+    /// secondary slot marked for swap, with the permanent flag
+    pending: Option<bool>,
+}
+
+/// Image flags and saved settings, as stored in `state.json`
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredState {
+    images: Vec<StoredImage>,
+    /// saved settings, hex encoded
+    settings: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredImage {
+    confirmed: bool,
+    pending: Option<bool>,
+}
+
+/// Storage directory, with the state last written to it
+#[derive(Debug, Clone)]
+struct Storage {
+    dir: PathBuf,
+    slots: Vec<[Option<Vec<u8>>; 2]>,
+    files: BTreeMap<String, Vec<u8>>,
+    dirs: BTreeSet<String>,
+    state: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+struct ImageUpload {
+    image: usize,
+    len: usize,
+    sha: Option<Vec<u8>>,
+    data: Vec<u8>,
+}
+
+/// Simulated SMP device
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    config: SimulatorConfig,
+    images: Vec<ImageSlots>,
+    upload: Option<ImageUpload>,
+    files: BTreeMap<String, Vec<u8>>,
+    dirs: BTreeSet<String>,
+    settings: BTreeMap<String, Vec<u8>>,
+    saved_settings: BTreeMap<String, Vec<u8>>,
+    booted: Instant,
+    resets: u32,
+    rx_count: i64,
+    tx_count: i64,
+    error_count: i64,
+    storage: Option<Storage>,
+    /// hashes of images that crash at boot
+    crashing: BTreeSet<Vec<u8>>,
+    /// flip a bit of each uploaded image, like a failing flash write
+    corrupt_uploads: bool,
+    /// files written through the fs group still to corrupt
+    corrupt_files: u32,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new(SimulatorConfig::default())
+    }
+}
+
+type HandlerResult = std::result::Result<Value, NmpErr>;
+
+impl Simulator {
+    /// Create a device running version 1.0.0 in the primary slot of each image
+    pub fn new(config: SimulatorConfig) -> Simulator {
+        let images = (0..config.images)
+            .map(|i| ImageSlots {
+                primary: Some(Slot::new(build_image(
+                    (1, 0, 0, 0),
+                    &vec![i as u8; 1024],
+                ))),
+                secondary: None,
+                confirmed: true,
+                pending: None,
+            })
+            .collect();
+        Simulator {
+            config,
+            images,
+            upload: None,
+            files: BTreeMap::new(),
+            dirs: ["/".to_string(), "/lfs".to_string()].into_iter().collect(),
+            settings: BTreeMap::new(),
+            saved_settings: BTreeMap::new(),
+            booted: Instant::now(),
+            resets: 0,
+            rx_count: 0,
+            tx_count: 0,
+            error_count: 0,
+            storage: None,
+            crashing: BTreeSet::new(),
+            corrupt_uploads: false,
+            corrupt_files: 0,
+        }
+    }
+
+    /// Create a device persisting its flash slots, filesystem and saved
+    /// settings to a directory, and load them if the directory was used before
+    pub fn open(config: SimulatorConfig, dir: &Path) -> Result<Simulator, Error> {
+        let mut simulator = Simulator::new(config);
+        let state_path = dir.join(STATE_FILE);
+        if state_path.exists() {
+            let state: StoredState = serde_json::from_slice(&std::fs::read(&state_path)?)
+                .with_context(|| format!("invalid {}", state_path.display()))?;
+            for (i, slots) in simulator.images.iter_mut().enumerate() {
+                let stored = state.images.get(i);
+                slots.confirmed = stored.is_none_or(|s| s.confirmed);
+                slots.pending = stored.and_then(|s| s.pending);
+                slots.primary = read_if_exists(&slot_path(dir, i, 0))?.map(Slot::new);
+                slots.secondary = read_if_exists(&slot_path(dir, i, 1))?.map(Slot::new);
+            }
+            for (name, value) in state.settings {
+                let value = hex::decode(&value)
+                    .with_context(|| format!("invalid value of setting {name}"))?;
+                simulator.saved_settings.insert(name, value);
+            }
+            simulator.settings = simulator.saved_settings.clone();
+            load_fs_dir(&dir.join(FS_DIR), "", &mut simulator)?;
+        }
+        simulator.storage = Some(Storage {
+            dir: dir.to_path_buf(),
+            slots: Vec::new(),
+            files: BTreeMap::new(),
+            dirs: BTreeSet::new(),
+            state: Vec::new(),
+        });
+        simulator.sync_storage()?;
+        Ok(simulator)
+    }
+
+    pub fn config(&self) -> &SimulatorConfig {
+        &self.config
+    }
+
+    /// Number of resets since the simulator was created
+    pub fn resets(&self) -> u32 {
+        self.resets
+    }
+
+    /// Current image state, as returned by the image list command
+    pub fn image_state(&self) -> ImageStateRsp {
+        let mut images = Vec::new();
+        for (i, slots) in self.images.iter().enumerate() {
+            if let Some(primary) = &slots.primary {
+                images.push(ImageStateEntry {
+                    image: i as u32,
+                    slot: 0,
+                    version: primary.version.clone(),
+                    hash: primary.hash.clone(),
+                    bootable: true,
+                    pending: false,
+                    confirmed: slots.confirmed,
+                    active: true,
+                    permanent: false,
+                });
+            }
+            if let Some(secondary) = &slots.secondary {
+                images.push(ImageStateEntry {
+                    image: i as u32,
+                    slot: 1,
+                    version: secondary.version.clone(),
+                    hash: secondary.hash.clone(),
+                    bootable: true,
+                    pending: slots.pending.is_some(),
+                    confirmed: false,
+                    active: false,
+                    permanent: slots.pending == Some(true),
+                });
+            }
+        }
+        ImageStateRsp {
+            images,
+            split_status: None,
+        }
+    }
+
+    /// Contents of a slot, slot 0 being the primary slot
+    pub fn slot_data(&self, image: usize, slot: u32) -> Option<&[u8]> {
+        let slots = self.images.get(image)?;
+        let slot = match slot {
+            0 => slots.primary.as_ref(),
+            1 => slots.secondary.as_ref(),
+            _ => None,
+        };
+        slot.map(|s| s.data.as_slice())
+    }
+
+    /// Contents of a file
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        self.files.get(path).map(|f| f.as_slice())
+    }
+
+    /// Create or replace a file, creating its parent directories
+    pub fn put_file(&mut self, path: &str, data: &[u8]) {
+        let mut parent = parent_dir(path);
+        while !self.dirs.contains(&parent) {
+            self.dirs.insert(parent.clone());
+            parent = parent_dir(&parent);
+        }
+        self.files.insert(path.to_string(), data.to_vec());
+        self.persist();
+    }
+
+    /// Value of a setting in RAM
+    pub fn setting(&self, name: &str) -> Option<&[u8]> {
+        self.settings.get(name).map(|v| v.as_slice())
+    }
+
+    /// Make an image crash at boot, like a broken build: the watchdog resets
+    /// the device and the bootloader reverts a test image
+    pub fn crash_at_boot(&mut self, hash: &[u8]) {
+        self.crashing.insert(hash.to_vec());
+    }
+
+    /// Corrupt the uploaded images in flash, like a failing flash write: the
+    /// device reports that the data doesn't match its SHA256
+    pub fn corrupt_uploads(&mut self, corrupt: bool) {
+        self.corrupt_uploads = corrupt;
+    }
+
+    /// Corrupt the next `count` files written through the fs group, like a
+    /// dropped chunk: their fs hash no longer matches the data sent
+    pub fn corrupt_files(&mut self, count: u32) {
+        self.corrupt_files = count;
+    }
+
+    /// Reboot the device: apply pending image swaps or reverts, reload the
+    /// saved settings and abort transfers in progress
+    pub fn reboot(&mut self) {
+        let overwrite_only = self.config.mcuboot_mode == 2;
+        for slots in self.images.iter_mut() {
+            if let (Some(permanent), true) = (slots.pending, slots.secondary.is_some()) {
+                if overwrite_only {
+                    slots.primary = slots.secondary.take();
+                    slots.confirmed = true;
+                } else {
+                    std::mem::swap(&mut slots.primary, &mut slots.secondary);
+                    slots.confirmed = permanent;
+                }
+            } else if !slots.confirmed && slots.secondary.is_some() && !overwrite_only {
+                // test image was not confirmed, revert to the previous one
+                std::mem::swap(&mut slots.primary, &mut slots.secondary);
+                slots.confirmed = true;
+            }
+            let crashes = slots
+                .primary
+                .as_ref()
+                .is_some_and(|p| self.crashing.contains(&p.hash));
+            if crashes && !slots.confirmed && slots.secondary.is_some() {
+                std::mem::swap(&mut slots.primary, &mut slots.secondary);
+                slots.confirmed = true;
+            }
+            slots.pending = None;
+        }
+        self.upload = None;
+        self.settings = self.saved_settings.clone();
+        self.booted = Instant::now();
+        self.resets += 1;
+        self.persist();
+    }
+
+    /// Handle a request packet (SMP header and CBOR body) and return the
+    /// response packet
+    pub fn handle_packet(&mut self, packet: &[u8]) -> Result<Vec<u8>, Error> {
+        let header = NmpHdr::parse(packet)?;
+        let body = &packet[8..];
+        self.rx_count += 1;
+
+        let mut reset = false;
+        let result = match (header.group, header.id) {
+            (NmpGroup::Default, id) if id == NmpIdDef::Reset as u8 => {
+                reset = true;
+                Ok(Value::Map(BTreeMap::new()))
+            }
+            (NmpGroup::Default, id) => self.handle_default(header.op, id, body),
+            (NmpGroup::Image, id) => self.handle_image(header.op, id, body),
+            (NmpGroup::Stat, id) => self.handle_stat(id, body),
+            (NmpGroup::Config, id) => self.handle_config(header.op, id, body),
+            (NmpGroup::Fs, id) => self.handle_fs(header.op, id, body),
+            (NmpGroup::Shell, id) if id == NmpIdShell::Exec as u8 => self.handle_shell(body),
+            _ => Err(NmpErr::ENotSup),
+        };
+        let response = result.unwrap_or_else(|rc| {
+            debug!("simulator: {} failed: {:?}", command_name(header.group, header.id), rc);
+            self.error_count += 1;
+            map(vec![("rc", int(rc as i64))])
+        });
+
+        let packet = response_packet(packet, &response)?;
+        self.tx_count += 1;
+
+        if reset {
+            self.reboot();
+        } else if header.op == NmpOp::Write {
+            self.persist();
+        }
+        Ok(packet)
+    }
+
+    /// Write the changes to the storage directory, if any
+    fn persist(&mut self) {
+        if let Err(e) = self.sync_storage() {
+            error!("simulator: failed to write storage: {:#}", e);
+        }
+    }
+
+    /// Write what changed since the last sync to the storage directory
+    fn sync_storage(&mut self) -> Result<(), Error> {
+        let Some(storage) = &mut self.storage else {
+            return Ok(());
+        };
+        let dir = storage.dir.clone();
+
+        std::fs::create_dir_all(dir.join(SLOTS_DIR))?;
+        for (i, slots) in self.images.iter().enumerate() {
+            if storage.slots.len() <= i {
+                storage.slots.push([None, None]);
+            }
+            for (slot, current) in [&slots.primary, &slots.secondary].into_iter().enumerate() {
+                let current = current.as_ref().map(|s| &s.data);
+                if storage.slots[i][slot].as_ref() == current {
+                    continue;
+                }
+                let path = slot_path(&dir, i, slot);
+                match current {
+                    Some(data) => std::fs::write(&path, data)?,
+                    None => remove_if_exists(&path)?,
+                }
+                storage.slots[i][slot] = current.cloned();
+            }
+        }
+
+        for path in self.dirs.difference(&storage.dirs) {
+            std::fs::create_dir_all(fs_path(&dir, path))?;
+        }
+        for (path, data) in &self.files {
+            if storage.files.get(path) != Some(data) {
+                std::fs::write(fs_path(&dir, path), data)?;
+            }
+        }
+        for path in storage.files.keys().filter(|f| !self.files.contains_key(*f)) {
+            remove_if_exists(&fs_path(&dir, path))?;
+        }
+        // children sort after their parents and are removed first
+        for path in storage.dirs.iter().rev().filter(|d| !self.dirs.contains(*d)) {
+            let _ = std::fs::remove_dir(fs_path(&dir, path));
+        }
+        storage.files.clone_from(&self.files);
+        storage.dirs.clone_from(&self.dirs);
+
+        let state = StoredState {
+            images: self
+                .images
+                .iter()
+                .map(|slots| StoredImage {
+                    confirmed: slots.confirmed,
+                    pending: slots.pending,
+                })
+                .collect(),
+            settings: self
+                .saved_settings
+                .iter()
+                .map(|(name, value)| (name.clone(), hex::encode(value)))
+                .collect(),
+        };
+        let state = serde_json::to_vec_pretty(&state)?;
+        if storage.state != state {
+            std::fs::write(dir.join(STATE_FILE), &state)?;
+            storage.state = state;
+        }
+        Ok(())
+    }
+
+    fn handle_default(&mut self, op: NmpOp, id: u8, body: &[u8]) -> HandlerResult {
+        match id {
+            id if id == NmpIdDef::Echo as u8 => {
+                let req: EchoReq = decode(body)?;
+                Ok(map(vec![("r", Value::Text(req.d))]))
+            }
+            id if id == NmpIdDef::TaskStat as u8 && op == NmpOp::Read => {
+                let uptime = self.booted.elapsed().as_millis() as i128;
+                let task = |prio: i128, stkuse: i128, stksiz: i128| {
+                    map(vec![
+                        ("prio", int(prio)),
+                        ("state", int(0)),
+                        ("stkuse", int(stkuse)),
+                        ("stksiz", int(stksiz)),
+                        ("cswcnt", int(self.rx_count as i128)),
+                        ("runtime", int(uptime)),
+                        ("last_checkin", int(0)),
+                        ("next_checkin", int(0)),
+                    ])
+                };
+                let mut tasks = BTreeMap::new();
+                tasks.insert(text("idle"), task(15, 12, 80));
+                tasks.insert(text("main"), task(0, 402, 1024));
+                tasks.insert(text("smp"), task(7, 820, 2048));
+                Ok(map(vec![("tasks", Value::Map(tasks))]))
+            }
+            id if id == NmpIdDef::McumgrParams as u8 && op == NmpOp::Read => Ok(map(vec![
+                ("buf_size", int(self.config.buf_size as i128)),
+                ("buf_count", int(self.config.buf_count as i128)),
+            ])),
+            id if id == NmpIdDef::Info as u8 && op == NmpOp::Read => {
+                let req: OsInfoReq = decode(body)?;
+                let format = req.format.unwrap_or_else(|| "s".to_string());
+                Ok(map(vec![("output", Value::Text(os_info(&format)?))]))
+            }
+            id if id == NmpIdDef::BootloaderInfo as u8 && op == NmpOp::Read => {
+                let req: BootloaderInfoReq = decode(body)?;
+                match req.query.as_deref() {
+                    None => Ok(map(vec![("bootloader", text("MCUboot"))])),
+                    Some("mode") => Ok(map(vec![
+                        ("mode", int(self.config.mcuboot_mode as i128)),
+                        ("no-downgrade", Value::Bool(self.config.no_downgrade)),
+                    ])),
+                    Some(_) => Err(NmpErr::ENotSup),
+                }
+            }
+            _ => Err(NmpErr::ENotSup),
+        }
+    }
+
+    fn handle_image(&mut self, op: NmpOp, id: u8, body: &[u8]) -> HandlerResult {
+        match (op, id) {
+            (NmpOp::Read, id) if id == NmpIdImage::State as u8 => self.image_list(),
+            (NmpOp::Write, id) if id == NmpIdImage::State as u8 => {
+                let req: ImageStateReq = decode(body)?;
+                self.image_test(&req.hash, req.confirm.unwrap_or(false))?;
+                self.image_list()
+            }
+            (NmpOp::Write, id) if id == NmpIdImage::Upload as u8 => {
+                let req: ImageUploadReq = decode(body)?;
+                self.image_upload(req)
+            }
+            (NmpOp::Write, id) if id == NmpIdImage::Erase as u8 => {
+                let req: ImageEraseReq = decode(body)?;
+                self.image_erase(req.slot.unwrap_or(1))?;
+                Ok(map(vec![("rc", int(0))]))
+            }
+            _ => Err(NmpErr::ENotSup),
+        }
+    }
+
+    fn image_list(&self) -> HandlerResult {
+        serde_cbor::value::to_value(self.image_state()).map_err(|_| NmpErr::EUnknown)
+    }
+
+    fn image_test(&mut self, hash: &[u8], confirm: bool) -> std::result::Result<(), NmpErr> {
+        if hash.is_empty() {
+            // confirm the running image
+            if !confirm {
+                return Err(NmpErr::EInvalid);
+            }
+            for slots in self.images.iter_mut() {
+                slots.confirmed = true;
+            }
+            return Ok(());
+        }
+
+        for slots in self.images.iter_mut() {
+            if slots.primary.as_ref().is_some_and(|s| s.hash == hash) {
+                if !confirm {
+                    // the running image can only be confirmed
+                    return Err(NmpErr::EBadState);
+                }
+                slots.confirmed = true;
+                return Ok(());
+            }
+            if slots.secondary.as_ref().is_some_and(|s| s.hash == hash) {
+                slots.pending = Some(confirm || self.config.mcuboot_mode == 2);
+                return Ok(());
+            }
+        }
+        Err(NmpErr::EInvalid)
+    }
+
+    fn image_upload(&mut self, req: ImageUploadReq) -> HandlerResult {
+        let image = req.image_num as usize;
+        if image >= self.images.len() {
+            return Err(NmpErr::EInvalid);
+        }
+        let off = req.off as usize;
+
+        if off == 0 {
+            let len = req.len.ok_or(NmpErr::EInvalid)? as usize;
+            // resume an interrupted upload of the same data
+            if let Some(upload) = &self.upload {
+                if upload.image == image
+                    && upload.len == len
+                    && upload.sha.is_some()
+                    && upload.sha == req.data_sha
+                    && !upload.data.is_empty()
+                {
+                    return Ok(map(vec![("rc", int(0)), ("off", int(upload.data.len() as i128))]));
+                }
+            }
+            if len > self.config.slot_size {
+                return Err(NmpErr::EInvalid);
+            }
+            let slots = &self.images[image];
+            if slots.pending.is_some() || !slots.confirmed {
+                // the secondary slot is in use by a pending swap or revert
+                return Err(NmpErr::EBadState);
+            }
+            if req.upgrade == Some(true) || self.config.no_downgrade {
+                // the first chunk holds the header
+                let new_version = ImageHeader::parse(&req.data).ok().map(|h| h.version);
+                let old_version = slots.primary.as_ref().and_then(|s| s.version.parse().ok());
+                if let (Some(new), Some(old)) = (new_version, old_version) {
+                    // upgrades must be newer, downgrade prevention allows the
+                    // same version
+                    let order = new.cmp_release(&old);
+                    if order.is_lt() || (order.is_eq() && req.upgrade == Some(true)) {
+                        return Err(NmpErr::EBadState);
+                    }
+                }
+            }
+            self.images[image].secondary = None;
+            self.upload = Some(ImageUpload {
+                image,
+                len,
+                sha: req.data_sha,
+                data: Vec::with_capacity(len),
+            });
+        }
+
+        let upload = match &mut self.upload {
+            Some(upload) if upload.image == image => upload,
+            // no upload in progress, e.g. after a reset: restart from the beginning
+            _ => return Ok(map(vec![("rc", int(0)), ("off", int(0))])),
+        };
+        if off != upload.data.len() {
+            // tell the client where to continue
+            return Ok(map(vec![("rc", int(0)), ("off", int(upload.data.len() as i128))]));
+        }
+        if off + req.data.len() > upload.len {
+            return Err(NmpErr::EInvalid);
+        }
+        upload.data.extend_from_slice(&req.data);
+        let off = upload.data.len();
+
+        let mut response = vec![("rc", int(0)), ("off", int(off as i128))];
+        if off == upload.len {
+            let mut upload = self.upload.take().unwrap();
+            if self.corrupt_uploads {
+                let last = upload.data.len() - 1;
+                upload.data[last] ^= 1;
+            }
+            if let Some(sha) = &upload.sha {
+                let matches = Sha256::digest(&upload.data).as_slice() == sha.as_slice();
+                response.push(("match", Value::Bool(matches)));
+            }
+            self.images[image].secondary = Some(Slot::new(upload.data));
+        }
+        Ok(map(response))
+    }
+
+    fn image_erase(&mut self, slot: u32) -> std::result::Result<(), NmpErr> {
+        let image = (slot / 2) as usize;
+        if image >= self.images.len() {
+            return Err(NmpErr::EInvalid);
+        }
+        let slots = &mut self.images[image];
+        if slot.is_multiple_of(2) || slots.pending.is_some() || !slots.confirmed {
+            // primary slot, or secondary slot needed for a swap or revert
+            return Err(NmpErr::EBadState);
+        }
+        slots.secondary = None;
+        if self.upload.as_ref().is_some_and(|u| u.image == image) {
+            self.upload = None;
+        }
+        Ok(())
+    }
+
+    fn handle_stat(&mut self, id: u8, body: &[u8]) -> HandlerResult {
+        match id {
+            id if id == NmpIdStat::List as u8 => Ok(map(vec![
+                ("rc", int(0)),
+                ("stat_list", Value::Array(vec![text("smp")])),
+            ])),
+            id if id == NmpIdStat::Read as u8 => {
+                let req: StatReadReq = decode(body)?;
+                if req.name != "smp" {
+                    return Err(NmpErr::ENoEnt);
+                }
+                let mut fields = BTreeMap::new();
+                fields.insert(text("rx"), int(self.rx_count as i128));
+                fields.insert(text("tx"), int(self.tx_count as i128));
+                fields.insert(text("errors"), int(self.error_count as i128));
+                Ok(map(vec![
+                    ("rc", int(0)),
+                    ("name", Value::Text(req.name)),
+                    ("group", text("sim")),
+                    ("fields", Value::Map(fields)),
+                ]))
+            }
+            _ => Err(NmpErr::ENotSup),
+        }
+    }
+
+    fn handle_config(&mut self, op: NmpOp, id: u8, body: &[u8]) -> HandlerResult {
+        match (op, id) {
+            (NmpOp::Read, id) if id == NmpIdConfig::Val as u8 => {
+                let req: SettingsReadReq = decode(body)?;
+                let mut val = self.settings.get(&req.name).ok_or(NmpErr::ENoEnt)?.clone();
+                if let Some(max_size) = req.max_size {
+                    val.truncate(max_size as usize);
+                }
+                Ok(map(vec![("val", Value::Bytes(val))]))
+            }
+            (NmpOp::Write, id) if id == NmpIdConfig::Val as u8 => {
+                let req: SettingsWriteReq = decode(body)?;
+                self.settings.insert(req.name, req.val);
+                Ok(map(vec![]))
+            }
+            (NmpOp::Write, id) if id == NmpIdConfig::Delete as u8 => {
+                let req: SettingsDeleteReq = decode(body)?;
+                self.settings.remove(&req.name);
+                self.saved_settings.remove(&req.name);
+                Ok(map(vec![]))
+            }
+            (NmpOp::Write, id) if id == NmpIdConfig::Commit as u8 => Ok(map(vec![])),
+            (NmpOp::Read, id) if id == NmpIdConfig::LoadSave as u8 => {
+                self.settings = self.saved_settings.clone();
+                Ok(map(vec![]))
+            }
+            (NmpOp::Write, id) if id == NmpIdConfig::LoadSave as u8 => {
+                self.saved_settings = self.settings.clone();
+                Ok(map(vec![]))
+            }
+            _ => Err(NmpErr::ENotSup),
+        }
+    }
+
+    fn handle_fs(&mut self, op: NmpOp, id: u8, body: &[u8]) -> HandlerResult {
+        match (op, id) {
+            (NmpOp::Read, id) if id == NmpIdFs::File as u8 => {
+                let req: FsDownloadReq = decode(body)?;
+                let file = self.files.get(&req.name).ok_or(NmpErr::ENoEnt)?;
+                let off = req.off as usize;
+                if off > file.len() {
+                    return Err(NmpErr::EInvalid);
+                }
+                let end = file.len().min(off + FS_CHUNK_SIZE);
+                let mut response = vec![
+                    ("off", int(off as i128)),
+                    ("data", Value::Bytes(file[off..end].to_vec())),
+                ];
+                if off == 0 {
+                    response.push(("len", int(file.len() as i128)));
+                }
+                Ok(map(response))
+            }
+            (NmpOp::Write, id) if id == NmpIdFs::File as u8 => {
+                let req: FsUploadReq = decode(body)?;
+                if req.off == 0 {
+                    let parent = parent_dir(&req.name);
+                    if parent == "/" || !self.dirs.contains(&parent) || self.dirs.contains(&req.name)
+                    {
+                        return Err(NmpErr::ENoEnt);
+                    }
+                    self.files.insert(req.name.clone(), Vec::new());
+                }
+                let file = self.files.get_mut(&req.name).ok_or(NmpErr::ENoEnt)?;
+                if req.off as usize == file.len() {
+                    file.extend_from_slice(&req.data);
+                    if req.off == 0 && !req.data.is_empty() && self.corrupt_files > 0 {
+                        self.corrupt_files -= 1;
+                        file[0] ^= 1;
+                    }
+                }
+                Ok(map(vec![("rc", int(0)), ("off", int(file.len() as i128))]))
+            }
+            (NmpOp::Read, id) if id == NmpIdFs::FileStat as u8 => {
+                let req: FsStatReq = decode(body)?;
+                let file = self.files.get(&req.name).ok_or(NmpErr::ENoEnt)?;
+                Ok(map(vec![("rc", int(0)), ("len", int(file.len() as i128))]))
+            }
+            (NmpOp::Read, id) if id == NmpIdFs::FileHash as u8 => {
+                let req: FsHashReq = decode(body)?;
+                let file = self.files.get(&req.name).ok_or(NmpErr::ENoEnt)?;
+                let off = req.off.unwrap_or(0) as usize;
+                if off > file.len() {
+                    return Err(NmpErr::EInvalid);
+                }
+                let end = match req.len {
+                    Some(len) => file.len().min(off + len as usize),
+                    None => file.len(),
+                };
+                let data = &file[off..end];
+                let hash_type = req.hash_type.unwrap_or_else(|| "crc32".to_string());
+                // like Zephyr, the checksum is returned as integer, the hash as bytes
+                let output = match hash_type.as_str() {
+                    "crc32" => int(crc32fast::hash(data) as i128),
+                    "sha256" => Value::Bytes(Sha256::digest(data).to_vec()),
+                    _ => return Err(NmpErr::ENotSup),
+                };
+                Ok(map(vec![
+                    ("type", Value::Text(hash_type)),
+                    ("off", int(off as i128)),
+                    ("len", int(data.len() as i128)),
+                    ("output", output),
+                ]))
+            }
+            (NmpOp::Read, id) if id == NmpIdFs::SupportedHashTypes as u8 => {
+                let hash_type = |format: i128, size: i128| {
+                    map(vec![("format", int(format)), ("size", int(size))])
+                };
+                let mut types = BTreeMap::new();
+                types.insert(text("crc32"), hash_type(0, 4));
+                types.insert(text("sha256"), hash_type(1, 32));
+                Ok(map(vec![("types", Value::Map(types))]))
+            }
+            (NmpOp::Write, id) if id == NmpIdFs::FileClose as u8 => Ok(map(vec![])),
+            _ => Err(NmpErr::ENotSup),
+        }
+    }
+
+    fn handle_shell(&mut self, body: &[u8]) -> HandlerResult {
+        let req: ShellExecReq = decode(body)?;
+        let argv: Vec<&str> = req.argv.iter().map(|s| s.as_str()).collect();
+        let (output, ret) = match argv.as_slice() {
+            [] => return Err(NmpErr::EInvalid),
+            ["echo", args @ ..] => (format!("{}\n", args.join(" ")), 0),
+            ["kernel", "version"] => ("Zephyr version 3.7.0\n".to_string(), 0),
+            ["kernel", "uptime"] => (
+                format!("Uptime: {} ms\n", self.booted.elapsed().as_millis()),
+                0,
+            ),
+            ["fs", "ls"] => self.shell_ls("/"),
+            ["fs", "ls", path] => self.shell_ls(path),
+            ["fs", "mkdir", path] => self.shell_mkdir(path),
+            ["fs", "rm", path] => self.shell_rm(path),
+            [command, ..] => (format!("{command}: command not found\n"), -8),
+        };
+        Ok(map(vec![("o", Value::Text(output)), ("ret", int(ret))]))
+    }
+
+    fn shell_ls(&self, path: &str) -> (String, i128) {
+        let path = normalize_path(path);
+        if !self.dirs.contains(&path) {
+            return (format!("Unable to open {path} (err -2)\n"), -8);
+        }
+        let mut output = String::new();
+        for dir in self.dirs.iter().filter(|d| *d != "/" && parent_dir(d) == path) {
+            output.push_str(&format!("{}/\n", file_name(dir)));
+        }
+        for file in self.files.keys().filter(|f| parent_dir(f) == path) {
+            output.push_str(&format!("{}\n", file_name(file)));
+        }
+        (output, 0)
+    }
+
+    fn shell_mkdir(&mut self, path: &str) -> (String, i128) {
+        let path = normalize_path(path);
+        let parent = parent_dir(&path);
+        if self.dirs.contains(&path) || self.files.contains_key(&path) {
+            return ("Error creating dir[-17]\n".to_string(), -8);
+        }
+        if parent == "/" || !self.dirs.contains(&parent) {
+            return ("Error creating dir[-2]\n".to_string(), -8);
+        }
+        self.dirs.insert(path);
+        (String::new(), 0)
+    }
+
+    fn shell_rm(&mut self, path: &str) -> (String, i128) {
+        let path = normalize_path(path);
+        if self.files.remove(&path).is_some() {
+            return (String::new(), 0);
+        }
+        if parent_dir(&path) == "/" || !self.dirs.contains(&path) {
+            return (format!("Failed to remove {path} (-2)\n"), -8);
+        }
+        let is_empty = !self.dirs.iter().any(|d| d != "/" && parent_dir(d) == path)
+            && !self.files.keys().any(|f| parent_dir(f) == path);
+        if !is_empty {
+            // ENOTEMPTY of Zephyr
+            return (format!("Failed to remove {path} (-90)\n"), -8);
+        }
+        self.dirs.remove(&path);
+        (String::new(), 0)
+    }
+}
+
+
+/// Build the response packet to a request packet
+///
+/// The version bits of the request header are copied to the response, so
+/// both SMP v1 (serial) and v2 (UDP) clients are answered.
+fn response_packet(request: &[u8], response: &Value) -> Result<Vec<u8>, Error> {
+    let header = NmpHdr::parse(request)?;
+    let response_op = match header.op {
+        NmpOp::Read => NmpOp::ReadRsp,
+        NmpOp::Write => NmpOp::WriteRsp,
+        op => anyhow::bail!("unexpected request op {:?}", op),
+    };
+    let body = serde_cbor::to_vec(response)?;
+    let mut packet = NmpHdr {
+        op: response_op,
+        flags: 0,
+        len: body.len() as u16,
+        group: header.group,
+        seq: header.seq,
+        id: header.id,
+    }
+    .serialize()?;
+    packet[0] |= request[0] & 0x18;
+    packet.extend_from_slice(&body);
+    Ok(packet)
+}
+
+/// Build an error response with the result code to a request packet
+pub(crate) fn error_response(request: &[u8], rc: i32) -> Result<Vec<u8>, Error> {
+    response_packet(request, &map(vec![("rc", int(rc))]))
+}
+
+fn map(entries: Vec<(&str, Value)>) -> Value {
+    Value::Map(entries.into_iter().map(|(k, v)| (text(k), v)).collect())
+}
+
+fn int(value: impl Into<i128>) -> Value {
+    Value::Integer(value.into())
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_string())
+}
+
+fn decode<T: DeserializeOwned>(body: &[u8]) -> std::result::Result<T, NmpErr> {
+    serde_cbor::from_slice(body).map_err(|e| {
+        debug!("simulator: invalid request body: {}", e);
+        NmpErr::EInvalid
+    })
+}
+
+/// Answer an os info request, with the format characters of `uname`
+fn os_info(format: &str) -> std::result::Result<String, NmpErr> {
+    let format = if format == "a" { "snrvbmpio" } else { format };
+    let mut fields = Vec::new();
+    for c in format.chars() {
+        fields.push(match c {
+            's' => "Zephyr",
+            'n' => "mcumgr-sim",
+            'r' => "3.7.0",
+            'v' => "v3.7.0",
+            'b' => "Oct 18 2026 12:00:00",
+            'm' => "sim",
+            'p' => "sim",
+            'i' => "native_sim",
+            'o' => "Zephyr",
+            _ => return Err(NmpErr::EInvalid),
+        });
+    }
+    Ok(fields.join(" "))
+}
+
+fn slot_path(dir: &Path, image: usize, slot: usize) -> PathBuf {
+    dir.join(SLOTS_DIR).join(format!("image{image}-slot{slot}.bin"))
+}
+
+/// Local path of a simulated file or directory in a storage directory
+fn fs_path(dir: &Path, path: &str) -> PathBuf {
+    dir.join(FS_DIR).join(path.trim_start_matches('/'))
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::new(e).context(format!("failed to read {}", path.display()))),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(Error::new(e).context(format!("failed to remove {}", path.display())))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Load the files and directories of a storage directory below `prefix`
+fn load_fs_dir(local: &Path, prefix: &str, simulator: &mut Simulator) -> Result<(), Error> {
+    let entries = match std::fs::read_dir(local) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            simulator.dirs.insert(path.clone());
+            load_fs_dir(&entry.path(), &path, simulator)?;
+        } else {
+            simulator.files.insert(path, std::fs::read(entry.path())?);
+        }
+    }
+    Ok(())
+}
+
+fn normalize_path(path: &str) -> String {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        "/".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+fn parent_dir(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(pos) => path[..pos].to_string(),
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Build a minimal MCUboot image: header, payload and a SHA256 TLV
+///
+/// The version is given as (major, minor, revision, build number).
+pub fn build_image(version: (u8, u8, u16, u32), payload: &[u8]) -> Vec<u8> {
+    let mut image = vec![0u8; IMAGE_HEADER_SIZE];
+    LittleEndian::write_u32(&mut image[0..], IMAGE_MAGIC);
+    LittleEndian::write_u16(&mut image[8..], IMAGE_HEADER_SIZE as u16);
+    LittleEndian::write_u32(&mut image[12..], payload.len() as u32);
+    image[20] = version.0;
+    image[21] = version.1;
+    LittleEndian::write_u16(&mut image[22..], version.2);
+    LittleEndian::write_u32(&mut image[24..], version.3);
+    image.extend_from_slice(payload);
+
+    let hash = Sha256::digest(&image);
+    let mut tlv = [0u8; 8];
+    LittleEndian::write_u16(&mut tlv[0..], TLV_INFO_MAGIC);
+    LittleEndian::write_u16(&mut tlv[2..], (8 + hash.len()) as u16);
+    LittleEndian::write_u16(&mut tlv[4..], TLV_SHA256);
+    LittleEndian::write_u16(&mut tlv[6..], hash.len() as u16);
+    image.extend_from_slice(&tlv);
+    image.extend_from_slice(&hash);
+    image
+}
+
+/// Get the version and hash of an MCUboot image, like the image manager of
+/// the device does
+fn parse_image(data: &[u8]) -> Option<(String, Vec<u8>)> {
+    let image = McubootImage::parse(data).ok()?;
+    Some((image.header.version.to_string(), image.hash()))
+}
+
+lazy_static! {
+    static ref SHARED: Mutex<HashMap<String, Arc<Mutex<Simulator>>>> = Mutex::new(HashMap::new());
+}
+
+/// Get a simulator shared by name, creating it on first use
+///
+/// The `test` serial device uses the simulator named "" and `test:<name>`
+/// the one with that name, so the state survives reopening the port.
+pub fn shared_simulator(name: &str) -> Arc<Mutex<Simulator>> {
+    SHARED
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .clone()
+}
+
+/// UDP server answering SMP requests with a simulator, until dropped
+pub struct UdpServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl UdpServer {
+    /// Serve on a free port of the loopback interface
+    pub fn spawn(simulator: Arc<Mutex<Simulator>>) -> Result<Self, Error> {
+        Self::bind("127.0.0.1:0", simulator)
+    }
+
+    /// Serve on the given address
+    pub fn bind(addr: &str, simulator: Arc<Mutex<Simulator>>) -> Result<Self, Error> {
+        let socket = UdpSocket::bind(addr).with_context(|| format!("failed to bind {addr}"))?;
+        socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+        let addr = socket.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::spawn(move || {
+            let mut buf = [0u8; 65536];
+            while !thread_stop.load(Ordering::Relaxed) {
+                let (len, peer) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                };
+                let response = simulator.lock().unwrap().handle_packet(&buf[..len]);
+                match response {
+                    Ok(response) => {
+                        let _ = socket.send_to(&response, peer);
+                    }
+                    Err(e) => debug!("simulator: dropped packet: {}", e),
+                }
+            }
+        });
+        Ok(UdpServer {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for UdpServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// TCP server answering SMP requests with a simulator, until dropped
+///
+/// Requests and responses are plain SMP packets, each sized by the length
+/// of its header, and several clients can be connected at once.
+pub struct TcpServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TcpServer {
+    /// Serve on the given address
+    pub fn bind(addr: &str, simulator: Arc<Mutex<Simulator>>) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr).with_context(|| format!("failed to bind {addr}"))?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        debug!("simulator: connection from {}", peer);
+                        let simulator = simulator.clone();
+                        let stop = thread_stop.clone();
+                        std::thread::spawn(move || serve_tcp(stream, simulator, stop));
+                    }
+                    Err(e) => {
+                        if e.kind() != ErrorKind::WouldBlock {
+                            debug!("simulator: accept failed: {}", e);
+                        }
+                        std::thread::sleep(Duration::from_millis(50));
+                    }
+                }
+            }
+        });
+        Ok(TcpServer {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for TcpServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Answer the requests of a TCP connection until it's closed
+fn serve_tcp(mut stream: TcpStream, simulator: Arc<Mutex<Simulator>>, stop: Arc<AtomicBool>) {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(50)));
+    let mut received = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !stop.load(Ordering::Relaxed) {
+        match stream.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => received.extend_from_slice(&buf[..len]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(_) => return,
+        }
+        while received.len() >= 8 {
+            let len = 8 + BigEndian::read_u16(&received[2..4]) as usize;
+            if received.len() < len {
+                break;
+            }
+            let packet: Vec<u8> = received.drain(..len).collect();
+            let response = simulator.lock().unwrap().handle_packet(&packet);
+            match response {
+                Ok(response) => {
+                    if stream.write_all(&response).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => debug!("simulator: dropped packet: {}", e),
+            }
+        }
+    }
+}
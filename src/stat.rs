@@ -39,8 +39,7 @@ pub fn stat_list(specs: &SerialSpecs) -> Result<StatListRsp, Error> {
 
     let mut port = open_port(specs)?;
 
-    let body: Vec<u8> =
-        serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
+    let body = serde_cbor::to_vec(&StatListReq {})?;
 
     let (data, request_header) = encode_request(
         specs.linelength,
@@ -113,8 +112,7 @@ pub fn stat_read(specs: &SerialSpecs, name: &str) -> Result<StatReadRsp, Error>
 pub fn stat_list_transport(transport: &mut dyn Transport) -> Result<StatListRsp, Error> {
     info!("send stat list request");
 
-    let body: Vec<u8> =
-        serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
+    let body = serde_cbor::to_vec(&StatListReq {})?;
 
     let (_response_header, response_body) = transport.transceive(
         NmpOp::Read,
@@ -0,0 +1,218 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Rendering of the image state: slots with their flags, and what the
+//! bootloader does at the next reset
+
+use std::fmt;
+
+use crate::nmp_hdr::{ImageStateEntry, ImageStateRsp, SplitStatus};
+use crate::os::mcuboot_mode_name;
+use crate::update::BootMode;
+
+/// Number of hash bytes shown in the table
+const SHORT_HASH_LEN: usize = 4;
+
+/// Flag of an image slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotFlag {
+    /// The image runs
+    Active,
+    /// The image stays after the next reset
+    Confirmed,
+    /// The image boots at the next reset
+    Pending,
+    /// The image is valid for the bootloader
+    Bootable,
+    /// The pending image is confirmed in advance, it won't be reverted
+    Permanent,
+}
+
+impl fmt::Display for SlotFlag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SlotFlag::Active => "active",
+            SlotFlag::Confirmed => "confirmed",
+            SlotFlag::Pending => "pending",
+            SlotFlag::Bootable => "bootable",
+            SlotFlag::Permanent => "permanent",
+        })
+    }
+}
+
+impl ImageStateEntry {
+    /// Flags set on the slot
+    pub fn flags(&self) -> Vec<SlotFlag> {
+        [
+            (self.active, SlotFlag::Active),
+            (self.confirmed, SlotFlag::Confirmed),
+            (self.pending, SlotFlag::Pending),
+            (self.bootable, SlotFlag::Bootable),
+            (self.permanent, SlotFlag::Permanent),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| flag)
+        .collect()
+    }
+}
+
+/// What happens to each image at the next reset, given the MCUboot mode
+/// from bootloader info if known
+pub fn next_reset(state: &ImageStateRsp, mode: Option<i32>) -> Vec<String> {
+    // unknown modes aren't assumed to revert
+    let boot_mode = BootMode::from_mode(mode).ok();
+    let reverts = boot_mode.is_some_and(BootMode::reverts);
+    let mut images: Vec<u32> = state.images.iter().map(|e| e.image).collect();
+    images.sort();
+    images.dedup();
+
+    let mut notes = Vec::new();
+    for image in images {
+        let slot = |n: u32| {
+            state
+                .images
+                .iter()
+                .find(|e| e.image == image && e.slot == n)
+        };
+        let running = state
+            .images
+            .iter()
+            .find(|e| e.image == image && e.active)
+            .or_else(|| slot(0));
+        let pending = state
+            .images
+            .iter()
+            .find(|e| e.image == image && e.pending);
+
+        let note = match (pending, running) {
+            (Some(p), _) if boot_mode == Some(BootMode::Overwrite) => format!(
+                "slot {} ({}) will overwrite slot 0, without revert",
+                p.slot, p.version
+            ),
+            (Some(p), _) if p.permanent || !reverts => {
+                format!("slot {} ({}) will boot permanently", p.slot, p.version)
+            }
+            (Some(p), _) => format!(
+                "slot {} ({}) will be tested: booted once, reverted unless confirmed",
+                p.slot, p.version
+            ),
+            (None, Some(r)) if !r.confirmed && reverts => {
+                let previous = state
+                    .images
+                    .iter()
+                    .find(|e| e.image == image && e.slot != r.slot)
+                    .map_or("the previous image".to_string(), |e| e.version.clone());
+                format!(
+                    "image will revert to {}, {} is not confirmed",
+                    previous, r.version
+                )
+            }
+            (None, Some(r)) => format!("{} keeps running", r.version),
+            (None, None) => "no image to boot".to_string(),
+        };
+        notes.push(format!("image {}: {}", image, note));
+    }
+    match state.split_status {
+        Some(SplitStatus::Matching) => {
+            notes.push("split image: the application matches the loader".to_string())
+        }
+        Some(SplitStatus::NotMatching) => notes.push(
+            "split image: the application doesn't match the loader, only the loader runs"
+                .to_string(),
+        ),
+        Some(SplitStatus::NotApplicable) | None => {}
+    }
+    notes
+}
+
+/// Render the image state as a table, followed by the bootloader mode and
+/// what happens at the next reset
+pub fn format_image_state(state: &ImageStateRsp, mode: Option<i32>) -> String {
+    let mut out = format!(
+        "{:<6} {:<5} {:<16} {:<9} Flags\n",
+        "Image", "Slot", "Version", "Hash"
+    );
+    for entry in &state.images {
+        let hash = &entry.hash[..entry.hash.len().min(SHORT_HASH_LEN)];
+        let flags: Vec<String> = entry.flags().iter().map(|f| f.to_string()).collect();
+        out += &format!(
+            "{:<6} {:<5} {:<16} {:<9} {}\n",
+            entry.image,
+            entry.slot,
+            entry.version,
+            hex::encode(hash),
+            flags.join(" ")
+        );
+    }
+    if state.images.is_empty() {
+        out += "no images\n";
+    }
+    out += "\n";
+    if let Some(mode) = mode {
+        out += &format!("Bootloader: {}\n", mcuboot_mode_name(mode));
+    }
+    out += "Next reset:\n";
+    for note in next_reset(state, mode) {
+        out += &format!("  {note}\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(slot: u32, version: &str, flags: &[SlotFlag]) -> ImageStateEntry {
+        ImageStateEntry {
+            image: 0,
+            slot,
+            version: version.to_string(),
+            hash: vec![slot as u8; 32],
+            active: flags.contains(&SlotFlag::Active),
+            confirmed: flags.contains(&SlotFlag::Confirmed),
+            pending: flags.contains(&SlotFlag::Pending),
+            bootable: flags.contains(&SlotFlag::Bootable),
+            permanent: flags.contains(&SlotFlag::Permanent),
+        }
+    }
+
+    #[test]
+    fn test_next_reset() {
+        use SlotFlag::*;
+        let mut state = ImageStateRsp {
+            images: vec![
+                entry(0, "1.0.0", &[Active, Confirmed, Bootable]),
+                entry(1, "1.1.0", &[Pending, Bootable]),
+            ],
+            split_status: None,
+        };
+        assert_eq!(
+            next_reset(&state, Some(3)),
+            ["image 0: slot 1 (1.1.0) will be tested: booted once, reverted unless confirmed"]
+        );
+        assert_eq!(
+            next_reset(&state, Some(2)),
+            ["image 0: slot 1 (1.1.0) will overwrite slot 0, without revert"]
+        );
+        // a firmware loader has nothing to revert to
+        assert_eq!(
+            next_reset(&state, Some(7)),
+            ["image 0: slot 1 (1.1.0) will boot permanently"]
+        );
+
+        // after the reset the test image runs unconfirmed
+        state.images = vec![
+            entry(0, "1.1.0", &[Active, Bootable]),
+            entry(1, "1.0.0", &[Bootable]),
+        ];
+        state.split_status = Some(SplitStatus::NotMatching);
+        let notes = next_reset(&state, None);
+        assert_eq!(notes[0], "image 0: image will revert to 1.0.0, 1.1.0 is not confirmed");
+        assert!(notes[1].contains("doesn't match"));
+        assert_eq!(next_reset(&state, Some(0))[0], "image 0: 1.1.0 keeps running");
+
+        let table = format_image_state(&state, Some(3));
+        assert!(table.contains("0      0     1.1.0            00000000  active bootable\n"));
+        assert!(table.contains("Bootloader: Swap without scratch\n"));
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Targeting of uploads: which image, and so which slot, a file is written to
+//!
+//! A DFU package names the image of each file in its manifest. A firmware
+//! file goes to the image given with `--image`, else to the image of the
+//! first rule of the rules file matching its name, else to image 0. MCUboot
+//! images carry no image number of their own.
+
+use anyhow::{bail, Context, Error, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+use crate::image::upload_slot;
+use crate::mcuboot::{ImageHeader, ImageVersion};
+use crate::nmp_hdr::ImageStateRsp;
+use crate::package::UploadImage;
+
+/// Rule of a rules file: files whose name matches `pattern` go to `image`
+#[derive(Debug, Clone, Deserialize)]
+pub struct TargetRule {
+    /// Glob pattern matched against the file name, e.g. `*-net.bin`
+    pub pattern: String,
+    pub image: u8,
+}
+
+/// Rules mapping file names to image numbers, read from a JSON file such as
+/// `{"rules": [{"pattern": "*-net.bin", "image": 1}]}`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TargetRules {
+    pub rules: Vec<TargetRule>,
+}
+
+impl TargetRules {
+    /// Read the rules from a JSON file
+    pub fn load(path: &Path) -> Result<TargetRules, Error> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let rules: TargetRules = serde_json::from_slice(&data)
+            .with_context(|| format!("{}: invalid rules file", path.display()))?;
+        for rule in &rules.rules {
+            glob::Pattern::new(&rule.pattern)
+                .with_context(|| format!("{}: invalid pattern {}", path.display(), rule.pattern))?;
+        }
+        Ok(rules)
+    }
+
+    /// First rule matching the name of a file
+    pub fn find(&self, filename: &Path) -> Option<&TargetRule> {
+        let name = filename.file_name()?.to_string_lossy();
+        self.rules.iter().find(|rule| {
+            glob::Pattern::new(&rule.pattern).is_ok_and(|pattern| pattern.matches(&name))
+        })
+    }
+}
+
+/// Where the image number of an upload comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetSource {
+    /// The manifest of a DFU package
+    Manifest,
+    /// `--image`
+    Explicit,
+    /// A rule of the rules file, with its pattern
+    Rule(String),
+    /// Nothing chose the image, it is image 0
+    Default,
+}
+
+impl fmt::Display for TargetSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TargetSource::Manifest => f.write_str("from the package manifest"),
+            TargetSource::Explicit => f.write_str("from --image"),
+            TargetSource::Rule(pattern) => write!(f, "from the rule {pattern}"),
+            TargetSource::Default => f.write_str("by default"),
+        }
+    }
+}
+
+/// Image and slot a file is written to
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub file: String,
+    pub version: ImageVersion,
+    pub image: u8,
+    /// The slot the device writes, the one of the image not running
+    pub slot: u32,
+    pub source: TargetSource,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) -> image {}, slot {} ({})",
+            self.file, self.version, self.image, self.slot, self.source
+        )
+    }
+}
+
+/// Targets of an upload
+#[derive(Debug, Clone)]
+pub struct UploadPlan {
+    /// Image number of a firmware file, to pass to `upload`; packages carry
+    /// their own
+    pub image: u8,
+    pub targets: Vec<Target>,
+    /// Why the targets may be wrong, when nothing chose the image of a file
+    /// for a device with several images
+    pub ambiguous: Option<String>,
+}
+
+/// Work out which image and slot each image of a file is written to, given
+/// the image state of the device if known
+///
+/// The images of a firmware file, loaded with `upload_images`, are numbered
+/// with the chosen image.
+pub fn plan_upload(
+    filename: &Path,
+    uploads: &mut [UploadImage],
+    image: Option<u8>,
+    rules: &TargetRules,
+    state: Option<&ImageStateRsp>,
+) -> Result<UploadPlan, Error> {
+    let package = uploads.iter().any(|upload| upload.packaged);
+    let (number, source) = match (image, rules.find(filename)) {
+        (Some(_), _) if package => {
+            bail!("a DFU package names its images in the manifest, --image can't be used")
+        }
+        _ if package => (0, TargetSource::Manifest),
+        (Some(image), _) => (image, TargetSource::Explicit),
+        (None, Some(rule)) => (rule.image, TargetSource::Rule(rule.pattern.clone())),
+        (None, None) => (0, TargetSource::Default),
+    };
+
+    let mut targets = Vec::new();
+    for upload in uploads.iter_mut() {
+        if !package {
+            upload.image = number;
+        }
+        let header = ImageHeader::parse(&upload.data)?;
+        targets.push(Target {
+            file: upload.file.clone(),
+            version: header.version,
+            image: upload.image,
+            slot: upload_slot(state, upload.image),
+            source: source.clone(),
+        });
+    }
+
+    let images = state.map_or(0, |state| {
+        let mut images: Vec<u32> = state.images.iter().map(|e| e.image).collect();
+        images.sort();
+        images.dedup();
+        images.len()
+    });
+    let ambiguous = (source == TargetSource::Default && images > 1).then(|| {
+        format!(
+            "the device has {} images and nothing chose the image of {}",
+            images,
+            filename.display()
+        )
+    });
+    Ok(UploadPlan {
+        image: number,
+        targets,
+        ambiguous,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::upload_images;
+    use crate::simulator::build_image;
+
+    #[test]
+    fn test_plan_upload() {
+        let dir = std::env::temp_dir().join(format!("mcumgr-target-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // the name no longer chooses the slot
+        let path = dir.join("app-slot3.bin");
+        std::fs::write(&path, build_image((1, 2, 0, 0), &[0x22; 100])).unwrap();
+        let rules: TargetRules =
+            serde_json::from_str(r#"{"rules": [{"pattern": "*-net.bin", "image": 1}]}"#).unwrap();
+
+        let mut uploads = upload_images(&path, 0).unwrap();
+        let plan = plan_upload(&path, &mut uploads, None, &rules, None).unwrap();
+        assert_eq!(plan.image, 0);
+        assert_eq!(plan.targets[0].source, TargetSource::Default);
+        assert!(plan.ambiguous.is_none());
+        assert_eq!(
+            plan.targets[0].to_string(),
+            format!("{} (1.2.0) -> image 0, slot 1 (by default)", path.display())
+        );
+
+        let net = dir.join("radio-net.bin");
+        std::fs::copy(&path, &net).unwrap();
+        let mut uploads = upload_images(&net, 0).unwrap();
+        let plan = plan_upload(&net, &mut uploads, None, &rules, None).unwrap();
+        assert_eq!(plan.image, 1);
+        assert_eq!(uploads[0].image, 1);
+        assert_eq!(plan.targets[0].source, TargetSource::Rule("*-net.bin".into()));
+
+        let plan = plan_upload(&net, &mut uploads, Some(2), &rules, None).unwrap();
+        assert_eq!(plan.image, 2);
+        assert_eq!(plan.targets[0].source, TargetSource::Explicit);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,184 +1,203 @@
 // Copyright © 2023-2024 Vouch.io LLC, 2026 Rudis Laboratories LLC
 
-use base64::engine::{general_purpose::STANDARD, Engine};
-use byteorder::{BigEndian, ByteOrder};
-use crc16::State;
-use crc16::XMODEM;
+use anyhow::{Context, Error, Result};
+use log::debug;
 use serialport::DataBits;
 use serialport::FlowControl;
 use serialport::Parity;
 use serialport::SerialPort;
 use serialport::StopBits;
-use std::io::Cursor;
+use std::collections::VecDeque;
 use std::io::{Read, Write};
-use std::thread;
-use std::time::Duration;
-
-use crate::nmp_hdr::*;
-use crate::transfer::encode_request;
-
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::fault::{port_faults, Fault, FaultInjector};
+use crate::simulator::{error_response, shared_simulator, Simulator};
+use crate::transfer::{decode_packet, encode_request, FrameDecoder};
+
+/// Line length of the simulated device's responses
+const RESPONSE_LINELENGTH: usize = 128;
+
+/// Serial port connected to a simulated device
+///
+/// Requests written to the port are decoded from the console framing and
+/// handled by the simulator, the framed responses can be read back. Faults
+/// set with `set_port_faults` are applied to the frames.
 pub struct TestSerialPort {
-    data: Vec<u8>,
-    position: usize,
-    total_len: u32,
-    images: Vec<ImageStateEntry>,
+    simulator: Arc<Mutex<Simulator>>,
+    faults: Option<Arc<Mutex<FaultInjector>>>,
+    decoder: FrameDecoder,
+    line: Vec<u8>,
+    output: VecDeque<u8>,
+    /// delayed responses, with the time they arrive
+    delayed: Vec<(Instant, Vec<u8>)>,
+    /// reordered response, sent after the next one
+    held: Option<Vec<u8>>,
+    timeout: Duration,
 }
 
 impl TestSerialPort {
-    pub fn new() -> TestSerialPort {
+    /// Open the port of the shared simulator with the given name
+    pub fn new(name: &str) -> TestSerialPort {
+        let mut port = Self::with_simulator(shared_simulator(name));
+        port.faults = port_faults(name);
+        port
+    }
+
+    pub fn with_simulator(simulator: Arc<Mutex<Simulator>>) -> TestSerialPort {
         TestSerialPort {
-            data: Vec::new(),
-            position: 0,
-            total_len: 0,
-            images: vec![ImageStateEntry {
-                image: 1,
-                slot: 0,
-                version: "1.0.0".to_string(),
-                hash: hex::decode(
-                    "61ddbce8f52e53715f57b360a5af0700ba17122114c94a11b86d9097f7e09cc3",
-                )
-                .unwrap(),
-                bootable: false,
-                pending: false,
-                confirmed: false,
-                active: true,
-                permanent: false,
-            }],
+            simulator,
+            faults: None,
+            decoder: FrameDecoder::new(),
+            line: Vec::new(),
+            output: VecDeque::new(),
+            delayed: Vec::new(),
+            held: None,
+            timeout: Duration::from_secs(1),
         }
     }
-}
 
-impl Read for TestSerialPort {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let available_data = &self.data[self.position..];
-        let bytes_to_read = std::cmp::min(available_data.len(), buf.len());
-        buf[..bytes_to_read].copy_from_slice(&available_data[..bytes_to_read]);
-        self.position += bytes_to_read;
-        Ok(bytes_to_read)
+    fn handle_line(&mut self, line: &[u8]) {
+        let packet = match self.decoder.push_line(line) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("test port: dropped frame: {}", e);
+                return;
+            }
+        };
+        let faults = match (&self.faults, decode_packet(&packet)) {
+            (Some(faults), Ok((header, body))) => {
+                faults.lock().unwrap().faults(header.group, header.id, body)
+            }
+            _ => Vec::new(),
+        };
+
+        let response = {
+            let mut simulator = self.simulator.lock().unwrap();
+            if faults.contains(&Fault::Reset) {
+                simulator.reboot();
+                return;
+            }
+            if faults.contains(&Fault::Drop) {
+                return;
+            }
+            let error_rc = faults.iter().find_map(|f| match f {
+                Fault::ErrorRc { rc } => Some(*rc),
+                _ => None,
+            });
+            match error_rc {
+                Some(rc) => error_response(&packet, rc),
+                None => simulator.handle_packet(&packet),
+            }
+        };
+        let mut response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("test port: dropped packet: {}", e);
+                return;
+            }
+        };
+
+        if faults.contains(&Fault::WrongSeq) {
+            response[6] = response[6].wrapping_add(1);
+        }
+        let (header, body) = decode_packet(&response).unwrap();
+        let (mut framed, _) = encode_request(
+            RESPONSE_LINELENGTH,
+            header.op,
+            header.group,
+            header.id,
+            &body.to_vec(),
+            header.seq,
+        )
+        .unwrap();
+        if faults.contains(&Fault::CorruptCrc) {
+            // change one base64 character of the first line
+            framed[7] = if framed[7] == b'A' { b'B' } else { b'A' };
+        }
+        if faults.contains(&Fault::TruncateBase64) {
+            let end = framed.len() - 1;
+            framed.drain(end - 3..end);
+        }
+
+        let delay: u64 = faults
+            .iter()
+            .map(|f| match f {
+                Fault::Delay { ms } => *ms,
+                _ => 0,
+            })
+            .sum();
+        let arrival = Instant::now() + Duration::from_millis(delay);
+        if faults.contains(&Fault::Reorder) && self.held.is_none() {
+            self.held = Some(framed);
+            return;
+        }
+        if faults.contains(&Fault::Duplicate) {
+            self.send(arrival, framed.clone());
+        }
+        self.send(arrival, framed);
+        if let Some(held) = self.held.take() {
+            self.send(arrival, held);
+        }
     }
-}
 
-impl Write for TestSerialPort {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut cursor = Cursor::new(buf);
-        let mut received_data = Vec::new();
-
-        while cursor.position() < buf.len() as u64 {
-            let _marker = byteorder::ReadBytesExt::read_u16::<BigEndian>(&mut cursor).unwrap();
-            let base64_end_pos = buf[cursor.position() as usize..]
-                .iter()
-                .position(|&x| x == b'\n')
-                .unwrap()
-                + cursor.position() as usize;
-            let base64_data = &buf[cursor.position() as usize..base64_end_pos];
-            let binary_data = STANDARD.decode(base64_data).unwrap();
-            cursor.set_position(base64_end_pos as u64 + 1);
-
-            received_data.extend_from_slice(&binary_data);
+    fn send(&mut self, arrival: Instant, framed: Vec<u8>) {
+        if arrival <= Instant::now() && self.delayed.is_empty() {
+            self.output.extend(framed);
+        } else {
+            self.delayed.push((arrival, framed));
         }
+    }
 
-        let data = received_data[2..received_data.len() - 2].to_vec();
-        let read_checksum = BigEndian::read_u16(&received_data[received_data.len() - 2..]);
-        let calculated_checksum = State::<XMODEM>::calculate(&data);
-        if read_checksum != calculated_checksum {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "wrong checksum",
-            ));
+    /// Move the delayed responses which have arrived to the output
+    fn receive_delayed(&mut self) {
+        let now = Instant::now();
+        while !self.delayed.is_empty() && self.delayed[0].0 <= now {
+            let (_, framed) = self.delayed.remove(0);
+            self.output.extend(framed);
         }
+    }
+}
 
-        let mut request_cursor = Cursor::new(&data);
-        let request_header = NmpHdr::deserialize(&mut request_cursor).unwrap();
-        // let header_len: usize = 8;
-        // let request_body = data[header_len..].to_vec();
-
-        match request_header.id {
-            id if id == NmpIdImage::State as u8 => {
-                if request_header.op == NmpOp::Read {
-                    let state_response = ImageStateRsp {
-                        images: self.images.clone(),
-                        split_status: None,
-                    };
-                    let body = serde_cbor::to_vec(&state_response).unwrap();
-                    let (encoded_response, _) = encode_request(
-                        100,
-                        NmpOp::ReadRsp,
-                        NmpGroup::Image,
-                        NmpIdImage::State,
-                        &body,
-                        request_header.seq,
-                    )
-                    .unwrap();
-                    self.data.extend_from_slice(&encoded_response);
-                } else if request_header.op == NmpOp::Write {
-                    // let request: ImageStateReq = serde_cbor::from_slice(request_body.as_slice()).unwrap();
-                    let body = serde_cbor::to_vec(&serde_cbor::Value::Null).unwrap();
-                    let (encoded_response, _) = encode_request(
-                        100,
-                        NmpOp::WriteRsp,
-                        NmpGroup::Image,
-                        NmpIdImage::Erase,
-                        &body,
-                        request_header.seq,
-                    )
-                    .unwrap();
-                    self.data.extend_from_slice(&encoded_response);
-                }
+impl Read for TestSerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.receive_delayed();
+        if self.output.is_empty() {
+            if let Some((arrival, _)) = self.delayed.first() {
+                let wait = arrival.saturating_duration_since(Instant::now());
+                thread::sleep(wait.min(self.timeout));
+                self.receive_delayed();
             }
-            id if id == NmpIdImage::Upload as u8 => {
-                let body_start = request_cursor.position() as usize;
-                let body_end = data.len();
-                let body = &data[body_start..body_end];
-
-                let image_upload_req: ImageUploadReq = serde_cbor::from_slice(body).unwrap();
-                if image_upload_req.off == 0 {
-                    self.total_len = image_upload_req.len.unwrap();
-                }
-                let chunk_len = image_upload_req.data.len() as u32;
-                let mut off_value = image_upload_req.off + chunk_len;
-                if off_value > self.total_len {
-                    off_value = self.total_len;
-                }
+        }
+        if self.output.is_empty() {
+            // nothing was answered, behave like a serial port timeout
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+        let len = self.output.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.output.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
 
-                let mut response_map = std::collections::BTreeMap::new();
-                response_map.insert("rc", 0);
-                response_map.insert("off", off_value);
-
-                let cbor_body = serde_cbor::to_vec(&response_map).unwrap();
-                let (encoded_response, _) = encode_request(
-                    4096,
-                    NmpOp::WriteRsp,
-                    NmpGroup::Image,
-                    NmpIdImage::Upload,
-                    &cbor_body,
-                    request_header.seq,
-                )
-                .unwrap();
-                self.data.extend_from_slice(&encoded_response);
-            }
-            id if id == NmpIdImage::Erase as u8 => {
-                // let request: ImageEraseReq = serde_cbor::from_slice(request_body.as_slice()).unwrap();
-                let body = serde_cbor::to_vec(&serde_cbor::Value::Null).unwrap();
-                let (encoded_response, _) = encode_request(
-                    100,
-                    NmpOp::WriteRsp,
-                    NmpGroup::Image,
-                    NmpIdImage::Erase,
-                    &body,
-                    request_header.seq,
-                )
-                .unwrap();
-                self.data.extend_from_slice(&encoded_response);
-            }
-            _ => {
-                // Handle other cases or return an error
+impl Write for TestSerialPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &b in buf {
+            if b == b'\n' {
+                let line = std::mem::take(&mut self.line);
+                self.handle_line(&line);
+            } else {
+                self.line.push(b);
             }
         }
-
-        // add some delay for simulating real transfers
-        // simulating 10 kB/s
-        thread::sleep(Duration::from_millis((buf.len() / 10) as u64));
-
         Ok(buf.len())
     }
 
@@ -213,7 +232,7 @@ impl SerialPort for TestSerialPort {
     }
 
     fn timeout(&self) -> Duration {
-        Duration::from_secs(1)
+        self.timeout
     }
 
     fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
@@ -239,7 +258,8 @@ impl SerialPort for TestSerialPort {
         Ok(())
     }
 
-    fn set_timeout(&mut self, _timeout: Duration) -> serialport::Result<()> {
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
         Ok(())
     }
 
@@ -268,7 +288,14 @@ impl SerialPort for TestSerialPort {
     }
 
     fn bytes_to_read(&self) -> serialport::Result<u32> {
-        Ok(0)
+        let now = Instant::now();
+        let arrived: usize = self
+            .delayed
+            .iter()
+            .take_while(|(arrival, _)| *arrival <= now)
+            .map(|(_, framed)| framed.len())
+            .sum();
+        Ok((self.output.len() + arrived) as u32)
     }
 
     fn bytes_to_write(&self) -> serialport::Result<u32> {
@@ -280,7 +307,7 @@ impl SerialPort for TestSerialPort {
     }
 
     fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
-        unimplemented!()
+        Ok(Box::new(TestSerialPort::with_simulator(self.simulator.clone())))
     }
 
     fn set_break(&self) -> serialport::Result<()> {
@@ -291,3 +318,70 @@ impl SerialPort for TestSerialPort {
         Ok(())
     }
 }
+
+/// Simulated serial port served on a pseudo-terminal, until dropped
+///
+/// Other programs open the terminal like the console of a device, e.g.
+/// `mcumgr-client -d /dev/pts/3 list`.
+#[cfg(unix)]
+pub struct PtyServer {
+    path: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(unix)]
+impl PtyServer {
+    /// Serve on a new pseudo-terminal
+    pub fn spawn(simulator: Arc<Mutex<Simulator>>) -> Result<Self, Error> {
+        let (mut master, terminal) =
+            serialport::TTYPort::pair().context("failed to open a pseudo-terminal")?;
+        let path = terminal.name().context("pseudo-terminal without name")?;
+        master.set_timeout(Duration::from_millis(50))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            // kept open, so that the terminal survives clients closing it
+            let _terminal = terminal;
+            let mut port = TestSerialPort::with_simulator(simulator);
+            let mut buf = [0u8; 1024];
+            while !thread_stop.load(Ordering::Relaxed) {
+                let len = match master.read(&mut buf) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+                    Err(e) => {
+                        debug!("pty: read failed: {}", e);
+                        thread::sleep(Duration::from_millis(50));
+                        continue;
+                    }
+                };
+                let _ = port.write_all(&buf[..len]);
+                while let Ok(len) = port.read(&mut buf) {
+                    if let Err(e) = master.write_all(&buf[..len]) {
+                        debug!("pty: write failed: {}", e);
+                    }
+                }
+            }
+        });
+        Ok(PtyServer {
+            path,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Path of the terminal to open, like `/dev/pts/3`
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl Drop for PtyServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
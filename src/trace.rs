@@ -0,0 +1,267 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+use anyhow::{Error, Result};
+use log::info;
+use serde_cbor::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::nmp_hdr::*;
+use crate::transfer::{decode_packet, Direction, PacketTap, Transport};
+
+/// Byte strings longer than this are elided in the trace output
+const MAX_BYTES_SHOWN: usize = 16;
+
+/// Format a CBOR value in compact diagnostic notation (RFC 8949, section 8)
+///
+/// Byte strings longer than 16 bytes are shortened to their first and last
+/// bytes with the total length, e.g. `h'00010203..fcfdfeff'(512)`.
+pub fn cbor_diag(value: &Value) -> String {
+    let mut out = String::new();
+    write_diag(&mut out, value);
+    out
+}
+
+fn write_diag(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Integer(i) => out.push_str(&i.to_string()),
+        Value::Float(f) => out.push_str(&format!("{f:?}")),
+        Value::Bytes(bytes) => {
+            if bytes.len() > MAX_BYTES_SHOWN {
+                let half = MAX_BYTES_SHOWN / 4;
+                out.push_str(&format!(
+                    "h'{}..{}'({})",
+                    hex::encode(&bytes[..half]),
+                    hex::encode(&bytes[bytes.len() - half..]),
+                    bytes.len()
+                ));
+            } else {
+                out.push_str(&format!("h'{}'", hex::encode(bytes)));
+            }
+        }
+        Value::Text(text) => out.push_str(&format!("{text:?}")),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_diag(out, item);
+            }
+            out.push(']');
+        }
+        Value::Map(entries) => {
+            out.push('{');
+            for (i, (key, val)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_diag(out, key);
+                out.push_str(": ");
+                write_diag(out, val);
+            }
+            out.push('}');
+        }
+        Value::Tag(tag, inner) => {
+            out.push_str(&format!("{tag}("));
+            write_diag(out, inner);
+            out.push(')');
+        }
+        _ => out.push_str("undefined"),
+    }
+}
+
+/// Format a request body for the trace, falling back to hex if it is not CBOR
+fn body_diag(body: &[u8]) -> String {
+    if body.is_empty() {
+        return String::new();
+    }
+    match serde_cbor::from_slice::<Value>(body) {
+        Ok(value) => cbor_diag(&value),
+        Err(_) => cbor_diag(&Value::Bytes(body.to_vec())),
+    }
+}
+
+/// Format the trace line of a request packet (header and CBOR body)
+fn request_line(packet: &[u8]) -> String {
+    match decode_packet(packet) {
+        Ok((header, body)) => format!(
+            "-> {} seq={} op={:?} len={} {}",
+            command_name(header.group, header.id),
+            header.seq,
+            header.op,
+            body.len(),
+            body_diag(body)
+        ),
+        Err(_) => format!("-> invalid packet {}", hex::encode(packet)),
+    }
+}
+
+/// Transport wrapper printing one line per request and response
+///
+/// The request is printed from the packet tap of the inner transport, as it
+/// is sent, so it shows the sequence number even if no response arrives.
+///
+/// ```text
+/// -> Image/Upload seq=42 op=Write len=578 {"len": 91204, "off": 0, "sha": h'4a1c..22f1'(32), ...}
+/// <- Image/Upload seq=42 op=WriteRsp len=12 rtt=23.4ms {"rc": 0, "off": 512}
+/// ```
+pub struct TraceTransport<T: Transport> {
+    inner: T,
+    /// Whether the inner transport passes the requests to our tap
+    tapped: bool,
+    /// Set by our tap when it printed a request
+    sent: Arc<AtomicBool>,
+    /// Tap set by an outer wrapper, called after ours
+    outer_tap: Arc<Mutex<Option<PacketTap>>>,
+}
+
+impl<T: Transport> TraceTransport<T> {
+    pub fn new(mut inner: T) -> Self {
+        let outer_tap = Arc::new(Mutex::new(None::<PacketTap>));
+        let chained = outer_tap.clone();
+        let sent = Arc::new(AtomicBool::new(false));
+        let printed = sent.clone();
+        let tapped = inner.set_tap(Box::new(move |direction, packet| {
+            if direction == Direction::Outbound {
+                info!("{}", request_line(packet));
+                printed.store(true, Ordering::Relaxed);
+            }
+            if let Some(tap) = &mut *chained.lock().unwrap() {
+                tap(direction, packet);
+            }
+        }));
+        TraceTransport {
+            inner,
+            tapped,
+            sent,
+            outer_tap,
+        }
+    }
+
+    /// Get the wrapped transport
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for TraceTransport<T> {
+    fn transceive(
+        &mut self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        // without raw packets, e.g. when replaying, there is no sequence
+        // number to show before the response
+        let name = command_name(group, id);
+        if !self.tapped {
+            info!("-> {} op={:?} len={} {}", name, op, body.len(), body_diag(body));
+        }
+        self.sent.store(false, Ordering::Relaxed);
+        let start = Instant::now();
+        let result = self.inner.transceive(op, group, id, body);
+        let rtt_ms = start.elapsed().as_secs_f64() * 1000.0;
+        if self.tapped && !self.sent.load(Ordering::Relaxed) {
+            // e.g. dropped by fault injection before reaching the link
+            info!("-> {} op={:?} len={} {} (not sent)", name, op, body.len(), body_diag(body));
+        }
+
+        match &result {
+            Ok((header, value)) => {
+                let response_name = command_name(header.group, header.id);
+                info!(
+                    "<- {} seq={} op={:?} len={} rtt={:.1}ms {}",
+                    response_name,
+                    header.seq,
+                    header.op,
+                    header.len,
+                    rtt_ms,
+                    cbor_diag(value)
+                );
+            }
+            Err(e) => info!("<- {} failed after {:.1}ms: {}", name, rtt_ms, e),
+        }
+
+        result
+    }
+
+    fn set_timeout(&mut self, timeout_ms: u32) -> Result<(), Error> {
+        self.inner.set_timeout(timeout_ms)
+    }
+
+    fn timeout(&self) -> Option<u32> {
+        self.inner.timeout()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn linelength(&self) -> usize {
+        self.inner.linelength()
+    }
+
+    fn set_tap(&mut self, tap: PacketTap) -> bool {
+        if !self.tapped {
+            return false;
+        }
+        *self.outer_tap.lock().unwrap() = Some(tap);
+        true
+    }
+
+    fn set_frame_tap(&mut self, tap: PacketTap) -> bool {
+        self.inner.set_frame_tap(tap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_cbor_diag() {
+        let mut map = BTreeMap::new();
+        map.insert(Value::Text("rc".to_string()), Value::Integer(-2));
+        map.insert(Value::Text("ok".to_string()), Value::Bool(true));
+        map.insert(Value::Text("short".to_string()), Value::Bytes(vec![0xde, 0xad]));
+        map.insert(Value::Text("data".to_string()), Value::Bytes((0..=255).collect()));
+        map.insert(
+            Value::Text("list".to_string()),
+            Value::Array(vec![Value::Null, Value::Text("a\"b".to_string())]),
+        );
+        assert_eq!(
+            cbor_diag(&Value::Map(map)),
+            r#"{"ok": true, "rc": -2, "data": h'00010203..fcfdfeff'(256), "list": [null, "a\"b"], "short": h'dead'}"#
+        );
+        assert_eq!(
+            cbor_diag(&Value::Tag(24, Box::new(Value::Float(1.5)))),
+            "24(1.5)"
+        );
+    }
+
+    #[test]
+    fn test_request_line() {
+        let mut header = NmpHdr::new_req(NmpOp::Write, NmpGroup::Image, NmpIdImage::Upload);
+        header.seq = 42;
+        header.len = 4;
+        let mut packet = header.serialize().unwrap();
+        packet.extend_from_slice(&[0xa1, 0x61, 0x61, 0x01]);
+        assert_eq!(
+            request_line(&packet),
+            r#"-> Image/Upload seq=42 op=Write len=4 {"a": 1}"#
+        );
+    }
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(command_name(NmpGroup::Image, 1), "Image/Upload");
+        assert_eq!(command_name(NmpGroup::Default, 8), "Default/BootloaderInfo");
+        assert_eq!(command_name(NmpGroup::Fs, 42), "Fs/42");
+    }
+}
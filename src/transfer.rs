@@ -7,6 +7,7 @@ use crc16::*;
 use lazy_static::lazy_static;
 use log::debug;
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 use serialport::SerialPort;
 use std::cmp::min;
 use std::io::Cursor;
@@ -15,8 +16,51 @@ use std::sync::atomic::{AtomicU8, Ordering};
 use std::time::Duration;
 
 use crate::nmp_hdr::*;
+#[cfg(any(test, feature = "sim"))]
 use crate::test_serial_port::TestSerialPort;
 
+/// Direction of an SMP packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Request sent to the device
+    Outbound,
+    /// Response received from the device
+    Inbound,
+}
+
+/// Receiver of the SMP packets (header and CBOR body) exactly as sent to and
+/// received from the device, see `Transport::set_tap`
+pub type PacketTap = Box<dyn FnMut(Direction, &[u8]) + Send>;
+
+/// Error of a lost or damaged frame, attached to the returned errors
+///
+/// Find it with `downcast_ref` on the error chain, see `transport_error`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportError {
+    /// No response arrived in time
+    Timeout,
+    /// The frame checksum doesn't match its data
+    Crc,
+    /// The frame has a wrong length or unexpected bytes
+    Framing,
+    /// The response has another sequence number than the request
+    Seq,
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TransportError::Timeout => "Operation timed out",
+            TransportError::Crc => "wrong checksum",
+            TransportError::Framing => "invalid frame",
+            TransportError::Seq => "wrong sequence number",
+        })
+    }
+}
+
+impl std::error::Error for TransportError {}
+
 /// Trait for SMP transport implementations
 pub trait Transport {
     /// Send an SMP request and receive a response
@@ -31,11 +75,68 @@ pub trait Transport {
     /// Set the timeout for subsequent operations
     fn set_timeout(&mut self, timeout_ms: u32) -> Result<(), Error>;
 
+    /// Get the timeout for subsequent operations in ms, if the transport has
+    /// one, e.g. to restore it after `set_timeout`
+    fn timeout(&self) -> Option<u32> {
+        None
+    }
+
     /// Get the MTU for this transport
     fn mtu(&self) -> usize;
 
     /// Get the line length for this transport (for serial framing)
     fn linelength(&self) -> usize;
+
+    /// Pass the packets sent and received from now on to a tap, before they
+    /// are decoded; returns false if the transport has no raw packets
+    fn set_tap(&mut self, tap: PacketTap) -> bool {
+        let _ = tap;
+        false
+    }
+
+    /// Pass the bytes written to and read from the link from now on to a
+    /// tap, with the console framing of the packets (base64 lines and CRC);
+    /// returns false if the transport sends the packets unframed
+    fn set_frame_tap(&mut self, tap: PacketTap) -> bool {
+        let _ = tap;
+        false
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn transceive(
+        &mut self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: u8,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        (**self).transceive(op, group, id, body)
+    }
+
+    fn set_timeout(&mut self, timeout_ms: u32) -> Result<(), Error> {
+        (**self).set_timeout(timeout_ms)
+    }
+
+    fn timeout(&self) -> Option<u32> {
+        (**self).timeout()
+    }
+
+    fn mtu(&self) -> usize {
+        (**self).mtu()
+    }
+
+    fn linelength(&self) -> usize {
+        (**self).linelength()
+    }
+
+    fn set_tap(&mut self, tap: PacketTap) -> bool {
+        (**self).set_tap(tap)
+    }
+
+    fn set_frame_tap(&mut self, tap: PacketTap) -> bool {
+        (**self).set_frame_tap(tap)
+    }
 }
 
 /// Connection specification - either serial or UDP
@@ -106,6 +207,8 @@ impl Default for UdpSpecs {
 pub struct SerialTransport {
     port: Box<dyn SerialPort>,
     specs: SerialSpecs,
+    tap: Option<PacketTap>,
+    frame_tap: Option<PacketTap>,
 }
 
 impl SerialTransport {
@@ -122,6 +225,8 @@ impl SerialTransport {
                 mtu: specs.mtu,
                 baudrate: specs.baudrate,
             },
+            tap: None,
+            frame_tap: None,
         })
     }
 }
@@ -137,28 +242,29 @@ impl Transport for SerialTransport {
         let seq_id = next_seq_id();
         let body_vec = body.to_vec();
 
-        // Create a temporary NmpId wrapper
-        struct TempId(u8);
-        impl NmpId for TempId {
-            fn to_u8(&self) -> u8 {
-                self.0
-            }
-        }
-
         let (data, request_header) = encode_request(
             self.specs.linelength,
             op,
             group,
-            TempId(id),
+            id,
             &body_vec,
             seq_id,
         )?;
 
-        let (response_header, response_body) = transceive(&mut *self.port, &data)?;
+        if let Some(tap) = &mut self.tap {
+            let mut packet = request_header.serialize()?;
+            packet.extend_from_slice(body);
+            tap(Direction::Outbound, &packet);
+        }
+        let packet = transceive_packet(&mut *self.port, &data, self.frame_tap.as_mut())?;
+        if let Some(tap) = &mut self.tap {
+            tap(Direction::Inbound, &packet);
+        }
+        let (response_header, response_body) = decode_response(packet)?;
 
         // Verify sequence id
         if response_header.seq != request_header.seq {
-            bail!("wrong sequence number");
+            bail!(TransportError::Seq);
         }
 
         // Verify response type
@@ -181,6 +287,10 @@ impl Transport for SerialTransport {
         Ok(())
     }
 
+    fn timeout(&self) -> Option<u32> {
+        Some(self.port.timeout().as_millis() as u32)
+    }
+
     fn mtu(&self) -> usize {
         self.specs.mtu
     }
@@ -188,6 +298,16 @@ impl Transport for SerialTransport {
     fn linelength(&self) -> usize {
         self.specs.linelength
     }
+
+    fn set_tap(&mut self, tap: PacketTap) -> bool {
+        self.tap = Some(tap);
+        true
+    }
+
+    fn set_frame_tap(&mut self, tap: PacketTap) -> bool {
+        self.frame_tap = Some(tap);
+        true
+    }
 }
 
 /// UDP transport for SMP over network
@@ -196,6 +316,7 @@ pub struct UdpTransport {
     addr: SocketAddr,
     seq: u8,
     mtu: usize,
+    tap: Option<PacketTap>,
 }
 
 impl UdpTransport {
@@ -223,6 +344,7 @@ impl UdpTransport {
             addr,
             seq: 0,
             mtu: config.mtu,
+            tap: None,
         })
     }
 
@@ -313,35 +435,45 @@ impl Transport for UdpTransport {
         debug!("UDP TX: {} bytes to {}", packet.len(), self.addr);
         debug!("UDP TX header: {:02x?}", &header);
 
+        if let Some(tap) = &mut self.tap {
+            tap(Direction::Outbound, &packet);
+        }
+
         // Send packet
         self.socket
             .send_to(&packet, self.addr)
             .with_context(|| "Failed to send UDP packet")?;
 
-        // Receive response
+        // Receive response, skipping late responses to earlier requests
         let mut buf = [0u8; 4096];
-        let (len, _src) = self.socket
-            .recv_from(&mut buf)
-            .with_context(|| "Failed to receive UDP response")?;
-
-        debug!("UDP RX: {} bytes", len);
+        let (len, response_header) = loop {
+            let (len, _src) = self.socket
+                .recv_from(&mut buf)
+                .with_context(|| "Failed to receive UDP response")?;
+
+            debug!("UDP RX: {} bytes", len);
+            if let Some(tap) = &mut self.tap {
+                tap(Direction::Inbound, &buf[..len]);
+            }
 
-        if len < 8 {
-            bail!("Response too short: {} bytes", len);
-        }
+            if len < 8 {
+                bail!("Response too short: {} bytes", len);
+            }
 
-        // Parse header
-        let response_header = self.decode_header(&buf[..len])?;
-        debug!("UDP RX header: {:?}", response_header);
+            // Parse header
+            let response_header = self.decode_header(&buf[..len])?;
+            debug!("UDP RX header: {:?}", response_header);
 
-        // Verify sequence number
-        if response_header.seq != seq {
-            bail!(
+            // Verify sequence number
+            if response_header.seq == seq {
+                break (len, response_header);
+            }
+            debug!(
                 "Sequence mismatch: expected {}, got {}",
                 seq,
                 response_header.seq
             );
-        }
+        };
 
         // Verify response type
         let expected_op_type = match op {
@@ -375,6 +507,11 @@ impl Transport for UdpTransport {
         Ok(())
     }
 
+    fn timeout(&self) -> Option<u32> {
+        let timeout = self.socket.read_timeout().ok()??;
+        Some(timeout.as_millis() as u32)
+    }
+
     fn mtu(&self) -> usize {
         self.mtu
     }
@@ -383,31 +520,78 @@ impl Transport for UdpTransport {
         // Not used for UDP, but return a reasonable value
         self.mtu
     }
+
+    fn set_tap(&mut self, tap: PacketTap) -> bool {
+        self.tap = Some(tap);
+        true
+    }
 }
 
-fn read_byte(port: &mut dyn SerialPort) -> Result<u8, Error> {
+/// Read a byte, keeping it in `raw`
+fn read_byte(port: &mut dyn SerialPort, raw: &mut Vec<u8>) -> Result<u8, Error> {
     let mut byte = [0u8];
     port.read_exact(&mut byte)?;
+    raw.push(byte[0]);
     Ok(byte[0])
 }
 
-fn expect_byte(port: &mut dyn SerialPort, b: u8) -> Result<(), Error> {
-    let read = read_byte(port)?;
+fn expect_byte(port: &mut dyn SerialPort, raw: &mut Vec<u8>, b: u8) -> Result<(), Error> {
+    let read = read_byte(port, raw)?;
     if read != b {
-        bail!("read error, expected: {}, read: {}", b, read);
+        return Err(Error::new(TransportError::Framing)
+            .context(format!("read error, expected: {}, read: {}", b, read)));
     }
     Ok(())
 }
 
+/// Get the kind of lost or damaged frame causing an error
+///
+/// Timeouts of the port or socket are `Timeout`, base64 decoding errors are
+/// `Framing`. Other errors, e.g. error codes of the device, give `None`.
+pub fn transport_error(e: &Error) -> Option<TransportError> {
+    e.chain().find_map(|cause| {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            return match io.kind() {
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
+                    Some(TransportError::Timeout)
+                }
+                _ => None,
+            };
+        }
+        if cause.downcast_ref::<base64::DecodeError>().is_some() {
+            return Some(TransportError::Framing);
+        }
+        cause.downcast_ref::<TransportError>().copied()
+    })
+}
+
+/// Check if an error is caused by a lost or damaged frame
+///
+/// These errors are transient, the request can be sent again: timeouts,
+/// frames failing the length, checksum or base64 decoding, and responses
+/// with another sequence number.
+pub fn is_transient_error(e: &Error) -> bool {
+    transport_error(e).is_some()
+}
+
+/// Open the serial port of the specs
+///
+/// With the `sim` feature, the device `test` is a simulated device,
+/// `test:<name>` selects one of several independent simulated devices.
 pub fn open_port(specs: &SerialSpecs) -> Result<Box<dyn SerialPort>, Error> {
-    if specs.device.to_lowercase() == "test" {
-        Ok(Box::new(TestSerialPort::new()))
-    } else {
-        serialport::new(&specs.device, specs.baudrate)
-            .timeout(Duration::from_secs(specs.initial_timeout_s as u64))
-            .open()
-            .with_context(|| format!("failed to open serial port {}", &specs.device))
+    #[cfg(any(test, feature = "sim"))]
+    {
+        let device = specs.device.to_lowercase();
+        if device == "test" {
+            return Ok(Box::new(TestSerialPort::new("")));
+        } else if let Some(name) = device.strip_prefix("test:") {
+            return Ok(Box::new(TestSerialPort::new(name)));
+        }
     }
+    serialport::new(&specs.device, specs.baudrate)
+        .timeout(Duration::from_secs(specs.initial_timeout_s as u64))
+        .open()
+        .with_context(|| format!("failed to open serial port {}", &specs.device))
 }
 
 // thread-safe counter, initialized with a random value on first call
@@ -474,19 +658,116 @@ pub fn encode_request(
     Ok((data, request_header))
 }
 
+/// Incremental decoder for the console framing of SMP packets
+///
+/// The first line of a packet starts with the bytes 6 9, continuation lines
+/// with 4 20. Other lines, e.g. console log output, are ignored.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    base64: Vec<u8>,
+    in_packet: bool,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed one line (without the newline) to the decoder
+    ///
+    /// Returns the packet (header and CBOR body) when its last line was
+    /// received, after verifying the length and the CRC.
+    pub fn push_line(&mut self, line: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(&[6, 9]) {
+            if self.in_packet {
+                debug!("incomplete packet dropped");
+            }
+            self.base64 = line[2..].to_vec();
+            self.in_packet = true;
+        } else if line.starts_with(&[4, 20]) && self.in_packet {
+            self.base64.extend_from_slice(&line[2..]);
+        } else {
+            return Ok(None);
+        }
+
+        // lines can split the base64 data anywhere, decode only full quads
+        let complete = self.base64.len() / 4 * 4;
+        let decoded = general_purpose::STANDARD.decode(&self.base64[..complete])?;
+        if decoded.len() < 2 {
+            return Ok(None);
+        }
+        let len = BigEndian::read_u16(&decoded) as usize;
+        if decoded.len() - 2 < len {
+            return Ok(None);
+        }
+        self.in_packet = false;
+        self.base64.clear();
+        if decoded.len() - 2 != len || len < 2 {
+            return Err(Error::new(TransportError::Framing).context("wrong chunk length"));
+        }
+
+        let data = decoded[2..decoded.len() - 2].to_vec();
+        let read_checksum = BigEndian::read_u16(&decoded[decoded.len() - 2..]);
+        if read_checksum != State::<XMODEM>::calculate(&data) {
+            bail!(TransportError::Crc);
+        }
+
+        Ok(Some(data))
+    }
+}
+
+/// Split a packet into header and CBOR body
+pub fn decode_packet(packet: &[u8]) -> Result<(NmpHdr, &[u8]), Error> {
+    let header = NmpHdr::parse(packet)?;
+    Ok((header, &packet[8..]))
+}
+
 pub fn transceive(
     port: &mut dyn SerialPort,
     data: &[u8],
 ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    decode_response(transceive_packet(port, data, None)?)
+}
+
+/// Send an encoded request and read the response packet (header and CBOR
+/// body), verifying its length and checksum
+///
+/// The frame tap gets the bytes as written and read, including the stale
+/// bytes discarded before writing and a partial response on errors.
+fn transceive_packet(
+    port: &mut dyn SerialPort,
+    data: &[u8],
+    mut frame_tap: Option<&mut PacketTap>,
+) -> Result<Vec<u8>, Error> {
     // empty input buffer
+    let mut stale = Vec::new();
     let to_read = port.bytes_to_read()?;
     for _ in 0..to_read {
-        read_byte(&mut *port)?;
+        read_byte(&mut *port, &mut stale)?;
+    }
+    if let Some(tap) = &mut frame_tap {
+        if !stale.is_empty() {
+            tap(Direction::Inbound, &stale);
+        }
+        tap(Direction::Outbound, data);
     }
 
     // write request
     port.write_all(data)?;
 
+    let mut raw = Vec::new();
+    let result = read_packet(port, &mut raw);
+    if let Some(tap) = &mut frame_tap {
+        if !raw.is_empty() {
+            tap(Direction::Inbound, &raw);
+        }
+    }
+    result
+}
+
+/// Read a response packet, keeping the bytes read in `raw`
+fn read_packet(port: &mut dyn SerialPort, raw: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
     // read result
     let mut bytes_read = 0;
     let mut expected_len = 0;
@@ -494,16 +775,16 @@ pub fn transceive(
     loop {
         // first wait for the chunk start marker
         if bytes_read == 0 {
-            expect_byte(&mut *port, 6)?;
-            expect_byte(&mut *port, 9)?;
+            expect_byte(&mut *port, raw, 6)?;
+            expect_byte(&mut *port, raw, 9)?;
         } else {
-            expect_byte(&mut *port, 4)?;
-            expect_byte(&mut *port, 20)?;
+            expect_byte(&mut *port, raw, 4)?;
+            expect_byte(&mut *port, raw, 20)?;
         }
 
         // next read until newline
         loop {
-            let b = read_byte(&mut *port)?;
+            let b = read_byte(&mut *port, raw)?;
             if b == 0xa {
                 break;
             } else {
@@ -535,7 +816,7 @@ pub fn transceive(
     // verify length: must be the decoded length, minus the 2 bytes to encode the length
     let len = BigEndian::read_u16(&decoded) as usize;
     if len != decoded.len() - 2 {
-        bail!("wrong chunk length");
+        return Err(Error::new(TransportError::Framing).context("wrong chunk length"));
     }
 
     // verify checksum
@@ -543,9 +824,14 @@ pub fn transceive(
     let read_checksum = BigEndian::read_u16(&decoded[decoded.len() - 2..]);
     let calculated_checksum = State::<XMODEM>::calculate(&data);
     if read_checksum != calculated_checksum {
-        bail!("wrong checksum");
+        bail!(TransportError::Crc);
     }
 
+    Ok(data)
+}
+
+/// Decode the header and CBOR body of a response packet
+fn decode_response(data: Vec<u8>) -> Result<(NmpHdr, serde_cbor::Value), Error> {
     // read header
     let mut cursor = Cursor::new(&data);
     let response_header = NmpHdr::deserialize(&mut cursor).unwrap();
@@ -561,9 +847,48 @@ pub fn transceive(
 
 #[cfg(test)]
 mod tests {
-    use super::next_seq_id;
+    use super::{
+        decode_packet, encode_request, is_transient_error, next_seq_id, FrameDecoder,
+        TransportError,
+    };
+    use crate::nmp_hdr::{NmpGroup, NmpIdDef, NmpOp};
     use std::collections::HashSet;
 
+    #[test]
+    fn test_frame_decoder() {
+        let body = serde_cbor::to_vec(&"x".repeat(300)).unwrap();
+        // line length not a multiple of 4, so lines split base64 quads
+        let (data, _) =
+            encode_request(62, NmpOp::Write, NmpGroup::Default, NmpIdDef::Echo, &body, 7).unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decoder.push_line(b"console output").unwrap(), None);
+        let lines: Vec<&[u8]> = data.split(|&b| b == b'\n').filter(|l| !l.is_empty()).collect();
+        assert!(lines.len() > 2);
+        let (last, first) = lines.split_last().unwrap();
+        for line in first {
+            assert_eq!(decoder.push_line(line).unwrap(), None);
+        }
+        let packet = decoder.push_line(last).unwrap().unwrap();
+        let (header, decoded_body) = decode_packet(&packet).unwrap();
+        assert_eq!(header.seq, 7);
+        assert_eq!(header.group, NmpGroup::Default);
+        assert_eq!(decoded_body, &body[..]);
+
+        // a corrupted line fails the checksum
+        let mut corrupted = lines[1].to_vec();
+        corrupted[10] = if corrupted[10] == b'A' { b'B' } else { b'A' };
+        decoder.push_line(lines[0]).unwrap();
+        decoder.push_line(&corrupted).unwrap();
+        let mut result = Ok(None);
+        for line in &lines[2..] {
+            result = decoder.push_line(line);
+        }
+        let err = result.unwrap_err();
+        assert_eq!(err.downcast_ref::<TransportError>(), Some(&TransportError::Crc));
+        assert!(is_transient_error(&err));
+    }
+
     #[test]
     fn test_next_seq_id() {
         let mut ids = HashSet::new();
@@ -0,0 +1,626 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! One-shot firmware update: check the device, upload, mark for test, reset,
+//! wait for the reboot, check that the new image runs and confirm it
+
+use anyhow::{bail, Error, Result};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::default::{reset, reset_transport};
+use crate::image::*;
+use crate::mcuboot::{ImageVersion, McubootImage};
+use crate::nmp_hdr::{BootloaderInfoRsp, ImageStateEntry, ImageStateRsp};
+use crate::os::{
+    bootloader_info, bootloader_info_transport, echo, echo_transport, mcuboot_mode_name,
+};
+use crate::package::UploadImage;
+use crate::transfer::{SerialSpecs, Transport};
+
+/// Interval between echo requests while the device reboots
+const REBOOT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Options of `update`
+#[derive(Debug, Clone)]
+pub struct UpdateOptions {
+    /// Only warn about downgrades and unmet dependencies
+    pub allow_downgrade: bool,
+    /// Have the device refuse images not newer than the running ones
+    pub upgrade_only: bool,
+    /// Erase the target slots before the upload
+    pub erase: bool,
+    /// Confirm the new images once they run; otherwise they stay in test and
+    /// are reverted at the next reset
+    pub confirm: bool,
+    /// Time for the upload of all images
+    pub upload_timeout: Duration,
+    /// Time for the device to answer again after the reset
+    pub reboot_timeout: Duration,
+    /// Time for marking the images for test
+    pub test_timeout: Duration,
+    /// Time for the reset request
+    pub reset_timeout: Duration,
+    /// Time for checking that the new images run after the reboot
+    pub verify_timeout: Duration,
+    /// Time for confirming the new images
+    pub confirm_timeout: Duration,
+    /// Security counters of the running images by image number, see
+    /// `UpgradeCheck`
+    pub security_counters: BTreeMap<u32, u32>,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        UpdateOptions {
+            allow_downgrade: false,
+            upgrade_only: false,
+            erase: false,
+            confirm: true,
+            upload_timeout: Duration::from_secs(600),
+            reboot_timeout: Duration::from_secs(60),
+            test_timeout: Duration::from_secs(30),
+            reset_timeout: Duration::from_secs(30),
+            verify_timeout: Duration::from_secs(30),
+            confirm_timeout: Duration::from_secs(30),
+            security_counters: BTreeMap::new(),
+        }
+    }
+}
+
+/// Phase of an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdatePhase {
+    Check,
+    Upload,
+    Test,
+    Reset,
+    Reboot,
+    Verify,
+    Confirm,
+}
+
+impl fmt::Display for UpdatePhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UpdatePhase::Check => "check",
+            UpdatePhase::Upload => "upload",
+            UpdatePhase::Test => "test",
+            UpdatePhase::Reset => "reset",
+            UpdatePhase::Reboot => "reboot",
+            UpdatePhase::Verify => "verify",
+            UpdatePhase::Confirm => "confirm",
+        })
+    }
+}
+
+/// How an update ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// The new images run and are confirmed
+    Confirmed,
+    /// The new images run in test, the next reset reverts them
+    Testing,
+    /// The new images run, the bootloader has no revert in this mode
+    Permanent,
+    /// The images already ran on the device, nothing was done
+    UpToDate,
+}
+
+impl fmt::Display for UpdateOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UpdateOutcome::Confirmed => "updated and confirmed",
+            UpdateOutcome::Testing => "updated, running in test until confirmed",
+            UpdateOutcome::Permanent => "updated, permanent (no revert in this mode)",
+            UpdateOutcome::UpToDate => "already up to date",
+        })
+    }
+}
+
+/// Image of an update
+#[derive(Debug, Clone)]
+pub struct UpdatedImage {
+    pub image: u8,
+    pub file: String,
+    /// Version running before the update
+    pub previous: Option<String>,
+    pub version: ImageVersion,
+    pub hash: Vec<u8>,
+}
+
+/// Report of a successful update
+#[derive(Debug, Clone)]
+pub struct UpdateReport {
+    /// MCUboot mode, if the device reports it
+    pub mode: Option<i32>,
+    pub images: Vec<UpdatedImage>,
+    /// Duration of each phase that ran
+    pub phases: Vec<(UpdatePhase, Duration)>,
+    pub outcome: UpdateOutcome,
+}
+
+impl fmt::Display for UpdateReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = self.mode.map_or("unknown", mcuboot_mode_name);
+        writeln!(f, "mode:    {}", mode)?;
+        for image in &self.images {
+            writeln!(
+                f,
+                "image {}: {} -> {} ({}, {})",
+                image.image,
+                image.previous.as_deref().unwrap_or("none"),
+                image.version,
+                image.file,
+                hex::encode(&image.hash)
+            )?;
+        }
+        for (phase, duration) in &self.phases {
+            writeln!(f, "{:<8} {:.1}s", format!("{phase}:"), duration.as_secs_f32())?;
+        }
+        write!(f, "result:  {}", self.outcome)
+    }
+}
+
+/// What the bootloader does with a test image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BootMode {
+    /// The image is swapped into the primary slot, and reverted unless confirmed
+    Swap,
+    /// The image is copied into the primary slot, no revert
+    Overwrite,
+    /// The image runs from its slot and is reverted unless confirmed
+    DirectXipRevert,
+    /// The newest image runs from its slot or RAM, no revert
+    Newest,
+    /// A single application or a firmware loader, no secondary slot
+    Single,
+}
+
+impl BootMode {
+    /// Boot mode of the MCUboot mode reported by bootloader info
+    pub(crate) fn from_mode(mode: Option<i32>) -> Result<BootMode, Error> {
+        Ok(match mode {
+            // devices without bootloader info are usually swapping
+            None | Some(1) | Some(3) | Some(9) => BootMode::Swap,
+            Some(2) => BootMode::Overwrite,
+            Some(5) => BootMode::DirectXipRevert,
+            Some(4) | Some(6) | Some(8) => BootMode::Newest,
+            Some(0) | Some(7) => BootMode::Single,
+            Some(mode) => bail!("unknown bootloader mode {}", mode),
+        })
+    }
+
+    /// The bootloader reverts a test image that isn't confirmed
+    pub(crate) fn reverts(self) -> bool {
+        matches!(self, BootMode::Swap | BootMode::DirectXipRevert)
+    }
+}
+
+/// Requests the update is made of, over a serial port or a transport
+trait Device {
+    fn list(&mut self) -> Result<ImageStateRsp, Error>;
+    fn bootloader_info(&mut self) -> Result<BootloaderInfoRsp, Error>;
+    fn upload(
+        &mut self,
+        images: &[UploadImage],
+        options: &UploadOptions,
+        progress: &mut dyn FnMut(u64, u64) -> Result<(), Error>,
+    ) -> Result<(), Error>;
+    fn test(&mut self, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error>;
+    fn reset(&mut self) -> Result<(), Error>;
+    fn echo(&mut self) -> Result<(), Error>;
+    /// Limit the timeout of the following requests to the time left of a
+    /// phase, or restore the timeout of the connection with `None`
+    fn limit(&mut self, left: Option<Duration>) -> Result<(), Error>;
+}
+
+/// Serial port, opened for each request with the timeouts of the specs
+struct SerialDevice<'a> {
+    specs: &'a SerialSpecs,
+    limited: SerialSpecs,
+}
+
+impl<'a> SerialDevice<'a> {
+    fn new(specs: &'a SerialSpecs) -> Self {
+        SerialDevice {
+            specs,
+            limited: specs.clone(),
+        }
+    }
+}
+
+impl Device for SerialDevice<'_> {
+    fn list(&mut self) -> Result<ImageStateRsp, Error> {
+        list(&self.limited)
+    }
+
+    fn bootloader_info(&mut self) -> Result<BootloaderInfoRsp, Error> {
+        bootloader_info(&self.limited, Some("mode"))
+    }
+
+    fn upload(
+        &mut self,
+        images: &[UploadImage],
+        options: &UploadOptions,
+        progress: &mut dyn FnMut(u64, u64) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        send_images(&self.limited, images, options, progress)
+    }
+
+    fn test(&mut self, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error> {
+        test(&self.limited, hash, confirm)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        reset(&self.limited)
+    }
+
+    fn echo(&mut self) -> Result<(), Error> {
+        echo(&self.limited, "update").map(|_| ())
+    }
+
+    fn limit(&mut self, left: Option<Duration>) -> Result<(), Error> {
+        self.limited = self.specs.clone();
+        if let Some(left) = left {
+            // the port timeout is in whole seconds
+            let secs = left.as_secs_f64().ceil() as u32;
+            self.limited.initial_timeout_s = self.specs.initial_timeout_s.min(secs.max(1));
+        }
+        Ok(())
+    }
+}
+
+/// Transport, whose timeout is restored after the update
+struct TransportDevice<'a> {
+    transport: &'a mut dyn Transport,
+    timeout_ms: Option<u32>,
+}
+
+impl<'a> TransportDevice<'a> {
+    fn new(transport: &'a mut dyn Transport) -> Self {
+        let timeout_ms = transport.timeout();
+        TransportDevice {
+            transport,
+            timeout_ms,
+        }
+    }
+}
+
+impl Device for TransportDevice<'_> {
+    fn list(&mut self) -> Result<ImageStateRsp, Error> {
+        list_transport(self.transport)
+    }
+
+    fn bootloader_info(&mut self) -> Result<BootloaderInfoRsp, Error> {
+        bootloader_info_transport(self.transport, Some("mode"))
+    }
+
+    fn upload(
+        &mut self,
+        images: &[UploadImage],
+        options: &UploadOptions,
+        progress: &mut dyn FnMut(u64, u64) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        send_images_transport(self.transport, images, options, progress)
+    }
+
+    fn test(&mut self, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error> {
+        test_transport(self.transport, hash, confirm)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        reset_transport(self.transport)
+    }
+
+    fn echo(&mut self) -> Result<(), Error> {
+        echo_transport(self.transport, "update").map(|_| ())
+    }
+
+    fn limit(&mut self, left: Option<Duration>) -> Result<(), Error> {
+        let timeout_ms = match (left, self.timeout_ms) {
+            (Some(left), Some(timeout_ms)) => timeout_ms.min(left.as_millis() as u32),
+            (Some(left), None) => left.as_millis() as u32,
+            (None, Some(timeout_ms)) => timeout_ms,
+            // no timeout to restore
+            (None, None) => return Ok(()),
+        };
+        self.transport.set_timeout(timeout_ms.max(1))
+    }
+}
+
+/// Update the device with the images of a firmware file or a DFU package,
+/// see `upload_images`
+///
+/// The images are checked against the device, uploaded, marked for test and
+/// booted. When they run, they are confirmed. If they don't come up, the
+/// bootloader reverts them and the update fails with the reason.
+pub fn update<F>(
+    specs: &SerialSpecs,
+    uploads: &[UploadImage],
+    options: &UpdateOptions,
+    progress: Option<F>,
+) -> Result<UpdateReport, Error>
+where
+    F: FnMut(u64, u64),
+{
+    run_update(&mut SerialDevice::new(specs), uploads, options, progress)
+}
+
+/// Update the device with the images of a firmware file or a DFU package
+/// using a transport, see `update`
+pub fn update_transport<F>(
+    transport: &mut dyn Transport,
+    uploads: &[UploadImage],
+    options: &UpdateOptions,
+    progress: Option<F>,
+) -> Result<UpdateReport, Error>
+where
+    F: FnMut(u64, u64),
+{
+    run_update(&mut TransportDevice::new(transport), uploads, options, progress)
+}
+
+fn run_update<F>(
+    device: &mut dyn Device,
+    uploads: &[UploadImage],
+    options: &UpdateOptions,
+    progress: Option<F>,
+) -> Result<UpdateReport, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let result = run_phases(device, uploads, options, progress);
+    // the requests after the update get the timeout of the connection again
+    let restored = device.limit(None);
+    let report = result?;
+    restored?;
+    Ok(report)
+}
+
+/// Error of an update phase
+fn phase_error(phase: UpdatePhase, e: Error) -> Error {
+    anyhow::anyhow!("update failed in the {} phase: {}", phase, e)
+}
+
+/// Run a request of a phase in the time left of the phase
+///
+/// The timeout of the request is limited to the time left, and a request
+/// finishing after the phase's time fails the phase too.
+fn within<T>(
+    device: &mut dyn Device,
+    phase: UpdatePhase,
+    start: Instant,
+    timeout: Duration,
+    request: impl FnOnce(&mut dyn Device) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let overdue = || {
+        phase_error(phase, anyhow::anyhow!("not done after {}s", timeout.as_secs()))
+    };
+    let left = timeout.saturating_sub(start.elapsed());
+    if left.is_zero() {
+        return Err(overdue());
+    }
+    device.limit(Some(left)).map_err(|e| phase_error(phase, e))?;
+    let result = request(device);
+    if start.elapsed() > timeout {
+        return Err(overdue());
+    }
+    result.map_err(|e| phase_error(phase, e))
+}
+
+fn run_phases<F>(
+    device: &mut dyn Device,
+    uploads: &[UploadImage],
+    options: &UpdateOptions,
+    mut progress: Option<F>,
+) -> Result<UpdateReport, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let mut phases = Vec::new();
+    let mut phase = |phase: UpdatePhase, start: Instant| {
+        info!("update: {} done", phase);
+        phases.push((phase, start.elapsed()));
+    };
+
+    // check the device state
+    let start = Instant::now();
+    // not all devices support bootloader info
+    let info = match device.bootloader_info() {
+        Ok(info) => Some(info),
+        Err(e) => {
+            debug!("no bootloader info: {}", e);
+            None
+        }
+    };
+    let mode = info.as_ref().and_then(|i| i.mode);
+    let no_downgrade = info.and_then(|i| i.no_downgrade).unwrap_or(false);
+    let state = device.list().map_err(|e| phase_error(UpdatePhase::Check, e))?;
+    let boot_mode = BootMode::from_mode(mode).map_err(|e| phase_error(UpdatePhase::Check, e))?;
+    if boot_mode == BootMode::Single {
+        let e = anyhow::anyhow!(
+            "the bootloader mode '{}' has no secondary slot to update",
+            mcuboot_mode_name(mode.unwrap_or_default())
+        );
+        return Err(phase_error(UpdatePhase::Check, e));
+    }
+    let mut images = Vec::new();
+    for upload in uploads {
+        let image = McubootImage::parse(&upload.data)?;
+        images.push(UpdatedImage {
+            image: upload.image,
+            file: upload.file.clone(),
+            previous: running(&state, upload.image, boot_mode).map(|e| e.version.clone()),
+            version: image.header.version,
+            hash: image.hash(),
+        });
+    }
+    if images.iter().all(|i| is_running(&state, i, boot_mode, true)) {
+        phase(UpdatePhase::Check, start);
+        return Ok(UpdateReport {
+            mode,
+            images,
+            phases,
+            outcome: UpdateOutcome::UpToDate,
+        });
+    }
+    let check = UpgradeCheck {
+        no_downgrade,
+        allow_downgrade: options.allow_downgrade,
+        security_counters: options.security_counters.clone(),
+    };
+    check_upgrade(uploads, &state, &check).map_err(|e| phase_error(UpdatePhase::Check, e))?;
+    if !boot_mode.reverts() {
+        warn!("the bootloader can't revert in this mode, a broken image stays");
+    }
+    phase(UpdatePhase::Check, start);
+
+    // upload to the secondary slots
+    let start = Instant::now();
+    let deadline = start + options.upload_timeout;
+    let upload_options = UploadOptions {
+        upgrade_only: options.upgrade_only,
+        erase: options.erase,
+        ..Default::default()
+    };
+    device
+        .upload(uploads, &upload_options, &mut |off, total| {
+            if let Some(ref mut f) = progress {
+                f(off, total);
+            }
+            if Instant::now() > deadline {
+                bail!("not done after {}s", options.upload_timeout.as_secs());
+            }
+            Ok(())
+        })
+        .map_err(|e| phase_error(UpdatePhase::Upload, e))?;
+    phase(UpdatePhase::Upload, start);
+
+    // mark for test: the bootloader boots the images once
+    let start = Instant::now();
+    if boot_mode != BootMode::Newest {
+        for image in &images {
+            within(device, UpdatePhase::Test, start, options.test_timeout, |device| {
+                device.test(image.hash.clone(), None)
+            })?;
+        }
+    }
+    phase(UpdatePhase::Test, start);
+
+    let start = Instant::now();
+    within(device, UpdatePhase::Reset, start, options.reset_timeout, |device| {
+        device.reset()
+    })?;
+    phase(UpdatePhase::Reset, start);
+
+    // wait for the device to answer again
+    let start = Instant::now();
+    loop {
+        std::thread::sleep(REBOOT_POLL_INTERVAL);
+        let left = options.reboot_timeout.saturating_sub(start.elapsed());
+        device
+            .limit(Some(left.max(REBOOT_POLL_INTERVAL)))
+            .map_err(|e| phase_error(UpdatePhase::Reboot, e))?;
+        match device.echo() {
+            Ok(()) => break,
+            Err(e) if start.elapsed() > options.reboot_timeout => {
+                let e = anyhow::anyhow!(
+                    "no answer {}s after the reset ({}){}",
+                    options.reboot_timeout.as_secs(),
+                    e,
+                    if boot_mode.reverts() {
+                        ", the bootloader reverts the images if they don't run"
+                    } else {
+                        ""
+                    }
+                );
+                return Err(phase_error(UpdatePhase::Reboot, e));
+            }
+            Err(e) => debug!("waiting for the device: {}", e),
+        }
+    }
+    phase(UpdatePhase::Reboot, start);
+
+    // the new images must run
+    let start = Instant::now();
+    let state = within(device, UpdatePhase::Verify, start, options.verify_timeout, |device| {
+        device.list()
+    })?;
+    for image in &images {
+        if !is_running(&state, image, boot_mode, false) {
+            let now = running(&state, image.image, boot_mode)
+                .map_or("no image".to_string(), |e| format!("version {}", e.version));
+            let e = if boot_mode.reverts() {
+                anyhow::anyhow!(
+                    "image {} {} didn't boot, the bootloader reverted to {}",
+                    image.image,
+                    image.version,
+                    now
+                )
+            } else {
+                anyhow::anyhow!(
+                    "image {} {} doesn't run, {} does",
+                    image.image,
+                    image.version,
+                    now
+                )
+            };
+            return Err(phase_error(UpdatePhase::Verify, e));
+        }
+    }
+    phase(UpdatePhase::Verify, start);
+
+    let outcome = if !boot_mode.reverts() {
+        UpdateOutcome::Permanent
+    } else if options.confirm {
+        let start = Instant::now();
+        for image in &images {
+            within(device, UpdatePhase::Confirm, start, options.confirm_timeout, |device| {
+                device.test(image.hash.clone(), Some(true))
+            })?;
+        }
+        let state = within(device, UpdatePhase::Confirm, start, options.confirm_timeout, |device| {
+            device.list()
+        })?;
+        if !images.iter().all(|i| is_running(&state, i, boot_mode, true)) {
+            return Err(phase_error(
+                UpdatePhase::Confirm,
+                anyhow::anyhow!("the device doesn't list the images as confirmed"),
+            ));
+        }
+        phase(UpdatePhase::Confirm, start);
+        UpdateOutcome::Confirmed
+    } else {
+        UpdateOutcome::Testing
+    };
+    Ok(UpdateReport {
+        mode,
+        images,
+        phases,
+        outcome,
+    })
+}
+
+/// Entry of the running image of an image number
+fn running(state: &ImageStateRsp, image: u8, boot_mode: BootMode) -> Option<&ImageStateEntry> {
+    let mut entries = state.images.iter().filter(|e| e.image == image as u32);
+    match boot_mode {
+        // images run from the primary slot
+        BootMode::Swap | BootMode::Overwrite | BootMode::Single => entries.find(|e| e.slot == 0),
+        BootMode::DirectXipRevert | BootMode::Newest => entries.find(|e| e.active),
+    }
+}
+
+/// Whether an image runs on the device, and is confirmed if required
+fn is_running(
+    state: &ImageStateRsp,
+    image: &UpdatedImage,
+    boot_mode: BootMode,
+    confirmed: bool,
+) -> bool {
+    running(state, image.image, boot_mode).is_some_and(|e| {
+        e.hash == image.hash && (!confirmed || e.confirmed || !boot_mode.reverts())
+    })
+}
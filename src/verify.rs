@@ -0,0 +1,242 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Verification of MCUboot image signatures against trusted public keys
+
+use anyhow::{bail, Context, Error, Result};
+use p256::pkcs8::{DecodePublicKey, EncodePublicKey};
+use rsa::pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey};
+use rsa::traits::PublicKeyParts;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+
+use crate::mcuboot::*;
+use crate::sign::{KeyType, SigningKey};
+
+/// Public key to verify images with
+#[derive(Clone)]
+pub enum VerifyingKey {
+    EcdsaP256(p256::ecdsa::VerifyingKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+    Rsa(rsa::RsaPublicKey),
+}
+
+impl fmt::Debug for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VerifyingKey({})", self.key_type())
+    }
+}
+
+impl VerifyingKey {
+    /// Read a public key in PEM, SubjectPublicKeyInfo or PKCS#1 RSA, or take
+    /// the public part of a private key
+    pub fn from_pem(pem: &str) -> Result<VerifyingKey, Error> {
+        if pem.contains("PRIVATE KEY-----") {
+            return Ok(SigningKey::from_pem(pem)?.verifying_key());
+        }
+        if pem.contains("-----BEGIN RSA PUBLIC KEY-----") {
+            let key = rsa::RsaPublicKey::from_pkcs1_pem(pem).context("invalid RSA key")?;
+            return VerifyingKey::rsa(key);
+        }
+        if let Ok(key) = p256::ecdsa::VerifyingKey::from_public_key_pem(pem) {
+            return Ok(VerifyingKey::EcdsaP256(key));
+        }
+        if let Ok(key) = ed25519_dalek::VerifyingKey::from_public_key_pem(pem) {
+            return Ok(VerifyingKey::Ed25519(key));
+        }
+        if let Ok(key) = rsa::RsaPublicKey::from_public_key_pem(pem) {
+            return VerifyingKey::rsa(key);
+        }
+        bail!("not an ECDSA P-256, Ed25519, RSA-2048 or RSA-3072 key in PEM")
+    }
+
+    fn rsa(key: rsa::RsaPublicKey) -> Result<VerifyingKey, Error> {
+        match key.size() * 8 {
+            2048 | 3072 => Ok(VerifyingKey::Rsa(key)),
+            bits => bail!("RSA keys of {} bits are not supported", bits),
+        }
+    }
+
+    /// Read a key from a PEM file
+    pub fn load(path: &Path) -> Result<VerifyingKey, Error> {
+        let pem = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        VerifyingKey::from_pem(&pem).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            VerifyingKey::EcdsaP256(_) => KeyType::EcdsaP256,
+            VerifyingKey::Ed25519(_) => KeyType::Ed25519,
+            VerifyingKey::Rsa(key) if key.size() == 256 => KeyType::Rsa2048,
+            VerifyingKey::Rsa(_) => KeyType::Rsa3072,
+        }
+    }
+
+    /// Public key as embedded in MCUboot: SubjectPublicKeyInfo DER for EC
+    /// keys, PKCS#1 DER for RSA keys
+    pub fn to_der(&self) -> Result<Vec<u8>, Error> {
+        let der = match self {
+            VerifyingKey::EcdsaP256(key) => key.to_public_key_der()?.into_vec(),
+            VerifyingKey::Ed25519(key) => key.to_public_key_der()?.into_vec(),
+            VerifyingKey::Rsa(key) => key.to_pkcs1_der()?.into_vec(),
+        };
+        Ok(der)
+    }
+
+    /// SHA256 of the public key, as in the `KEYHASH` TLV
+    pub fn key_hash(&self) -> Result<Vec<u8>, Error> {
+        Ok(Sha256::digest(self.to_der()?).to_vec())
+    }
+
+    /// Check a signature of the image hash
+    fn verify(&self, digest: &[u8], signature: &[u8]) -> bool {
+        match self {
+            VerifyingKey::EcdsaP256(key) => {
+                use p256::ecdsa::signature::hazmat::PrehashVerifier;
+                // older imgtool versions pad the DER signature with zeros
+                let len = signature.get(1).map_or(0, |len| *len as usize + 2);
+                let der = &signature[..len.min(signature.len())];
+                let Ok(signature) = p256::ecdsa::Signature::from_der(der) else {
+                    return false;
+                };
+                key.verify_prehash(digest, &signature).is_ok()
+            }
+            VerifyingKey::Ed25519(key) => {
+                use ed25519_dalek::Verifier;
+                let Ok(signature) = ed25519_dalek::Signature::from_slice(signature) else {
+                    return false;
+                };
+                key.verify(digest, &signature).is_ok()
+            }
+            VerifyingKey::Rsa(key) => {
+                let padding = rsa::Pss::new_with_salt::<Sha256>(32);
+                key.verify(padding, digest, signature).is_ok()
+            }
+        }
+    }
+}
+
+/// Image whose signature was verified
+#[derive(Debug, Clone)]
+pub struct VerifiedImage {
+    pub version: ImageVersion,
+    /// Hash identifying the image on the device
+    pub hash: Vec<u8>,
+    /// SHA256 of the public key of the signer
+    pub key_hash: Vec<u8>,
+    pub key_type: KeyType,
+    /// Index of the signer in the trusted keys
+    pub key_index: usize,
+    /// The payload is encrypted, only the bootloader can check its hash
+    pub encrypted: bool,
+}
+
+/// Check that an image is intact and signed by one of the trusted keys, the
+/// way MCUboot checks it at boot
+pub fn verify_image(data: &[u8], keys: &[VerifyingKey]) -> Result<VerifiedImage, Error> {
+    let image = McubootImage::parse(data)?;
+    image.check_hash()?;
+    let digest = image.hash();
+
+    let key_hash = match (image.tlv(TLV_KEYHASH), image.tlv(TLV_PUBKEY)) {
+        (Some(hash), _) => hash.to_vec(),
+        (None, Some(key)) => Sha256::digest(key).to_vec(),
+        (None, None) if signatures(&image).next().is_none() => bail!("the image is not signed"),
+        (None, None) => bail!("the image has no key hash or public key TLV"),
+    };
+    let Some((key_index, key)) = keys
+        .iter()
+        .enumerate()
+        .find(|(_, key)| key.key_hash().is_ok_and(|hash| hash == key_hash))
+    else {
+        bail!(
+            "the image is signed by an untrusted key, key hash {}",
+            hex::encode(&key_hash)
+        );
+    };
+
+    let kind = key.key_type().signature_tlv();
+    let mut found = false;
+    for tlv in signatures(&image).filter(|t| t.kind == kind) {
+        found = true;
+        if key.verify(&digest, &tlv.value) {
+            return Ok(VerifiedImage {
+                version: image.header.version,
+                hash: digest,
+                key_hash,
+                key_type: key.key_type(),
+                key_index,
+                encrypted: image.header.is_encrypted(),
+            });
+        }
+    }
+    if !found {
+        bail!(
+            "the image has no {} signature for key hash {}",
+            key.key_type(),
+            hex::encode(&key_hash)
+        );
+    }
+    bail!(
+        "invalid {} signature of key hash {}",
+        key.key_type(),
+        hex::encode(&key_hash)
+    )
+}
+
+fn signatures(image: &McubootImage) -> impl Iterator<Item = &Tlv> {
+    image.tlvs.iter().filter(|t| {
+        !t.protected
+            && [TLV_RSA2048_PSS, TLV_ECDSA_SIG, TLV_RSA3072_PSS, TLV_ED25519].contains(&t.kind)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sign::{sign_image, PublicKeyFormat, SignOptions};
+
+    #[test]
+    fn test_verify_image() {
+        let binary = vec![0x5a; 3000];
+        let mut options = SignOptions {
+            version: "1.2.3".parse().unwrap(),
+            pad_header: true,
+            ..Default::default()
+        };
+        let other = SigningKey::generate(KeyType::Ed25519).unwrap().verifying_key();
+        for key_type in [KeyType::EcdsaP256, KeyType::Ed25519, KeyType::Rsa2048] {
+            let key = SigningKey::generate(key_type).unwrap();
+            let public = key.verifying_key();
+            let pem = match &public {
+                VerifyingKey::Rsa(key) => key.to_pkcs1_pem(Default::default()).unwrap(),
+                VerifyingKey::EcdsaP256(key) => key.to_public_key_pem(Default::default()).unwrap(),
+                VerifyingKey::Ed25519(key) => key.to_public_key_pem(Default::default()).unwrap(),
+            };
+            let parsed = VerifyingKey::from_pem(&pem).unwrap();
+            assert_eq!(parsed.to_der().unwrap(), public.to_der().unwrap());
+
+            options.public_key_format = PublicKeyFormat::Full;
+            let mut data = sign_image(&binary, Some(&key), &options).unwrap();
+            let verified = verify_image(&data, &[other.clone(), public.clone()]).unwrap();
+            assert_eq!(verified.key_index, 1);
+            assert_eq!(verified.key_type, key_type);
+            assert_eq!(verified.key_hash, public.key_hash().unwrap());
+            assert!(!verified.encrypted);
+
+            let err = verify_image(&data, std::slice::from_ref(&other)).unwrap_err();
+            assert!(err.to_string().contains("untrusted key"), "{err}");
+
+            // a bad signature of the right key
+            let len = data.len();
+            data[len - 10] ^= 1;
+            let err = verify_image(&data, std::slice::from_ref(&public)).unwrap_err();
+            assert!(err.to_string().contains("invalid"), "{err}");
+        }
+
+        let data = sign_image(&binary, None, &options).unwrap();
+        let err = verify_image(&data, &[other]).unwrap_err();
+        assert_eq!(err.to_string(), "the image is not signed");
+    }
+}
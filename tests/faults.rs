@@ -0,0 +1,264 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! Upload retry and resume behavior on bad links, with faults injected into
+//! the frames of the simulated serial device and into a UDP transport, and
+//! replayed from a recording
+
+use mcumgr_client::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+fn specs(name: &str) -> SerialSpecs {
+    SerialSpecs {
+        device: format!("test:{name}"),
+        initial_timeout_s: 1,
+        subsequent_timeout_ms: 200,
+        nb_retry: 4,
+        linelength: 128,
+        mtu: 256,
+        baudrate: 115_200,
+    }
+}
+
+/// Upload options writing to an image
+fn target(image: u8) -> UploadOptions {
+    UploadOptions {
+        image,
+        ..Default::default()
+    }
+}
+
+fn image_file(name: &str, size: usize) -> (PathBuf, Vec<u8>) {
+    let payload: Vec<u8> = (0..size).map(|i| (i * 13 % 251) as u8).collect();
+    let image = build_image((1, 4, 0, 0), &payload);
+    let path = std::env::temp_dir().join(format!("mcumgr-faults-{}-{}", std::process::id(), name));
+    std::fs::write(&path, &image).unwrap();
+    (path, image)
+}
+
+fn scenario(json: &str) -> FaultScenario {
+    serde_json::from_str(json).unwrap()
+}
+
+#[test]
+fn serial_upload_survives_frame_faults() {
+    set_port_faults(
+        "link",
+        Some(scenario(
+            r#"{
+                "seed": 1234,
+                "rules": [
+                    { "fault": "drop", "command": "Image/Upload", "probability": 0.08 },
+                    { "fault": "duplicate", "command": "Image/Upload", "probability": 0.08 },
+                    { "fault": "reorder", "command": "Image/Upload", "probability": 0.08 },
+                    { "fault": "corrupt_crc", "command": "Image/Upload", "probability": 0.08 },
+                    { "fault": "truncate_base64", "command": "Image/Upload", "probability": 0.08 },
+                    { "fault": "wrong_seq", "command": "Image/Upload", "probability": 0.08 },
+                    { "fault": { "delay": { "ms": 300 } }, "nth": 5 }
+                ]
+            }"#,
+        )),
+    );
+    let (path, image) = image_file("link.bin", 20_000);
+    upload(&specs("link"), &path, &target(0), None::<fn(u64, u64)>).unwrap();
+
+    assert_eq!(
+        shared_simulator("link").lock().unwrap().slot_data(0, 1),
+        Some(&image[..])
+    );
+    let log = port_fault_log("link").join("\n");
+    for fault in ["Drop", "Duplicate", "Reorder", "CorruptCrc", "TruncateBase64", "WrongSeq", "Delay"] {
+        assert!(log.contains(fault), "no {fault} injected:\n{log}");
+    }
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn serial_upload_restarts_after_device_reset() {
+    set_port_faults(
+        "reset",
+        Some(scenario(r#"{ "rules": [ { "fault": "reset", "offset": 6000, "times": 1 } ] }"#)),
+    );
+    let (path, image) = image_file("reset.bin", 10_000);
+    let mut offsets = Vec::new();
+    upload(&specs("reset"), &path, &target(0), Some(|off, _| offsets.push(off))).unwrap();
+
+    // the device lost the transfer and asked to start over
+    assert!(offsets.windows(2).any(|w| w[1] < w[0]), "{offsets:?}");
+    let simulator = shared_simulator("reset");
+    let simulator = simulator.lock().unwrap();
+    assert_eq!(simulator.resets(), 1);
+    assert_eq!(simulator.slot_data(0, 1), Some(&image[..]));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn serial_upload_resumes_after_error() {
+    set_port_faults(
+        "resume",
+        Some(scenario(
+            r#"{ "rules": [ { "fault": { "error_rc": { "rc": 6 } }, "offset": 5000, "times": 1 } ] }"#,
+        )),
+    );
+    let (path, image) = image_file("resume.bin", 10_000);
+    let err = upload(&specs("resume"), &path, &target(0), None::<fn(u64, u64)>).unwrap_err();
+    assert_eq!(err.to_string(), "rc = 6");
+
+    // the same image is continued where it stopped
+    let mut offsets = Vec::new();
+    upload(&specs("resume"), &path, &target(0), Some(|off, _| offsets.push(off))).unwrap();
+    assert!(offsets[0] > 4000 && offsets[0] <= 5000, "{offsets:?}");
+    assert_eq!(
+        shared_simulator("resume").lock().unwrap().slot_data(0, 1),
+        Some(&image[..])
+    );
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn transport_upload_retries() {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let server = UdpServer::spawn(simulator.clone()).unwrap();
+    let udp = UdpTransport::new(&UdpSpecs {
+        host: "127.0.0.1".to_string(),
+        port: server.addr().port(),
+        timeout_s: 1,
+        mtu: 256,
+    })
+    .unwrap();
+    let mut transport = FaultTransport::new(
+        udp,
+        scenario(
+            r#"{
+                "seed": 2,
+                "rules": [
+                    { "fault": "drop", "probability": 0.04 },
+                    { "fault": "duplicate", "probability": 0.04 },
+                    { "fault": "corrupt_crc", "probability": 0.04 },
+                    { "fault": "wrong_seq", "probability": 0.04 },
+                    { "fault": "reorder", "probability": 0.04 },
+                    { "fault": "truncate_base64", "probability": 0.04 },
+                    { "fault": "reset", "offset": 3000, "times": 1 }
+                ]
+            }"#,
+        ),
+    );
+
+    let (path, image) = image_file("udp.bin", 12_000);
+    upload_image_transport(&mut transport, &path, &target(0), None::<fn(u64, u64)>).unwrap();
+    assert!(transport.injector().log().len() > 5);
+    let log = transport.injector().log().join("\n");
+    for fault in ["Reorder", "TruncateBase64"] {
+        assert!(log.contains(fault), "no {fault} injected:\n{log}");
+    }
+    let simulator = simulator.lock().unwrap();
+    assert_eq!(simulator.resets(), 1);
+    assert_eq!(simulator.slot_data(0, 1), Some(&image[..]));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn retries_are_limited() {
+    set_port_faults(
+        "dead",
+        Some(scenario(r#"{ "rules": [ { "fault": "drop", "command": "Image/Upload", "nth": 1 }, { "fault": "drop", "offset": 512 } ] }"#)),
+    );
+    let (path, _) = image_file("dead.bin", 4000);
+    let err = upload(&specs("dead"), &path, &target(0), None::<fn(u64, u64)>).unwrap_err();
+    assert!(is_transient_error(&err), "{err}");
+    // first chunk retried once, second chunk sent with all retries
+    assert_eq!(port_fault_log("dead").len(), 6);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn update_phase_timeout() {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let server = UdpServer::spawn(simulator.clone()).unwrap();
+    let udp = UdpTransport::new(&UdpSpecs {
+        host: "127.0.0.1".to_string(),
+        port: server.addr().port(),
+        timeout_s: 1,
+        mtu: 256,
+    })
+    .unwrap();
+    // the reset answers late, after the upload and the test phase went well
+    let mut transport = FaultTransport::new(
+        udp,
+        scenario(
+            r#"{ "rules": [
+                { "fault": { "delay": { "ms": 1500 } }, "command": "Default/Reset" }
+            ] }"#,
+        ),
+    );
+    let options = UpdateOptions {
+        reset_timeout: std::time::Duration::from_secs(1),
+        ..Default::default()
+    };
+
+    let (path, _) = image_file("phase.bin", 2000);
+    let images = upload_images(&path, 0).unwrap();
+    let err = update_transport(&mut transport, &images, &options, None::<fn(u64, u64)>)
+        .unwrap_err();
+    assert_eq!(err.to_string(), "update failed in the reset phase: not done after 1s");
+    assert_eq!(transport.injector().log().len(), 1);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn update_request_limited_to_phase_timeout() {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let server = UdpServer::spawn(simulator.clone()).unwrap();
+    let udp = UdpTransport::new(&UdpSpecs {
+        host: "127.0.0.1".to_string(),
+        port: server.addr().port(),
+        timeout_s: 10,
+        mtu: 256,
+    })
+    .unwrap();
+    // the reset isn't answered within the transport timeout
+    let mut transport = FaultTransport::new(
+        udp,
+        scenario(
+            r#"{ "rules": [
+                { "fault": { "delay": { "ms": 20000 } }, "command": "Default/Reset" }
+            ] }"#,
+        ),
+    );
+    let options = UpdateOptions {
+        reset_timeout: std::time::Duration::from_secs(1),
+        ..Default::default()
+    };
+
+    let (path, _) = image_file("limit.bin", 2000);
+    let images = upload_images(&path, 0).unwrap();
+    let start = std::time::Instant::now();
+    let err = update_transport(&mut transport, &images, &options, None::<fn(u64, u64)>)
+        .unwrap_err();
+    assert_eq!(err.to_string(), "update failed in the reset phase: not done after 1s");
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    // the transport timeout is restored
+    assert_eq!(transport.timeout(), Some(10_000));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn replay_retries_like_the_recording() {
+    // upload recorded over UDP with a timeout, a checksum error and a wrong
+    // sequence number, each retried
+    let recording = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/upload-retries.jsonl");
+    let mut replay = ReplayTransport::open(&recording).unwrap().strict(true);
+
+    let (path, _) = image_file("replay.bin", 2000);
+    upload_image_transport(&mut replay, &path, &target(0), None::<fn(u64, u64)>).unwrap();
+    assert_eq!(replay.remaining(), 0);
+
+    std::fs::remove_file(path).unwrap();
+}
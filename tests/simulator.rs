@@ -0,0 +1,886 @@
+// Copyright © 2026 Rudis Laboratories LLC
+
+//! End to end tests of all commands against the simulated device, over the
+//! serial framing (`-d test:<name>`), UDP, TCP and a pseudo-terminal
+
+use mcumgr_client::*;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Serial specs of a simulated device, each test uses its own one
+fn specs(name: &str) -> SerialSpecs {
+    SerialSpecs {
+        device: format!("test:{name}"),
+        initial_timeout_s: 1,
+        subsequent_timeout_ms: 200,
+        nb_retry: 1,
+        linelength: 128,
+        mtu: 256,
+        baudrate: 115_200,
+    }
+}
+
+/// Upload options writing to an image
+fn target(image: u8) -> UploadOptions {
+    UploadOptions {
+        image,
+        ..Default::default()
+    }
+}
+
+/// Images of a firmware file, as image `image`
+fn images(path: &Path, image: u8) -> Vec<UploadImage> {
+    upload_images(path, image).unwrap()
+}
+
+fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("mcumgr-sim-{}-{}", std::process::id(), name));
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+fn udp(server: &UdpServer) -> UdpTransport {
+    UdpTransport::new(&UdpSpecs {
+        host: "127.0.0.1".to_string(),
+        port: server.addr().port(),
+        timeout_s: 2,
+        mtu: 512,
+    })
+    .unwrap()
+}
+
+#[test]
+fn os_commands() {
+    let specs = specs("os");
+    assert_eq!(echo(&specs, "hello").unwrap(), "hello");
+    assert_eq!(os_info(&specs, None).unwrap(), "Zephyr");
+    assert_eq!(os_info(&specs, Some("si")).unwrap(), "Zephyr native_sim");
+    assert!(os_info(&specs, Some("x")).is_err());
+    assert!(taskstat(&specs).unwrap().tasks.contains_key("main"));
+    assert_eq!(mcumgr_params(&specs).unwrap().buf_size, 1024);
+    assert_eq!(bootloader_info(&specs, None).unwrap().bootloader, "MCUboot");
+    assert_eq!(bootloader_info(&specs, Some("mode")).unwrap().mode, Some(3));
+
+    reset(&specs).unwrap();
+    assert_eq!(shared_simulator("os").lock().unwrap().resets(), 1);
+}
+
+#[test]
+fn image_test_and_confirm() {
+    let specs = specs("image");
+    let initial = list(&specs).unwrap();
+    assert_eq!(initial.images.len(), 2);
+    assert!(initial.images[0].active && initial.images[0].confirmed);
+    assert_eq!(initial.images[0].version, "1.0.0");
+
+    let image = build_image((1, 1, 0, 0), &[0xa5; 3000]);
+    let path = temp_file("image.bin", &image);
+    let mut progress = Vec::new();
+    upload(&specs, &path, &target(0), Some(|off, total| progress.push((off, total)))).unwrap();
+    assert_eq!(progress.last(), Some(&(image.len() as u64, image.len() as u64)));
+
+    let state = list(&specs).unwrap();
+    let secondary = state.images.iter().find(|i| i.image == 0 && i.slot == 1).unwrap();
+    assert_eq!(secondary.version, "1.1.0");
+    assert_eq!(
+        shared_simulator("image").lock().unwrap().slot_data(0, 1),
+        Some(&image[..])
+    );
+
+    // the running image can't be tested, unknown hashes are rejected
+    assert!(test(&specs, initial.images[0].hash.clone(), None).is_err());
+    assert!(test(&specs, vec![0; 32], None).is_err());
+
+    test(&specs, secondary.hash.clone(), None).unwrap();
+    let state = list(&specs).unwrap();
+    assert!(state.images.iter().any(|i| i.slot == 1 && i.pending && !i.permanent));
+
+    // boot the test image, then confirm it
+    reset(&specs).unwrap();
+    let state = list(&specs).unwrap();
+    assert_eq!(state.images[0].version, "1.1.0");
+    assert!(state.images[0].active && !state.images[0].confirmed);
+    test(&specs, Vec::new(), Some(true)).unwrap();
+    reset(&specs).unwrap();
+    let state = list(&specs).unwrap();
+    assert_eq!(state.images[0].version, "1.1.0");
+    assert!(state.images[0].confirmed);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn image_revert_and_erase() {
+    let specs = specs("revert");
+    let image = build_image((2, 0, 0, 7), &[0x11; 700]);
+    let path = temp_file("revert.bin", &image);
+    upload(&specs, &path, &target(1), None::<fn(u64, u64)>).unwrap();
+
+    let state = list(&specs).unwrap();
+    let secondary = state.images.iter().find(|i| i.image == 1 && i.slot == 1).unwrap();
+    assert_eq!(secondary.version, "2.0.0.7");
+    test(&specs, secondary.hash.clone(), None).unwrap();
+
+    // the secondary slot can't be erased while it's pending, the device
+    // refuses it too when forced
+    let err = erase(&specs, Some(3), false).unwrap_err();
+    assert!(err.to_string().contains("holds a pending image"), "{err}");
+    assert!(erase(&specs, Some(3), true).is_err());
+
+    // not confirmed: the second reset reverts to the previous image
+    reset(&specs).unwrap();
+    assert_eq!(list(&specs).unwrap().images[1].version, "2.0.0.7");
+    reset(&specs).unwrap();
+    let state = list(&specs).unwrap();
+    let primary = state.images.iter().find(|i| i.image == 1 && i.slot == 0).unwrap();
+    assert_eq!(primary.version, "1.0.0");
+    assert!(primary.confirmed);
+
+    // the primary slot can't be erased, the secondary one can
+    let err = erase(&specs, Some(2), false).unwrap_err();
+    assert!(err.to_string().contains("slot 2 holds the running image (1.0.0)"), "{err}");
+    erase(&specs, Some(3), false).unwrap();
+    let state = list(&specs).unwrap();
+    assert!(!state.images.iter().any(|i| i.image == 1 && i.slot == 1));
+
+    // erase the target slot before uploading
+    let options = UploadOptions {
+        image: 1,
+        erase: true,
+        ..Default::default()
+    };
+    upload(&specs, &path, &options, None::<fn(u64, u64)>).unwrap();
+    let state = list(&specs).unwrap();
+    assert!(state.images.iter().any(|i| i.image == 1 && i.slot == 1));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn image_upload_hex() {
+    let specs = specs("hex");
+    let image = build_image((1, 3, 0, 0), &[0x77; 1500]);
+
+    // Intel HEX at 0x0000c000, like zephyr.signed.hex
+    let record = |kind: u8, addr: u16, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        format!(":{}\n", hex::encode_upper(bytes))
+    };
+    let mut hex = String::new();
+    for (i, chunk) in image.chunks(16).enumerate() {
+        hex += &record(0, 0xc000 + i as u16 * 16, chunk);
+    }
+    hex += &record(1, 0, &[]);
+    let hex_path = temp_file("image.hex", hex.as_bytes());
+    upload(&specs, &hex_path, &target(0), None::<fn(u64, u64)>).unwrap();
+    assert_eq!(
+        shared_simulator("hex").lock().unwrap().slot_data(0, 1),
+        Some(&image[..])
+    );
+
+    // a corrupted image is refused before sending anything
+    let mut corrupted = image.clone();
+    corrupted[100] ^= 1;
+    let path = temp_file("corrupted.bin", &corrupted);
+    let err = upload(&specs, &path, &target(0), None::<fn(u64, u64)>).unwrap_err();
+    assert!(format!("{err:#}").contains("SHA256 mismatch"), "{err:#}");
+
+    std::fs::remove_file(hex_path).unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn dfu_package() {
+    let specs = specs("package");
+    let app = build_image((2, 0, 0, 0), &[0xaa; 3000]);
+    let net = build_image((2, 0, 1, 0), &[0xbb; 1000]);
+    let manifest = r#"{
+        "format-version": 0,
+        "files": [
+            { "type": "application", "image_index": "0", "file": "app_update.bin" },
+            { "type": "application", "image_index": "1", "file": "net_core_app_update.bin" }
+        ],
+        "name": "dfu_application"
+    }"#;
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, data) in [
+        ("manifest.json", manifest.as_bytes()),
+        ("app_update.bin", &app),
+        ("net_core_app_update.bin", &net),
+    ] {
+        zip.start_file(name, zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(data).unwrap();
+    }
+    let path = temp_file("dfu_application.zip", &zip.finish().unwrap().into_inner());
+
+    let mut progress = Vec::new();
+    upload(&specs, &path, &target(0), Some(|off, total| progress.push((off, total)))).unwrap();
+    let total = (app.len() + net.len()) as u64;
+    assert!(progress.iter().all(|(_, t)| *t == total));
+    assert!(progress.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(progress.last(), Some(&(total, total)));
+
+    // mark both images for test, then boot them
+    for image in images(&path, 0) {
+        test(&specs, image.hash().unwrap(), None).unwrap();
+    }
+    reset(&specs).unwrap();
+    let state = list(&specs).unwrap();
+    let versions: Vec<_> = state
+        .images
+        .iter()
+        .filter(|i| i.slot == 0)
+        .map(|i| i.version.as_str())
+        .collect();
+    assert_eq!(versions, ["2.0.0", "2.0.1"]);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn upgrade_checks() {
+    let config = SimulatorConfig {
+        no_downgrade: true,
+        ..Default::default()
+    };
+    let simulator = Arc::new(Mutex::new(Simulator::new(config)));
+    let server = UdpServer::spawn(simulator.clone()).unwrap();
+    let mut transport = udp(&server);
+
+    // the device runs 1.0.0 of both images
+    let check = UpgradeCheck::default();
+    let allow = UpgradeCheck {
+        allow_downgrade: true,
+        ..Default::default()
+    };
+    let older = temp_file("older.bin", &build_image((0, 9, 0, 0), &[1; 1000]));
+    let err = check_upload_transport(&mut transport, &images(&older, 0), &check).unwrap_err();
+    assert!(err.to_string().contains("older than the running 1.0.0"), "{err}");
+    check_upload_transport(&mut transport, &images(&older, 0), &allow).unwrap();
+    assert!(upload_image_transport(&mut transport, &older, &target(0), None::<fn(u64, u64)>).is_err());
+
+    let options = |version: &str, dependencies: Vec<(u8, &str)>| SignOptions {
+        version: version.parse().unwrap(),
+        pad_header: true,
+        dependencies: dependencies
+            .into_iter()
+            .map(|(image, version)| (image, version.parse().unwrap()))
+            .collect(),
+        ..Default::default()
+    };
+    let app = sign_image(&[2; 1000], None, &options("2.0.0", vec![(1, "1.5.0")])).unwrap();
+    let app = temp_file("app.bin", &app);
+    let err = check_upload_transport(&mut transport, &images(&app, 0), &check).unwrap_err();
+    assert!(err.to_string().contains("depends on image 1 version 1.5.0, it would be 1.0.0"), "{err}");
+
+    // a pending update of image 1 meets the dependency
+    let net = sign_image(&[3; 1000], None, &options("1.5.0", vec![])).unwrap();
+    let net_path = temp_file("net.bin", &net);
+    check_upload_transport(&mut transport, &images(&net_path, 1), &check).unwrap();
+    upload_image_transport(&mut transport, &net_path, &target(1), None::<fn(u64, u64)>).unwrap();
+    let hash = McubootImage::parse(&net).unwrap().hash();
+    test_transport(&mut transport, hash, None).unwrap();
+    check_upload_transport(&mut transport, &images(&app, 0), &check).unwrap();
+
+    // an image already on the device is only a warning
+    check_upload_transport(&mut transport, &images(&net_path, 1), &check).unwrap();
+
+    // a lower security counter than the running image is refused
+    let mut lower = options("2.1.0", vec![]);
+    lower.security_counter = Some(3);
+    let lower = temp_file("lower.bin", &sign_image(&[4; 1000], None, &lower).unwrap());
+    let counters = UpgradeCheck {
+        security_counters: [(0, 5)].into(),
+        ..Default::default()
+    };
+    let err = check_upload_transport(&mut transport, &images(&lower, 0), &counters).unwrap_err();
+    assert!(err.to_string().contains("security counter 3 is lower than the running 5"), "{err}");
+    check_upload_transport(&mut transport, &images(&lower, 0), &check).unwrap();
+    let options = UpdateOptions {
+        security_counters: [(0, 5)].into(),
+        ..Default::default()
+    };
+    let err = update_transport(&mut transport, &images(&lower, 0), &options, None::<fn(u64, u64)>)
+        .unwrap_err();
+    assert!(err.to_string().starts_with("update failed in the check phase"), "{err}");
+
+    for path in [older, app, net_path, lower] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn upgrade_only_and_hash_checks() {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let server = UdpServer::spawn(simulator.clone()).unwrap();
+    let mut transport = udp(&server);
+    let upgrade_only = UploadOptions {
+        upgrade_only: true,
+        ..Default::default()
+    };
+
+    // the device runs 1.0.0, the same version is no upgrade
+    let same = temp_file("same.bin", &build_image((1, 0, 0, 0), &[4; 1000]));
+    let err = upload_image_transport(&mut transport, &same, &upgrade_only, None::<fn(u64, u64)>)
+        .unwrap_err();
+    assert!(err.to_string().contains("not an upgrade"), "{err}");
+    upload_image_transport(&mut transport, &same, &target(0), None::<fn(u64, u64)>).unwrap();
+    let newer = temp_file("newer.bin", &build_image((1, 1, 0, 0), &[5; 1000]));
+    upload_image_transport(&mut transport, &newer, &upgrade_only, None::<fn(u64, u64)>).unwrap();
+
+    simulator.lock().unwrap().corrupt_uploads(true);
+    let err = upload_image_transport(&mut transport, &newer, &target(0), None::<fn(u64, u64)>)
+        .unwrap_err();
+    assert!(err.to_string().contains("doesn't match its SHA256"), "{err}");
+
+    for path in [same, newer] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn update_workflow() {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let server = UdpServer::spawn(simulator.clone()).unwrap();
+    let mut transport = udp(&server);
+    let options = UpdateOptions {
+        reboot_timeout: std::time::Duration::from_secs(2),
+        ..Default::default()
+    };
+
+    let image = build_image((1, 1, 0, 0), &[0x11; 3000]);
+    let path = temp_file("update.bin", &image);
+    let uploads = images(&path, 0);
+    let report =
+        update_transport(&mut transport, &uploads, &options, None::<fn(u64, u64)>).unwrap();
+    assert_eq!(report.outcome, UpdateOutcome::Confirmed);
+    assert_eq!(report.images[0].previous.as_deref(), Some("1.0.0"));
+    let phases: Vec<_> = report.phases.iter().map(|(p, _)| *p).collect();
+    assert_eq!(phases.len(), 7);
+    assert_eq!(phases.last(), Some(&UpdatePhase::Confirm));
+    let state = list_transport(&mut transport).unwrap();
+    assert_eq!((state.images[0].version.as_str(), state.images[0].confirmed), ("1.1.0", true));
+
+    let report =
+        update_transport(&mut transport, &uploads, &options, None::<fn(u64, u64)>).unwrap();
+    assert_eq!(report.outcome, UpdateOutcome::UpToDate);
+
+    // a broken image is reverted by the bootloader
+    let broken = build_image((1, 2, 0, 0), &[0x22; 3000]);
+    simulator
+        .lock()
+        .unwrap()
+        .crash_at_boot(&McubootImage::parse(&broken).unwrap().hash());
+    let broken_path = temp_file("broken.bin", &broken);
+    let broken_images = images(&broken_path, 0);
+    let err = update_transport(&mut transport, &broken_images, &options, None::<fn(u64, u64)>)
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "update failed in the verify phase: image 0 1.2.0 didn't boot, the bootloader reverted to version 1.1.0"
+    );
+
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(broken_path).unwrap();
+}
+
+#[test]
+fn update_overwrite_only() {
+    let config = SimulatorConfig {
+        mcuboot_mode: 2,
+        ..Default::default()
+    };
+    let simulator = Arc::new(Mutex::new(Simulator::new(config)));
+    let server = UdpServer::spawn(simulator.clone()).unwrap();
+    let mut transport = udp(&server);
+
+    let image = build_image((1, 5, 0, 0), &[0x55; 2000]);
+    let path = temp_file("overwrite.bin", &image);
+    let options = UpdateOptions::default();
+    let uploads = images(&path, 0);
+    let report =
+        update_transport(&mut transport, &uploads, &options, None::<fn(u64, u64)>).unwrap();
+    assert_eq!(report.outcome, UpdateOutcome::Permanent);
+    assert_eq!(simulator.lock().unwrap().slot_data(0, 0), Some(&image[..]));
+    assert!(report.to_string().contains("result:  updated, permanent"));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn fs_commands() {
+    let specs = specs("fs");
+    let options = TransferOptions::default();
+    let data: Vec<u8> = (0..2000u32).map(|i| (i * 7) as u8).collect();
+    let local = temp_file("fs-up.bin", &data);
+    let report = fs_upload(&specs, &local, "/lfs/data.bin", &options).unwrap();
+    assert_eq!(report.to_string(), "2000 bytes, verified by sha256");
+    let simulator = shared_simulator("fs");
+    assert_eq!(simulator.lock().unwrap().file("/lfs/data.bin"), Some(&data[..]));
+
+    // a mismatch is transferred again, up to the retries
+    simulator.lock().unwrap().corrupt_files(1);
+    let report = fs_upload(&specs, &local, "/lfs/data.bin", &options).unwrap();
+    assert_eq!(report.attempts, 2);
+    assert_eq!(simulator.lock().unwrap().file("/lfs/data.bin"), Some(&data[..]));
+    simulator.lock().unwrap().corrupt_files(3);
+    let err = fs_upload(&specs, &local, "/lfs/data.bin", &options).unwrap_err();
+    assert!(err.to_string().contains("doesn't match the transferred data, 3 transfers"));
+    simulator.lock().unwrap().corrupt_files(1);
+    let unchecked = TransferOptions {
+        verify: false,
+        ..Default::default()
+    };
+    let report = fs_upload(&specs, &local, "/lfs/data.bin", &unchecked).unwrap();
+    assert_eq!((report.verified, report.attempts), (None, 1));
+    fs_upload(&specs, &local, "/lfs/data.bin", &options).unwrap();
+
+    assert_eq!(fs_stat(&specs, "/lfs/data.bin").unwrap().len, 2000);
+    assert!(fs_stat(&specs, "/lfs/missing").is_err());
+
+    let crc = fs_hash(&specs, "/lfs/data.bin", None, None, None).unwrap();
+    assert_eq!(crc.hash_type, "crc32");
+    assert_eq!(crc.output, crc32fast::hash(&data).to_be_bytes());
+    let sha = fs_hash(&specs, "/lfs/data.bin", Some("sha256"), Some(10), Some(100)).unwrap();
+    assert_eq!(sha.output, Sha256::digest(&data[10..110]).to_vec());
+    assert_eq!(sha.len, 100);
+
+    let downloaded = std::env::temp_dir().join(format!("mcumgr-sim-{}-fs-down", std::process::id()));
+    fs_download(&specs, "/lfs/data.bin", &downloaded, &options).unwrap();
+    assert_eq!(std::fs::read(&downloaded).unwrap(), data);
+    assert!(!downloaded.with_extension("part").exists());
+    // a failed download leaves no partial file
+    let missing = downloaded.with_extension("missing");
+    assert!(fs_download(&specs, "/lfs/missing", &missing, &options).is_err());
+    assert!(!missing.exists() && !missing.with_extension("missing.part").exists());
+
+    // streaming to and from memory
+    let mut sink = Vec::new();
+    let report = fs_download_to(&specs, "/lfs/data.bin", &mut sink, &options).unwrap();
+    assert_eq!(report.len, 2000);
+    assert_eq!(sink, data);
+    fs_upload_from(&specs, &mut &data[..500], 500, "/lfs/part.bin", &options).unwrap();
+    assert_eq!(fs_stat(&specs, "/lfs/part.bin").unwrap().len, 500);
+    fs_upload_from(&specs, &mut &[][..], 0, "/lfs/empty.bin", &options).unwrap();
+    assert_eq!(fs_stat(&specs, "/lfs/empty.bin").unwrap().len, 0);
+    // the reader ends before the length given
+    assert!(fs_upload_from(&specs, &mut &data[..100], 500, "/lfs/short.bin", &options).is_err());
+
+    // no such directory
+    assert!(fs_upload(&specs, &local, "/lfs/nodir/data.bin", &options).is_err());
+
+    std::fs::remove_file(local).unwrap();
+    std::fs::remove_file(downloaded).unwrap();
+}
+
+#[test]
+fn fs_directory_trees() {
+    let specs = specs("fs-tree");
+    let options = TransferOptions::default();
+    let local = std::env::temp_dir().join(format!("mcumgr-sim-{}-tree", std::process::id()));
+    std::fs::create_dir_all(local.join("audio/alarms")).unwrap();
+    std::fs::create_dir_all(local.join("cache")).unwrap();
+    std::fs::write(local.join("config.json"), b"{}").unwrap();
+    std::fs::write(local.join("audio/alarms/beep.wav"), [1; 700]).unwrap();
+    std::fs::write(local.join("audio/notes.tmp"), b"x").unwrap();
+    std::fs::write(local.join("cache/data.bin"), b"cached").unwrap();
+
+    let filter = TreeFilter::new(&[], &["*.tmp".into(), "cache".into()]).unwrap();
+    let actions = fs_upload_dir(&specs, &local, "/lfs/bundle", &filter, &options, true).unwrap();
+    let steps: Vec<String> = actions.iter().map(|a| a.to_string()).collect();
+    assert_eq!(
+        steps[..3],
+        ["mkdir /lfs/bundle", "mkdir /lfs/bundle/audio", "mkdir /lfs/bundle/audio/alarms"]
+    );
+    assert_eq!(actions.len(), 5);
+    // a dry run changes nothing
+    assert!(fs_list_dir(&specs, "/lfs/bundle").is_err());
+
+    fs_upload_dir(&specs, &local, "/lfs/bundle", &filter, &options, false).unwrap();
+    let simulator = shared_simulator("fs-tree");
+    assert_eq!(
+        simulator.lock().unwrap().file("/lfs/bundle/audio/alarms/beep.wav"),
+        Some(&[1; 700][..])
+    );
+    assert!(simulator.lock().unwrap().file("/lfs/bundle/audio/notes.tmp").is_none());
+    let entries = fs_list_dir(&specs, "/lfs/bundle").unwrap();
+    assert!(entries.iter().any(|e| e.name == "audio" && e.is_dir));
+
+    // uploading again only uploads the files
+    let actions = fs_upload_dir(&specs, &local, "/lfs/bundle", &filter, &options, true).unwrap();
+    assert_eq!(actions.len(), 2);
+
+    let down = local.with_extension("down");
+    let filter = TreeFilter::new(&["*.wav".into()], &[]).unwrap();
+    let actions = fs_download_dir(&specs, "/lfs/bundle", &down, &filter, &options, false).unwrap();
+    assert_eq!(actions.len(), 4);
+    assert_eq!(std::fs::read(down.join("audio/alarms/beep.wav")).unwrap(), [1; 700]);
+    assert!(!down.join("config.json").exists());
+
+    std::fs::remove_dir_all(local).unwrap();
+    std::fs::remove_dir_all(down).unwrap();
+}
+
+#[test]
+fn fs_sync_tree() {
+    let specs = specs("fs-sync");
+    let options = TransferOptions::default();
+    let local = std::env::temp_dir().join(format!("mcumgr-sim-{}-sync", std::process::id()));
+    std::fs::create_dir_all(local.join("sounds")).unwrap();
+    std::fs::write(local.join("config.json"), b"{}").unwrap();
+    std::fs::write(local.join("sounds/beep.wav"), [1; 700]).unwrap();
+
+    let filter = TreeFilter::new(&[], &[]).unwrap();
+    let actions = fs_sync_dir(&specs, &local, "/lfs/app", &filter, false, &options, false).unwrap();
+    assert_eq!(actions.len(), 4);
+
+    // only the changed file is uploaded again
+    std::fs::write(local.join("config.json"), b"{\"on\": 1}").unwrap();
+    let actions = fs_sync_dir(&specs, &local, "/lfs/app", &filter, false, &options, false).unwrap();
+    let steps: Vec<String> = actions.iter().map(|a| a.to_string()).collect();
+    assert!(steps[0].starts_with("upload "));
+    assert_eq!(steps[1], "unchanged /lfs/app/sounds/beep.wav (same sha256)");
+    let simulator = shared_simulator("fs-sync");
+    assert_eq!(
+        simulator.lock().unwrap().file("/lfs/app/config.json"),
+        Some(&b"{\"on\": 1}"[..])
+    );
+
+    // files and directories missing locally are removed with delete only
+    fs_mkdir(&specs, "/lfs/app/old").unwrap();
+    fs_upload(&specs, &local.join("config.json"), "/lfs/app/old/stale.json", &options).unwrap();
+    std::fs::remove_file(local.join("sounds/beep.wav")).unwrap();
+    let actions = fs_sync_dir(&specs, &local, "/lfs/app", &filter, false, &options, false).unwrap();
+    assert_eq!(actions.len(), 1);
+    let actions = fs_sync_dir(&specs, &local, "/lfs/app", &filter, true, &options, false).unwrap();
+    let steps: Vec<String> = actions.iter().map(|a| a.to_string()).collect();
+    // the directory still present locally is kept
+    assert_eq!(
+        steps[1..],
+        ["rm /lfs/app/old/stale.json", "rm /lfs/app/sounds/beep.wav", "rm /lfs/app/old"]
+    );
+    assert!(fs_list_dir(&specs, "/lfs/app/old").is_err());
+    assert_eq!(fs_list_dir(&specs, "/lfs/app/sounds").unwrap(), []);
+
+    // a file that can't be hashed is uploaded again, a listing error aborts
+    let fault = |command: &str| {
+        let rule = FaultRule {
+            command: Some(command.to_string()),
+            ..FaultRule::new(Fault::ErrorRc { rc: 6 })
+        };
+        let scenario = FaultScenario {
+            rules: vec![rule],
+            ..Default::default()
+        };
+        set_port_faults("fs-sync", Some(scenario));
+    };
+    fault("Fs/FileHash");
+    let actions = fs_sync_dir(&specs, &local, "/lfs/app", &filter, false, &options, false).unwrap();
+    assert_eq!(actions.len(), 1);
+    assert!(actions[0].to_string().starts_with("upload "));
+    fault("Shell/Exec");
+    let err = fs_sync_dir(&specs, &local, "/lfs/app", &filter, false, &options, false).unwrap_err();
+    assert!(err.to_string().starts_with("fs ls /lfs/app: failed, ret=6"), "{err}");
+    set_port_faults("fs-sync", None);
+
+    std::fs::remove_dir_all(local).unwrap();
+}
+
+#[test]
+fn fs_shell_commands() {
+    let specs = specs("fs-shell");
+    let kind = |e: anyhow::Error| e.downcast_ref::<FsShellError>().map(|e| e.kind);
+    fs_mkdir(&specs, "/lfs/logs").unwrap();
+    assert_eq!(kind(fs_mkdir(&specs, "/lfs/logs").unwrap_err()), Some(FsErrorKind::Exists));
+    assert_eq!(kind(fs_mkdir(&specs, "/lfs/a/b").unwrap_err()), Some(FsErrorKind::NotFound));
+
+    let local = temp_file("fs-shell.txt", b"boot ok\n");
+    fs_upload(&specs, &local, "/lfs/logs/boot.txt", &TransferOptions::default()).unwrap();
+    let entries = fs_list_dir_long(&specs, "/lfs").unwrap();
+    assert_eq!(entries[0].to_string(), "dir           -  logs/");
+    let entries = fs_list_dir_long(&specs, "/lfs/logs").unwrap();
+    assert_eq!(
+        entries,
+        [DirEntry { name: "boot.txt".into(), is_dir: false, size: Some(8) }]
+    );
+
+    // the simulated shell has no fs mv, the file is copied
+    fs_rename(&specs, "/lfs/logs/boot.txt", "/lfs/logs/old.txt").unwrap();
+    let names: Vec<String> =
+        fs_list_dir(&specs, "/lfs/logs").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names, ["old.txt"]);
+
+    let err = fs_remove(&specs, "/lfs/logs").unwrap_err();
+    assert_eq!(err.to_string(), "fs rm /lfs/logs: directory not empty");
+    assert_eq!(kind(err), Some(FsErrorKind::NotEmpty));
+    fs_remove(&specs, "/lfs/logs/old.txt").unwrap();
+    fs_remove(&specs, "/lfs/logs").unwrap();
+    assert_eq!(kind(fs_remove(&specs, "/lfs/logs").unwrap_err()), Some(FsErrorKind::NotFound));
+    assert!(fs_rename(&specs, "/lfs/missing", "/lfs/other").is_err());
+
+    std::fs::remove_file(local).unwrap();
+}
+
+#[test]
+fn settings_commands() {
+    let specs = specs("settings");
+    settings_write(&specs, "app/name", b"sim".to_vec()).unwrap();
+    assert_eq!(settings_read(&specs, "app/name", None).unwrap().val, b"sim");
+    assert_eq!(settings_read(&specs, "app/name", Some(2)).unwrap().val, b"si");
+    assert!(settings_read(&specs, "app/missing", None).is_err());
+    settings_commit(&specs).unwrap();
+
+    // only saved settings survive a reset
+    settings_save(&specs).unwrap();
+    settings_write(&specs, "app/temp", b"1".to_vec()).unwrap();
+    reset(&specs).unwrap();
+    assert!(settings_read(&specs, "app/temp", None).is_err());
+    assert_eq!(settings_read(&specs, "app/name", None).unwrap().val, b"sim");
+
+    settings_write(&specs, "app/name", b"changed".to_vec()).unwrap();
+    settings_load(&specs).unwrap();
+    assert_eq!(settings_read(&specs, "app/name", None).unwrap().val, b"sim");
+
+    settings_delete(&specs, "app/name").unwrap();
+    assert!(settings_read(&specs, "app/name", None).is_err());
+
+    // typed values
+    let codec: SettingCodec = "u32".parse().unwrap();
+    settings_write_as(&specs, "app/interval", &codec, &SettingValue::Unsigned(1000)).unwrap();
+    assert_eq!(settings_read(&specs, "app/interval", None).unwrap().val, [0xe8, 3, 0, 0]);
+    let big = SettingCodec::new(SettingType::U32, Endian::Big);
+    assert_eq!(
+        settings_read_as(&specs, "app/interval", &big).unwrap(),
+        SettingValue::Unsigned(0xe803_0000)
+    );
+    let short = SettingCodec::new(SettingType::U16, Endian::Little);
+    assert!(settings_read_as(&specs, "app/interval", &short).is_err());
+    assert!(settings_write_as(&specs, "app/interval", &short, &SettingValue::Signed(-1)).is_err());
+}
+
+#[test]
+fn settings_schema_apply() {
+    let specs = specs("schema");
+    let schema = temp_file(
+        "settings-schema.toml",
+        br#"
+        [[settings]]
+        key = "app/interval"
+        type = "u32"
+        default = 1000
+        min = 100
+        max = 60000
+        description = "Sampling interval in ms"
+
+        [[settings]]
+        key = "app/name"
+        type = "string"
+
+        [[settings]]
+        key = "app/offset"
+        type = "i16"
+        "#,
+    );
+    let schema = SettingsSchema::load(&schema).unwrap();
+    let values = temp_file("settings.json", br#"{ "app": { "name": "probe", "interval": 500 } }"#);
+    let updates = schema.load_values(Some(&values), false).unwrap();
+    settings_apply(&specs, &updates).unwrap();
+    reset(&specs).unwrap();
+    assert_eq!(settings_read(&specs, "app/interval", None).unwrap().val, [0xf4, 1, 0, 0]);
+    assert_eq!(settings_read(&specs, "app/name", None).unwrap().val, b"probe");
+
+    let values = temp_file("settings-range.json", br#"{ "app/interval": 50 }"#);
+    assert!(schema.load_values(Some(&values), false).is_err());
+
+    // the third write fails: the first two are written back, the new one deleted
+    let values = temp_file(
+        "settings-rollback.toml",
+        br#""app/interval" = 2000
+        "app/name" = "other"
+        "app/offset" = -3"#,
+    );
+    let updates = schema.load_values(Some(&values), false).unwrap();
+    let scenario = r#"{ "rules": [ { "fault": { "error_rc": { "rc": 6 } }, "nth": 6 } ] }"#;
+    set_port_faults("schema", Some(serde_json::from_str(scenario).unwrap()));
+    let err = settings_apply(&specs, &updates).unwrap_err();
+    assert_eq!(port_fault_log("schema"), ["request 6 Config/Val: ErrorRc { rc: 6 }"]);
+    set_port_faults("schema", None);
+    assert!(err.to_string().contains("the previous values were restored"), "{err}");
+    assert_eq!(settings_read(&specs, "app/interval", None).unwrap().val, [0xf4, 1, 0, 0]);
+    assert_eq!(settings_read(&specs, "app/name", None).unwrap().val, b"probe");
+    assert!(settings_read(&specs, "app/offset", None).is_err());
+
+    // a current value that may be truncated when read back is never overwritten
+    settings_write(&specs, "app/name", vec![b'x'; 300]).unwrap();
+    let err = settings_apply(&specs, &updates).unwrap_err();
+    assert!(err.to_string().contains("may be longer than 256 bytes"), "{err}");
+    assert_eq!(settings_read(&specs, "app/name", None).unwrap().val, [b'x'; 300]);
+    assert_eq!(settings_read(&specs, "app/interval", None).unwrap().val, [0xf4, 1, 0, 0]);
+    settings_write(&specs, "app/name", b"probe".to_vec()).unwrap();
+
+    settings_write(&specs, "app/offset", vec![1]).unwrap();
+    let reports = settings_dump(&specs, &schema).unwrap();
+    let reports: Vec<_> = reports.iter().map(|r| r.to_string()).collect();
+    assert_eq!(
+        reports,
+        [
+            "app/interval = 500 (u32)",
+            "app/name = probe (string)",
+            "app/offset invalid (i16, 1 bytes can't be a i16, it takes 2)",
+        ]
+    );
+    settings_delete(&specs, "app/offset").unwrap();
+    settings_write(&specs, "app/interval", vec![0xe8, 3, 0, 0]).unwrap();
+    let reports = settings_dump(&specs, &schema).unwrap();
+    assert_eq!(reports[0].to_string(), "app/interval = 1000 (u32, default)");
+    assert_eq!(reports[2].state, SettingState::Missing);
+}
+
+#[test]
+fn stat_and_shell_commands() {
+    let specs = specs("shell");
+    assert_eq!(stat_list(&specs).unwrap().stat_list, vec!["smp"]);
+    let stats = stat_read(&specs, "smp").unwrap();
+    assert!(stats.fields["rx"] > 0);
+    assert!(stat_read(&specs, "nope").is_err());
+
+    let argv = |cmd: &str| cmd.split(' ').map(String::from).collect::<Vec<_>>();
+    let rsp = shell_exec(&specs, argv("echo hi there")).unwrap();
+    assert_eq!((rsp.o.as_str(), rsp.rc), ("hi there\n", 0));
+
+    assert_eq!(shell_exec(&specs, argv("fs mkdir /lfs/logs")).unwrap().rc, 0);
+    shared_simulator("shell")
+        .lock()
+        .unwrap()
+        .put_file("/lfs/config.txt", b"x");
+    assert_eq!(shell_exec(&specs, argv("fs ls /lfs")).unwrap().o, "logs/\nconfig.txt\n");
+    assert_eq!(shell_exec(&specs, argv("fs rm /lfs/logs")).unwrap().rc, 0);
+    assert_ne!(shell_exec(&specs, argv("fs ls /lfs/logs")).unwrap().rc, 0);
+
+    let rsp = shell_exec(&specs, argv("frobnicate")).unwrap();
+    assert_eq!(rsp.rc, -8);
+    assert!(rsp.o.contains("command not found"));
+}
+
+#[test]
+fn udp_transport() {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let server = UdpServer::spawn(simulator.clone()).unwrap();
+    let mut transport = udp(&server);
+
+    assert_eq!(echo_transport(&mut transport, "over udp").unwrap(), "over udp");
+    assert_eq!(mcumgr_params_transport(&mut transport).unwrap().buf_count, 4);
+
+    let image = build_image((1, 2, 3, 0), &[0x42; 5000]);
+    let path = temp_file("udp.bin", &image);
+    upload_image_transport(&mut transport, &path, &target(0), None::<fn(u64, u64)>).unwrap();
+    let state = list_transport(&mut transport).unwrap();
+    let secondary = state.images.iter().find(|i| i.slot == 1).unwrap();
+    assert_eq!(secondary.version, "1.2.3");
+    test_transport(&mut transport, secondary.hash.clone(), Some(true)).unwrap();
+    reset_transport(&mut transport).unwrap();
+    let state = list_transport(&mut transport).unwrap();
+    assert_eq!(state.images[0].version, "1.2.3");
+    assert!(state.images[0].confirmed);
+
+    upload_transport(&mut transport, &path, "/lfs/fw.bin", &Default::default()).unwrap();
+    // the longer timeout of the verifying hash isn't kept
+    assert_eq!(transport.timeout(), Some(200));
+    assert_eq!(stat_transport(&mut transport, "/lfs/fw.bin").unwrap().len as usize, image.len());
+    let hash = hash_transport(&mut transport, "/lfs/fw.bin", Some("sha256"), None, None).unwrap();
+    assert_eq!(hash.output, Sha256::digest(&image).to_vec());
+
+    settings_write_transport(&mut transport, "net/mode", vec![1]).unwrap();
+    assert_eq!(
+        settings_read_transport(&mut transport, "net/mode", None).unwrap().val,
+        vec![1]
+    );
+    assert_eq!(simulator.lock().unwrap().setting("net/mode"), Some(&[1u8][..]));
+
+    let rsp = shell_exec_transport(&mut transport, vec!["kernel".into(), "version".into()]).unwrap();
+    assert!(rsp.o.starts_with("Zephyr"));
+    assert!(stat_read_transport(&mut transport, "smp").is_ok());
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn persistent_storage() {
+    let dir = std::env::temp_dir().join(format!("mcumgr-sim-{}-storage", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let image = build_image((3, 0, 0, 0), &[0x5a; 2000]);
+    {
+        let simulator = Simulator::open(SimulatorConfig::default(), &dir).unwrap();
+        let simulator = Arc::new(Mutex::new(simulator));
+        let server = UdpServer::spawn(simulator.clone()).unwrap();
+        let mut transport = udp(&server);
+        let path = temp_file("storage.bin", &image);
+        upload_image_transport(&mut transport, &path, &target(0), None::<fn(u64, u64)>).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let hash = simulator.lock().unwrap().image_state().images[1].hash.clone();
+        test_transport(&mut transport, hash, Some(true)).unwrap();
+        shell_exec_transport(&mut transport, vec!["fs".into(), "mkdir".into(), "/lfs/logs".into()])
+            .unwrap();
+        simulator.lock().unwrap().put_file("/lfs/logs/boot.txt", b"ok");
+        settings_write_transport(&mut transport, "app/mode", vec![2]).unwrap();
+        settings_save_transport(&mut transport).unwrap();
+    }
+
+    // the pending image is swapped in at the first boot of the reopened device
+    let mut simulator = Simulator::open(SimulatorConfig::default(), &dir).unwrap();
+    assert_eq!(simulator.slot_data(0, 1), Some(&image[..]));
+    assert_eq!(simulator.file("/lfs/logs/boot.txt"), Some(&b"ok"[..]));
+    assert_eq!(simulator.setting("app/mode"), Some(&[2u8][..]));
+    simulator.reboot();
+    assert_eq!(simulator.image_state().images[0].version, "3.0.0");
+    drop(simulator);
+    let simulator = Simulator::open(SimulatorConfig::default(), &dir).unwrap();
+    assert_eq!(simulator.slot_data(0, 0), Some(&image[..]));
+    assert!(simulator.image_state().images[0].confirmed);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn tcp_server() {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let server = TcpServer::bind("127.0.0.1:0", simulator).unwrap();
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+
+    // echo request, sent in two parts
+    let request: BTreeMap<&str, &str> = [("d", "over tcp")].into_iter().collect();
+    let body = serde_cbor::to_vec(&request).unwrap();
+    let mut packet = vec![2, 0, 0, body.len() as u8, 0, 0, 7, 0];
+    packet.extend(body);
+    stream.write_all(&packet[..5]).unwrap();
+    stream.write_all(&packet[5..]).unwrap();
+
+    let mut response = vec![0u8; 8];
+    stream.read_exact(&mut response).unwrap();
+    response.resize(8 + response[3] as usize, 0);
+    stream.read_exact(&mut response[8..]).unwrap();
+    let (header, body) = decode_packet(&response).unwrap();
+    assert_eq!(header.seq, 7);
+    let rsp: BTreeMap<String, String> = serde_cbor::from_slice(body).unwrap();
+    assert_eq!(rsp["r"], "over tcp");
+}
+
+#[cfg(unix)]
+#[test]
+fn pty_server() {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let server = PtyServer::spawn(simulator.clone()).unwrap();
+    let mut specs = specs("unused");
+    specs.device = server.path().to_string();
+    assert_eq!(echo(&specs, "over a pty").unwrap(), "over a pty");
+    settings_write(&specs, "pty/ok", vec![1]).unwrap();
+    assert_eq!(simulator.lock().unwrap().setting("pty/ok"), Some(&[1u8][..]));
+}